@@ -0,0 +1,40 @@
+//! embedded-sdmmc-rs - Async I/O
+//!
+//! The filesystem, volume checker and SD card driver, built from the
+//! modules in `src/io` as `async` code on top of `AsyncBlockDevice` and
+//! `AsyncSpiTransport`.
+
+/// Leaves an `async fn` as it is.
+macro_rules! maybe_async {
+    ({ $($item:tt)* }) => {
+        $($item)*
+    };
+}
+
+/// Waits for an I/O operation to finish.
+macro_rules! maybe_await {
+    ($e:expr) => {
+        $e.await
+    };
+}
+
+pub(crate) use crate::blockdevice::AsyncBlockDevice as BlockDevice;
+pub(crate) use crate::sdmmc::AsyncSpiTransport as SpiTransport;
+
+#[path = "io/card.rs"]
+pub(crate) mod card;
+#[path = "io/controller.rs"]
+pub(crate) mod controller;
+#[cfg(feature = "exfat")]
+#[path = "io/exfat.rs"]
+pub(crate) mod exfat;
+#[path = "io/fat.rs"]
+pub(crate) mod fat;
+#[path = "io/fsck.rs"]
+pub(crate) mod fsck;
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
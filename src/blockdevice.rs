@@ -51,6 +51,35 @@ pub trait BlockDevice {
     fn num_blocks(&self) -> Result<BlockCount, Self::Error>;
 }
 
+/// An asynchronous version of `BlockDevice`, for devices which can let other
+/// tasks run while they wait for the hardware. Only supports devices which are
+/// <= 2 TiB in size.
+///
+/// The methods take `&mut self`, so the borrow checker makes sure only one
+/// operation is in progress at a time.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockDevice {
+    /// The errors that the `AsyncBlockDevice` can return. Must be debug
+    /// formattable.
+    type Error: core::fmt::Debug;
+    /// Read one or more blocks, starting at the given block index.
+    async fn read(
+        &mut self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error>;
+    /// Write one or more blocks, starting at the given block index.
+    async fn write(
+        &mut self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Self::Error>;
+    /// Determine how many blocks this device can hold.
+    async fn num_blocks(&mut self) -> Result<BlockCount, Self::Error>;
+}
+
 impl Block {
     /// All our blocks are a fixed length of 512 bytes. We do not support
     /// 'Advanced Format' Hard Drives with 4 KiB blocks, nor weird old
@@ -148,7 +177,7 @@ impl core::fmt::Debug for Block {
             }
             write!(fmt, " ")?;
             for &b in line {
-                if b >= 0x20 && b <= 0x7F {
+                if (0x20..=0x7F).contains(&b) {
                     write!(fmt, "{}", b as char)?;
                 } else {
                     write!(fmt, ".")?;
@@ -0,0 +1,40 @@
+//! embedded-sdmmc-rs - Blocking I/O
+//!
+//! The filesystem, volume checker and SD card driver, built from the
+//! modules in `src/io` as plain blocking code. The `asynchronous` module
+//! builds the same modules again as `async` code.
+
+/// Turns an `async fn` into an ordinary one.
+macro_rules! maybe_async {
+    ({ $(#[$meta:meta])* $vis:vis async fn $($rest:tt)* }) => {
+        $(#[$meta])* $vis fn $($rest)*
+    };
+}
+
+/// Runs an I/O operation, which doesn't need waiting for.
+macro_rules! maybe_await {
+    ($e:expr) => {
+        $e
+    };
+}
+
+pub(crate) use crate::blockdevice::BlockDevice;
+pub(crate) use crate::sdmmc::SpiTransport;
+
+#[path = "io/card.rs"]
+pub(crate) mod card;
+#[path = "io/controller.rs"]
+pub(crate) mod controller;
+#[cfg(feature = "exfat")]
+#[path = "io/exfat.rs"]
+pub(crate) mod exfat;
+#[path = "io/fat.rs"]
+pub(crate) mod fat;
+#[path = "io/fsck.rs"]
+pub(crate) mod fsck;
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1368 @@
+//! embedded-sdmmc-rs - exFAT file system implementation
+//!
+//! Implements the Extended File Allocation Table file system, as found on
+//! SDXC cards. Only available with the `exfat` feature enabled.
+//!
+//! exFAT only stores long file names. To keep `DirEntry` small, directory
+//! listings report an MS-DOS 8.3 style name derived from the long name. Like
+//! Windows NT, long names which aren't valid 8.3 names keep two characters
+//! and get four hex digits of the hash of the up-cased long name, so that
+//! names with a common prefix rarely collide. A `~N` tail tells apart the
+//! ones which still do, numbered in directory order and skipping any tail a
+//! file's long name already uses. As the tails aren't stored anywhere,
+//! creating or deleting one of those files can renumber the others. Files
+//! can be opened using either the long name or the derived short name.
+//!
+//! Files whose valid data length is shorter than their size (because
+//! another system pre-allocated them) are read as if all of their data was
+//! valid.
+
+use crate::blockdevice::BlockCount;
+use crate::fat::{VolumeName, RESERVED_ENTRIES};
+use crate::filesystem::FilenameError;
+use crate::{Attributes, Block, BlockIdx, Cluster, DirEntry, ShortFileName, Timestamp};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::warn;
+
+#[cfg(feature = "defmt-log")]
+use defmt::warn;
+
+/// The file system name found in the boot sector of an exFAT volume
+pub(crate) const FILE_SYSTEM_NAME: &[u8] = b"EXFAT   ";
+
+/// Number of blocks in a boot region (the boot sector, eight extended boot
+/// sectors, the OEM parameters, a reserved sector and the checksum sector).
+pub(crate) const BOOT_REGION_BLOCKS: u32 = 12;
+
+/// Number of 32-bit FAT entries in a block
+pub(crate) const FAT_ENTRIES_PER_BLOCK: u32 = Block::LEN_U32 / 4;
+
+/// FAT value marking a bad cluster
+pub(crate) const FAT_BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+
+/// FAT value marking the end of a cluster chain
+pub(crate) const FAT_END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+/// Length of an exFAT directory entry, in bytes
+pub(crate) const ENTRY_LEN: usize = 32;
+
+/// Marks the end of the directory. All later entries are unused too.
+pub(crate) const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+/// Set in the entry type of every entry which is in use
+pub(crate) const ENTRY_IN_USE: u8 = 0x80;
+/// Set in the entry type of every secondary entry
+const ENTRY_SECONDARY: u8 = 0x40;
+/// Describes the allocation bitmap
+pub(crate) const ENTRY_ALLOCATION_BITMAP: u8 = 0x81;
+/// Describes the up-case table
+pub(crate) const ENTRY_UPCASE_TABLE: u8 = 0x82;
+/// Holds the volume label
+pub(crate) const ENTRY_VOLUME_LABEL: u8 = 0x83;
+/// A volume label entry which is not in use
+pub(crate) const ENTRY_VOLUME_LABEL_UNUSED: u8 = 0x03;
+/// The primary entry of a file or directory
+pub(crate) const ENTRY_FILE: u8 = 0x85;
+/// Holds the size and location of a file's data
+pub(crate) const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+/// Holds part of a file's name
+pub(crate) const ENTRY_FILE_NAME: u8 = 0xC1;
+
+/// GeneralSecondaryFlags bit saying clusters have been allocated
+pub(crate) const FLAG_ALLOCATION_POSSIBLE: u8 = 0x01;
+/// GeneralSecondaryFlags bit saying the clusters are contiguous and the FAT
+/// is not used
+pub(crate) const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+/// Number of UTF-16 code units held in each File Name entry
+pub(crate) const NAME_CHARS_PER_ENTRY: usize = 15;
+
+/// Longest file name exFAT supports, in UTF-16 code units
+pub(crate) const MAX_NAME_LEN: usize = 255;
+
+/// Most secondary entries we will write for a file (one Stream Extension and
+/// enough File Name entries for the longest name)
+pub(crate) const MAX_SECONDARY_COUNT: usize = 1 + MAX_NAME_LEN.div_ceil(NAME_CHARS_PER_ENTRY);
+
+/// Identifies an exFAT Volume on the disk.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(PartialEq, Eq, Debug)]
+pub struct ExFatVolume {
+    /// The block number of the start of the partition. All other BlockIdx values are relative to this.
+    pub(crate) lba_start: BlockIdx,
+    /// The number of blocks in this volume
+    pub(crate) num_blocks: BlockCount,
+    /// The name of this volume, converted to ASCII
+    pub(crate) name: VolumeName,
+    /// The serial number from the boot sector
+    pub(crate) serial_number: u32,
+    /// Number of 512 byte blocks (or Blocks) in a cluster
+    pub(crate) blocks_per_cluster: u32,
+    /// The block the active FAT starts in. Relative to start of partition.
+    pub(crate) fat_start: BlockCount,
+    /// The block the cluster heap starts in. Relative to start of partition.
+    pub(crate) cluster_heap_start: BlockCount,
+    /// Total number of clusters
+    pub(crate) cluster_count: u32,
+    /// The cluster the root directory starts in
+    pub(crate) first_root_dir_cluster: Cluster,
+    /// The first cluster of the allocation bitmap. We check the bitmap is
+    /// contiguous when mounting the volume.
+    pub(crate) bitmap_start: Cluster,
+    /// The up-case table, used to compare file names
+    pub(crate) upcase_table: UpcaseTable,
+    /// Number of the next expected free cluster
+    pub(crate) next_free_cluster: Option<Cluster>,
+}
+
+/// Describes the up-case table of an exFAT volume, along with a decoded copy
+/// of the ASCII range so most file names can be compared without reading
+/// the disk.
+#[derive(PartialEq, Eq)]
+pub struct UpcaseTable {
+    /// The cluster the table starts in
+    pub(crate) first_cluster: Cluster,
+    /// The length of the (possibly compressed) table, in bytes
+    pub(crate) length: u32,
+    /// Up-case mapping for the first 128 characters
+    pub(crate) ascii: [u8; 128],
+}
+
+impl core::fmt::Debug for UpcaseTable {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt.debug_struct("UpcaseTable")
+            .field("first_cluster", &self.first_cluster)
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for UpcaseTable {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "UpcaseTable {{ first_cluster: {}, length: {} }}",
+            self.first_cluster,
+            self.length
+        )
+    }
+}
+
+/// Tracks our position as we step, block by block, through a chain of
+/// clusters.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ChainWalker {
+    /// The cluster we're in
+    pub(crate) cluster: Cluster,
+    /// Which block in that cluster we're at
+    pub(crate) block_in_cluster: u32,
+    /// If set, the clusters are contiguous and the FAT is not consulted
+    pub(crate) contiguous: bool,
+    /// How many more blocks we may step through, if known
+    pub(crate) blocks_left: Option<u32>,
+}
+
+/// The position of a 32-byte entry within a directory.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SlotPosition {
+    /// Which block the entry is in
+    pub(crate) walker: ChainWalker,
+    /// The offset of the entry in that block, in bytes
+    pub(crate) offset: usize,
+}
+
+/// The interesting parts of a File directory entry set.
+pub(crate) struct EntrySet {
+    /// Where the File entry lives
+    position: SlotPosition,
+    /// Number of entries following the File entry
+    pub(crate) secondary_count: u8,
+    /// FileAttributes field
+    attributes: u16,
+    /// When the file was created
+    ctime: Timestamp,
+    /// Hundredths of a second to add to `ctime`
+    ctime_hundredths: u8,
+    /// When the file was last accessed, if it has been recorded
+    atime: Option<Timestamp>,
+    /// When the file was last modified
+    mtime: Timestamp,
+    /// GeneralSecondaryFlags from the Stream Extension entry
+    flags: u8,
+    /// The length of the name, in UTF-16 code units
+    pub(crate) name_length: usize,
+    /// The hash of the up-cased name
+    pub(crate) name_hash: u16,
+    /// The first cluster of the data
+    first_cluster: Cluster,
+    /// The size of the file, in bytes
+    data_length: u64,
+    /// The name, in UTF-16 code units
+    pub(crate) name: [u16; MAX_NAME_LEN],
+}
+
+/// Assembles an `EntrySet` from a sequence of directory entries.
+pub(crate) struct EntrySetParser {
+    /// The entry set we're building
+    pub(crate) set: EntrySet,
+    /// Are we part-way through an entry set?
+    in_set: bool,
+    /// How many entries are left in this entry set
+    remaining: u8,
+    /// Index of the next entry in the set
+    index: u8,
+    /// Name characters collected so far
+    name_chars: usize,
+    /// The checksum of the entries so far
+    checksum: u16,
+    /// The checksum stored in the File entry
+    expected_checksum: u16,
+}
+
+/// A file name, encoded as UTF-16 as exFAT requires.
+pub(crate) struct LongFileName {
+    /// The name, in UTF-16 code units
+    pub(crate) chars: [u16; MAX_NAME_LEN],
+    /// How many code units are in use
+    pub(crate) len: usize,
+}
+
+impl ExFatVolume {
+    /// Number of bytes in a cluster.
+    pub(crate) fn bytes_per_cluster(&self) -> u32 {
+        self.blocks_per_cluster * Block::LEN_U32
+    }
+
+    /// Converts a cluster number (or `Cluster`) to a block number (or
+    /// `BlockIdx`). Gives an absolute `BlockIdx` you can pass to the
+    /// controller.
+    pub(crate) fn cluster_to_block(&self, cluster: Cluster) -> BlockIdx {
+        let cluster_num = match cluster {
+            Cluster::ROOT_DIR => self.first_root_dir_cluster.0,
+            c => c.0,
+        };
+        let first_block_of_cluster =
+            BlockCount((cluster_num - RESERVED_ENTRIES) * self.blocks_per_cluster);
+        self.lba_start + self.cluster_heap_start + first_block_of_cluster
+    }
+
+    /// Work out where the entry set for a `DirEntry` lives
+    pub(crate) fn slot_position(&self, entry: &DirEntry) -> SlotPosition {
+        let relative = entry.entry_block.0 - (self.lba_start + self.cluster_heap_start).0;
+        SlotPosition {
+            walker: ChainWalker {
+                cluster: Cluster(relative / self.blocks_per_cluster + RESERVED_ENTRIES),
+                block_in_cluster: relative % self.blocks_per_cluster,
+                contiguous: entry.dir_contiguous,
+                blocks_left: None,
+            },
+            offset: entry.entry_offset as usize,
+        }
+    }
+
+    /// Convert a parsed entry set into a `DirEntry`, which is known by the
+    /// given short name.
+    pub(crate) fn get_entry(&self, set: &EntrySet, name: ShortFileName) -> DirEntry {
+        DirEntry {
+            name,
+            mtime: set.mtime,
+            ctime: set.ctime,
+            ctime_hundredths: set.ctime_hundredths,
+            atime: set.atime,
+            // The bits we support are in the same place as in FAT
+            attributes: Attributes::create_from_fat(set.attributes as u8),
+            cluster: set.first_cluster,
+            size: set.data_length,
+            entry_block: set.position.walker.block(self),
+            // Safe, since Block::LEN always fits on a u32
+            entry_offset: u32::try_from(set.position.offset).unwrap(),
+            contiguous: set.flags & FLAG_NO_FAT_CHAIN != 0,
+            dir_contiguous: set.position.walker.contiguous,
+        }
+    }
+}
+
+impl ChainWalker {
+    /// Start walking at the beginning of the given cluster
+    pub(crate) fn new(cluster: Cluster, contiguous: bool, blocks_left: Option<u32>) -> ChainWalker {
+        ChainWalker {
+            cluster,
+            block_in_cluster: 0,
+            contiguous,
+            blocks_left,
+        }
+    }
+
+    /// The block we are currently at
+    pub(crate) fn block(&self, volume: &ExFatVolume) -> BlockIdx {
+        volume.cluster_to_block(self.cluster) + BlockCount(self.block_in_cluster)
+    }
+}
+
+impl EntrySet {
+    /// The short name this entry set is known by, or the stem of its alias
+    pub(crate) fn short_name(&self) -> ShortName {
+        short_name(&self.name[..self.name_length], self.name_hash)
+    }
+
+    /// The block and offset of the File entry, which identify the entry set
+    pub(crate) fn location(&self, volume: &ExFatVolume) -> (BlockIdx, usize) {
+        (self.position.walker.block(volume), self.position.offset)
+    }
+}
+
+impl EntrySetParser {
+    /// Create a parser, waiting for a File entry
+    pub(crate) fn new() -> EntrySetParser {
+        EntrySetParser {
+            set: EntrySet {
+                position: SlotPosition {
+                    walker: ChainWalker::new(Cluster(0), false, None),
+                    offset: 0,
+                },
+                secondary_count: 0,
+                attributes: 0,
+                ctime: Timestamp::from_fat(0, 0),
+                ctime_hundredths: 0,
+                atime: None,
+                mtime: Timestamp::from_fat(0, 0),
+                flags: 0,
+                name_length: 0,
+                name_hash: 0,
+                first_cluster: Cluster(0),
+                data_length: 0,
+                name: [0u16; MAX_NAME_LEN],
+            },
+            in_set: false,
+            remaining: 0,
+            index: 0,
+            name_chars: 0,
+            checksum: 0,
+            expected_checksum: 0,
+        }
+    }
+
+    /// Process the next directory entry. Returns the entry set once a valid
+    /// one is complete.
+    pub(crate) fn feed(&mut self, slot: &[u8], position: SlotPosition) -> Option<&EntrySet> {
+        let entry_type = slot[0];
+        if entry_type == ENTRY_FILE {
+            self.in_set = !slot_count_is_bad(slot[1]);
+            self.remaining = slot[1];
+            self.index = 1;
+            self.name_chars = 0;
+            self.checksum = entry_set_checksum(0, slot, true);
+            self.expected_checksum = LittleEndian::read_u16(&slot[2..4]);
+            self.set.position = position;
+            self.set.secondary_count = slot[1];
+            self.set.attributes = LittleEndian::read_u16(&slot[4..6]);
+            self.set.ctime = timestamp_from_exfat(LittleEndian::read_u32(&slot[8..12]), slot[20]);
+            self.set.ctime_hundredths = if slot[20] < 200 { slot[20] % 100 } else { 0 };
+            self.set.atime = match LittleEndian::read_u32(&slot[16..20]) {
+                0 => None,
+                atime => Some(timestamp_from_exfat(atime, 0)),
+            };
+            self.set.mtime = timestamp_from_exfat(LittleEndian::read_u32(&slot[12..16]), slot[21]);
+            self.set.name_length = 0;
+            return None;
+        }
+        if !self.in_set {
+            return None;
+        }
+        if entry_type & (ENTRY_IN_USE | ENTRY_SECONDARY) != (ENTRY_IN_USE | ENTRY_SECONDARY) {
+            // The entry set has been cut short
+            self.in_set = false;
+            return None;
+        }
+        self.checksum = entry_set_checksum(self.checksum, slot, false);
+        match entry_type {
+            ENTRY_STREAM_EXTENSION if self.index == 1 => {
+                self.set.flags = slot[1];
+                self.set.name_length = usize::from(slot[3]);
+                self.set.name_hash = LittleEndian::read_u16(&slot[4..6]);
+                self.set.first_cluster = Cluster(LittleEndian::read_u32(&slot[20..24]));
+                self.set.data_length = LittleEndian::read_u64(&slot[24..32]);
+            }
+            ENTRY_FILE_NAME => {
+                for pair in slot[2..].chunks(2) {
+                    if self.name_chars < self.set.name_length {
+                        self.set.name[self.name_chars] = LittleEndian::read_u16(pair);
+                        self.name_chars += 1;
+                    }
+                }
+            }
+            _ => {
+                // Vendor extensions, etc. Included in the checksum, but
+                // otherwise ignored.
+            }
+        }
+        self.index += 1;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.in_set = false;
+            if self.checksum != self.expected_checksum {
+                warn!("Skipping entry set with bad checksum");
+                return None;
+            }
+            if self.set.name_length == 0 || self.name_chars != self.set.name_length {
+                warn!("Skipping entry set with bad name");
+                return None;
+            }
+            return Some(&self.set);
+        }
+        None
+    }
+}
+
+impl LongFileName {
+    /// Encode a file name as UTF-16.
+    pub(crate) fn create_from_str(name: &str) -> Result<LongFileName, FilenameError> {
+        let mut lfn = LongFileName {
+            chars: [0u16; MAX_NAME_LEN],
+            len: 0,
+        };
+        for ch in name.encode_utf16() {
+            if lfn.len == MAX_NAME_LEN {
+                return Err(FilenameError::NameTooLong);
+            }
+            lfn.chars[lfn.len] = ch;
+            lfn.len += 1;
+        }
+        if lfn.len == 0 {
+            return Err(FilenameError::FilenameEmpty);
+        }
+        Ok(lfn)
+    }
+
+    /// Check the name doesn't contain any characters exFAT forbids.
+    pub(crate) fn check_valid(&self) -> Result<(), FilenameError> {
+        for &ch in &self.chars[..self.len] {
+            match ch {
+                0x0000..=0x001F
+                | 0x0022
+                | 0x002A
+                | 0x002F
+                | 0x003A
+                | 0x003C
+                | 0x003E
+                | 0x003F
+                | 0x005C
+                | 0x007C => return Err(FilenameError::InvalidCharacter),
+                _ => {}
+            }
+        }
+        match &self.chars[..self.len] {
+            [0x002E] | [0x002E, 0x002E] => Err(FilenameError::MisplacedPeriod),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decodes the (optionally compressed) up-case table, one `u16` at a time.
+pub(crate) struct UpcaseDecoder {
+    /// The character the next value maps
+    pub(crate) index: u32,
+    /// Was the last value the start of a run of unchanged characters?
+    in_run: bool,
+}
+
+impl UpcaseDecoder {
+    /// Start at the beginning of the table
+    pub(crate) fn new() -> UpcaseDecoder {
+        UpcaseDecoder {
+            index: 0,
+            in_run: false,
+        }
+    }
+
+    /// Process the next value from the table. Returns the character it maps,
+    /// and what that character maps to.
+    pub(crate) fn feed(&mut self, value: u16) -> Option<(u32, u16)> {
+        if self.in_run {
+            // This many characters map to themselves
+            self.index += u32::from(value);
+            self.in_run = false;
+            None
+        } else if value == 0xFFFF {
+            self.in_run = true;
+            None
+        } else {
+            let idx = self.index;
+            self.index += 1;
+            Some((idx, value))
+        }
+    }
+}
+
+/// Will an entry set with this many secondary entries fit our buffers?
+pub(crate) fn slot_count_is_bad(secondary_count: u8) -> bool {
+    // Need at least a Stream Extension and a File Name
+    secondary_count < 2
+}
+
+/// Calculate the checksum of an entry set, one entry at a time. The
+/// checksum field in the primary entry is skipped.
+pub(crate) fn entry_set_checksum(mut checksum: u16, slot: &[u8], is_primary: bool) -> u16 {
+    for (i, &b) in slot.iter().enumerate() {
+        if is_primary && (i == 2 || i == 3) {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(u16::from(b));
+    }
+    checksum
+}
+
+/// Calculate the checksum of a boot region, one block at a time. The
+/// VolumeFlags and PercentInUse fields in the boot sector are skipped.
+pub(crate) fn boot_checksum(mut checksum: u32, block: &[u8], is_boot_sector: bool) -> u32 {
+    for (i, &b) in block.iter().enumerate() {
+        if is_boot_sector && (i == 106 || i == 107 || i == 112) {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(u32::from(b));
+    }
+    checksum
+}
+
+/// Calculate the checksum of an up-case table, one block at a time.
+pub(crate) fn table_checksum(mut checksum: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        checksum = checksum.rotate_right(1).wrapping_add(u32::from(b));
+    }
+    checksum
+}
+
+/// Calculate the hash of an (up-cased) file name.
+pub(crate) fn name_hash(name: &[u16]) -> u16 {
+    let mut hash: u16 = 0;
+    for &ch in name {
+        for b in ch.to_le_bytes().iter() {
+            hash = hash.rotate_right(1).wrapping_add(u16::from(*b));
+        }
+    }
+    hash
+}
+
+/// Convert an exFAT timestamp (and its 10 ms increment) into a `Timestamp`
+fn timestamp_from_exfat(value: u32, increment_10ms: u8) -> Timestamp {
+    let mut timestamp = Timestamp::from_fat((value >> 16) as u16, value as u16);
+    // The increment covers the odd seconds FAT time can't hold
+    timestamp.seconds += (increment_10ms / 100).min(1);
+    timestamp
+}
+
+/// Convert a `Timestamp` into an exFAT timestamp and 10 ms increment
+pub(crate) fn timestamp_to_exfat(timestamp: Timestamp) -> (u32, u8) {
+    let value = LittleEndian::read_u32(&timestamp.serialize_to_fat());
+    (value, (timestamp.seconds % 2) * 100)
+}
+
+/// The MS-DOS 8.3 name a long file name is known by.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ShortName {
+    /// The long name is a valid 8.3 name, so it is used as it is
+    Literal(ShortFileName),
+    /// The long name isn't a valid 8.3 name. This is the stem of its alias,
+    /// which `alias_name` turns into the alias by adding a `~N` tail.
+    Alias(ShortFileName),
+}
+
+/// Derive an MS-DOS 8.3 name from a long file name and the hash of its
+/// up-cased form. Names which are valid 8.3 names are kept as they are.
+/// Everything else keeps up to two characters of the base name, then gets
+/// four hex digits of the hash and up to three characters of the extension.
+pub(crate) fn short_name(name: &[u16], hash: u16) -> ShortName {
+    let mut ascii = [0u8; 12];
+    if name.len() <= ascii.len() && name.iter().all(|&ch| ch < 0x80) {
+        for (dest, &ch) in ascii.iter_mut().zip(name.iter()) {
+            *dest = ch as u8;
+        }
+        if let Ok(s) = core::str::from_utf8(&ascii[..name.len()]) {
+            if let Ok(sfn) = ShortFileName::create_from_str_mixed_case(s) {
+                return ShortName::Literal(sfn);
+            }
+        }
+    }
+    let mut sfn = ShortFileName {
+        contents: [b' '; 11],
+    };
+    let dot = match name.iter().rposition(|&ch| ch == u16::from(b'.')) {
+        Some(0) | None => name.len(),
+        Some(idx) => idx,
+    };
+    let mut base_len = 0;
+    for &ch in &name[..dot] {
+        if let Some(b) = short_name_char(ch) {
+            if base_len < 2 {
+                sfn.contents[base_len] = b;
+                base_len += 1;
+            }
+        }
+    }
+    if base_len == 0 {
+        sfn.contents[0] = b'_';
+        base_len = 1;
+    }
+    for (idx, shift) in [12, 8, 4, 0].iter().enumerate() {
+        sfn.contents[base_len + idx] = b"0123456789ABCDEF"[usize::from((hash >> shift) & 0xF)];
+    }
+    let mut ext_len = 0;
+    for &ch in name.iter().skip(dot + 1) {
+        if let Some(b) = short_name_char(ch) {
+            if ext_len < 3 {
+                sfn.contents[8 + ext_len] = b;
+                ext_len += 1;
+            }
+        }
+    }
+    ShortName::Alias(sfn)
+}
+
+/// Add a `~N` tail to the stem of an alias, dropping characters from the
+/// end of the stem if they don't all fit.
+pub(crate) fn alias_name(stem: &ShortFileName, tail: u32) -> ShortFileName {
+    let mut digits = [0u8; 10];
+    let mut num_digits = 0;
+    let mut value = tail;
+    loop {
+        digits[num_digits] = b'0' + (value % 10) as u8;
+        num_digits += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    let stem_len = stem.contents[..8]
+        .iter()
+        .position(|&b| b == b' ')
+        .unwrap_or(8);
+    let keep = stem_len.min(8_usize.saturating_sub(num_digits + 1));
+    let mut sfn = ShortFileName {
+        contents: [b' '; 11],
+    };
+    sfn.contents[..keep].copy_from_slice(&stem.contents[..keep]);
+    sfn.contents[keep] = b'~';
+    for (idx, &digit) in digits[..num_digits].iter().rev().enumerate() {
+        sfn.contents[keep + 1 + idx] = digit;
+    }
+    sfn.contents[8..].copy_from_slice(&stem.contents[8..]);
+    sfn
+}
+
+/// If `sfn` is the alias made from this stem with some tail (ignoring case),
+/// get the tail.
+pub(crate) fn alias_tail(stem: &ShortFileName, sfn: &ShortFileName) -> Option<u32> {
+    let tilde = sfn.contents[..8].iter().rposition(|&b| b == b'~')?;
+    let mut tail: u32 = 0;
+    for &b in sfn.contents[tilde + 1..8]
+        .iter()
+        .take_while(|&&b| b != b' ')
+    {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        tail = tail.checked_mul(10)?.checked_add(u32::from(b - b'0'))?;
+    }
+    if tail != 0
+        && alias_name(stem, tail)
+            .contents
+            .eq_ignore_ascii_case(&sfn.contents)
+    {
+        Some(tail)
+    } else {
+        None
+    }
+}
+
+/// Pick the tail for an alias. Aliases with the same stem are numbered in
+/// directory order, skipping any tails already used by a file whose long
+/// name happens to look like one of these aliases. `earlier` is how many
+/// aliases with the same stem come before this one, and bit `N - 1` of
+/// `taken` is set if tail `N` is used by such a file (tails above 64 are
+/// assumed to be free).
+pub(crate) fn pick_alias_tail(mut earlier: u32, taken: u64) -> u32 {
+    let mut tail = 1;
+    loop {
+        if tail > 64 || taken & (1 << (tail - 1)) == 0 {
+            if earlier == 0 {
+                return tail;
+            }
+            earlier -= 1;
+        }
+        tail += 1;
+    }
+}
+
+/// Convert a character from a long file name into one suitable for a short
+/// file name, if possible.
+fn short_name_char(ch: u16) -> Option<u8> {
+    match ch {
+        0x30..=0x39 | 0x41..=0x5A => Some(ch as u8),
+        0x61..=0x7A => Some((ch as u8).to_ascii_uppercase()),
+        0x21 | 0x23..=0x29 | 0x2D | 0x40 | 0x5E..=0x60 | 0x7B | 0x7D | 0x7E => Some(ch as u8),
+        // Spaces and dots are dropped
+        0x20 | 0x2E => None,
+        _ => Some(b'_'),
+    }
+}
+
+// ****************************************************************************
+//
+// Unit Tests
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_disk::{make_controller, Clock, TestController};
+    use crate::{Error, Mode, TimeSource, VolumeIdx, VolumeType};
+
+    /// Where our test partition starts
+    const PARTITION_START: u32 = 8;
+    /// Where the FAT starts, relative to the partition
+    const FAT_OFFSET: u32 = 24;
+    /// Where the cluster heap starts, relative to the partition
+    const HEAP_OFFSET: u32 = 32;
+    /// How many (one block) clusters are in the test volume
+    const CLUSTER_COUNT: u32 = 200;
+    /// The clusters the allocation bitmap, up-case table and root directory
+    /// live in
+    const BITMAP_CLUSTER: u32 = 2;
+    const UPCASE_CLUSTER: u32 = 3;
+    const ROOT_CLUSTER: u32 = 4;
+
+    /// Build a partitioned disk holding an empty exFAT volume, with one block
+    /// per cluster so files quickly span several clusters.
+    fn make_disk() -> Vec<Block> {
+        let num_blocks = HEAP_OFFSET + CLUSTER_COUNT;
+        let mut disk = vec![Block::new(); (PARTITION_START + num_blocks) as usize];
+
+        // Master Boot Record
+        let mbr = &mut disk[0];
+        mbr[446 + 4] = 0x07;
+        LittleEndian::write_u32(&mut mbr[446 + 8..446 + 12], PARTITION_START);
+        LittleEndian::write_u32(&mut mbr[446 + 12..446 + 16], num_blocks);
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+
+        // Boot region
+        let mut region = vec![Block::new(); BOOT_REGION_BLOCKS as usize];
+        let boot = &mut region[0];
+        boot[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(FILE_SYSTEM_NAME);
+        LittleEndian::write_u64(&mut boot[64..72], u64::from(PARTITION_START));
+        LittleEndian::write_u64(&mut boot[72..80], u64::from(num_blocks));
+        LittleEndian::write_u32(&mut boot[80..84], FAT_OFFSET);
+        LittleEndian::write_u32(&mut boot[84..88], HEAP_OFFSET - FAT_OFFSET);
+        LittleEndian::write_u32(&mut boot[88..92], HEAP_OFFSET);
+        LittleEndian::write_u32(&mut boot[92..96], CLUSTER_COUNT);
+        LittleEndian::write_u32(&mut boot[96..100], ROOT_CLUSTER);
+        LittleEndian::write_u32(&mut boot[100..104], 0x1234_5678);
+        LittleEndian::write_u16(&mut boot[104..106], 0x0100);
+        boot[108] = 9;
+        boot[109] = 0;
+        boot[110] = 1;
+        boot[111] = 0x80;
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        for block in region[1..9].iter_mut() {
+            LittleEndian::write_u32(&mut block[508..512], 0xAA55_0000);
+        }
+        let mut checksum = 0;
+        for (i, block) in region[0..11].iter().enumerate() {
+            checksum = boot_checksum(checksum, &block[..], i == 0);
+        }
+        for word in region[11].chunks_mut(4) {
+            LittleEndian::write_u32(word, checksum);
+        }
+        for (i, block) in region.iter().enumerate() {
+            disk[PARTITION_START as usize + i] = block.clone();
+            disk[(PARTITION_START + BOOT_REGION_BLOCKS) as usize + i] = block.clone();
+        }
+
+        // FAT
+        let fat = &mut disk[(PARTITION_START + FAT_OFFSET) as usize];
+        LittleEndian::write_u32(&mut fat[0..4], 0xFFFF_FFF8);
+        for cluster in 1..=ROOT_CLUSTER as usize {
+            LittleEndian::write_u32(&mut fat[cluster * 4..cluster * 4 + 4], FAT_END_OF_CHAIN);
+        }
+
+        // Allocation bitmap
+        let cluster_block = |cluster: u32| (PARTITION_START + HEAP_OFFSET + cluster - 2) as usize;
+        disk[cluster_block(BITMAP_CLUSTER)][0] = 0b0000_0111;
+
+        // Compressed up-case table, which only maps `a-z`
+        let mut table = vec![0xFFFF, 0x0061];
+        table.extend(0x0041..=0x005A);
+        table.extend([0xFFFF, 0xFFFF - 0x007A]);
+        let table_block = &mut disk[cluster_block(UPCASE_CLUSTER)];
+        for (i, value) in table.iter().enumerate() {
+            LittleEndian::write_u16(&mut table_block[i * 2..i * 2 + 2], *value);
+        }
+        let table_checksum = table_checksum(0, &table_block[..table.len() * 2]);
+
+        // Root directory
+        let root = &mut disk[cluster_block(ROOT_CLUSTER)];
+        root[0] = ENTRY_VOLUME_LABEL;
+        root[1] = 7;
+        for (i, ch) in b"TESTVOL".iter().enumerate() {
+            root[2 + i * 2] = *ch;
+        }
+        root[32] = ENTRY_ALLOCATION_BITMAP;
+        LittleEndian::write_u32(&mut root[52..56], BITMAP_CLUSTER);
+        LittleEndian::write_u64(&mut root[56..64], u64::from(CLUSTER_COUNT.div_ceil(8)));
+        root[64] = ENTRY_UPCASE_TABLE;
+        LittleEndian::write_u32(&mut root[68..72], table_checksum);
+        LittleEndian::write_u32(&mut root[84..88], UPCASE_CLUSTER);
+        LittleEndian::write_u64(&mut root[88..96], (table.len() * 2) as u64);
+        disk
+    }
+
+    fn exfat_volume(volume: &crate::Volume) -> &ExFatVolume {
+        match &volume.volume_type {
+            VolumeType::ExFat(exfat) => exfat,
+            _ => panic!("Not an exFAT volume"),
+        }
+    }
+
+    fn read_fat(controller: &mut TestController, cluster: u32) -> u32 {
+        let disk = controller.device().blocks.borrow();
+        let fat = &disk[(PARTITION_START + FAT_OFFSET) as usize];
+        LittleEndian::read_u32(&fat[cluster as usize * 4..cluster as usize * 4 + 4])
+    }
+
+    fn is_allocated(controller: &mut TestController, cluster: u32) -> bool {
+        let disk = controller.device().blocks.borrow();
+        let bitmap = &disk[(PARTITION_START + HEAP_OFFSET + BITMAP_CLUSTER - 2) as usize];
+        let bit = cluster - 2;
+        bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+    }
+
+    #[test]
+    fn test_checksums() {
+        // Worked by hand: 0x41 goes in, then rotates right when 0x00 is added
+        assert_eq!(name_hash(&[0x0041]), 0x8020);
+        let mut slot = [0u8; ENTRY_LEN];
+        slot[0] = ENTRY_FILE;
+        slot[1] = 2;
+        let checksum = entry_set_checksum(0, &slot, true);
+        // The stored checksum is never included
+        slot[2] = 0xAB;
+        slot[3] = 0xCD;
+        assert_eq!(entry_set_checksum(0, &slot, true), checksum);
+        assert_ne!(entry_set_checksum(0, &slot, false), checksum);
+    }
+
+    #[test]
+    fn test_short_names() {
+        let encode = |s: &str| {
+            let lfn = LongFileName::create_from_str(s).unwrap();
+            let upcased: Vec<u16> = s
+                .chars()
+                .flat_map(char::to_uppercase)
+                .map(|ch| ch as u16)
+                .collect();
+            match short_name(&lfn.chars[..lfn.len], name_hash(&upcased)) {
+                ShortName::Literal(sfn) => sfn,
+                ShortName::Alias(stem) => alias_name(&stem, 1),
+            }
+        };
+        assert_eq!(&encode("README.TXT").contents, b"README  TXT");
+        assert_eq!(&encode("readme.txt").contents, b"readme  txt");
+        assert_eq!(&encode("A long file name.text").contents, b"AL8271~1TEX");
+        assert_eq!(&encode(".profile").contents, b"PR08CB~1   ");
+        assert_eq!(&encode("\u{00e9}t\u{00e9}").contents, b"_T4875~1   ");
+        assert_eq!(&encode("...").contents, b"_301E~1    ");
+        // The hash is of the up-cased name, so case doesn't matter
+        assert_eq!(encode("logfile_0001.csv"), encode("LOGFILE_0001.CSV"));
+    }
+
+    #[test]
+    fn test_alias_tails() {
+        let stem = match short_name(&[u16::from(b'~'); 9], 0x1234) {
+            ShortName::Alias(stem) => stem,
+            ShortName::Literal(_) => panic!("not an alias"),
+        };
+        assert_eq!(&stem.contents, b"~~1234     ");
+        assert_eq!(&alias_name(&stem, 9).contents, b"~~1234~9   ");
+        assert_eq!(&alias_name(&stem, 10).contents, b"~~123~10   ");
+        assert_eq!(&alias_name(&stem, 12345).contents, b"~~~12345   ");
+        for tail in [1, 9, 10, 12345] {
+            assert_eq!(alias_tail(&stem, &alias_name(&stem, tail)), Some(tail));
+        }
+        let other = ShortFileName::create_from_str("~~1235~1").unwrap();
+        assert_eq!(alias_tail(&stem, &other), None);
+        let zero = ShortFileName::create_from_str("~~1234~0").unwrap();
+        assert_eq!(alias_tail(&stem, &zero), None);
+        assert_eq!(pick_alias_tail(0, 0), 1);
+        assert_eq!(pick_alias_tail(2, 0), 3);
+        assert_eq!(pick_alias_tail(0, 0b011), 3);
+        assert_eq!(pick_alias_tail(1, 0b101), 4);
+        assert_eq!(pick_alias_tail(0, u64::MAX), 65);
+    }
+
+    #[test]
+    fn test_mount() {
+        let mut controller = make_controller(make_disk());
+        let volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let exfat = exfat_volume(&volume);
+        assert_eq!(exfat.name, VolumeName::new(*b"TESTVOL    "));
+        assert_eq!(exfat.serial_number, 0x1234_5678);
+        assert_eq!(exfat.blocks_per_cluster, 1);
+        assert_eq!(exfat.fat_start, BlockCount(FAT_OFFSET));
+        assert_eq!(exfat.cluster_heap_start, BlockCount(HEAP_OFFSET));
+        assert_eq!(exfat.cluster_count, CLUSTER_COUNT);
+        assert_eq!(exfat.first_root_dir_cluster, Cluster(ROOT_CLUSTER));
+        assert_eq!(exfat.bitmap_start, Cluster(BITMAP_CLUSTER));
+        assert_eq!(exfat.upcase_table.ascii[usize::from(b'q')], b'Q');
+        assert_eq!(exfat.upcase_table.ascii[usize::from(b'Q')], b'Q');
+    }
+
+    #[test]
+    fn test_volume_label() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"TESTVOL");
+        assert_eq!(volume.serial_number(), Some(0x1234_5678));
+        controller
+            .set_volume_label(&mut volume, "Backup 2")
+            .unwrap();
+        assert_eq!(volume.label().name(), b"BACKUP 2");
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"BACKUP 2");
+        // Removing the label leaves an unused label entry behind, which gets
+        // reused
+        controller.set_volume_label(&mut volume, "").unwrap();
+        let root_block = (PARTITION_START + HEAP_OFFSET + ROOT_CLUSTER - 2) as usize;
+        assert_eq!(
+            controller.device().blocks.borrow()[root_block][0],
+            ENTRY_VOLUME_LABEL_UNUSED
+        );
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"");
+        controller.set_volume_label(&mut volume, "ABC").unwrap();
+        assert_eq!(
+            controller.device().blocks.borrow()[root_block][0..4],
+            [ENTRY_VOLUME_LABEL, 3, b'A', 0]
+        );
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut count = 0;
+        controller
+            .iterate_dir(&volume, &root, |_entry| count += 1)
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_set_attributes_and_times() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let file = controller
+            .open_file_in_dir(&mut volume, &root, "Settings.json", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        let mut attributes = Attributes::create_from_fat(Attributes::ARCHIVE);
+        attributes.set_system(true);
+        controller
+            .set_attributes(&volume, &root, "settings.json", attributes)
+            .unwrap();
+        let ctime = Timestamp::from_calendar(2001, 2, 3, 4, 5, 7).unwrap();
+        let mtime = Timestamp::from_calendar(2002, 3, 4, 5, 6, 9).unwrap();
+        let atime = Timestamp::from_calendar(2003, 4, 5, 6, 7, 8).unwrap();
+        controller
+            .set_times(
+                &volume,
+                &root,
+                "Settings.json",
+                Some(ctime),
+                Some(mtime),
+                Some(atime),
+            )
+            .unwrap();
+        // Finding the entry again checks the entry set checksum
+        let entry = controller
+            .find_directory_entry(&volume, &root, "Settings.json")
+            .unwrap();
+        assert!(entry.attributes.is_system());
+        assert!(entry.attributes.is_archive());
+        assert!(!entry.attributes.is_directory());
+        assert_eq!(entry.ctime, ctime);
+        assert_eq!(entry.mtime, mtime);
+        let disk = controller.device().blocks.borrow();
+        let slot = &disk[entry.entry_block.0 as usize]
+            [entry.entry_offset as usize..entry.entry_offset as usize + ENTRY_LEN];
+        assert_eq!(
+            LittleEndian::read_u32(&slot[16..20]),
+            timestamp_to_exfat(atime).0
+        );
+    }
+
+    #[test]
+    fn test_access_date_on_read() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "log.txt", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.write(&mut volume, &mut file, b"hello").unwrap();
+        controller.close_file(&volume, file).unwrap();
+        let old = Timestamp::from_calendar(2000, 1, 1, 0, 0, 0).unwrap();
+        controller
+            .set_times(&volume, &root, "log.txt", None, None, Some(old))
+            .unwrap();
+        let mut buffer = [0u8; 8];
+        for update in [false, true] {
+            controller.set_update_access_dates(update);
+            let mut file = controller
+                .open_file_in_dir(&mut volume, &root, "log.txt", Mode::ReadOnly)
+                .unwrap();
+            assert_eq!(controller.read(&volume, &mut file, &mut buffer).unwrap(), 5);
+            controller.close_file(&volume, file).unwrap();
+            let entry = controller
+                .find_directory_entry(&volume, &root, "log.txt")
+                .unwrap();
+            // The access time only has two second resolution
+            let expected = if update {
+                Timestamp {
+                    seconds: 44,
+                    ..Clock.get_timestamp()
+                }
+            } else {
+                old
+            };
+            assert_eq!(entry.atime, Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_boot_region_checksum() {
+        // A damaged main boot region falls back to the backup
+        let mut disk = make_disk();
+        disk[PARTITION_START as usize + 9][0] ^= 0xFF;
+        let mut controller = make_controller(disk.clone());
+        assert!(controller.get_volume(VolumeIdx(0)).is_ok());
+        // The flags and percent-in-use fields are not covered
+        let mut flags_disk = make_disk();
+        flags_disk[PARTITION_START as usize][106] = 0x02;
+        flags_disk[PARTITION_START as usize][112] = 50;
+        flags_disk[(PARTITION_START + BOOT_REGION_BLOCKS) as usize][9] = 1;
+        let mut controller = make_controller(flags_disk);
+        assert!(controller.get_volume(VolumeIdx(0)).is_ok());
+        // But we need at least one good copy
+        disk[(PARTITION_START + BOOT_REGION_BLOCKS) as usize + 9][0] ^= 0xFF;
+        let mut controller = make_controller(disk);
+        assert!(matches!(
+            controller.get_volume(VolumeIdx(0)),
+            Err(Error::FormatError("Bad exFAT boot region checksum"))
+        ));
+    }
+
+    #[test]
+    fn test_create_write_read_delete() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let data: Vec<u8> = (0..2000u32).map(|x| (x % 251) as u8).collect();
+
+        let mut file = controller
+            .open_file_in_dir(
+                &mut volume,
+                &root,
+                "A long file name.txt",
+                Mode::ReadWriteCreate,
+            )
+            .unwrap();
+        assert_eq!(
+            controller.write(&mut volume, &mut file, &data).unwrap(),
+            data.len()
+        );
+        controller.close_file(&volume, file).unwrap();
+
+        let mut entries = Vec::new();
+        controller
+            .iterate_dir(&volume, &root, |entry| entries.push(entry.clone()))
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(&entries[0].name.contents, b"AL691E~1TXT");
+        assert_eq!(entries[0].size, 2000);
+        assert!(entries[0].contiguous);
+        assert_eq!(entries[0].mtime, Clock.get_timestamp());
+        assert_eq!(entries[0].cluster, Cluster(5));
+        // Contiguous files don't use the FAT
+        assert_eq!(read_fat(&mut controller, 5), 0);
+        for cluster in 5..=8 {
+            assert!(is_allocated(&mut controller, cluster));
+        }
+        let stats = controller.volume_stats(&volume).unwrap();
+        assert_eq!(stats.total_clusters, CLUSTER_COUNT);
+        assert_eq!(stats.free_clusters, CLUSTER_COUNT - 3 - 4);
+        assert_eq!(stats.free_bytes, u64::from(CLUSTER_COUNT - 7) * 512);
+
+        // Open using the long name (in a different case) and the short name
+        for name in ["A LONG FILE NAME.TXT", "al691e~1.txt"] {
+            let mut file = controller
+                .open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly)
+                .unwrap();
+            let mut buffer = vec![0u8; 2100];
+            assert_eq!(
+                controller.read(&volume, &mut file, &mut buffer).unwrap(),
+                2000
+            );
+            assert_eq!(&buffer[..2000], &data[..]);
+            controller.close_file(&volume, file).unwrap();
+        }
+
+        controller
+            .delete_file_in_dir(&volume, &root, "a long FILE name.txt")
+            .unwrap();
+        assert!(matches!(
+            controller.find_directory_entry(&volume, &root, "A long file name.txt"),
+            Err(Error::FileNotFound)
+        ));
+        for cluster in 5..=8 {
+            assert!(!is_allocated(&mut controller, cluster));
+        }
+        let stats = controller.volume_stats(&volume).unwrap();
+        assert_eq!(stats.free_clusters, CLUSTER_COUNT - 3);
+    }
+
+    #[test]
+    fn test_fragmented_file() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let first = [0x11u8; 512];
+        let second = [0x22u8; 512];
+
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "first.bin", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.write(&mut volume, &mut file, &first).unwrap();
+        controller.close_file(&volume, file).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "second.bin", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.write(&mut volume, &mut file, &second).unwrap();
+        controller.close_file(&volume, file).unwrap();
+
+        // The next cluster along is taken, so this moves the file to the FAT
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "first.bin", Mode::ReadWriteAppend)
+            .unwrap();
+        controller.write(&mut volume, &mut file, &second).unwrap();
+        controller.close_file(&volume, file).unwrap();
+
+        let entry = controller
+            .find_directory_entry(&volume, &root, "first.bin")
+            .unwrap();
+        assert_eq!(entry.cluster, Cluster(5));
+        assert_eq!(entry.size, 1024);
+        assert!(!entry.contiguous);
+        assert_eq!(read_fat(&mut controller, 5), 7);
+        assert_eq!(read_fat(&mut controller, 7), FAT_END_OF_CHAIN);
+
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "first.bin", Mode::ReadOnly)
+            .unwrap();
+        let mut buffer = [0u8; 1024];
+        assert_eq!(
+            controller.read(&volume, &mut file, &mut buffer).unwrap(),
+            1024
+        );
+        assert_eq!(&buffer[..512], &first[..]);
+        assert_eq!(&buffer[512..], &second[..]);
+        controller.close_file(&volume, file).unwrap();
+
+        // Truncating releases every cluster
+        let file = controller
+            .open_file_in_dir(&mut volume, &root, "first.bin", Mode::ReadWriteTruncate)
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        assert!(!is_allocated(&mut controller, 5));
+        assert!(is_allocated(&mut controller, 6));
+        assert!(!is_allocated(&mut controller, 7));
+        let entry = controller
+            .find_directory_entry(&volume, &root, "first.bin")
+            .unwrap();
+        assert_eq!(entry.cluster, Cluster(0));
+        assert_eq!(entry.size, 0);
+    }
+
+    #[test]
+    fn test_overwrite_keeps_length() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "data.bin", Mode::ReadWriteCreate)
+            .unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x11; 1000])
+            .unwrap();
+        // Writing over the middle of the file doesn't make it any longer
+        file.seek_from_start(100).unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x22; 50])
+            .unwrap();
+        assert_eq!(file.length(), 1000);
+        // But writing past the end does
+        file.seek_from_start(980).unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x33; 50])
+            .unwrap();
+        assert_eq!(file.length(), 1030);
+        controller.close_file(&volume, file).unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "data.bin")
+            .unwrap();
+        assert_eq!(entry.size, 1030);
+    }
+
+    #[test]
+    fn test_short_name_collisions() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        // The two log files which come first have the same up-cased hash, so
+        // only the tail tells them apart. The first file's long name looks
+        // like one of their aliases, so they can't have its tail.
+        let names = [
+            "lo35c3~2.csv",
+            "logfile_0001.csv",
+            "logfile_0040.csv",
+            "logfile_0002.csv",
+        ];
+        for name in names {
+            let mut file = controller
+                .open_file_in_dir(&mut volume, &root, name, Mode::ReadWriteCreate)
+                .unwrap();
+            controller
+                .write(&mut volume, &mut file, name.as_bytes())
+                .unwrap();
+            controller.close_file(&volume, file).unwrap();
+        }
+        let list = |controller: &mut TestController, volume: &crate::Volume| {
+            let mut names = Vec::new();
+            controller
+                .iterate_dir(volume, &root, |entry| names.push(entry.name.clone()))
+                .unwrap();
+            names
+        };
+        let short_names = list(&mut controller, &volume);
+        assert_eq!(short_names.len(), 4);
+        assert_eq!(&short_names[0].contents, b"lo35c3~2csv");
+        assert_eq!(&short_names[1].contents, b"LO35C3~1CSV");
+        assert_eq!(&short_names[2].contents, b"LO35C3~3CSV");
+        assert_eq!(&short_names[3].contents, b"LO3643~1CSV");
+        for (name, sfn) in names.iter().zip(short_names.iter()) {
+            let entry = controller
+                .find_directory_entry(&volume, &root, name)
+                .unwrap();
+            assert_eq!(entry.name, *sfn);
+            let entry = controller
+                .find_directory_entry(&volume, &root, &format!("{}", sfn))
+                .unwrap();
+            assert_eq!(entry.name, *sfn);
+        }
+
+        // Once that file has gone, the tail is free again
+        controller
+            .delete_file_in_dir(&volume, &root, "lo35c3~2.csv")
+            .unwrap();
+        let short_names = list(&mut controller, &volume);
+        assert_eq!(short_names.len(), 3);
+        assert_eq!(&short_names[0].contents, b"LO35C3~1CSV");
+        assert_eq!(&short_names[1].contents, b"LO35C3~2CSV");
+        assert_eq!(&short_names[2].contents, b"LO3643~1CSV");
+
+        // Deleting by the second short name must leave the first file alone
+        controller
+            .delete_file_in_dir(&volume, &root, "LO35C3~2.CSV")
+            .unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "LO35C3~1.CSV", Mode::ReadOnly)
+            .unwrap();
+        let mut buffer = [0u8; 32];
+        let len = controller.read(&volume, &mut file, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"logfile_0001.csv");
+        controller.close_file(&volume, file).unwrap();
+        assert!(matches!(
+            controller.find_directory_entry(&volume, &root, "logfile_0040.csv"),
+            Err(Error::FileNotFound)
+        ));
+        assert!(matches!(
+            controller.find_directory_entry(&volume, &root, "LO35C3~2.CSV"),
+            Err(Error::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_directory_grows() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        // Each file takes three of the sixteen entries in a cluster
+        for i in 0..10 {
+            let name = format!("file{}.txt", i);
+            let file = controller
+                .open_file_in_dir(&mut volume, &root, &name, Mode::ReadWriteCreate)
+                .unwrap();
+            controller.close_file(&volume, file).unwrap();
+        }
+        assert_ne!(read_fat(&mut controller, ROOT_CLUSTER), FAT_END_OF_CHAIN);
+        let mut count = 0;
+        controller
+            .iterate_dir(&volume, &root, |_entry| count += 1)
+            .unwrap();
+        assert_eq!(count, 10);
+        assert!(controller
+            .find_directory_entry(&volume, &root, "FILE9.TXT")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_large_file_size() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let file = controller
+            .open_file_in_dir(&mut volume, &root, "big.bin", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        let mut entry = controller
+            .find_directory_entry(&volume, &root, "big.bin")
+            .unwrap();
+        entry.size = 5 << 30;
+        crate::blocking::exfat::write_entry(exfat_volume(&volume), &mut controller, &entry)
+            .unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "big.bin")
+            .unwrap();
+        assert_eq!(entry.size, 5 << 30);
+    }
+
+    #[test]
+    fn test_bad_names() {
+        let mut controller = make_controller(make_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        for name in ["a:b", "what?", "", ".."] {
+            assert!(matches!(
+                controller.open_file_in_dir(&mut volume, &root, name, Mode::ReadWriteCreate),
+                Err(Error::FilenameError(_))
+            ));
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -1,27 +1,25 @@
-//! embedded-sdmmc-rs - FAT16/FAT32 file system implementation
+//! embedded-sdmmc-rs - FAT12/FAT16/FAT32 file system implementation
 //!
-//! Implements the File Allocation Table file system. Supports FAT16 and FAT32 volumes.
+//! Implements the File Allocation Table file system. Supports FAT12, FAT16 and FAT32 volumes.
 
 use crate::blockdevice::BlockCount;
 use crate::{
-    Attributes, Block, BlockDevice, BlockIdx, Cluster, Controller, DirEntry, Directory, Error,
-    ShortFileName, TimeSource, Timestamp, VolumeType,
+    Attributes, Block, BlockIdx, Cluster, DirEntry, FileSize, FilenameError, ShortFileName,
+    Timestamp,
 };
 use byteorder::{ByteOrder, LittleEndian};
-use core::convert::TryFrom;
-
-#[cfg(feature = "log")]
-use log::{debug, trace, warn};
-
-#[cfg(feature = "defmt-log")]
-use defmt::{debug, trace, warn};
 
 /// Number of entries reserved at the start of a File Allocation Table
 pub const RESERVED_ENTRIES: u32 = 2;
 
+/// Mask for the 12 bits of a FAT12 entry
+const FAT12_ENTRY_MASK: u16 = 0x0FFF;
+
 /// Indentifies the supported types of FAT format
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FatType {
+    /// FAT12 Format
+    Fat12,
     /// FAT16 Format
     Fat16,
     /// FAT32 Format
@@ -32,6 +30,8 @@ pub enum FatType {
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Eq, PartialEq)]
 pub enum FatSpecificInfo {
+    /// Fat12 Format
+    Fat12(Fat16Info),
     /// Fat16 Format
     Fat16(Fat16Info),
     /// Fat32 Format
@@ -49,7 +49,7 @@ pub struct Fat32Info {
     pub(crate) info_location: BlockIdx,
 }
 
-/// FAT16 specific data
+/// FAT12/FAT16 specific data
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Eq, PartialEq)]
 pub struct Fat16Info {
@@ -63,14 +63,68 @@ pub struct Fat16Info {
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(PartialEq, Eq)]
 pub struct VolumeName {
-    data: [u8; 11],
+    pub(crate) data: [u8; 11],
 }
 
 impl VolumeName {
+    /// The name a FAT volume without a label has in its boot sector
+    pub(crate) const NO_NAME: [u8; 11] = *b"NO NAME    ";
+
     /// Create a new VolumeName
     pub fn new(data: [u8; 11]) -> VolumeName {
         VolumeName { data }
     }
+
+    /// Create a new VolumeName from a string, converting it to upper case.
+    /// An empty string means the volume has no label.
+    pub fn create_from_str(name: &str) -> Result<VolumeName, FilenameError> {
+        let mut volume_name = VolumeName { data: [b' '; 11] };
+        if name.len() > volume_name.data.len() {
+            return Err(FilenameError::NameTooLong);
+        }
+        for (dest, ch) in volume_name.data.iter_mut().zip(name.bytes()) {
+            match ch {
+                // The same as for file names, except spaces are allowed and
+                // periods are not
+                0x00..=0x1F
+                | 0x22
+                | 0x2A
+                | 0x2B
+                | 0x2C
+                | 0x2E
+                | 0x2F
+                | 0x3A
+                | 0x3B
+                | 0x3C
+                | 0x3D
+                | 0x3E
+                | 0x3F
+                | 0x5B
+                | 0x5C
+                | 0x5D
+                | 0x7C
+                | 0x7F..=0xFF => {
+                    return Err(FilenameError::InvalidCharacter);
+                }
+                _ => *dest = ch.to_ascii_uppercase(),
+            }
+        }
+        Ok(volume_name)
+    }
+
+    /// Get the name, without the trailing spaces it is padded with. This is
+    /// empty if the volume has no label.
+    pub fn name(&self) -> &[u8] {
+        if self.data == Self::NO_NAME {
+            return &[];
+        }
+        let len = self
+            .data
+            .iter()
+            .rposition(|b| *b != b' ')
+            .map_or(0, |idx| idx + 1);
+        &self.data[..len]
+    }
 }
 
 /// Identifies a FAT16 Volume on the disk.
@@ -83,12 +137,18 @@ pub struct FatVolume {
     pub(crate) num_blocks: BlockCount,
     /// The name of this volume
     pub(crate) name: VolumeName,
+    /// The serial number from the boot sector, if it has one
+    pub(crate) serial_number: Option<u32>,
     /// Number of 512 byte blocks (or Blocks) in a cluster
     pub(crate) blocks_per_cluster: u8,
     /// The block the data starts in. Relative to start of partition (so add `self.lba_offset` before passing to controller)
     pub(crate) first_data_block: BlockCount,
     /// The block the FAT starts in. Relative to start of partition (so add `self.lba_offset` before passing to controller)
     pub(crate) fat_start: BlockCount,
+    /// The number of blocks in each copy of the FAT
+    pub(crate) fat_size: BlockCount,
+    /// The number of copies of the FAT
+    pub(crate) num_fats: u8,
     /// Expected number of free clusters
     pub(crate) free_clusters_count: Option<u32>,
     /// Number of the next expected free cluster
@@ -113,7 +173,7 @@ impl core::fmt::Debug for VolumeName {
 /// filesystem.
 pub struct Bpb<'a> {
     data: &'a [u8; 512],
-    fat_type: FatType,
+    pub(crate) fat_type: FatType,
     cluster_count: u32,
 }
 
@@ -121,7 +181,7 @@ impl<'a> Bpb<'a> {
     const FOOTER_VALUE: u16 = 0xAA55;
 
     /// Attempt to parse a Boot Parameter Block from a 512 byte sector.
-    pub fn create_from_bytes(data: &[u8; 512]) -> Result<Bpb, &'static str> {
+    pub fn create_from_bytes(data: &[u8; 512]) -> Result<Bpb<'_>, &'static str> {
         let mut bpb = Bpb {
             data,
             fat_type: FatType::Fat16,
@@ -131,16 +191,15 @@ impl<'a> Bpb<'a> {
             return Err("Bad BPB footer");
         }
 
-        let root_dir_blocks = ((u32::from(bpb.root_entries_count()) * OnDiskDirEntry::LEN_U32)
-            + (Block::LEN_U32 - 1))
-            / Block::LEN_U32;
+        let root_dir_blocks = (u32::from(bpb.root_entries_count()) * OnDiskDirEntry::LEN_U32)
+            .div_ceil(Block::LEN_U32);
         let data_blocks = bpb.total_blocks()
             - (u32::from(bpb.reserved_block_count())
                 + (u32::from(bpb.num_fats()) * bpb.fat_size())
                 + root_dir_blocks);
         bpb.cluster_count = data_blocks / u32::from(bpb.blocks_per_cluster());
         if bpb.cluster_count < 4085 {
-            return Err("FAT12 is unsupported");
+            bpb.fat_type = FatType::Fat12;
         } else if bpb.cluster_count < 65525 {
             bpb.fat_type = FatType::Fat16;
         } else {
@@ -148,7 +207,7 @@ impl<'a> Bpb<'a> {
         }
 
         match bpb.fat_type {
-            FatType::Fat16 => Ok(bpb),
+            FatType::Fat12 | FatType::Fat16 => Ok(bpb),
             FatType::Fat32 if bpb.fs_ver() == 0 => {
                 // Only support FAT32 version 0.0
                 Ok(bpb)
@@ -157,7 +216,7 @@ impl<'a> Bpb<'a> {
         }
     }
 
-    // FAT16/FAT32
+    // FAT12/FAT16/FAT32
     define_field!(bytes_per_block, u16, 11);
     define_field!(blocks_per_cluster, u8, 13);
     define_field!(reserved_block_count, u16, 14);
@@ -172,19 +231,25 @@ impl<'a> Bpb<'a> {
     define_field!(total_blocks32, u32, 32);
     define_field!(footer, u16, 510);
 
+    // FAT12/FAT16 only
+    define_field!(boot_signature16, u8, 38);
+    define_field!(volume_id16, u32, 39);
+
     // FAT32 only
     define_field!(fat_size32, u32, 36);
     define_field!(fs_ver, u16, 42);
     define_field!(first_root_dir_cluster, u32, 44);
     define_field!(fs_info, u16, 48);
     define_field!(backup_boot_block, u16, 50);
+    define_field!(boot_signature32, u8, 66);
+    define_field!(volume_id32, u32, 67);
 
     /// Get the OEM name string for this volume
     pub fn oem_name(&self) -> &[u8] {
         &self.data[3..11]
     }
 
-    // FAT16/FAT32 functions
+    // FAT12/FAT16/FAT32 functions
 
     /// Get the Volume Label string for this volume
     pub fn volume_label(&self) -> &[u8] {
@@ -195,10 +260,44 @@ impl<'a> Bpb<'a> {
         }
     }
 
+    /// Get the serial number (or Volume ID) for this volume, if the boot
+    /// sector has one.
+    pub fn volume_id(&self) -> Option<u32> {
+        let (signature, volume_id) = if self.fat_type != FatType::Fat32 {
+            (self.boot_signature16(), self.volume_id16())
+        } else {
+            (self.boot_signature32(), self.volume_id32())
+        };
+        // 0x28 means only the Volume ID is present, 0x29 means the label and
+        // file system type follow it
+        match signature {
+            0x28 | 0x29 => Some(volume_id),
+            _ => None,
+        }
+    }
+
+    /// Does the boot sector have space for a volume label?
+    pub fn has_volume_label(&self) -> bool {
+        if self.fat_type != FatType::Fat32 {
+            self.boot_signature16() == 0x29
+        } else {
+            self.boot_signature32() == 0x29
+        }
+    }
+
+    /// Where the volume label lives in the boot sector
+    pub(crate) fn volume_label_offset(&self) -> usize {
+        if self.fat_type != FatType::Fat32 {
+            43
+        } else {
+            71
+        }
+    }
+
     // FAT32 only functions
 
     /// On a FAT32 volume, return the free block count from the Info Block. On
-    /// a FAT16 volume, returns None.
+    /// a FAT12 or FAT16 volume, returns None.
     pub fn fs_info_block(&self) -> Option<BlockCount> {
         if self.fat_type != FatType::Fat32 {
             None
@@ -207,7 +306,7 @@ impl<'a> Bpb<'a> {
         }
     }
 
-    // Magic functions that get the right FAT16/FAT32 result
+    // Magic functions that get the right FAT12/FAT16/FAT32 result
 
     /// Get the size of the File Allocation Table in blocks.
     pub fn fat_size(&self) -> u32 {
@@ -251,7 +350,7 @@ impl<'a> InfoSector<'a> {
     const TRAIL_SIG: u32 = 0xAA55_0000;
 
     /// Try and create a new Info Sector from a block.
-    pub fn create_from_bytes(data: &[u8; 512]) -> Result<InfoSector, &'static str> {
+    pub fn create_from_bytes(data: &[u8; 512]) -> Result<InfoSector<'_>, &'static str> {
         let info = InfoSector { data };
         if info.lead_sig() != Self::LEAD_SIG {
             return Err("Bad lead signature on InfoSector");
@@ -324,13 +423,14 @@ impl<'a> core::fmt::Debug for OnDiskDirEntry<'a> {
     }
 }
 
-/// Represents the 32 byte directory entry. This is the same for FAT16 and
-/// FAT32 (except FAT16 doesn't use first_cluster_hi).
+/// Represents the 32 byte directory entry. This is the same for FAT12, FAT16
+/// and FAT32 (except FAT12 and FAT16 don't use first_cluster_hi).
 impl<'a> OnDiskDirEntry<'a> {
     pub(crate) const LEN: usize = 32;
     pub(crate) const LEN_U32: u32 = 32;
 
     define_field!(raw_attr, u8, 11);
+    define_field!(create_time_tenth, u8, 13);
     define_field!(create_time, u16, 14);
     define_field!(create_date, u16, 16);
     define_field!(last_access_data, u16, 18);
@@ -342,7 +442,7 @@ impl<'a> OnDiskDirEntry<'a> {
 
     /// Create a new on-disk directory entry from a block of 32 bytes read
     /// from a directory file.
-    pub fn new(data: &[u8]) -> OnDiskDirEntry {
+    pub fn new(data: &[u8]) -> OnDiskDirEntry<'_> {
         OnDiskDirEntry { data }
     }
 
@@ -421,7 +521,7 @@ impl<'a> OnDiskDirEntry<'a> {
         Cluster(cluster_no)
     }
 
-    /// Which cluster, if any, does this file start at? Assumes this is from a FAT16 volume.
+    /// Which cluster, if any, does this file start at? Assumes this is from a FAT12 or FAT16 volume.
     fn first_cluster_fat16(&self) -> Cluster {
         let cluster_no = u32::from(self.first_cluster_lo());
         Cluster(cluster_no)
@@ -440,201 +540,61 @@ impl<'a> OnDiskDirEntry<'a> {
             },
             mtime: Timestamp::from_fat(self.write_date(), self.write_time()),
             ctime: Timestamp::from_fat(self.create_date(), self.create_time()),
+            ctime_hundredths: 0,
+            atime: match self.last_access_data() {
+                0 => None,
+                date => Some(Timestamp::from_fat(date, 0)),
+            },
             attributes: Attributes::create_from_fat(self.raw_attr()),
             cluster: if fat_type == FatType::Fat32 {
                 self.first_cluster_fat32()
             } else {
                 self.first_cluster_fat16()
             },
-            size: self.file_size(),
+            size: FileSize::from(self.file_size()),
             entry_block,
             entry_offset,
+            #[cfg(feature = "exfat")]
+            contiguous: false,
+            #[cfg(feature = "exfat")]
+            dir_contiguous: false,
         };
+        // Anything over 199 is invalid, so ignore it
+        let tenth = self.create_time_tenth();
+        if tenth < 200 {
+            result.ctime.seconds += tenth / 100;
+            result.ctime_hundredths = tenth % 100;
+        }
         result.name.contents.copy_from_slice(&self.data[0..11]);
         result
     }
 }
 
 impl FatVolume {
-    /// Write a new entry in the FAT
-    pub fn update_info_sector<D, T>(
-        &mut self,
-        controller: &mut Controller<D, T>,
-    ) -> Result<(), Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(_) => {}
-            FatSpecificInfo::Fat32(fat32_info) => {
-                if self.free_clusters_count.is_none() && self.next_free_cluster.is_none() {
-                    return Ok(());
-                }
-                let mut blocks = [Block::new()];
-                controller
-                    .block_device
-                    .read(&mut blocks, fat32_info.info_location, "read_info_sector")
-                    .map_err(Error::DeviceError)?;
-                let block = &mut blocks[0];
-                if let Some(count) = self.free_clusters_count {
-                    block[488..492].copy_from_slice(&count.to_le_bytes());
-                }
-                if let Some(next_free_cluster) = self.next_free_cluster {
-                    block[492..496].copy_from_slice(&next_free_cluster.0.to_le_bytes());
-                }
-                controller
-                    .block_device
-                    .write(&blocks, fat32_info.info_location)
-                    .map_err(Error::DeviceError)?;
-            }
-        }
-        Ok(())
-    }
-
     /// Get the type of FAT this volume is
     pub(crate) fn get_fat_type(&self) -> FatType {
         match &self.fat_specific_info {
+            FatSpecificInfo::Fat12(_) => FatType::Fat12,
             FatSpecificInfo::Fat16(_) => FatType::Fat16,
             FatSpecificInfo::Fat32(_) => FatType::Fat32,
         }
     }
 
-    /// Write a new entry in the FAT
-    fn update_fat<D, T>(
-        &mut self,
-        controller: &mut Controller<D, T>,
-        cluster: Cluster,
-        new_value: Cluster,
-    ) -> Result<(), Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let mut blocks = [Block::new()];
-        let this_fat_block_num;
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(_fat16_info) => {
-                let fat_offset = cluster.0 * 2;
-                this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
-                controller
-                    .block_device
-                    .read(&mut blocks, this_fat_block_num, "read_fat")
-                    .map_err(Error::DeviceError)?;
-                let entry = match new_value {
-                    Cluster::INVALID => 0xFFF6,
-                    Cluster::BAD => 0xFFF7,
-                    Cluster::EMPTY => 0x0000,
-                    Cluster::END_OF_FILE => 0xFFFF,
-                    _ => new_value.0 as u16,
-                };
-                LittleEndian::write_u16(
-                    &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
-                    entry,
-                );
-            }
-            FatSpecificInfo::Fat32(_fat32_info) => {
-                // FAT32 => 4 bytes per entry
-                let fat_offset = cluster.0 as u32 * 4;
-                this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
-                controller
-                    .block_device
-                    .read(&mut blocks, this_fat_block_num, "read_fat")
-                    .map_err(Error::DeviceError)?;
-                let entry = match new_value {
-                    Cluster::INVALID => 0x0FFF_FFF6,
-                    Cluster::BAD => 0x0FFF_FFF7,
-                    Cluster::EMPTY => 0x0000_0000,
-                    _ => new_value.0,
-                };
-                let existing = LittleEndian::read_u32(
-                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
-                );
-                let new = (existing & 0xF000_0000) | (entry & 0x0FFF_FFFF);
-                LittleEndian::write_u32(
-                    &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
-                    new,
-                );
-            }
-        }
-        controller
-            .block_device
-            .write(&blocks, this_fat_block_num)
-            .map_err(Error::DeviceError)?;
-        Ok(())
-    }
-
-    /// Look in the FAT to see which cluster comes next.
-    pub(crate) fn next_cluster<D, T>(
-        &self,
-        controller: &Controller<D, T>,
-        cluster: Cluster,
-    ) -> Result<Cluster, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let mut blocks = [Block::new()];
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(_fat16_info) => {
-                let fat_offset = cluster.0 * 2;
-                let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
-                controller
-                    .block_device
-                    .read(&mut blocks, this_fat_block_num, "next_cluster")
-                    .map_err(Error::DeviceError)?;
-                let fat_entry = LittleEndian::read_u16(
-                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
-                );
-                match fat_entry {
-                    0xFFF7 => {
-                        // Bad cluster
-                        Err(Error::BadCluster)
-                    }
-                    0xFFF8..=0xFFFF => {
-                        // There is no next cluster
-                        Err(Error::EndOfFile)
-                    }
-                    f => {
-                        // Seems legit
-                        Ok(Cluster(u32::from(f)))
-                    }
-                }
-            }
-            FatSpecificInfo::Fat32(_fat32_info) => {
-                let fat_offset = cluster.0 * 4;
-                let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
-                controller
-                    .block_device
-                    .read(&mut blocks, this_fat_block_num, "next_cluster")
-                    .map_err(Error::DeviceError)?;
-                let fat_entry = LittleEndian::read_u32(
-                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
-                ) & 0x0FFF_FFFF;
-                match fat_entry {
-                    0x0000_0000 => {
-                        // Jumped to free space
-                        Err(Error::JumpedFree)
-                    }
-                    0x0FFF_FFF7 => {
-                        // Bad cluster
-                        Err(Error::BadCluster)
-                    }
-                    0x0000_0001 | 0x0FFF_FFF8..=0x0FFF_FFFF => {
-                        // There is no next cluster
-                        Err(Error::EndOfFile)
-                    }
-                    f => {
-                        // Seems legit
-                        Ok(Cluster(f))
-                    }
-                }
-            }
-        }
+    /// Work out where the FAT12 entry for the given cluster lives. Returns
+    /// the absolute block holding the first byte of the entry, the offset of
+    /// the entry within that block and the number of blocks (one or two) the
+    /// entry occupies, as 12-bit entries can straddle a block boundary.
+    pub(crate) fn fat12_entry_location(&self, cluster: Cluster) -> (BlockIdx, usize, usize) {
+        // FAT12 => 1.5 bytes per entry
+        let fat_offset = cluster.0 + (cluster.0 / 2);
+        let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+        let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+        let num_blocks = if this_fat_ent_offset == Block::LEN - 1 {
+            2
+        } else {
+            1
+        };
+        (this_fat_block_num, this_fat_ent_offset, num_blocks)
     }
 
     /// Number of bytes in a cluster.
@@ -647,7 +607,7 @@ impl FatVolume {
     /// controller.
     pub(crate) fn cluster_to_block(&self, cluster: Cluster) -> BlockIdx {
         match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(fat16_info) => {
+            FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info) => {
                 let block_num = match cluster {
                     Cluster::ROOT_DIR => fat16_info.first_root_dir_block,
                     Cluster(c) => {
@@ -671,774 +631,60 @@ impl FatVolume {
             }
         }
     }
+}
 
-    /// Finds a empty entry space and writes the new entry to it, allocates a new cluster if it's
-    /// needed
-    pub(crate) fn write_new_directory_entry<D, T>(
-        &mut self,
-        controller: &mut Controller<D, T>,
-        dir: &Directory,
-        name: ShortFileName,
-        attributes: Attributes,
-    ) -> Result<DirEntry, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(fat16_info) => {
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.lba_start + fat16_info.first_root_dir_block,
-                    _ => self.cluster_to_block(dir.cluster),
-                };
-                let mut current_cluster = Some(dir.cluster);
-                let mut blocks = [Block::new()];
-
-                let dir_size = match dir.cluster {
-                    Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
-                    ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
-                };
-                while let Some(cluster) = current_cluster {
-                    for block in first_dir_block_num.range(dir_size) {
-                        controller
-                            .block_device
-                            .read(&mut blocks, block, "read_dir")
-                            .map_err(Error::DeviceError)?;
-                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-                            let start = entry * OnDiskDirEntry::LEN;
-                            let end = (entry + 1) * OnDiskDirEntry::LEN;
-                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-                            // 0x00 or 0xE5 represents a free entry
-                            if !dir_entry.is_valid() {
-                                let ctime = controller.timesource.get_timestamp();
-                                let entry = DirEntry::new(
-                                    name,
-                                    attributes,
-                                    Cluster(0),
-                                    ctime,
-                                    block,
-                                    start as u32,
-                                );
-                                blocks[0][start..start + 32]
-                                    .copy_from_slice(&entry.serialize(FatType::Fat16)[..]);
-                                controller
-                                    .block_device
-                                    .write(&blocks, block)
-                                    .map_err(Error::DeviceError)?;
-                                return Ok(entry);
-                            }
-                        }
-                    }
-                    if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(controller, cluster) {
-                            Ok(n) => {
-                                first_dir_block_num = self.cluster_to_block(n);
-                                Some(n)
-                            }
-                            Err(Error::EndOfFile) => {
-                                let c = self.alloc_cluster(controller, Some(cluster), true)?;
-                                first_dir_block_num = self.cluster_to_block(c);
-                                Some(c)
-                            }
-                            _ => None,
-                        };
-                    } else {
-                        current_cluster = None;
-                    }
-                }
-                Err(Error::NotEnoughSpace)
-            }
-            FatSpecificInfo::Fat32(fat32_info) => {
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.cluster_to_block(fat32_info.first_root_dir_cluster),
-                    _ => self.cluster_to_block(dir.cluster),
-                };
-                let mut current_cluster = Some(dir.cluster);
-                let mut blocks = [Block::new()];
-
-                let dir_size = BlockCount(u32::from(self.blocks_per_cluster));
-                while let Some(cluster) = current_cluster {
-                    for block in first_dir_block_num.range(dir_size) {
-                        controller
-                            .block_device
-                            .read(&mut blocks, block, "read_dir")
-                            .map_err(Error::DeviceError)?;
-                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-                            let start = entry * OnDiskDirEntry::LEN;
-                            let end = (entry + 1) * OnDiskDirEntry::LEN;
-                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-                            // 0x00 or 0xE5 represents a free entry
-                            if !dir_entry.is_valid() {
-                                let ctime = controller.timesource.get_timestamp();
-                                let entry = DirEntry::new(
-                                    name,
-                                    attributes,
-                                    Cluster(0),
-                                    ctime,
-                                    block,
-                                    start as u32,
-                                );
-                                blocks[0][start..start + 32]
-                                    .copy_from_slice(&entry.serialize(FatType::Fat32)[..]);
-                                controller
-                                    .block_device
-                                    .write(&blocks, block)
-                                    .map_err(Error::DeviceError)?;
-                                return Ok(entry);
-                            }
-                        }
-                    }
-                    current_cluster = match self.next_cluster(controller, cluster) {
-                        Ok(n) => {
-                            first_dir_block_num = self.cluster_to_block(n);
-                            Some(n)
-                        }
-                        Err(Error::EndOfFile) => {
-                            let c = self.alloc_cluster(controller, Some(cluster), true)?;
-                            first_dir_block_num = self.cluster_to_block(c);
-                            Some(c)
-                        }
-                        _ => None,
-                    };
-                }
-                Err(Error::NotEnoughSpace)
-            }
-        }
-    }
-
-    /// Calls callback `func` with every valid entry in the given directory.
-    /// Useful for performing directory listings.
-    pub(crate) fn iterate_dir<D, T, F>(
-        &self,
-        controller: &Controller<D, T>,
-        dir: &Directory,
-        mut func: F,
-    ) -> Result<(), Error<D::Error>>
-    where
-        F: FnMut(&DirEntry),
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(fat16_info) => {
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.lba_start + fat16_info.first_root_dir_block,
-                    _ => self.cluster_to_block(dir.cluster),
-                };
-                let mut current_cluster = Some(dir.cluster);
-                let dir_size = match dir.cluster {
-                    Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
-                    ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
-                };
-                let mut blocks = [Block::new()];
-                while let Some(cluster) = current_cluster {
-                    for block in first_dir_block_num.range(dir_size) {
-                        controller
-                            .block_device
-                            .read(&mut blocks, block, "read_dir")
-                            .map_err(Error::DeviceError)?;
-                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-                            let start = entry * OnDiskDirEntry::LEN;
-                            let end = (entry + 1) * OnDiskDirEntry::LEN;
-                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-                            if dir_entry.is_end() {
-                                // Can quit early
-                                return Ok(());
-                            } else if dir_entry.is_valid() && !dir_entry.is_lfn() {
-                                // Safe, since Block::LEN always fits on a u32
-                                let start = u32::try_from(start).unwrap();
-                                let entry = dir_entry.get_entry(FatType::Fat16, block, start);
-                                func(&entry);
-                            }
-                        }
-                    }
-                    if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(controller, cluster) {
-                            Ok(n) => {
-                                first_dir_block_num = self.cluster_to_block(n);
-                                Some(n)
-                            }
-                            _ => None,
-                        };
-                    } else {
-                        current_cluster = None;
-                    }
-                }
-                Ok(())
-            }
-            FatSpecificInfo::Fat32(fat32_info) => {
-                let mut current_cluster = match dir.cluster {
-                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
-                    _ => Some(dir.cluster),
-                };
-                let mut blocks = [Block::new()];
-                while let Some(cluster) = current_cluster {
-                    let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
-                        controller
-                            .block_device
-                            .read(&mut blocks, block, "read_dir")
-                            .map_err(Error::DeviceError)?;
-                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-                            let start = entry * OnDiskDirEntry::LEN;
-                            let end = (entry + 1) * OnDiskDirEntry::LEN;
-                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-                            if dir_entry.is_end() {
-                                // Can quit early
-                                return Ok(());
-                            } else if dir_entry.is_valid() && !dir_entry.is_lfn() {
-                                // Safe, since Block::LEN always fits on a u32
-                                let start = u32::try_from(start).unwrap();
-                                let entry = dir_entry.get_entry(FatType::Fat32, block, start);
-                                func(&entry);
-                            }
-                        }
-                    }
-                    current_cluster = match self.next_cluster(controller, cluster) {
-                        Ok(n) => Some(n),
-                        _ => None,
-                    };
-                }
-                Ok(())
-            }
-        }
-    }
-
-    /// Get an entry from the given directory
-    pub(crate) fn find_directory_entry<D, T>(
-        &self,
-        controller: &mut Controller<D, T>,
-        dir: &Directory,
-        name: &str,
-    ) -> Result<DirEntry, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(fat16_info) => {
-                let mut current_cluster = Some(dir.cluster);
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.lba_start + fat16_info.first_root_dir_block,
-                    _ => self.cluster_to_block(dir.cluster),
-                };
-                let dir_size = match dir.cluster {
-                    Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
-                    ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
-                };
-
-                while let Some(cluster) = current_cluster {
-                    for block in first_dir_block_num.range(dir_size) {
-                        match self.find_entry_in_block(
-                            controller,
-                            FatType::Fat16,
-                            &match_name,
-                            block,
-                        ) {
-                            Err(Error::NotInBlock) => continue,
-                            x => return x,
-                        }
-                    }
-                    if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(controller, cluster) {
-                            Ok(n) => {
-                                first_dir_block_num = self.cluster_to_block(n);
-                                Some(n)
-                            }
-                            _ => None,
-                        };
-                    } else {
-                        current_cluster = None;
-                    }
-                }
-                Err(Error::FileNotFound)
-            }
-            FatSpecificInfo::Fat32(fat32_info) => {
-                let mut current_cluster = match dir.cluster {
-                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
-                    _ => Some(dir.cluster),
-                };
-                while let Some(cluster) = current_cluster {
-                    let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
-                        match self.find_entry_in_block(
-                            controller,
-                            FatType::Fat32,
-                            &match_name,
-                            block,
-                        ) {
-                            Err(Error::NotInBlock) => continue,
-                            x => return x,
-                        }
-                    }
-                    current_cluster = match self.next_cluster(controller, cluster) {
-                        Ok(n) => Some(n),
-                        _ => None,
-                    }
-                }
-                Err(Error::FileNotFound)
-            }
-        }
-    }
-
-    /// Finds an entry in a given block
-    fn find_entry_in_block<D, T>(
-        &self,
-        controller: &mut Controller<D, T>,
-        fat_type: FatType,
-        match_name: &ShortFileName,
-        block: BlockIdx,
-    ) -> Result<DirEntry, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let mut blocks = [Block::new()];
-        controller
-            .block_device
-            .read(&mut blocks, block, "read_dir")
-            .map_err(Error::DeviceError)?;
-        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-            let start = entry * OnDiskDirEntry::LEN;
-            let end = (entry + 1) * OnDiskDirEntry::LEN;
-            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-            if dir_entry.is_end() {
-                // Can quit early
-                return Err(Error::FileNotFound);
-            } else if dir_entry.matches(&match_name) {
-                // Found it
-                // Safe, since Block::LEN always fits on a u32
-                let start = u32::try_from(start).unwrap();
-                return Ok(dir_entry.get_entry(fat_type, block, start));
-            }
-        }
-        Err(Error::NotInBlock)
-    }
-
-    /// Delete an entry from the given directory
-    pub(crate) fn delete_directory_entry<D, T>(
-        &self,
-        controller: &mut Controller<D, T>,
-        dir: &Directory,
-        name: &str,
-    ) -> Result<(), Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(fat16_info) => {
-                let mut current_cluster = Some(dir.cluster);
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.lba_start + fat16_info.first_root_dir_block,
-                    _ => self.cluster_to_block(dir.cluster),
-                };
-                let dir_size = match dir.cluster {
-                    Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
-                    ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
-                };
-
-                while let Some(cluster) = current_cluster {
-                    for block in first_dir_block_num.range(dir_size) {
-                        match self.delete_entry_in_block(controller, &match_name, block) {
-                            Err(Error::NotInBlock) => continue,
-                            x => return x,
-                        }
-                    }
-                    if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(controller, cluster) {
-                            Ok(n) => {
-                                first_dir_block_num = self.cluster_to_block(n);
-                                Some(n)
-                            }
-                            _ => None,
-                        };
-                    } else {
-                        current_cluster = None;
-                    }
-                }
-                Err(Error::FileNotFound)
-            }
-            FatSpecificInfo::Fat32(fat32_info) => {
-                let mut current_cluster = match dir.cluster {
-                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
-                    _ => Some(dir.cluster),
-                };
-                while let Some(cluster) = current_cluster {
-                    let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
-                        match self.delete_entry_in_block(controller, &match_name, block) {
-                            Err(Error::NotInBlock) => continue,
-                            x => return x,
-                        }
-                    }
-                    current_cluster = match self.next_cluster(controller, cluster) {
-                        Ok(n) => Some(n),
-                        _ => None,
-                    }
-                }
-                Err(Error::FileNotFound)
-            }
-        }
-    }
-
-    /// Deletes an entry in a given block
-    fn delete_entry_in_block<D, T>(
-        &self,
-        controller: &mut Controller<D, T>,
-        match_name: &ShortFileName,
-        block: BlockIdx,
-    ) -> Result<(), Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let mut blocks = [Block::new()];
-        controller
-            .block_device
-            .read(&mut blocks, block, "read_dir")
-            .map_err(Error::DeviceError)?;
-        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
-            let start = entry * OnDiskDirEntry::LEN;
-            let end = (entry + 1) * OnDiskDirEntry::LEN;
-            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
-            if dir_entry.is_end() {
-                // Can quit early
-                return Err(Error::FileNotFound);
-            } else if dir_entry.matches(&match_name) {
-                let mut blocks = blocks;
-                blocks[0].contents[start] = 0xE5;
-                controller
-                    .block_device
-                    .write(&blocks, block)
-                    .map_err(Error::DeviceError)?;
-                return Ok(());
-            }
-        }
-        Err(Error::NotInBlock)
-    }
-
-    /// Finds the next free cluster after the start_cluster and before end_cluster
-    pub(crate) fn find_next_free_cluster<D, T>(
-        &self,
-        controller: &mut Controller<D, T>,
-        start_cluster: Cluster,
-        end_cluster: Cluster,
-    ) -> Result<Cluster, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        let mut blocks = [Block::new()];
-        let mut current_cluster = start_cluster;
-        match &self.fat_specific_info {
-            FatSpecificInfo::Fat16(_fat16_info) => {
-                while current_cluster.0 < end_cluster.0 {
-                    trace!(
-                        "current_cluster={:?}, end_cluster={:?}",
-                        current_cluster,
-                        end_cluster
-                    );
-                    let fat_offset = current_cluster.0 * 2;
-                    trace!("fat_offset = {:?}", fat_offset);
-                    let this_fat_block_num =
-                        self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                    trace!("this_fat_block_num = {:?}", this_fat_block_num);
-                    let mut this_fat_ent_offset = usize::try_from(fat_offset % Block::LEN_U32)
-                        .map_err(|_| Error::ConversionError)?;
-                    trace!("Reading block {:?}", this_fat_block_num);
-                    controller
-                        .block_device
-                        .read(&mut blocks, this_fat_block_num, "next_cluster")
-                        .map_err(Error::DeviceError)?;
-
-                    while this_fat_ent_offset <= Block::LEN - 2 {
-                        let fat_entry = LittleEndian::read_u16(
-                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
-                        );
-                        if fat_entry == 0 {
-                            return Ok(current_cluster);
-                        }
-                        this_fat_ent_offset += 2;
-                        current_cluster += 1;
-                    }
-                }
-            }
-            FatSpecificInfo::Fat32(_fat32_info) => {
-                while current_cluster.0 < end_cluster.0 {
-                    trace!(
-                        "current_cluster={:?}, end_cluster={:?}",
-                        current_cluster,
-                        end_cluster
-                    );
-                    let fat_offset = current_cluster.0 * 4;
-                    trace!("fat_offset = {:?}", fat_offset);
-                    let this_fat_block_num =
-                        self.lba_start + self.fat_start.offset_bytes(fat_offset);
-                    trace!("this_fat_block_num = {:?}", this_fat_block_num);
-                    let mut this_fat_ent_offset = usize::try_from(fat_offset % Block::LEN_U32)
-                        .map_err(|_| Error::ConversionError)?;
-                    trace!("Reading block {:?}", this_fat_block_num);
-                    controller
-                        .block_device
-                        .read(&mut blocks, this_fat_block_num, "next_cluster")
-                        .map_err(Error::DeviceError)?;
-
-                    while this_fat_ent_offset <= Block::LEN - 4 {
-                        let fat_entry = LittleEndian::read_u32(
-                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
-                        ) & 0x0FFF_FFFF;
-                        if fat_entry == 0 {
-                            return Ok(current_cluster);
-                        }
-                        this_fat_ent_offset += 4;
-                        current_cluster += 1;
-                    }
-                }
-            }
-        }
-        warn!("Out of space...");
-        Err(Error::NotEnoughSpace)
-    }
-
-    /// Tries to allocate a cluster
-    pub(crate) fn alloc_cluster<D, T>(
-        &mut self,
-        controller: &mut Controller<D, T>,
-        prev_cluster: Option<Cluster>,
-        zero: bool,
-    ) -> Result<Cluster, Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        debug!("Allocating new cluster, prev_cluster={:?}", prev_cluster);
-        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
-        let start_cluster = match self.next_free_cluster {
-            Some(cluster) if cluster.0 < end_cluster.0 => cluster,
-            _ => Cluster(RESERVED_ENTRIES),
-        };
-        trace!(
-            "Finding next free between {:?}..={:?}",
-            start_cluster,
-            end_cluster
-        );
-        let new_cluster = match self.find_next_free_cluster(controller, start_cluster, end_cluster)
-        {
-            Ok(cluster) => cluster,
-            Err(_) if start_cluster.0 > RESERVED_ENTRIES => {
-                debug!(
-                    "Retrying, finding next free between {:?}..={:?}",
-                    Cluster(RESERVED_ENTRIES),
-                    end_cluster
-                );
-                self.find_next_free_cluster(controller, Cluster(RESERVED_ENTRIES), end_cluster)?
-            }
-            Err(e) => return Err(e),
-        };
-        self.update_fat(controller, new_cluster, Cluster::END_OF_FILE)?;
-        if let Some(cluster) = prev_cluster {
-            trace!(
-                "Updating old cluster {:?} to {:?} in FAT",
-                cluster,
-                new_cluster
-            );
-            self.update_fat(controller, cluster, new_cluster)?;
-        }
-        trace!(
-            "Finding next free between {:?}..={:?}",
-            new_cluster,
-            end_cluster
-        );
-        self.next_free_cluster =
-            match self.find_next_free_cluster(controller, new_cluster, end_cluster) {
-                Ok(cluster) => Some(cluster),
-                Err(_) if new_cluster.0 > RESERVED_ENTRIES => {
-                    match self.find_next_free_cluster(
-                        controller,
-                        Cluster(RESERVED_ENTRIES),
-                        end_cluster,
-                    ) {
-                        Ok(cluster) => Some(cluster),
-                        Err(e) => return Err(e),
-                    }
-                }
-                Err(e) => return Err(e),
-            };
-        debug!("Next free cluster is {:?}", self.next_free_cluster);
-        if let Some(ref mut number_free_cluster) = self.free_clusters_count {
-            *number_free_cluster -= 1;
-        };
-        if zero {
-            let blocks = [Block::new()];
-            let first_block = self.cluster_to_block(new_cluster);
-            let num_blocks = BlockCount(u32::from(self.blocks_per_cluster));
-            for block in first_block.range(num_blocks) {
-                controller
-                    .block_device
-                    .write(&blocks, block)
-                    .map_err(Error::DeviceError)?;
-            }
-        }
-        debug!("All done, returning {:?}", new_cluster);
-        Ok(new_cluster)
-    }
-
-    /// Marks the input cluster as an EOF and all the subsequent clusters in the chain as free
-    pub(crate) fn truncate_cluster_chain<D, T>(
-        &mut self,
-        controller: &mut Controller<D, T>,
-        cluster: Cluster,
-    ) -> Result<(), Error<D::Error>>
-    where
-        D: BlockDevice,
-        T: TimeSource,
-    {
-        if cluster.0 < RESERVED_ENTRIES {
-            // file doesn't have any valid cluster allocated, there is nothing to do
-            return Ok(());
-        }
-        let mut next = match self.next_cluster(controller, cluster) {
-            Ok(n) => n,
-            Err(Error::EndOfFile) => return Ok(()),
-            Err(e) => return Err(e),
-        };
-        if let Some(ref mut next_free_cluster) = self.next_free_cluster {
-            if next_free_cluster.0 > next.0 {
-                *next_free_cluster = next;
-            }
-        } else {
-            self.next_free_cluster = Some(next);
-        }
-        self.update_fat(controller, cluster, Cluster::END_OF_FILE)?;
-        loop {
-            match self.next_cluster(controller, next) {
-                Ok(n) => {
-                    self.update_fat(controller, next, Cluster::EMPTY)?;
-                    next = n;
-                }
-                Err(Error::EndOfFile) => {
-                    self.update_fat(controller, next, Cluster::EMPTY)?;
-                    break;
-                }
-                Err(e) => return Err(e),
-            }
-            if let Some(ref mut number_free_cluster) = self.free_clusters_count {
-                *number_free_cluster += 1;
-            };
-        }
-        Ok(())
+/// Read a 12-bit FAT entry from a pair of consecutive FAT blocks, starting
+/// at the given byte offset into the first block.
+pub(crate) fn read_fat12_entry(blocks: &[Block; 2], cluster: Cluster, offset: usize) -> u16 {
+    let lo = blocks[0][offset];
+    let hi = if offset + 1 < Block::LEN {
+        blocks[0][offset + 1]
+    } else {
+        blocks[1][0]
+    };
+    let value = u16::from(lo) | (u16::from(hi) << 8);
+    // Odd clusters live in the top 12 bits, even clusters in the bottom 12.
+    if cluster.0 & 1 == 1 {
+        value >> 4
+    } else {
+        value & FAT12_ENTRY_MASK
     }
 }
 
-/// Load the boot parameter block from the start of the given partition and
-/// determine if the partition contains a valid FAT16 or FAT32 file system.
-pub fn parse_volume<D, T>(
-    controller: &mut Controller<D, T>,
-    lba_start: BlockIdx,
-    num_blocks: BlockCount,
-) -> Result<VolumeType, Error<D::Error>>
-where
-    D: BlockDevice,
-    T: TimeSource,
-    D::Error: core::fmt::Debug,
-{
-    let mut blocks = [Block::new()];
-    controller
-        .block_device
-        .read(&mut blocks, lba_start, "read_bpb")
-        .map_err(Error::DeviceError)?;
-    let block = &blocks[0];
-    let bpb = Bpb::create_from_bytes(&block).map_err(Error::FormatError)?;
-    match bpb.fat_type {
-        FatType::Fat16 => {
-            if bpb.bytes_per_block() as usize != Block::LEN {
-                return Err(Error::BadBlockSize(bpb.bytes_per_block()));
-            }
-            // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz) + RootDirSectors;
-            let root_dir_blocks = ((u32::from(bpb.root_entries_count()) * OnDiskDirEntry::LEN_U32)
-                + (Block::LEN_U32 - 1))
-                / Block::LEN_U32;
-            let fat_start = BlockCount(u32::from(bpb.reserved_block_count()));
-            let first_root_dir_block =
-                fat_start + BlockCount(u32::from(bpb.num_fats()) * bpb.fat_size());
-            let first_data_block = first_root_dir_block + BlockCount(root_dir_blocks);
-            let mut volume = FatVolume {
-                lba_start,
-                num_blocks,
-                name: VolumeName { data: [0u8; 11] },
-                blocks_per_cluster: bpb.blocks_per_cluster(),
-                first_data_block: (first_data_block),
-                fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
-                free_clusters_count: None,
-                next_free_cluster: None,
-                cluster_count: bpb.total_clusters(),
-                fat_specific_info: FatSpecificInfo::Fat16(Fat16Info {
-                    root_entries_count: bpb.root_entries_count(),
-                    first_root_dir_block,
-                }),
-            };
-            volume.name.data[..].copy_from_slice(bpb.volume_label());
-            Ok(VolumeType::Fat(volume))
-        }
-        FatType::Fat32 => {
-            // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz);
-            let first_data_block = u32::from(bpb.reserved_block_count())
-                + (u32::from(bpb.num_fats()) * bpb.fat_size());
-
-            // Safe to unwrap since this is a Fat32 Type
-            let info_location = bpb.fs_info_block().unwrap();
-            let mut info_blocks = [Block::new()];
-            controller
-                .block_device
-                .read(
-                    &mut info_blocks,
-                    lba_start + info_location,
-                    "read_info_sector",
-                )
-                .map_err(Error::DeviceError)?;
-            let info_block = &info_blocks[0];
-            let info_sector =
-                InfoSector::create_from_bytes(&info_block).map_err(Error::FormatError)?;
-
-            let mut volume = FatVolume {
-                lba_start,
-                num_blocks,
-                name: VolumeName { data: [0u8; 11] },
-                blocks_per_cluster: bpb.blocks_per_cluster(),
-                first_data_block: BlockCount(first_data_block),
-                fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
-                free_clusters_count: info_sector.free_clusters_count(),
-                next_free_cluster: info_sector.next_free_cluster(),
-                cluster_count: bpb.total_clusters(),
-                fat_specific_info: FatSpecificInfo::Fat32(Fat32Info {
-                    info_location: lba_start + info_location,
-                    first_root_dir_cluster: Cluster(bpb.first_root_dir_cluster()),
-                }),
-            };
-            volume.name.data[..].copy_from_slice(bpb.volume_label());
-            Ok(VolumeType::Fat(volume))
-        }
+/// Write a 12-bit FAT entry into a pair of consecutive FAT blocks, starting
+/// at the given byte offset into the first block. The neighbouring entry that
+/// shares a byte with this one is preserved.
+pub(crate) fn write_fat12_entry(
+    blocks: &mut [Block; 2],
+    cluster: Cluster,
+    offset: usize,
+    entry: u16,
+) {
+    let entry = entry & FAT12_ENTRY_MASK;
+    let (lo, hi) = if cluster.0 & 1 == 1 {
+        let lo = (blocks[0][offset] & 0x0F) | ((entry << 4) as u8);
+        (lo, (entry >> 4) as u8)
+    } else {
+        let hi_idx = (offset + 1) % Block::LEN;
+        let hi_block = (offset + 1) / Block::LEN;
+        let hi = (blocks[hi_block][hi_idx] & 0xF0) | ((entry >> 8) as u8);
+        (entry as u8, hi)
+    };
+    blocks[0][offset] = lo;
+    if offset + 1 < Block::LEN {
+        blocks[0][offset + 1] = hi;
+    } else {
+        blocks[1][0] = hi;
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_disk::{
+        make_controller, make_fat12_disk, set_dir_entry, set_fat, PARTITION_START, ROOT_DIR_START,
+    };
+    use crate::{Error, Mode, VolumeIdx, VolumeStats};
 
     fn parse(input: &str) -> Vec<u8> {
         let mut output = Vec::new();
@@ -1514,11 +760,17 @@ mod test {
                 name: ShortFileName::create_from_str_mixed_case("boot").unwrap(),
                 mtime: Timestamp::from_calendar(2015, 11, 21, 19, 35, 18).unwrap(),
                 ctime: Timestamp::from_calendar(2015, 11, 21, 19, 35, 18).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2015, 11, 21, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::VOLUME),
                 cluster: Cluster(0),
                 size: 0,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1532,11 +784,17 @@ mod test {
                 name: ShortFileName::create_from_str("OVERLAYS").unwrap(),
                 mtime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 54).unwrap(),
                 ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 54).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2016, 3, 1, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::DIRECTORY),
                 cluster: Cluster(3),
                 size: 0,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1556,12 +814,18 @@ mod test {
             Expected::Short(DirEntry {
                 name: ShortFileName::create_from_str("BCM270~1.DTB").unwrap(),
                 mtime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 34).unwrap(),
-                ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 34).unwrap(),
+                ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 35).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2016, 3, 1, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::ARCHIVE),
                 cluster: Cluster(9),
                 size: 11120,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1574,11 +838,17 @@ mod test {
                 name: ShortFileName::create_from_str("COPYIN~1.LIN").unwrap(),
                 mtime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 30).unwrap(),
                 ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 30).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2016, 3, 1, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::ARCHIVE),
                 cluster: Cluster(5),
                 size: 18693,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1599,11 +869,17 @@ mod test {
                 name: ShortFileName::create_from_str("LICENC~1.BRO").unwrap(),
                 mtime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 34).unwrap(),
                 ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 34).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2016, 3, 1, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::ARCHIVE),
                 cluster: Cluster(8),
                 size: 1494,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1623,12 +899,18 @@ mod test {
             Expected::Short(DirEntry {
                 name: ShortFileName::create_from_str("BCM270~4.DTB").unwrap(),
                 mtime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 36).unwrap(),
-                ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 36).unwrap(),
+                ctime: Timestamp::from_calendar(2016, 3, 1, 19, 56, 37).unwrap(),
+                ctime_hundredths: 0,
+                atime: Some(Timestamp::from_calendar(2016, 3, 1, 0, 0, 0).unwrap()),
                 attributes: Attributes::create_from_fat(Attributes::ARCHIVE),
                 cluster: Cluster(15),
                 size: 12108,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                #[cfg(feature = "exfat")]
+                contiguous: false,
+                #[cfg(feature = "exfat")]
+                dir_contiguous: false,
             }),
             Expected::Lfn(
                 true,
@@ -1726,6 +1008,300 @@ mod test {
         assert_eq!(bpb.total_blocks(), 122_880);
         assert_eq!(bpb.fat_type, FatType::Fat16);
     }
+
+    #[test]
+    fn test_bpb_fat12() {
+        // A 1.44 MB floppy disk image, formatted with mkfs.fat
+        const BPB_EXAMPLE: [u8; 512] = hex!(
+            "EB 3C 90 6D 6B 66 73 2E 66 61 74 00 02 01 01 00
+             02 E0 00 40 0B F0 09 00 12 00 02 00 00 00 00 00
+             00 00 00 00 00 00 29 78 56 34 12 4E 4F 20 4E 41
+             4D 45 20 20 20 20 46 41 54 31 32 20 20 20 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00 00 00 00 00 00 00 55 AA"
+        );
+        let bpb = Bpb::create_from_bytes(&BPB_EXAMPLE).unwrap();
+        assert_eq!(bpb.footer(), Bpb::FOOTER_VALUE);
+        assert_eq!(bpb.oem_name(), b"mkfs.fat");
+        assert_eq!(bpb.bytes_per_block(), 512);
+        assert_eq!(bpb.blocks_per_cluster(), 1);
+        assert_eq!(bpb.reserved_block_count(), 1);
+        assert_eq!(bpb.num_fats(), 2);
+        assert_eq!(bpb.root_entries_count(), 224);
+        assert_eq!(bpb.total_blocks16(), 2880);
+        assert_eq!(bpb.fat_size16(), 9);
+        assert_eq!(bpb.volume_label(), b"NO NAME    ");
+        assert_eq!(bpb.fat_size(), 9);
+        assert_eq!(bpb.total_blocks(), 2880);
+        assert_eq!(bpb.total_clusters(), 2847);
+        assert_eq!(bpb.fat_type, FatType::Fat12);
+    }
+
+    #[test]
+    fn test_fat12_entries() {
+        let mut blocks = [Block::new(), Block::new()];
+        // Clusters 2 and 3 share the middle byte
+        write_fat12_entry(&mut blocks, Cluster(2), 3, 0xABC);
+        write_fat12_entry(&mut blocks, Cluster(3), 4, 0x123);
+        assert_eq!(&blocks[0][3..6], &[0xBC, 0x3A, 0x12]);
+        assert_eq!(read_fat12_entry(&blocks, Cluster(2), 3), 0xABC);
+        assert_eq!(read_fat12_entry(&blocks, Cluster(3), 4), 0x123);
+        // Cluster 341 starts at byte 511 and runs over into the next block
+        write_fat12_entry(&mut blocks, Cluster(340), 510, 0xFFF);
+        write_fat12_entry(&mut blocks, Cluster(341), 511, 0x456);
+        assert_eq!(blocks[0][511], 0x6F);
+        assert_eq!(blocks[1][0], 0x45);
+        assert_eq!(read_fat12_entry(&blocks, Cluster(340), 510), 0xFFF);
+        assert_eq!(read_fat12_entry(&blocks, Cluster(341), 511), 0x456);
+        // Cluster 342 lives wholly in the second block
+        let mut second = [blocks[1].clone(), Block::new()];
+        write_fat12_entry(&mut second, Cluster(342), 1, 0x789);
+        assert_eq!(second[0][0], 0x45);
+        assert_eq!(read_fat12_entry(&second, Cluster(342), 1), 0x789);
+    }
+
+    #[test]
+    fn test_volume_stats() {
+        let mut controller = make_controller(make_fat12_disk());
+        set_fat(&mut controller, 50, 51);
+        set_fat(&mut controller, 51, 0xFFF);
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let stats = controller.volume_stats(&volume).unwrap();
+        assert_eq!(
+            stats,
+            VolumeStats {
+                cluster_size: 512,
+                total_clusters: 96,
+                free_clusters: 94,
+                total_bytes: 96 * 512,
+                free_bytes: 94 * 512,
+            }
+        );
+        // The count is kept up to date as clusters are allocated and freed
+        assert_eq!(
+            controller
+                .recount_free_clusters(&mut volume, false)
+                .unwrap()
+                .free_clusters,
+            94
+        );
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "DATA.BIN", Mode::ReadWriteCreate)
+            .unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0xAA; 1500])
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        assert_eq!(controller.volume_stats(&volume).unwrap().free_clusters, 91);
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "DATA.BIN", Mode::ReadWriteTruncate)
+            .unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0xAA; 10])
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        assert_eq!(controller.volume_stats(&volume).unwrap().free_clusters, 93);
+        assert_eq!(
+            controller
+                .recount_free_clusters(&mut volume, false)
+                .unwrap()
+                .free_clusters,
+            93
+        );
+    }
+
+    #[test]
+    fn test_volume_label() {
+        let mut controller = make_controller(make_fat12_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"FSCKTEST");
+        assert_eq!(volume.serial_number(), Some(0x1234_5678));
+
+        controller.set_volume_label(&mut volume, "Card 7").unwrap();
+        assert_eq!(volume.label().name(), b"CARD 7");
+        {
+            let disk = controller.device().blocks.borrow();
+            assert_eq!(&disk[PARTITION_START as usize][43..54], b"CARD 7     ");
+            assert_eq!(&disk[ROOT_DIR_START as usize][0..12], b"CARD 7     \x08");
+        }
+        // The root directory entry wins over the boot sector
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            0,
+            b"OTHER      ",
+            Attributes::VOLUME,
+            0,
+            0,
+        );
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"OTHER");
+        let mut bitmap = [0u8; 16];
+        let report = controller
+            .fsck(&mut volume, &mut bitmap, false, |_issue, _repaired| {})
+            .unwrap();
+        assert_eq!(report.issues_found, 0);
+
+        controller.set_volume_label(&mut volume, "").unwrap();
+        assert_eq!(volume.label().name(), b"");
+        {
+            let disk = controller.device().blocks.borrow();
+            assert_eq!(&disk[PARTITION_START as usize][43..54], b"NO NAME    ");
+            assert_eq!(disk[ROOT_DIR_START as usize][0], 0xE5);
+        }
+        let volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(volume.label().name(), b"");
+
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        for bad in ["A.B", "TWELVE CHARS", "Caf\u{e9}"] {
+            assert!(matches!(
+                controller.set_volume_label(&mut volume, bad),
+                Err(Error::FilenameError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_set_attributes_and_times() {
+        let mut controller = make_controller(make_fat12_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "CONFIG.TXT", Mode::ReadWriteCreate)
+            .unwrap();
+        controller.write(&mut volume, &mut file, b"x=1").unwrap();
+        assert!(matches!(
+            controller.set_times(&volume, &root, "CONFIG.TXT", None, None, None),
+            Err(Error::FileIsOpen)
+        ));
+        controller.close_file(&volume, file).unwrap();
+
+        let mut attributes = Attributes::create_from_fat(0);
+        attributes.set_read_only(true);
+        attributes.set_hidden(true);
+        attributes.set_archive(false);
+        controller
+            .set_attributes(&volume, &root, "CONFIG.TXT", attributes)
+            .unwrap();
+        let ctime = Timestamp::from_calendar(2001, 2, 3, 4, 5, 6).unwrap();
+        let mtime = Timestamp::from_calendar(2002, 3, 4, 5, 6, 8).unwrap();
+        let atime = Timestamp::from_calendar(2003, 4, 5, 0, 0, 0).unwrap();
+        controller
+            .set_times(
+                &volume,
+                &root,
+                "CONFIG.TXT",
+                Some(ctime),
+                Some(mtime),
+                Some(atime),
+            )
+            .unwrap();
+        // Leaving the times alone keeps the access date
+        controller
+            .set_times(&volume, &root, "CONFIG.TXT", None, None, None)
+            .unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "CONFIG.TXT")
+            .unwrap();
+        assert!(entry.attributes.is_read_only());
+        assert!(entry.attributes.is_hidden());
+        assert!(!entry.attributes.is_archive());
+        assert_eq!(entry.ctime, ctime);
+        assert_eq!(entry.mtime, mtime);
+        assert_eq!(entry.size, 3);
+        let disk = controller.device().blocks.borrow();
+        assert_eq!(
+            disk[ROOT_DIR_START as usize][18..20],
+            atime.serialize_to_fat()[2..4]
+        );
+        drop(disk);
+        assert!(matches!(
+            controller.open_file_in_dir(&mut volume, &root, "CONFIG.TXT", Mode::ReadWriteAppend),
+            Err(Error::ReadOnly)
+        ));
+
+        // An entry with no access date doesn't get one by being updated
+        set_dir_entry(&mut controller, ROOT_DIR_START, 1, b"OLD     TXT", 0, 0, 0);
+        controller
+            .set_attributes(&volume, &root, "OLD.TXT", Attributes::create_from_fat(0))
+            .unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "OLD.TXT")
+            .unwrap();
+        assert_eq!(entry.atime, None);
+        let disk = controller.device().blocks.borrow();
+        assert_eq!(disk[ROOT_DIR_START as usize][32 + 18..32 + 20], [0, 0]);
+    }
+
+    #[test]
+    fn test_overwrite_keeps_length() {
+        let mut controller = make_controller(make_fat12_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "DATA.BIN", Mode::ReadWriteCreate)
+            .unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x11; 1000])
+            .unwrap();
+        // Writing over the middle of the file doesn't make it any longer
+        file.seek_from_start(100).unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x22; 50])
+            .unwrap();
+        assert_eq!(file.length(), 1000);
+        // But writing past the end does
+        file.seek_from_start(980).unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0x33; 50])
+            .unwrap();
+        assert_eq!(file.length(), 1030);
+        controller.close_file(&volume, file).unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "DATA.BIN")
+            .unwrap();
+        assert_eq!(entry.size, 1030);
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "DATA.BIN", Mode::ReadOnly)
+            .unwrap();
+        let mut buffer = [0u8; 1100];
+        assert_eq!(
+            controller.read(&volume, &mut file, &mut buffer).unwrap(),
+            1030
+        );
+        assert!(buffer[..100].iter().all(|&b| b == 0x11));
+        assert!(buffer[100..150].iter().all(|&b| b == 0x22));
+        assert!(buffer[150..980].iter().all(|&b| b == 0x11));
+        assert!(buffer[980..1030].iter().all(|&b| b == 0x33));
+        controller.close_file(&volume, file).unwrap();
+    }
 }
 
 // ****************************************************************************
@@ -22,8 +22,13 @@ use core::convert::TryFrom;
 use crate::blockdevice::BlockIdx;
 use crate::fat::{FatType, OnDiskDirEntry};
 
-/// Maximum file size supported by this library
-pub const MAX_FILE_SIZE: u32 = core::u32::MAX;
+/// Maximum file size supported by this library on FAT volumes
+pub const MAX_FILE_SIZE: u32 = u32::MAX;
+
+/// The type of file sizes and offsets. Only exFAT files can be larger than
+/// 4 GiB (see `MAX_FILE_SIZE`), but it is a `u64` either way, so the API
+/// doesn't change with the `exfat` feature.
+pub type FileSize = u64;
 
 /// Things that impl this can tell you the current time.
 pub trait TimeSource {
@@ -31,6 +36,40 @@ pub trait TimeSource {
     fn get_timestamp(&self) -> Timestamp;
 }
 
+/// A `TimeSource` which always gives the same time. Useful if you have no
+/// clock, or want reproducible timestamps.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FixedTimeSource(pub Timestamp);
+
+impl TimeSource for FixedTimeSource {
+    fn get_timestamp(&self) -> Timestamp {
+        self.0
+    }
+}
+
+/// A `TimeSource` which calls a function to get the time. For example, with
+/// an RTC which counts seconds since 1970:
+///
+/// ```rust
+/// # fn rtc_seconds() -> i64 { 1_600_000_000 }
+/// use embedded_sdmmc::{FnTimeSource, Timestamp};
+/// let time_source = FnTimeSource(|| Timestamp::from_unix_seconds(rtc_seconds()));
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct FnTimeSource<F>(pub F)
+where
+    F: Fn() -> Timestamp;
+
+impl<F> TimeSource for FnTimeSource<F>
+where
+    F: Fn() -> Timestamp,
+{
+    fn get_timestamp(&self) -> Timestamp {
+        (self.0)()
+    }
+}
+
 /// Represents a cluster on disk.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -47,16 +86,29 @@ pub struct DirEntry {
     pub mtime: Timestamp,
     /// When the file was first created
     pub ctime: Timestamp,
+    /// Hundredths of a second to add to `ctime`, from 0 to 99
+    pub ctime_hundredths: u8,
+    /// When the file was last accessed, or `None` if the volume hasn't
+    /// recorded it. FAT volumes only record the date, so the time is
+    /// midnight.
+    pub atime: Option<Timestamp>,
     /// The file attributes (Read Only, Archive, etc)
     pub attributes: Attributes,
     /// The starting cluster of the file. The FAT tells us the following Clusters.
     pub cluster: Cluster,
     /// The size of the file in bytes.
-    pub size: u32,
+    pub size: FileSize,
     /// The disk block of this entry
     pub entry_block: BlockIdx,
     /// The offset on its block (in bytes)
     pub entry_offset: u32,
+    /// exFAT only: the clusters of this file are contiguous on disk and are
+    /// not recorded in the FAT.
+    #[cfg(feature = "exfat")]
+    pub(crate) contiguous: bool,
+    /// exFAT only: the directory holding this entry is contiguous on disk.
+    #[cfg(feature = "exfat")]
+    pub(crate) dir_contiguous: bool,
 }
 
 /// An MS-DOS 8.3 filename. 7-bit ASCII only. All lower-case is converted to
@@ -100,11 +152,11 @@ pub struct File {
     /// The starting point of the file.
     pub(crate) starting_cluster: Cluster,
     /// The current cluster, and how many bytes that short-cuts us
-    pub(crate) current_cluster: (u32, Cluster),
+    pub(crate) current_cluster: (FileSize, Cluster),
     /// How far through the file we've read (in bytes).
-    pub(crate) current_offset: u32,
+    pub(crate) current_offset: FileSize,
     /// The length of the file, in bytes.
-    pub(crate) length: u32,
+    pub(crate) length: FileSize,
     /// What mode the file was opened in
     pub(crate) mode: Mode,
     /// DirEntry of this file
@@ -117,7 +169,9 @@ pub struct File {
 pub struct Directory {
     /// The starting point of the directory listing.
     pub(crate) cluster: Cluster,
-    /// Dir Entry of this directory, None for the root directory
+    /// Dir Entry of this directory, None for the root directory. Only exFAT
+    /// needs it, to find the directory's stream extension.
+    #[cfg(feature = "exfat")]
     pub(crate) entry: Option<DirEntry>,
 }
 
@@ -231,17 +285,22 @@ impl DirEntry {
         data[0..11].copy_from_slice(&self.name.contents);
         data[11] = self.attributes.0;
         // 12: Reserved. Must be set to zero
-        // 13: CrtTimeTenth, not supported, set to zero
+        // 13: CrtTimeTenth, which actually counts in hundredths, and covers
+        // the odd second the creation time can't hold
+        data[13] = (self.ctime.seconds % 2) * 100 + self.ctime_hundredths.min(99);
         data[14..18].copy_from_slice(&self.ctime.serialize_to_fat()[..]);
-        // 0 + 18: LastAccDate, not supported, set to zero
+        // A last access date of zero means there isn't one
+        if let Some(atime) = self.atime {
+            data[18..20].copy_from_slice(&atime.serialize_to_fat()[2..4]);
+        }
         let cluster_number = self.cluster.0;
-        let cluster_hi = if fat_type == FatType::Fat16 {
-            [0u8; 2]
-        } else {
+        let cluster_hi = if fat_type == FatType::Fat32 {
             // Safe due to the AND operation
             u16::try_from((cluster_number >> 16) & 0x0000_FFFF)
                 .unwrap()
                 .to_le_bytes()
+        } else {
+            [0u8; 2]
         };
         data[20..22].copy_from_slice(&cluster_hi[..]);
         data[22..26].copy_from_slice(&self.mtime.serialize_to_fat()[..]);
@@ -250,7 +309,9 @@ impl DirEntry {
             .unwrap()
             .to_le_bytes();
         data[26..28].copy_from_slice(&cluster_lo[..]);
-        data[28..32].copy_from_slice(&self.size.to_le_bytes()[..]);
+        // Files on FAT volumes never exceed `MAX_FILE_SIZE`
+        let size = u32::try_from(self.size).unwrap_or(MAX_FILE_SIZE);
+        data[28..32].copy_from_slice(&size.to_le_bytes()[..]);
         data
     }
 
@@ -266,11 +327,23 @@ impl DirEntry {
             name,
             mtime: ctime,
             ctime,
+            ctime_hundredths: 0,
+            // Only the date is kept on FAT volumes
+            atime: Some(Timestamp {
+                hours: 0,
+                minutes: 0,
+                seconds: 0,
+                ..ctime
+            }),
             attributes,
             cluster,
             size: 0,
             entry_block,
             entry_offset,
+            #[cfg(feature = "exfat")]
+            contiguous: false,
+            #[cfg(feature = "exfat")]
+            dir_contiguous: false,
         }
     }
 }
@@ -279,6 +352,20 @@ impl ShortFileName {
     const FILENAME_BASE_MAX_LEN: usize = 8;
     const FILENAME_MAX_LEN: usize = 11;
 
+    /// The name of the `.` entry found in every sub-directory.
+    pub fn this_dir() -> ShortFileName {
+        ShortFileName {
+            contents: *b".          ",
+        }
+    }
+
+    /// The name of the `..` entry found in every sub-directory.
+    pub fn parent_dir() -> ShortFileName {
+        ShortFileName {
+            contents: *b"..         ",
+        }
+    }
+
     /// Get base name (name without extension) of file name
     pub fn base_name(&self) -> &[u8] {
         Self::bytes_before_space(&self.contents[..Self::FILENAME_BASE_MAX_LEN])
@@ -324,7 +411,7 @@ impl ShortFileName {
                 }
                 // Denotes the start of the file extension
                 b'.' => {
-                    if idx >= 1 && idx <= Self::FILENAME_BASE_MAX_LEN {
+                    if (1..=Self::FILENAME_BASE_MAX_LEN).contains(&idx) {
                         idx = Self::FILENAME_BASE_MAX_LEN;
                         seen_dot = true;
                     } else {
@@ -332,14 +419,14 @@ impl ShortFileName {
                     }
                 }
                 _ => {
-                    let ch = if ch >= b'a' && ch <= b'z' {
+                    let ch = if ch.is_ascii_lowercase() {
                         // Uppercase characters only
                         ch - 32
                     } else {
                         ch
                     };
                     if seen_dot {
-                        if idx >= Self::FILENAME_BASE_MAX_LEN && idx < Self::FILENAME_MAX_LEN {
+                        if (Self::FILENAME_BASE_MAX_LEN..Self::FILENAME_MAX_LEN).contains(&idx) {
                             sfn.contents[idx] = ch;
                         } else {
                             return Err(FilenameError::NameTooLong);
@@ -391,7 +478,7 @@ impl ShortFileName {
                 }
                 // Denotes the start of the file extension
                 b'.' => {
-                    if idx >= 1 && idx <= Self::FILENAME_BASE_MAX_LEN {
+                    if (1..=Self::FILENAME_BASE_MAX_LEN).contains(&idx) {
                         idx = Self::FILENAME_BASE_MAX_LEN;
                         seen_dot = true;
                     } else {
@@ -400,7 +487,7 @@ impl ShortFileName {
                 }
                 _ => {
                     if seen_dot {
-                        if idx >= Self::FILENAME_BASE_MAX_LEN && idx < Self::FILENAME_MAX_LEN {
+                        if (Self::FILENAME_BASE_MAX_LEN..Self::FILENAME_MAX_LEN).contains(&idx) {
                             sfn.contents[idx] = ch;
                         } else {
                             return Err(FilenameError::NameTooLong);
@@ -455,7 +542,7 @@ impl core::fmt::Debug for ShortFileName {
 impl Timestamp {
     /// Create a `Timestamp` from the 16-bit FAT date and time fields.
     pub fn from_fat(date: u16, time: u16) -> Timestamp {
-        let year = (1980 + (date >> 9)) as u16;
+        let year = 1980 + (date >> 9);
         let month = ((date >> 5) & 0x000F) as u8;
         let day = (date & 0x001F) as u8;
         let hours = ((time >> 11) & 0x001F) as u8;
@@ -506,17 +593,17 @@ impl Timestamp {
         seconds: u8,
     ) -> Result<Timestamp, &'static str> {
         Ok(Timestamp {
-            year_since_1970: if year >= 1970 && year <= (1970 + 255) {
+            year_since_1970: if (1970..=(1970 + 255)).contains(&year) {
                 (year - 1970) as u8
             } else {
                 return Err("Bad year");
             },
-            zero_indexed_month: if month >= 1 && month <= 12 {
+            zero_indexed_month: if (1..=12).contains(&month) {
                 month - 1
             } else {
                 return Err("Bad month");
             },
-            zero_indexed_day: if day >= 1 && day <= 31 {
+            zero_indexed_day: if (1..=31).contains(&day) {
                 day - 1
             } else {
                 return Err("Bad day");
@@ -538,6 +625,152 @@ impl Timestamp {
             },
         })
     }
+
+    /// Create a `Timestamp` from the number of seconds since
+    /// 1970-01-01 00:00:00. Times outside the range FAT can record (1980 to
+    /// 2107) are clamped to the nearest end of it.
+    pub fn from_unix_seconds(seconds: i64) -> Timestamp {
+        let seconds = seconds.clamp(FAT_MIN_UNIX_SECONDS, FAT_MAX_UNIX_SECONDS);
+        let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+        let seconds_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        // The clamping above means all of these fit
+        Timestamp {
+            year_since_1970: (year - 1970) as u8,
+            zero_indexed_month: (month - 1) as u8,
+            zero_indexed_day: (day - 1) as u8,
+            hours: (seconds_of_day / 3600) as u8,
+            minutes: ((seconds_of_day / 60) % 60) as u8,
+            seconds: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    /// Create a local time `Timestamp` from the number of seconds since
+    /// 1970-01-01 00:00:00 UTC. `utc_offset` is the number of seconds that
+    /// local time is ahead of UTC (negative west of Greenwich).
+    pub fn from_unix_seconds_with_offset(seconds: i64, utc_offset: i32) -> Timestamp {
+        Self::from_unix_seconds(seconds.saturating_add(i64::from(utc_offset)))
+    }
+
+    /// Get the number of seconds since 1970-01-01 00:00:00.
+    pub fn to_unix_seconds(&self) -> i64 {
+        let days = days_from_civil(
+            i64::from(self.year_since_1970) + 1970,
+            i64::from(self.zero_indexed_month) + 1,
+            i64::from(self.zero_indexed_day) + 1,
+        );
+        days * SECONDS_PER_DAY
+            + i64::from(self.hours) * 3600
+            + i64::from(self.minutes) * 60
+            + i64::from(self.seconds)
+    }
+
+    /// Get the number of seconds since 1970-01-01 00:00:00 UTC, treating this
+    /// `Timestamp` as a local time `utc_offset` seconds ahead of UTC.
+    pub fn to_unix_seconds_with_offset(&self, utc_offset: i32) -> i64 {
+        self.to_unix_seconds() - i64::from(utc_offset)
+    }
+}
+
+/// 1980-01-01 00:00:00, the earliest time FAT can record
+const FAT_MIN_UNIX_SECONDS: i64 = 315_532_800;
+
+/// 2107-12-31 23:59:59, the latest time FAT can record
+const FAT_MAX_UNIX_SECONDS: i64 = 4_354_819_199;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Convert a proleptic Gregorian calendar date into the number of days since
+/// 1970-01-01. See <http://howardhinnant.github.io/date_algorithms.html>.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Convert a number of days since 1970-01-01 into a (year, month, day) in the
+/// proleptic Gregorian calendar. The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(feature = "chrono")]
+impl From<chrono::NaiveDateTime> for Timestamp {
+    /// Times outside the range FAT can record are clamped to fit.
+    fn from(datetime: chrono::NaiveDateTime) -> Timestamp {
+        use chrono::{Datelike, Timelike};
+        let days = days_from_civil(
+            i64::from(datetime.year()),
+            i64::from(datetime.month()),
+            i64::from(datetime.day()),
+        );
+        Timestamp::from_unix_seconds(
+            days * SECONDS_PER_DAY + i64::from(datetime.num_seconds_from_midnight()),
+        )
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<Timestamp> for chrono::NaiveDateTime {
+    fn from(timestamp: Timestamp) -> chrono::NaiveDateTime {
+        // Every `Timestamp` is well within chrono's range, so these can't fail
+        let seconds = timestamp.to_unix_seconds();
+        let date = chrono::NaiveDate::from_num_days_from_ce_opt(
+            (seconds.div_euclid(SECONDS_PER_DAY) + 719_163) as i32,
+        )
+        .unwrap();
+        let time = chrono::NaiveTime::from_num_seconds_from_midnight_opt(
+            seconds.rem_euclid(SECONDS_PER_DAY) as u32,
+            0,
+        )
+        .unwrap();
+        date.and_time(time)
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<time::PrimitiveDateTime> for Timestamp {
+    /// Times outside the range FAT can record are clamped to fit.
+    fn from(datetime: time::PrimitiveDateTime) -> Timestamp {
+        let days = days_from_civil(
+            i64::from(datetime.year()),
+            i64::from(u8::from(datetime.month())),
+            i64::from(datetime.day()),
+        );
+        let (hours, minutes, seconds) = datetime.as_hms();
+        Timestamp::from_unix_seconds(
+            days * SECONDS_PER_DAY
+                + i64::from(hours) * 3600
+                + i64::from(minutes) * 60
+                + i64::from(seconds),
+        )
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<Timestamp> for time::PrimitiveDateTime {
+    fn from(timestamp: Timestamp) -> time::PrimitiveDateTime {
+        // Every `Timestamp` is well within time's range, so this can't fail
+        let datetime =
+            time::OffsetDateTime::from_unix_timestamp(timestamp.to_unix_seconds()).unwrap();
+        time::PrimitiveDateTime::new(datetime.date(), datetime.time())
+    }
 }
 
 impl core::fmt::Debug for Timestamp {
@@ -585,9 +818,32 @@ impl Attributes {
         Attributes(value)
     }
 
-    pub(crate) fn set_archive(&mut self, flag: bool) {
-        let archive = if flag { 0x20 } else { 0x00 };
-        self.0 |= archive;
+    /// Set or clear the read-only attribute.
+    pub fn set_read_only(&mut self, flag: bool) {
+        self.set_flag(Self::READ_ONLY, flag);
+    }
+
+    /// Set or clear the hidden attribute.
+    pub fn set_hidden(&mut self, flag: bool) {
+        self.set_flag(Self::HIDDEN, flag);
+    }
+
+    /// Set or clear the system attribute.
+    pub fn set_system(&mut self, flag: bool) {
+        self.set_flag(Self::SYSTEM, flag);
+    }
+
+    /// Set or clear the archive attribute.
+    pub fn set_archive(&mut self, flag: bool) {
+        self.set_flag(Self::ARCHIVE, flag);
+    }
+
+    fn set_flag(&mut self, mask: u8, flag: bool) {
+        if flag {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
     }
 
     /// Does this file has the read-only attribute set?
@@ -663,12 +919,13 @@ impl File {
     }
 
     /// How long is the file?
-    pub fn length(&self) -> u32 {
+    pub fn length(&self) -> FileSize {
         self.length
     }
 
     /// Seek to a new position in the file, relative to the start of the file.
-    pub fn seek_from_start(&mut self, offset: u32) -> Result<(), ()> {
+    #[allow(clippy::result_unit_err)]
+    pub fn seek_from_start(&mut self, offset: FileSize) -> Result<(), ()> {
         if offset <= self.length {
             self.current_offset = offset;
             if offset < self.current_cluster.0 {
@@ -682,7 +939,8 @@ impl File {
     }
 
     /// Seek to a new position in the file, relative to the end of the file.
-    pub fn seek_from_end(&mut self, offset: u32) -> Result<(), ()> {
+    #[allow(clippy::result_unit_err)]
+    pub fn seek_from_end(&mut self, offset: FileSize) -> Result<(), ()> {
         if offset <= self.length {
             self.current_offset = self.length - offset;
             if offset < self.current_cluster.0 {
@@ -696,22 +954,30 @@ impl File {
     }
 
     /// Seek to a new position in the file, relative to the current position.
+    #[allow(clippy::result_unit_err)]
     pub fn seek_from_current(&mut self, offset: i32) -> Result<(), ()> {
-        let new_offset = i64::from(self.current_offset) + i64::from(offset);
-        if new_offset >= 0 && new_offset <= i64::from(self.length) {
-            self.current_offset = new_offset as u32;
-            Ok(())
+        let new_offset = if offset >= 0 {
+            self.current_offset
+                .checked_add(FileSize::from(offset.unsigned_abs()))
         } else {
-            Err(())
+            self.current_offset
+                .checked_sub(FileSize::from(offset.unsigned_abs()))
+        };
+        match new_offset {
+            Some(new_offset) if new_offset <= self.length => {
+                self.current_offset = new_offset;
+                Ok(())
+            }
+            _ => Err(()),
         }
     }
 
     /// Amount of file left to read.
-    pub fn left(&self) -> u32 {
+    pub fn left(&self) -> FileSize {
         self.length - self.current_offset
     }
 
-    pub(crate) fn update_length(&mut self, new: u32) {
+    pub(crate) fn update_length(&mut self, new: FileSize) {
         self.length = new;
         self.entry.size = new;
     }
@@ -814,6 +1080,147 @@ mod test {
         assert!(ShortFileName::create_from_str("123456789").is_err());
         assert!(ShortFileName::create_from_str("12345678.ABCD").is_err());
     }
+
+    #[test]
+    fn dir_entry_timestamps() {
+        let mut entry = DirEntry::new(
+            ShortFileName::create_from_str("TIMES.TXT").unwrap(),
+            Attributes::create_from_fat(Attributes::ARCHIVE),
+            Cluster(5),
+            Timestamp::from_calendar(2020, 6, 7, 8, 9, 11).unwrap(),
+            BlockIdx(0),
+            0,
+        );
+        entry.ctime_hundredths = 42;
+        entry.mtime = Timestamp::from_calendar(2021, 1, 2, 3, 4, 6).unwrap();
+        entry.atime = Some(Timestamp::from_calendar(2022, 12, 31, 0, 0, 0).unwrap());
+        let data = entry.serialize(FatType::Fat16);
+        assert_eq!(data[13], 142);
+        let parsed =
+            crate::fat::OnDiskDirEntry::new(&data).get_entry(FatType::Fat16, BlockIdx(0), 0);
+        assert_eq!(parsed, entry);
+    }
+
+    #[test]
+    fn dir_entry_access_date() {
+        let mut entry = DirEntry::new(
+            ShortFileName::create_from_str("TIMES.TXT").unwrap(),
+            Attributes::create_from_fat(Attributes::ARCHIVE),
+            Cluster(5),
+            Timestamp::from_calendar(2020, 6, 7, 8, 9, 11).unwrap(),
+            BlockIdx(0),
+            0,
+        );
+        // A new entry was last accessed on the day it was created
+        assert_eq!(
+            entry.atime,
+            Some(Timestamp::from_calendar(2020, 6, 7, 0, 0, 0).unwrap())
+        );
+        // No last access date stays that way
+        entry.atime = None;
+        let data = entry.serialize(FatType::Fat16);
+        assert_eq!(&data[18..20], &[0, 0]);
+        let parsed =
+            crate::fat::OnDiskDirEntry::new(&data).get_entry(FatType::Fat16, BlockIdx(0), 0);
+        assert_eq!(parsed.atime, None);
+        assert_eq!(parsed.serialize(FatType::Fat16), data);
+    }
+
+    #[test]
+    fn timestamp_unix_seconds() {
+        for (seconds, (year, month, day, hours, minutes, secs)) in [
+            (315_532_800, (1980, 1, 1, 0, 0, 0)),
+            (951_827_696, (2000, 2, 29, 12, 34, 56)),
+            (951_868_800, (2000, 3, 1, 0, 0, 0)),
+            (1_709_251_199, (2024, 2, 29, 23, 59, 59)),
+            (4_107_542_400, (2100, 3, 1, 0, 0, 0)),
+            (4_354_819_199, (2107, 12, 31, 23, 59, 59)),
+        ] {
+            let timestamp =
+                Timestamp::from_calendar(year, month, day, hours, minutes, secs).unwrap();
+            assert_eq!(Timestamp::from_unix_seconds(seconds), timestamp);
+            assert_eq!(timestamp.to_unix_seconds(), seconds);
+        }
+        // 2100 is not a leap year
+        assert_eq!(
+            Timestamp::from_unix_seconds(4_107_542_400 - 86400),
+            Timestamp::from_calendar(2100, 2, 28, 0, 0, 0).unwrap()
+        );
+        // Out of range times are clamped
+        assert_eq!(
+            Timestamp::from_unix_seconds(0),
+            Timestamp::from_calendar(1980, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Timestamp::from_unix_seconds(i64::MAX),
+            Timestamp::from_calendar(2107, 12, 31, 23, 59, 59).unwrap()
+        );
+        // Earlier times can still be converted the other way
+        assert_eq!(
+            Timestamp::from_calendar(1970, 1, 2, 0, 0, 0)
+                .unwrap()
+                .to_unix_seconds(),
+            86400
+        );
+    }
+
+    #[test]
+    fn timestamp_utc_offset() {
+        // 09:00 in UTC+2 is 07:00 UTC
+        let local = Timestamp::from_calendar(2023, 7, 1, 9, 0, 0).unwrap();
+        let utc = Timestamp::from_calendar(2023, 7, 1, 7, 0, 0).unwrap();
+        assert_eq!(
+            local.to_unix_seconds_with_offset(7200),
+            utc.to_unix_seconds()
+        );
+        assert_eq!(
+            Timestamp::from_unix_seconds_with_offset(utc.to_unix_seconds(), 7200),
+            local
+        );
+        // Going west can cross midnight
+        assert_eq!(
+            Timestamp::from_unix_seconds_with_offset(utc.to_unix_seconds(), -8 * 3600),
+            Timestamp::from_calendar(2023, 6, 30, 23, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn time_sources() {
+        let timestamp = Timestamp::from_calendar(2023, 7, 1, 9, 0, 0).unwrap();
+        assert_eq!(FixedTimeSource(timestamp).get_timestamp(), timestamp);
+        let source = FnTimeSource(|| Timestamp::from_unix_seconds(1_688_202_000));
+        assert_eq!(source.get_timestamp(), timestamp);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn timestamp_chrono() {
+        let datetime = chrono::NaiveDate::from_ymd_opt(2024, 2, 29)
+            .unwrap()
+            .and_hms_opt(13, 14, 15)
+            .unwrap();
+        let timestamp = Timestamp::from(datetime);
+        assert_eq!(
+            timestamp,
+            Timestamp::from_calendar(2024, 2, 29, 13, 14, 15).unwrap()
+        );
+        assert_eq!(chrono::NaiveDateTime::from(timestamp), datetime);
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn timestamp_time() {
+        let datetime = time::PrimitiveDateTime::new(
+            time::Date::from_calendar_date(2024, time::Month::February, 29).unwrap(),
+            time::Time::from_hms(13, 14, 15).unwrap(),
+        );
+        let timestamp = Timestamp::from(datetime);
+        assert_eq!(
+            timestamp,
+            Timestamp::from_calendar(2024, 2, 29, 13, 14, 15).unwrap()
+        );
+        assert_eq!(time::PrimitiveDateTime::from(timestamp), datetime);
+    }
 }
 
 // ****************************************************************************
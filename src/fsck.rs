@@ -0,0 +1,398 @@
+//! embedded-sdmmc-rs - FAT file system consistency checker
+//!
+//! Walks every directory and cluster chain on a FAT volume, looking for the
+//! kinds of damage left behind when power is lost part-way through a write.
+//! The simple cases can optionally be repaired.
+
+use crate::blockdevice::BlockCount;
+use crate::fat::RESERVED_ENTRIES;
+use crate::{Cluster, FileSize, ShortFileName, Volume, VolumeType};
+
+/// How deeply nested the directories can be before we give up checking
+/// them.
+pub const MAX_DEPTH: usize = 8;
+
+/// A problem found while checking a volume.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssue {
+    /// This cluster is marked as used in the FAT, but no file or directory
+    /// refers to it.
+    LostCluster(Cluster),
+    /// This cluster belongs to more than one file or directory. The named
+    /// entry is the second one we found using it.
+    CrossLinked {
+        /// The entry whose chain ran into someone else's cluster
+        name: ShortFileName,
+        /// The cluster in question
+        cluster: Cluster,
+    },
+    /// The chain leads to a free or bad cluster, or off the end of the
+    /// volume. Gives the last good cluster, if there was one.
+    BrokenChain {
+        /// The entry with the broken chain
+        name: ShortFileName,
+        /// The last good cluster in the chain
+        last_good: Option<Cluster>,
+    },
+    /// The file's chain has fewer clusters than its size needs.
+    ChainTooShort {
+        /// The file in question
+        name: ShortFileName,
+        /// The size in the directory entry
+        size: FileSize,
+        /// The number of clusters in the chain
+        clusters: u32,
+    },
+    /// The file's chain has more clusters than its size needs.
+    ChainTooLong {
+        /// The file in question
+        name: ShortFileName,
+        /// The size in the directory entry
+        size: FileSize,
+        /// The number of clusters in the chain
+        clusters: u32,
+    },
+    /// A directory's `.` or `..` entry is missing or points to the wrong
+    /// cluster.
+    BadDotEntry {
+        /// The directory in question
+        dir: Cluster,
+        /// Which entry (`.` or `..`) is wrong
+        name: ShortFileName,
+    },
+    /// A copy of the FAT differs from the first copy.
+    FatMismatch {
+        /// Which copy of the FAT (1 is the second copy)
+        copy: u8,
+        /// Which block of the FAT, counting from the start of that copy
+        block: BlockCount,
+    },
+    /// The FAT32 FSInfo sector has the wrong free cluster count.
+    WrongFreeCount {
+        /// The count in the FSInfo sector
+        recorded: u32,
+        /// The count we found by scanning the FAT
+        actual: u32,
+    },
+    /// The directories are nested more than `MAX_DEPTH` deep, so this
+    /// directory was not checked.
+    TooDeep(Cluster),
+}
+
+/// A summary of a volume check.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// The number of files found
+    pub files: u32,
+    /// The number of directories found (not counting the root directory)
+    pub directories: u32,
+    /// The number of free clusters, after any repairs
+    pub free_clusters: u32,
+    /// The number of problems found
+    pub issues_found: u32,
+    /// The number of problems repaired
+    pub issues_repaired: u32,
+}
+
+/// Returns how many bytes of buffer `Controller::fsck` needs for the given
+/// volume. This is one bit for every cluster.
+pub fn bitmap_len(volume: &Volume) -> usize {
+    match &volume.volume_type {
+        VolumeType::Fat(fat) => (fat.cluster_count + RESERVED_ENTRIES).div_ceil(8) as usize,
+        #[cfg(feature = "exfat")]
+        VolumeType::ExFat(_) => 0,
+    }
+}
+
+// ****************************************************************************
+//
+// Unit Tests
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_disk::{
+        make_controller, make_fat12_disk, set_dir_entry, set_fat, TestController, ROOT_DIR_START,
+    };
+    use crate::{Attributes, Mode, VolumeIdx};
+
+    /// Run a check, and collect the issues found
+    fn check(
+        controller: &mut TestController,
+        repair: bool,
+    ) -> (FsckReport, Vec<(FsckIssue, bool)>) {
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let mut bitmap = [0u8; 16];
+        assert!(bitmap_len(&volume) <= bitmap.len());
+        let mut issues = Vec::new();
+        let report = controller
+            .fsck(&mut volume, &mut bitmap, repair, |issue, repaired| {
+                issues.push((issue.clone(), repaired))
+            })
+            .unwrap();
+        (report, issues)
+    }
+
+    fn name(s: &str) -> ShortFileName {
+        ShortFileName::create_from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_clean_volume() {
+        let mut controller = make_controller(make_fat12_disk());
+        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let mut file = controller
+            .open_file_in_dir(&mut volume, &root, "DATA.BIN", Mode::ReadWriteCreate)
+            .unwrap();
+        controller
+            .write(&mut volume, &mut file, &[0xAA; 1500])
+            .unwrap();
+        controller.close_file(&volume, file).unwrap();
+        controller.close_dir(&volume, root);
+
+        // Writing a file only updates the first FAT
+        let (_report, issues) = check(&mut controller, true);
+        assert_eq!(
+            issues,
+            [(
+                FsckIssue::FatMismatch {
+                    copy: 1,
+                    block: BlockCount(0)
+                },
+                true
+            )]
+        );
+        let (report, issues) = check(&mut controller, false);
+        assert_eq!(issues, []);
+        assert_eq!(report.files, 1);
+        assert_eq!(report.free_clusters, 96 - 3);
+    }
+
+    #[test]
+    fn test_lost_clusters() {
+        let mut controller = make_controller(make_fat12_disk());
+        set_fat(&mut controller, 50, 51);
+        set_fat(&mut controller, 51, 0xFFF);
+        let (report, issues) = check(&mut controller, false);
+        assert_eq!(
+            issues,
+            [
+                (FsckIssue::LostCluster(Cluster(50)), false),
+                (FsckIssue::LostCluster(Cluster(51)), false),
+            ]
+        );
+        assert_eq!(report.free_clusters, 94);
+        let (report, _issues) = check(&mut controller, true);
+        assert_eq!(report.issues_repaired, 2);
+        assert_eq!(report.free_clusters, 96);
+        let (_report, issues) = check(&mut controller, false);
+        assert_eq!(issues, []);
+    }
+
+    #[test]
+    fn test_chain_length() {
+        let mut controller = make_controller(make_fat12_disk());
+        // One cluster, but two are needed
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            0,
+            b"SHORT   TXT",
+            0,
+            2,
+            1000,
+        );
+        set_fat(&mut controller, 2, 0xFFF);
+        // Three clusters, but only one is needed
+        set_dir_entry(&mut controller, ROOT_DIR_START, 1, b"LONG    TXT", 0, 3, 10);
+        set_fat(&mut controller, 3, 4);
+        set_fat(&mut controller, 4, 5);
+        set_fat(&mut controller, 5, 0xFFF);
+        // A chain, but an empty file
+        set_dir_entry(&mut controller, ROOT_DIR_START, 2, b"EMPTY   TXT", 0, 6, 0);
+        set_fat(&mut controller, 6, 0xFFF);
+        let (_report, issues) = check(&mut controller, true);
+        assert_eq!(
+            issues,
+            [
+                (
+                    FsckIssue::ChainTooShort {
+                        name: name("SHORT.TXT"),
+                        size: 1000,
+                        clusters: 1
+                    },
+                    true
+                ),
+                (
+                    FsckIssue::ChainTooLong {
+                        name: name("LONG.TXT"),
+                        size: 10,
+                        clusters: 3
+                    },
+                    true
+                ),
+                (
+                    FsckIssue::ChainTooLong {
+                        name: name("EMPTY.TXT"),
+                        size: 0,
+                        clusters: 1
+                    },
+                    true
+                ),
+                (FsckIssue::LostCluster(Cluster(4)), true),
+                (FsckIssue::LostCluster(Cluster(5)), true),
+                (FsckIssue::LostCluster(Cluster(6)), true),
+            ]
+        );
+        let (report, issues) = check(&mut controller, false);
+        assert_eq!(issues, []);
+        assert_eq!(report.free_clusters, 94);
+        let volume = controller.get_volume(VolumeIdx(0)).unwrap();
+        let root = controller.open_root_dir(&volume).unwrap();
+        let entry = controller
+            .find_directory_entry(&volume, &root, "SHORT.TXT")
+            .unwrap();
+        assert_eq!(entry.size, 512);
+        let entry = controller
+            .find_directory_entry(&volume, &root, "EMPTY.TXT")
+            .unwrap();
+        assert_eq!(entry.cluster, Cluster(0));
+    }
+
+    #[test]
+    fn test_cross_linked_and_broken() {
+        let mut controller = make_controller(make_fat12_disk());
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            0,
+            b"ONE     TXT",
+            0,
+            2,
+            1024,
+        );
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            1,
+            b"TWO     TXT",
+            0,
+            10,
+            1024,
+        );
+        set_fat(&mut controller, 2, 3);
+        set_fat(&mut controller, 3, 0xFFF);
+        set_fat(&mut controller, 10, 3);
+        // This chain runs into free space
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            2,
+            b"BROKEN  TXT",
+            0,
+            20,
+            512,
+        );
+        set_fat(&mut controller, 20, 21);
+        let (report, issues) = check(&mut controller, true);
+        assert_eq!(
+            issues,
+            [
+                (
+                    FsckIssue::CrossLinked {
+                        name: name("TWO.TXT"),
+                        cluster: Cluster(3)
+                    },
+                    false
+                ),
+                (
+                    FsckIssue::BrokenChain {
+                        name: name("BROKEN.TXT"),
+                        last_good: Some(Cluster(21))
+                    },
+                    true
+                ),
+                (
+                    FsckIssue::ChainTooLong {
+                        name: name("BROKEN.TXT"),
+                        size: 512,
+                        clusters: 2
+                    },
+                    true
+                ),
+            ]
+        );
+        assert_eq!(report.issues_found, 3);
+        assert_eq!(report.issues_repaired, 2);
+        let (_report, issues) = check(&mut controller, false);
+        assert_eq!(
+            issues,
+            [(
+                FsckIssue::CrossLinked {
+                    name: name("TWO.TXT"),
+                    cluster: Cluster(3)
+                },
+                false
+            )]
+        );
+    }
+
+    #[test]
+    fn test_dot_entries() {
+        let mut controller = make_controller(make_fat12_disk());
+        let sub_dir_block = ROOT_DIR_START + 1;
+        set_dir_entry(
+            &mut controller,
+            ROOT_DIR_START,
+            0,
+            b"SUBDIR     ",
+            Attributes::DIRECTORY,
+            2,
+            0,
+        );
+        set_fat(&mut controller, 2, 0xFFF);
+        set_dir_entry(
+            &mut controller,
+            sub_dir_block,
+            0,
+            b".          ",
+            Attributes::DIRECTORY,
+            7,
+            0,
+        );
+        set_dir_entry(
+            &mut controller,
+            sub_dir_block,
+            1,
+            b"..         ",
+            Attributes::DIRECTORY,
+            0,
+            0,
+        );
+        let (report, issues) = check(&mut controller, true);
+        assert_eq!(
+            issues,
+            [(
+                FsckIssue::BadDotEntry {
+                    dir: Cluster(2),
+                    name: ShortFileName::this_dir()
+                },
+                true
+            )]
+        );
+        assert_eq!(report.directories, 1);
+        let (_report, issues) = check(&mut controller, false);
+        assert_eq!(issues, []);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1171 @@
+//! embedded-sdmmc-rs - SD Card Driver
+//!
+//! The card state and the SD/MMC protocol, on top of whatever transport we
+//! have. `SdMmcSpi` and `AsyncSdMmcSpi` are built on this.
+
+use super::SpiTransport;
+use crate::sdmmc::{
+    AcquireOpts, CardStatus, CardType, DataErrorToken, Error, R1Status, RetryStats, State, Timeout,
+    FORCE_ERASE_TIMEOUT_MS, HIGH_SPEED_HZ, INIT_CLOCK_HZ, PRE_ERASE_MIN_BLOCKS,
+    SWITCH_CHECK_HIGH_SPEED, SWITCH_SET_HIGH_SPEED,
+};
+use crate::sdmmc_proto::*;
+use crate::{Block, BlockCount, BlockIdx};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::{debug, trace, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace, warn};
+
+/// The card we're talking to, and how we talk to it.
+pub(crate) struct Card<T>
+where
+    T: SpiTransport,
+{
+    pub(crate) transport: T,
+    pub(crate) card_type: CardType,
+    pub(crate) state: State,
+    pub(crate) options: AcquireOpts,
+    pub(crate) retry_stats: RetryStats,
+    consecutive_failures: u8,
+    locked: bool,
+    powered: bool,
+}
+
+impl<T> Card<T>
+where
+    T: SpiTransport,
+{
+    pub(crate) fn new(transport: T) -> Card<T> {
+        Card {
+            transport,
+            card_type: CardType::SD1,
+            state: State::NoInit,
+            options: AcquireOpts::default(),
+            retry_stats: RetryStats::default(),
+            consecutive_failures: 0,
+            locked: false,
+            powered: false,
+        }
+    }
+
+    maybe_async!({
+        /// Wait a little while before polling the card again, or give up with
+        /// the given error if we've run out of time.
+        async fn delay(&mut self, timeout: &mut Timeout, err: Error) -> Result<(), Error> {
+            let mut delay_us = timeout.next_delay_us().ok_or(err)?;
+            while delay_us > 0 {
+                let step = delay_us.min(250);
+                maybe_await!(self.transport.delay_us(step as u8));
+                delay_us -= step;
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        async fn cs_high(&mut self) -> Result<(), Error> {
+            maybe_await!(self.transport.deselect())
+        }
+    });
+
+    maybe_async!({
+        async fn cs_low(&mut self) -> Result<(), Error> {
+            maybe_await!(self.transport.select())
+        }
+    });
+
+    maybe_async!({
+        /// Wait for the given number of milliseconds.
+        async fn delay_ms(&mut self, ms: u32) {
+            for _ in 0..ms {
+                for _ in 0..4 {
+                    maybe_await!(self.transport.delay_us(250));
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Switch the card's power on, if the transport can and it isn't on
+        /// already, and give it time to start up.
+        async fn power_up(&mut self) -> Result<(), Error> {
+            if self.powered {
+                return Ok(());
+            }
+            if self.transport.set_power(true)? {
+                debug!("Powering card up");
+                maybe_await!(self.delay_ms(self.options.power_up_delay_ms));
+            }
+            self.powered = true;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Initializes the card into a known state
+        pub(crate) async fn acquire(&mut self, options: AcquireOpts) -> Result<(), Error> {
+            debug!("acquiring card with opts: {:?}", options);
+            self.options = options;
+            maybe_await!(self.start())
+        }
+    });
+
+    maybe_async!({
+        /// Find the card, initialise it, and get it running as fast as it can.
+        /// Also used to start the card again from scratch, to get it out of
+        /// whatever state it has got itself into.
+        async fn start(&mut self) -> Result<(), Error> {
+            let options = self.options;
+            if !self.card_present()? {
+                return Err(Error::CardNotFound);
+            }
+            maybe_await!(self.power_up())?;
+            self.transport.set_clock_hz(INIT_CLOCK_HZ)?;
+            let result = maybe_await!(self.init());
+            maybe_await!(self.cs_high())?;
+            let _ = maybe_await!(self.receive());
+            result?;
+            // A locked card won't give up its data until it has been unlocked
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_card_status());
+            maybe_await!(self.cs_high())?;
+            let status = result?;
+            self.locked = status.is_card_locked();
+            if status.is_card_locked() {
+                warn!("Card is locked");
+            }
+            let high_speed = options.high_speed && maybe_await!(self.enable_high_speed())?;
+            let max_hz = if high_speed {
+                HIGH_SPEED_HZ
+            } else {
+                match maybe_await!(self.card_csd())? {
+                    Csd::V1(ref contents) => contents.max_transfer_rate_bps(),
+                    Csd::V2(ref contents) => contents.max_transfer_rate_bps(),
+                    Csd::V3(ref contents) => contents.max_transfer_rate_bps(),
+                    Csd::Mmc(ref contents) => contents.max_transfer_rate_bps(),
+                }
+            };
+            debug!("Switching clock to {} Hz", max_hz);
+            self.transport.set_clock_hz(max_hz)
+        }
+    });
+
+    /// The driver has finished with the card. Unless it was put to sleep, it
+    /// will have to be acquired again.
+    pub(crate) fn release(&mut self) {
+        if !matches!(self.state, State::Asleep | State::PoweredDown) {
+            self.state = State::NoInit;
+        }
+    }
+
+    maybe_async!({
+        /// Wait for the card to finish whatever it is doing (such as programming
+        /// the last block written), then leave it deselected until it is woken
+        /// up by `wake`.
+        pub(crate) async fn sleep(&mut self) -> Result<(), Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_status_when_idle());
+            let status = maybe_await!(self.end_operation(result))?;
+            self.locked = status.is_card_locked();
+            self.state = State::Asleep;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Put the card to sleep, then switch its power off if the transport
+        /// can. The power is switched off even if the card never finishes, so a
+        /// card which has hung doesn't keep draining the supply.
+        pub(crate) async fn power_down(&mut self) -> Result<(), Error> {
+            let result = maybe_await!(self.sleep());
+            if self.transport.set_power(false)? {
+                debug!("Powered card down");
+                self.powered = false;
+                self.state = State::PoweredDown;
+            }
+            result
+        }
+    });
+
+    maybe_async!({
+        /// Get the card going again, with the options it was last acquired
+        /// with. A card which was put to sleep, and kept its power, only needs
+        /// to be checked; anything else has to be initialised again.
+        pub(crate) async fn wake(&mut self) -> Result<(), Error> {
+            if self.state == State::Asleep && self.card_present()? {
+                self.state = State::Idle;
+                self.check_card()?;
+                maybe_await!(self.cs_low())?;
+                let result = maybe_await!(self.read_card_status());
+                match maybe_await!(self.end_operation(result)) {
+                    Ok(status) => {
+                        self.locked = status.is_card_locked();
+                        return Ok(());
+                    }
+                    Err(_e) => warn!("Card didn't wake up ({:?}), re-initialising", _e),
+                }
+            }
+            maybe_await!(self.start())
+        }
+    });
+
+    maybe_async!({
+        /// Decide whether a read or write which ended with `result` should be
+        /// tried again, re-initialising the card first if it keeps failing, and
+        /// keep count.
+        async fn should_retry(&mut self, result: &Result<(), Error>, attempts: &mut u8) -> bool {
+            let error = match result {
+                Ok(()) => {
+                    self.consecutive_failures = 0;
+                    return false;
+                }
+                Err(error) => *error,
+            };
+            let options = self.options;
+            let failures = self.consecutive_failures.saturating_add(1);
+            self.consecutive_failures = failures;
+            let mut stats = self.retry_stats;
+            if !error.is_transient() {
+                stats.failures += 1;
+                self.retry_stats = stats;
+                return false;
+            }
+            // Re-initialising doesn't need retries to be on - the next read or
+            // write gets a fresh card either way
+            let reinit =
+                options.reinit_after_failures != 0 && failures >= options.reinit_after_failures;
+            if reinit {
+                stats.reinits += 1;
+                self.consecutive_failures = 0;
+            }
+            let retry = *attempts < options.retries;
+            if retry {
+                *attempts += 1;
+                stats.retries += 1;
+                warn!("Got {:?}, retrying (attempt {})", error, *attempts);
+            } else {
+                stats.failures += 1;
+            }
+            self.retry_stats = stats;
+            if reinit {
+                warn!("Re-initialising card after {} failures", failures);
+                if let Err(_e) = maybe_await!(self.start()) {
+                    warn!("Re-initialising failed: {:?}", _e);
+                    return false;
+                }
+            }
+            retry
+        }
+    });
+
+    maybe_async!({
+        /// Try to switch the card into high-speed mode with CMD6. Returns
+        /// whether it worked - older cards don't have CMD6, and not all newer
+        /// ones can go faster.
+        async fn enable_high_speed(&mut self) -> Result<bool, Error> {
+            let supported = match maybe_await!(self.card_csd())? {
+                Csd::V1(ref contents) => contents.supports_command_class(10),
+                Csd::V2(ref contents) => contents.supports_command_class(10),
+                Csd::V3(ref contents) => contents.supports_command_class(10),
+                // CMD6 means something else to an MMC card
+                Csd::Mmc(_) => false,
+            };
+            if !supported {
+                return Ok(false);
+            }
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.switch_high_speed());
+            maybe_await!(self.cs_high())?;
+            result
+        }
+    });
+
+    maybe_async!({
+        /// Ask the card to switch to high-speed mode, and check it did.
+        async fn switch_high_speed(&mut self) -> Result<bool, Error> {
+            let mut status = [0u8; 64];
+            for arg in [SWITCH_CHECK_HIGH_SPEED, SWITCH_SET_HIGH_SPEED] {
+                if maybe_await!(self.card_command(CMD6, arg))? != 0 {
+                    return Ok(false);
+                }
+                maybe_await!(self.read_data(&mut status))?;
+                // Function group 1 says which access mode it picked
+                if status[16] & 0x0F != 1 {
+                    return Ok(false);
+                }
+            }
+            debug!("Card is in high-speed mode");
+            Ok(true)
+        }
+    });
+
+    maybe_async!({
+        /// Reset the card and work out what sort it is.
+        async fn init(&mut self) -> Result<(), Error> {
+            let options = self.options;
+            // Assume it hasn't worked
+            self.state = State::Error;
+            trace!("Reset card..");
+            // Supply minimum of 74 clock cycles without CS asserted.
+            maybe_await!(self.transport.send_initial_clocks())?;
+            // Assert CS
+            maybe_await!(self.cs_low())?;
+            // Enter SPI mode
+            let mut timeout = Timeout::new(options.init_timeout_ms);
+            let mut attempts = 32;
+            while attempts > 0 {
+                trace!("Enter SPI mode, attempt: {}..", 32i32 - attempts);
+
+                match maybe_await!(self.card_command(CMD0, 0)) {
+                    Err(Error::TimeoutCommand(0)) => {
+                        // Try again?
+                        warn!("Timed out, trying again..");
+                        attempts -= 1;
+                    }
+                    Err(e) => {
+                        return Err(e);
+                    }
+                    Ok(R1_IDLE_STATE) => {
+                        break;
+                    }
+                    Ok(r) => {
+                        // Try again
+                        warn!("Got response: {:x}, trying again..", r);
+                    }
+                }
+
+                maybe_await!(self.delay(&mut timeout, Error::TimeoutCommand(CMD0)))?;
+            }
+            if attempts == 0 {
+                return Err(Error::CardNotFound);
+            }
+            // Enable CRC
+            debug!("Enable CRC: {}", options.require_crc);
+            if maybe_await!(self.card_command(CMD59, 1))? != R1_IDLE_STATE && options.require_crc {
+                return Err(Error::CantEnableCRC);
+            }
+            // Check card version
+            let mut timeout = Timeout::new(options.init_timeout_ms);
+            loop {
+                if maybe_await!(self.card_command(CMD8, 0x1AA))?
+                    == (R1_ILLEGAL_COMMAND | R1_IDLE_STATE)
+                {
+                    self.card_type = CardType::SD1;
+                    break;
+                }
+                let mut r7 = [0u8; 4];
+                maybe_await!(self.read_bytes(&mut r7))?;
+                if r7[3] == 0xAA {
+                    self.card_type = CardType::SD2;
+                    break;
+                }
+                maybe_await!(self.delay(&mut timeout, Error::TimeoutCommand(CMD8)))?;
+            }
+            debug!("Card version: {:?}", self.card_type);
+
+            let arg = match self.card_type {
+                CardType::SD1 | CardType::MMC | CardType::MMCHC => 0,
+                CardType::SD2 | CardType::SDHC => 0x4000_0000,
+            };
+
+            let mut timeout = Timeout::new(options.init_timeout_ms);
+            loop {
+                let r1 = maybe_await!(self.card_acmd(ACMD41, arg))?;
+                if r1 == R1_READY_STATE {
+                    break;
+                }
+                if (r1 & R1_ILLEGAL_COMMAND) != 0 && self.card_type == CardType::SD1 {
+                    // Only SD cards know ACMD41, so this must be an MMC card
+                    return maybe_await!(self.init_mmc(&mut timeout));
+                }
+                maybe_await!(self.delay(&mut timeout, Error::TimeoutACommand(ACMD41)))?;
+            }
+
+            if self.card_type == CardType::SD2 {
+                if maybe_await!(self.card_command(CMD58, 0))? != 0 {
+                    return Err(Error::Cmd58Error);
+                }
+                let mut ocr = [0u8; 4];
+                maybe_await!(self.read_bytes(&mut ocr))?;
+                if (ocr[0] & 0xC0) == 0xC0 {
+                    self.card_type = CardType::SDHC;
+                }
+            }
+            self.state = State::Idle;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Finish initialising an MMC card, which uses CMD1 rather than ACMD41,
+        /// and work out how it is addressed.
+        async fn init_mmc(&mut self, timeout: &mut Timeout) -> Result<(), Error> {
+            // Cards bigger than 2 GiB only work if we ask for sector addressing
+            while maybe_await!(self.card_command(CMD1, OCR_MMC_SECTOR_MODE))? != R1_READY_STATE {
+                maybe_await!(self.delay(timeout, Error::TimeoutCommand(CMD1)))?;
+            }
+            if maybe_await!(self.card_command(CMD58, 0))? != 0 {
+                return Err(Error::Cmd58Error);
+            }
+            let mut ocr = [0u8; 4];
+            maybe_await!(self.read_bytes(&mut ocr))?;
+            if u32::from_be_bytes(ocr) & OCR_MMC_ACCESS_MODE == OCR_MMC_SECTOR_MODE {
+                self.card_type = CardType::MMCHC;
+            } else {
+                self.card_type = CardType::MMC;
+                // Byte-addressed cards don't always start with 512 byte blocks
+                maybe_await!(self.card_command_ok(CMD16, 512))?;
+            }
+            debug!("Card version: {:?}", self.card_type);
+            self.state = State::Idle;
+            Ok(())
+        }
+    });
+
+    /// Is there a card in the socket? If not, any card we had initialised is
+    /// gone.
+    pub(crate) fn card_present(&mut self) -> Result<bool, Error> {
+        let present = self.transport.card_present()?;
+        if !present {
+            self.state = State::NoInit;
+        }
+        Ok(present)
+    }
+
+    /// Check there is an initialised card to run an operation on, before
+    /// selecting it with `cs_low`.
+    fn check_card(&mut self) -> Result<(), Error> {
+        if !self.card_present()? {
+            return Err(Error::CardNotFound);
+        }
+        if self.state != State::Idle {
+            return Err(Error::BadState);
+        }
+        Ok(())
+    }
+
+    /// Like `check_card`, but for operations on the card's data, so they're
+    /// refused if the card is locked.
+    fn check_unlocked_card(&mut self) -> Result<(), Error> {
+        if self.locked {
+            return Err(Error::CardLocked);
+        }
+        self.check_card()
+    }
+
+    /// Like `check_unlocked_card`, but for operations which change what's on
+    /// the card, so they're refused if the card's write-protect tab is set.
+    fn check_writable_card(&mut self) -> Result<(), Error> {
+        if self.transport.write_protected()? {
+            return Err(Error::WriteProtected);
+        }
+        self.check_unlocked_card()
+    }
+
+    maybe_async!({
+        /// Finish an operation on the card, which ended with `result`, by
+        /// releasing the chipselect - even if the operation errored. If the card
+        /// has been pulled out during the operation, it will have to be acquired
+        /// again.
+        async fn end_operation<R>(&mut self, result: Result<R, Error>) -> Result<R, Error> {
+            maybe_await!(self.cs_high())?;
+            if result.is_err() && !self.card_present()? {
+                return Err(Error::CardNotFound);
+            }
+            result
+        }
+    });
+
+    maybe_async!({
+        /// Perform an application-specific command.
+        async fn card_acmd(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+            maybe_await!(self.card_command(CMD55, 0))?;
+            maybe_await!(self.card_command(command, arg))
+        }
+    });
+
+    maybe_async!({
+        /// Perform a command.
+        async fn card_command(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+            // The card might still be busy finishing a write
+            maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+            let mut buf = [
+                0x40 | command,
+                (arg >> 24) as u8,
+                (arg >> 16) as u8,
+                (arg >> 8) as u8,
+                arg as u8,
+                0,
+            ];
+            buf[5] = crc7(&buf[0..5]);
+
+            maybe_await!(self.write_bytes(&buf))?;
+
+            // skip stuff byte for stop read
+            if command == CMD12 {
+                let _result = maybe_await!(self.receive())?;
+            }
+
+            for _ in 0..512 {
+                let result = maybe_await!(self.receive())?;
+                if (result & 0x80) == ERROR_OK {
+                    return Ok(result);
+                }
+            }
+
+            Err(Error::TimeoutCommand(command))
+        }
+    });
+
+    maybe_async!({
+        /// Perform a command, which fails unless the card says it is ready.
+        async fn card_command_ok(&mut self, command: u8, arg: u32) -> Result<(), Error> {
+            match maybe_await!(self.card_command(command, arg))? {
+                R1_READY_STATE => Ok(()),
+                r1 => Err(Error::CommandError(command, R1Status(r1))),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Perform an application-specific command, which fails unless the card
+        /// says it is ready.
+        async fn card_acmd_ok(&mut self, command: u8, arg: u32) -> Result<(), Error> {
+            maybe_await!(self.card_command_ok(CMD55, 0))?;
+            maybe_await!(self.card_command_ok(command, arg))
+        }
+    });
+
+    maybe_async!({
+        /// Read the second byte of an R2 response, and fail if it has any error
+        /// bits set.
+        async fn check_card_status(&mut self) -> Result<(), Error> {
+            // Being locked isn't an error
+            match maybe_await!(self.receive())? & !R2_CARD_LOCKED {
+                0 => Ok(()),
+                status => Err(Error::CardStatusError(CardStatus(status))),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read the card's status with CMD13.
+        async fn read_card_status(&mut self) -> Result<CardStatus, Error> {
+            maybe_await!(self.card_command_ok(CMD13, 0))?;
+            Ok(CardStatus(maybe_await!(self.receive())?))
+        }
+    });
+
+    maybe_async!({
+        /// Wait for the card to finish whatever it is doing, then read its
+        /// status.
+        async fn read_status_when_idle(&mut self) -> Result<CardStatus, Error> {
+            maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+            maybe_await!(self.read_card_status())
+        }
+    });
+
+    /// Convert a block index into the address the card wants, which is in
+    /// bytes for standard capacity cards.
+    fn block_address(&self, block_idx: BlockIdx) -> u32 {
+        match self.card_type {
+            CardType::SD1 | CardType::SD2 | CardType::MMC => block_idx.0 * 512,
+            CardType::SDHC | CardType::MMCHC => block_idx.0,
+        }
+    }
+
+    maybe_async!({
+        /// Receive a byte from the SD card by clocking in an 0xFF byte.
+        async fn receive(&mut self) -> Result<u8, Error> {
+            maybe_await!(self.transfer(0xFF))
+        }
+    });
+
+    maybe_async!({
+        /// Send a byte from the SD card.
+        async fn send(&mut self, out: u8) -> Result<(), Error> {
+            let _ = maybe_await!(self.transfer(out))?;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Receive enough bytes from the SD card to fill the buffer, by clocking
+        /// out 0xFF bytes.
+        async fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+            buffer.fill(0xFF);
+            maybe_await!(self.transport.transfer(buffer))
+        }
+    });
+
+    maybe_async!({
+        /// Send some bytes to the SD card, ignoring what comes back.
+        async fn write_bytes(&mut self, buffer: &[u8]) -> Result<(), Error> {
+            maybe_await!(self.transport.write(buffer))
+        }
+    });
+
+    maybe_async!({
+        /// Send one byte and receive one byte.
+        async fn transfer(&mut self, out: u8) -> Result<u8, Error> {
+            let mut data = [out];
+            maybe_await!(self.transport.transfer(&mut data))?;
+            Ok(data[0])
+        }
+    });
+
+    maybe_async!({
+        /// Spin until the card returns 0xFF, or we run out of time and
+        /// timeout.
+        async fn wait_not_busy(&mut self, timeout_ms: u32) -> Result<(), Error> {
+            let mut timeout = Timeout::new(timeout_ms);
+            loop {
+                let s = maybe_await!(self.receive())?;
+                if s == 0xFF {
+                    break;
+                }
+                maybe_await!(self.delay(&mut timeout, Error::TimeoutWaitNotBusy))?;
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Return the usable size of this SD card in bytes.
+        pub(crate) async fn card_size_bytes(&mut self) -> Result<u64, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_card_size_bytes());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Work out the card's size from its CSD (or its Extended CSD, for big
+        /// MMC cards).
+        async fn read_card_size_bytes(&mut self) -> Result<u64, Error> {
+            let csd = maybe_await!(self.read_csd())?;
+            match csd {
+                Csd::V1(ref contents) => Ok(contents.card_capacity_bytes()),
+                Csd::V2(ref contents) => Ok(contents.card_capacity_bytes()),
+                Csd::V3(ref contents) => Ok(contents.card_capacity_bytes()),
+                // Sector-addressed cards are too big for their CSD
+                Csd::Mmc(_) if self.card_type == CardType::MMCHC => {
+                    Ok(maybe_await!(self.read_ext_csd())?.card_capacity_bytes())
+                }
+                Csd::Mmc(ref contents) => Ok(contents.card_capacity_bytes()),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read the card's identification register.
+        pub(crate) async fn card_info(&mut self) -> Result<Cid, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_cid());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read the 'card identification' register, and check its CRC.
+        async fn read_cid(&mut self) -> Result<Cid, Error> {
+            let mut cid = Cid::new();
+            maybe_await!(self.card_command_ok(CMD10, 0))?;
+            maybe_await!(self.read_data(&mut cid.data))?;
+            if !cid.crc_valid() {
+                return Err(Error::CrcError(
+                    u16::from(cid.data[15]),
+                    u16::from(crc7(&cid.data[0..15])),
+                ));
+            }
+            Ok(cid)
+        }
+    });
+
+    maybe_async!({
+        /// Read the card's 'card specific data' register.
+        pub(crate) async fn card_csd(&mut self) -> Result<Csd, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_csd());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read an MMC card's Extended CSD register.
+        pub(crate) async fn card_ext_csd(&mut self) -> Result<ExtCsd, Error> {
+            if !self.card_type.is_mmc() {
+                return Err(Error::BadState);
+            }
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_ext_csd());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read the card's SD Configuration Register.
+        pub(crate) async fn card_scr(&mut self) -> Result<Scr, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_scr());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read the SD Configuration Register with ACMD51.
+        async fn read_scr(&mut self) -> Result<Scr, Error> {
+            let mut scr = Scr::new();
+            maybe_await!(self.card_acmd_ok(ACMD51, 0))?;
+            maybe_await!(self.read_data(&mut scr.data))?;
+            Ok(scr)
+        }
+    });
+
+    maybe_async!({
+        /// Read the card's SD Status register.
+        pub(crate) async fn card_sd_status(&mut self) -> Result<SdStatus, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_sd_status());
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read the SD Status register with ACMD13.
+        async fn read_sd_status(&mut self) -> Result<SdStatus, Error> {
+            let mut status = SdStatus::new();
+            // The response is R2, so there's a second status byte
+            maybe_await!(self.card_acmd_ok(ACMD13, 0))?;
+            maybe_await!(self.check_card_status())?;
+            maybe_await!(self.read_data(&mut status.data))?;
+            Ok(status)
+        }
+    });
+
+    maybe_async!({
+        /// Erase some blocks on the card, from `first_block` to `last_block`
+        /// inclusive.
+        pub(crate) async fn erase(
+            &mut self,
+            first_block: BlockIdx,
+            last_block: BlockIdx,
+        ) -> Result<(), Error> {
+            self.check_writable_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.erase_blocks(first_block, last_block));
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Pick the blocks to erase, then erase them and wait for the card to
+        /// finish.
+        async fn erase_blocks(
+            &mut self,
+            first_block: BlockIdx,
+            last_block: BlockIdx,
+        ) -> Result<(), Error> {
+            let num_blocks = last_block.0.saturating_sub(first_block.0).saturating_add(1);
+            // MMC cards have their own commands for picking what to erase
+            let (start_command, end_command) = if self.card_type.is_mmc() {
+                (CMD35, CMD36)
+            } else {
+                (CMD32, CMD33)
+            };
+            maybe_await!(self.card_command_ok(start_command, self.block_address(first_block)))?;
+            maybe_await!(self.card_command_ok(end_command, self.block_address(last_block)))?;
+            maybe_await!(self.card_command_ok(CMD38, 0))?;
+            maybe_await!(
+                self.wait_not_busy(self.options.erase_timeout_ms.saturating_mul(num_blocks))
+            )
+        }
+    });
+
+    maybe_async!({
+        /// Ask the card whether it is locked.
+        pub(crate) async fn is_locked(&mut self) -> Result<bool, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_card_status());
+            let status = maybe_await!(self.end_operation(result))?;
+            self.locked = status.is_card_locked();
+            Ok(status.is_card_locked())
+        }
+    });
+
+    maybe_async!({
+        /// Send a LOCK_UNLOCK command with the given flags, and the old and new
+        /// passwords (as far as the flags need them), and check it worked.
+        pub(crate) async fn lock_unlock(
+            &mut self,
+            flags: u8,
+            old: &[u8],
+            new: &[u8],
+        ) -> Result<(), Error> {
+            if old.len() > MAX_PASSWORD_LEN || new.len() > MAX_PASSWORD_LEN {
+                return Err(Error::PasswordTooLong);
+            }
+            let mut data = [0u8; 2 + 2 * MAX_PASSWORD_LEN];
+            data[0] = flags;
+            data[1] = (old.len() + new.len()) as u8;
+            data[2..2 + old.len()].copy_from_slice(old);
+            data[2 + old.len()..2 + old.len() + new.len()].copy_from_slice(new);
+            // A forced erase is just the flags, but takes a long time
+            let (len, timeout_ms) = if flags == LOCK_ERASE {
+                (1, FORCE_ERASE_TIMEOUT_MS)
+            } else {
+                (2 + old.len() + new.len(), self.options.write_timeout_ms)
+            };
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.send_lock_unlock(&data[..len], timeout_ms));
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Send the LOCK_UNLOCK data block, with the block length set to fit it,
+        /// and see what the card made of it.
+        async fn send_lock_unlock(&mut self, data: &[u8], timeout_ms: u32) -> Result<(), Error> {
+            // The block length says how much data the command has
+            maybe_await!(self.card_command_ok(CMD16, data.len() as u32))?;
+            let result = maybe_await!(self.write_lock_unlock(data, timeout_ms));
+            let restored = maybe_await!(self.card_command_ok(CMD16, 512));
+            result.and(restored)?;
+            let status = maybe_await!(self.read_card_status())?;
+            self.locked = status.is_card_locked();
+            if status.is_wp_erase_skip() {
+                return Err(Error::LockUnlockFailed);
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Send CMD42 and its data block, and wait for the card to act on it.
+        async fn write_lock_unlock(&mut self, data: &[u8], timeout_ms: u32) -> Result<(), Error> {
+            maybe_await!(self.card_command_ok(CMD42, 0))?;
+            maybe_await!(self.write_data(DATA_START_BLOCK, data))?;
+            maybe_await!(self.wait_not_busy(timeout_ms))
+        }
+    });
+
+    maybe_async!({
+        /// Erase everything on the card, including its password.
+        pub(crate) async fn force_erase(&mut self) -> Result<(), Error> {
+            if self.transport.write_protected()? {
+                return Err(Error::WriteProtected);
+            }
+            maybe_await!(self.lock_unlock(LOCK_ERASE, &[], &[]))
+        }
+    });
+
+    maybe_async!({
+        /// Can this card erase single blocks?
+        pub(crate) async fn erase_single_block_enabled(&mut self) -> Result<bool, Error> {
+            self.check_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_csd());
+            let csd = maybe_await!(self.end_operation(result))?;
+            match csd {
+                Csd::V1(ref contents) => Ok(contents.erase_single_block_enabled()),
+                Csd::V2(ref contents) => Ok(contents.erase_single_block_enabled()),
+                Csd::V3(ref contents) => Ok(contents.erase_single_block_enabled()),
+                // MMC cards erase whole erase groups
+                Csd::Mmc(ref contents) => Ok(contents.erase_sector_size_blocks() == 1),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read the 'card specific data' block.
+        async fn read_csd(&mut self) -> Result<Csd, Error> {
+            let mut data = [0u8; 16];
+            maybe_await!(self.card_command_ok(CMD9, 0))?;
+            maybe_await!(self.read_data(&mut data))?;
+            if self.card_type.is_mmc() {
+                return Ok(Csd::Mmc(CsdMmc { data }));
+            }
+            // Version 2 standard capacity cards still have a version 1 CSD, so
+            // go by the CSD_STRUCTURE field rather than the card type
+            match data[0] >> 6 {
+                0 => Ok(Csd::V1(CsdV1 { data })),
+                1 => Ok(Csd::V2(CsdV2 { data })),
+                2 => Ok(Csd::V3(CsdV3 { data })),
+                _ => Err(Error::RegisterReadError),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read an MMC card's Extended CSD, with the command SD cards use for
+        /// SEND_IF_COND.
+        async fn read_ext_csd(&mut self) -> Result<ExtCsd, Error> {
+            let mut ext_csd = ExtCsd::new();
+            maybe_await!(self.card_command_ok(CMD8, 0))?;
+            maybe_await!(self.read_data(&mut ext_csd.data))?;
+            Ok(ext_csd)
+        }
+    });
+
+    maybe_async!({
+        /// Read an arbitrary number of bytes from the card. Always fills the
+        /// given buffer, so make sure it's the right size.
+        async fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+            // Get first non-FF byte.
+            let mut timeout = Timeout::new(self.options.read_timeout_ms);
+            let status = loop {
+                let s = maybe_await!(self.receive())?;
+                if s != 0xFF {
+                    break s;
+                }
+                maybe_await!(self.delay(&mut timeout, Error::TimeoutReadBuffer))?;
+            };
+            if status & DATA_ERROR_TOKEN_MASK == 0 {
+                return Err(Error::DataError(DataErrorToken(status)));
+            }
+            if status != DATA_START_BLOCK {
+                return Err(Error::ReadError);
+            }
+
+            maybe_await!(self.read_bytes(buffer))?;
+
+            let mut crc_bytes = [0u8; 2];
+            maybe_await!(self.read_bytes(&mut crc_bytes))?;
+            let crc = u16::from_be_bytes(crc_bytes);
+
+            let calc_crc = crc16(buffer);
+            if crc != calc_crc {
+                return Err(Error::CrcError(crc, calc_crc));
+            }
+
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Write an arbitrary number of bytes to the card.
+        async fn write_data(&mut self, token: u8, buffer: &[u8]) -> Result<(), Error> {
+            let calc_crc = crc16(buffer);
+            maybe_await!(self.send(token))?;
+            maybe_await!(self.write_bytes(buffer))?;
+            maybe_await!(self.write_bytes(&calc_crc.to_be_bytes()))?;
+            match maybe_await!(self.receive())? & DATA_RES_MASK {
+                DATA_RES_ACCEPTED => Ok(()),
+                DATA_RES_CRC_ERROR => Err(Error::WriteCrcError),
+                _ => Err(Error::WriteError),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read one or more blocks, starting at the given block index, retrying
+        /// as the options allow.
+        pub(crate) async fn read_blocks(
+            &mut self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            let mut attempts = 0;
+            loop {
+                let result = maybe_await!(self.read_blocks_once(blocks, start_block_idx));
+                if !maybe_await!(self.should_retry(&result, &mut attempts)) {
+                    return result;
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Write one or more blocks, starting at the given block index, retrying
+        /// as the options allow.
+        pub(crate) async fn write_blocks(
+            &mut self,
+            blocks: &[Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            let mut attempts = 0;
+            loop {
+                let result = maybe_await!(self.write_blocks_once(blocks, start_block_idx));
+                if !maybe_await!(self.should_retry(&result, &mut attempts)) {
+                    return result;
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read one or more blocks, starting at the given block index.
+        async fn read_blocks_once(
+            &mut self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            self.check_unlocked_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.read_blocks_selected(blocks, start_block_idx));
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Read one or more blocks from the selected card.
+        async fn read_blocks_selected(
+            &mut self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            let start_idx = self.block_address(start_block_idx);
+            if blocks.len() == 1 {
+                // Start a single-block read
+                maybe_await!(self.card_command_ok(CMD17, start_idx))?;
+                maybe_await!(self.read_data(&mut blocks[0].contents))?;
+            } else {
+                // Start a multi-block read
+                maybe_await!(self.card_command_ok(CMD18, start_idx))?;
+                let mut result = Ok(());
+                for block in blocks.iter_mut() {
+                    result = maybe_await!(self.read_data(&mut block.contents));
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                // Stop the read - if it went wrong, the card will otherwise
+                // carry on sending
+                if result.is_ok() || self.options.stop_on_error {
+                    let stopped = maybe_await!(self.card_command_ok(CMD12, 0));
+                    result = result.and(stopped);
+                }
+                result?;
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Write one or more blocks, starting at the given block index.
+        async fn write_blocks_once(
+            &mut self,
+            blocks: &[Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            self.check_writable_card()?;
+            maybe_await!(self.cs_low())?;
+            let result = maybe_await!(self.write_blocks_selected(blocks, start_block_idx));
+            maybe_await!(self.end_operation(result))
+        }
+    });
+
+    maybe_async!({
+        /// Write one or more blocks to the selected card.
+        async fn write_blocks_selected(
+            &mut self,
+            blocks: &[Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Error> {
+            let start_idx = self.block_address(start_block_idx);
+            if blocks.len() == 1 {
+                // Start a single-block write
+                maybe_await!(self.card_command_ok(CMD24, start_idx))?;
+                maybe_await!(self.write_data(DATA_START_BLOCK, &blocks[0].contents))?;
+                maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+                maybe_await!(self.card_command_ok(CMD13, 0))?;
+                maybe_await!(self.check_card_status())?;
+            } else {
+                // Tell the card how many blocks are coming, so it can erase
+                // them all first. It's only a hint, so carry on without it.
+                let is_mmc = self.card_type.is_mmc();
+                if !is_mmc && blocks.len() >= PRE_ERASE_MIN_BLOCKS {
+                    if let Err(_e) = maybe_await!(self.card_acmd_ok(ACMD23, blocks.len() as u32)) {
+                        debug!("Card won't pre-erase: {:?}", _e);
+                    }
+                }
+                // Start a multi-block write
+                maybe_await!(self.card_command_ok(CMD25, start_idx))?;
+                if let Err(e) = maybe_await!(self.write_multiple_blocks(blocks)) {
+                    // Only SD cards can say how much got written
+                    if is_mmc {
+                        return Err(e);
+                    }
+                    // Find out how much of it made it onto the card
+                    return match maybe_await!(self.blocks_written()) {
+                        Ok(written) if (written as usize) < blocks.len() => {
+                            warn!("Write failed with {:?} after {} blocks", e, written);
+                            Err(Error::PartialWrite(written))
+                        }
+                        _ => Err(e),
+                    };
+                }
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Send the blocks of a multi-block write, then stop it and check it
+        /// worked. If a block is rejected, the write is abandoned.
+        async fn write_multiple_blocks(&mut self, blocks: &[Block]) -> Result<(), Error> {
+            for block in blocks.iter() {
+                let result = maybe_await!(self.write_next_block(block));
+                if result.is_err() {
+                    // Abandon the write, whether or not the card is still
+                    // expecting more blocks, or it will wait for them forever
+                    let _ = maybe_await!(self.send(STOP_TRAN_TOKEN));
+                    let _ = maybe_await!(self.card_command(CMD12, 0));
+                    return result;
+                }
+            }
+            // Stop the write, and once the card has finished programming the
+            // last block, see if anything went wrong
+            maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+            maybe_await!(self.send(STOP_TRAN_TOKEN))?;
+            maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+            maybe_await!(self.card_command_ok(CMD13, 0))?;
+            maybe_await!(self.check_card_status())
+        }
+    });
+
+    maybe_async!({
+        /// Send one block of a multi-block write, once the card is ready for it.
+        async fn write_next_block(&mut self, block: &Block) -> Result<(), Error> {
+            maybe_await!(self.wait_not_busy(self.options.write_timeout_ms))?;
+            maybe_await!(self.write_data(WRITE_MULTIPLE_TOKEN, &block.contents))
+        }
+    });
+
+    maybe_async!({
+        /// Ask the card how many blocks of the last multi-block write it wrote
+        /// without errors.
+        async fn blocks_written(&mut self) -> Result<u32, Error> {
+            let mut count = [0u8; 4];
+            maybe_await!(self.card_acmd_ok(ACMD22, 0))?;
+            maybe_await!(self.read_data(&mut count))?;
+            Ok(u32::from_be_bytes(count))
+        }
+    });
+
+    maybe_async!({
+        /// Determine how many blocks this device can hold.
+        pub(crate) async fn num_blocks(&mut self) -> Result<BlockCount, Error> {
+            let num_bytes = maybe_await!(self.card_size_bytes())?;
+            let num_blocks = u32::try_from(num_bytes / 512).map_err(|_| Error::CardTooLarge)?;
+            Ok(BlockCount(num_blocks))
+        }
+    });
+}
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1084 @@
+//! embedded-sdmmc-rs - Controller
+//!
+//! Opens the volumes on a block device, and the files and directories within
+//! them.
+
+#[cfg(feature = "exfat")]
+use super::exfat;
+use super::BlockDevice;
+use super::{fat, fsck};
+#[cfg(feature = "exfat")]
+use crate::PARTITION_ID_EXFAT;
+use crate::{
+    fat::{FatType, VolumeName, RESERVED_ENTRIES},
+    solve_mode_variant, Attributes, Block, BlockCount, BlockIdx, Cluster, DirEntry, Directory,
+    Error, File, FileSize, FsckIssue, FsckReport, Mode, ShortFileName, TimeSource, Timestamp,
+    Volume, VolumeIdx, VolumeStats, VolumeType, MAX_FILE_SIZE, MAX_OPEN_DIRS, PARTITION_ID_FAT12,
+    PARTITION_ID_FAT16, PARTITION_ID_FAT16_LBA, PARTITION_ID_FAT32_CHS_LBA, PARTITION_ID_FAT32_LBA,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::debug;
+
+#[cfg(feature = "defmt-log")]
+use defmt::debug;
+
+/// A `Controller` wraps a block device and gives access to the volumes within it.
+pub struct Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    <D as BlockDevice>::Error: core::fmt::Debug,
+{
+    pub(crate) block_device: D,
+    pub(crate) timesource: T,
+    open_dirs: [(VolumeIdx, Cluster); MAX_OPEN_DIRS],
+    open_files: [(VolumeIdx, Cluster); MAX_OPEN_DIRS],
+    update_access_dates: bool,
+}
+
+impl<D, T> Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    <D as BlockDevice>::Error: core::fmt::Debug,
+{
+    /// Create a new Disk Controller using a generic `BlockDevice`. From this
+    /// controller we can open volumes (partitions) and with those we can open
+    /// files.
+    pub fn new(block_device: D, timesource: T) -> Controller<D, T> {
+        debug!("Creating new embedded-sdmmc::Controller");
+        Controller {
+            block_device,
+            timesource,
+            open_dirs: [(VolumeIdx(0), Cluster::INVALID); 4],
+            open_files: [(VolumeIdx(0), Cluster::INVALID); 4],
+            update_access_dates: false,
+        }
+    }
+
+    /// Choose whether reading from a file updates its last access date. This
+    /// is off by default, as it means writing the file's directory entry
+    /// (although at most once a day for each open file).
+    pub fn set_update_access_dates(&mut self, enabled: bool) {
+        self.update_access_dates = enabled;
+    }
+
+    /// Temporarily get access to the underlying block device.
+    pub fn device(&mut self) -> &mut D {
+        &mut self.block_device
+    }
+
+    maybe_async!({
+        /// Get a volume (or partition) based on entries in the Master Boot
+        /// Record. We do not support GUID Partition Table disks. Nor do we
+        /// support any concept of drive letters - that is for a higher layer to
+        /// handle.
+        pub async fn get_volume(
+            &mut self,
+            volume_idx: VolumeIdx,
+        ) -> Result<Volume, Error<D::Error>> {
+            const PARTITION1_START: usize = 446;
+            const PARTITION2_START: usize = PARTITION1_START + PARTITION_INFO_LENGTH;
+            const PARTITION3_START: usize = PARTITION2_START + PARTITION_INFO_LENGTH;
+            const PARTITION4_START: usize = PARTITION3_START + PARTITION_INFO_LENGTH;
+            const FOOTER_START: usize = 510;
+            const FOOTER_VALUE: u16 = 0xAA55;
+            const PARTITION_INFO_LENGTH: usize = 16;
+            const PARTITION_INFO_STATUS_INDEX: usize = 0;
+            const PARTITION_INFO_TYPE_INDEX: usize = 4;
+            const PARTITION_INFO_LBA_START_INDEX: usize = 8;
+            const PARTITION_INFO_NUM_BLOCKS_INDEX: usize = 12;
+
+            let (part_type, lba_start, num_blocks) = {
+                let mut blocks = [Block::new()];
+                maybe_await!(self.block_device.read(&mut blocks, BlockIdx(0), "read_mbr"))
+                    .map_err(Error::DeviceError)?;
+                let block = &blocks[0];
+                // We only support Master Boot Record (MBR) partitioned cards, not
+                // GUID Partition Table (GPT)
+                if LittleEndian::read_u16(&block[FOOTER_START..FOOTER_START + 2]) != FOOTER_VALUE {
+                    return Err(Error::FormatError("Invalid MBR signature"));
+                }
+                let partition = match volume_idx {
+                    VolumeIdx(0) => {
+                        &block[PARTITION1_START..(PARTITION1_START + PARTITION_INFO_LENGTH)]
+                    }
+                    VolumeIdx(1) => {
+                        &block[PARTITION2_START..(PARTITION2_START + PARTITION_INFO_LENGTH)]
+                    }
+                    VolumeIdx(2) => {
+                        &block[PARTITION3_START..(PARTITION3_START + PARTITION_INFO_LENGTH)]
+                    }
+                    VolumeIdx(3) => {
+                        &block[PARTITION4_START..(PARTITION4_START + PARTITION_INFO_LENGTH)]
+                    }
+                    _ => {
+                        return Err(Error::NoSuchVolume);
+                    }
+                };
+                // Only 0x80 and 0x00 are valid (bootable, and non-bootable)
+                if (partition[PARTITION_INFO_STATUS_INDEX] & 0x7F) != 0x00 {
+                    return Err(Error::FormatError("Invalid partition status"));
+                }
+                let lba_start = LittleEndian::read_u32(
+                    &partition
+                        [PARTITION_INFO_LBA_START_INDEX..(PARTITION_INFO_LBA_START_INDEX + 4)],
+                );
+                let num_blocks = LittleEndian::read_u32(
+                    &partition
+                        [PARTITION_INFO_NUM_BLOCKS_INDEX..(PARTITION_INFO_NUM_BLOCKS_INDEX + 4)],
+                );
+                (
+                    partition[PARTITION_INFO_TYPE_INDEX],
+                    BlockIdx(lba_start),
+                    BlockCount(num_blocks),
+                )
+            };
+            match part_type {
+                PARTITION_ID_FAT32_CHS_LBA
+                | PARTITION_ID_FAT32_LBA
+                | PARTITION_ID_FAT16_LBA
+                | PARTITION_ID_FAT16
+                | PARTITION_ID_FAT12 => {
+                    let volume = maybe_await!(fat::parse_volume(self, lba_start, num_blocks))?;
+                    Ok(Volume {
+                        idx: volume_idx,
+                        volume_type: volume,
+                    })
+                }
+                #[cfg(feature = "exfat")]
+                PARTITION_ID_EXFAT => {
+                    let volume = maybe_await!(exfat::parse_volume(self, lba_start, num_blocks))?;
+                    Ok(Volume {
+                        idx: volume_idx,
+                        volume_type: volume,
+                    })
+                }
+                _ => Err(Error::FormatError("Partition type not supported")),
+            }
+        }
+    });
+
+    /// Open a directory.
+    ///
+    /// You can then read the directory entries with `iterate_dir` and `open_file_in_dir`.
+    ///
+    /// TODO: Work out how to prevent damage occuring to the file system while
+    /// this directory handle is open. In particular, stop this directory
+    /// being unlinked.
+    pub fn open_root_dir(&mut self, volume: &Volume) -> Result<Directory, Error<D::Error>> {
+        // Find a free directory entry, and check the root dir isn't open. As
+        // we already know the root dir's magic cluster number, we can do both
+        // checks in one loop.
+        let mut open_dirs_row = None;
+        for (i, d) in self.open_dirs.iter().enumerate() {
+            if *d == (volume.idx, Cluster::ROOT_DIR) {
+                return Err(Error::DirAlreadyOpen);
+            }
+            if d.1 == Cluster::INVALID {
+                open_dirs_row = Some(i);
+                break;
+            }
+        }
+        let open_dirs_row = open_dirs_row.ok_or(Error::TooManyOpenDirs)?;
+        // Remember this open directory
+        self.open_dirs[open_dirs_row] = (volume.idx, Cluster::ROOT_DIR);
+        Ok(Directory {
+            cluster: Cluster::ROOT_DIR,
+            #[cfg(feature = "exfat")]
+            entry: None,
+        })
+    }
+
+    maybe_async!({
+        /// Open a directory.
+        ///
+        /// You can then read the directory entries with `iterate_dir` and `open_file_in_dir`.
+        ///
+        /// TODO: Work out how to prevent damage occuring to the file system while
+        /// this directory handle is open. In particular, stop this directory
+        /// being unlinked.
+        pub async fn open_dir(
+            &mut self,
+            volume: &Volume,
+            parent_dir: &Directory,
+            name: &str,
+        ) -> Result<Directory, Error<D::Error>> {
+            // Find a free open directory table row
+            let mut open_dirs_row = None;
+            for (i, d) in self.open_dirs.iter().enumerate() {
+                if d.1 == Cluster::INVALID {
+                    open_dirs_row = Some(i);
+                }
+            }
+            let open_dirs_row = open_dirs_row.ok_or(Error::TooManyOpenDirs)?;
+
+            // Open the directory
+            let dir_entry = match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fat::find_directory_entry(fat, self, parent_dir, name))?
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::find_directory_entry(exfat, self, parent_dir, name))?
+                }
+            };
+
+            if !dir_entry.attributes.is_directory() {
+                return Err(Error::OpenedDirAsFile);
+            }
+
+            // Check it's not already open
+            for dir_table_row in self.open_dirs.iter() {
+                if *dir_table_row == (volume.idx, dir_entry.cluster) {
+                    return Err(Error::DirAlreadyOpen);
+                }
+            }
+            // Remember this open directory
+            self.open_dirs[open_dirs_row] = (volume.idx, dir_entry.cluster);
+            Ok(Directory {
+                cluster: dir_entry.cluster,
+                #[cfg(feature = "exfat")]
+                entry: Some(dir_entry),
+            })
+        }
+    });
+
+    /// Close a directory. You cannot perform operations on an open directory
+    /// and so must close it if you want to do something with it.
+    pub fn close_dir(&mut self, volume: &Volume, dir: Directory) {
+        let target = (volume.idx, dir.cluster);
+        for d in self.open_dirs.iter_mut() {
+            if *d == target {
+                d.1 = Cluster::INVALID;
+                break;
+            }
+        }
+    }
+
+    maybe_async!({
+        /// Look in a directory for a named file.
+        pub async fn find_directory_entry(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            name: &str,
+        ) -> Result<DirEntry, Error<D::Error>> {
+            match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fat::find_directory_entry(fat, self, dir, name))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::find_directory_entry(exfat, self, dir, name))
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Call a callback function for each directory entry in a directory.
+        pub async fn iterate_dir<F>(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            func: F,
+        ) -> Result<(), Error<D::Error>>
+        where
+            F: FnMut(&DirEntry),
+        {
+            match &volume.volume_type {
+                VolumeType::Fat(fat) => maybe_await!(fat::iterate_dir(fat, self, dir, func)),
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::iterate_dir(exfat, self, dir, func))
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Open a file from DirEntry. This is obtained by calling iterate_dir. A file can only be opened once.
+        pub async fn open_dir_entry(
+            &mut self,
+            volume: &mut Volume,
+            dir_entry: DirEntry,
+            mode: Mode,
+        ) -> Result<File, Error<D::Error>> {
+            let open_files_row = self.get_open_files_row()?;
+            // Check it's not already open
+            for dir_table_row in self.open_files.iter() {
+                if *dir_table_row == (volume.idx, dir_entry.cluster) {
+                    return Err(Error::DirAlreadyOpen);
+                }
+            }
+            if dir_entry.attributes.is_directory() {
+                return Err(Error::OpenedDirAsFile);
+            }
+            if dir_entry.attributes.is_read_only() && mode != Mode::ReadOnly {
+                return Err(Error::ReadOnly);
+            }
+
+            let mode = solve_mode_variant(mode, true);
+            let file = match mode {
+                Mode::ReadOnly => File {
+                    starting_cluster: dir_entry.cluster,
+                    current_cluster: (0, dir_entry.cluster),
+                    current_offset: 0,
+                    length: dir_entry.size,
+                    mode,
+                    entry: dir_entry,
+                },
+                Mode::ReadWriteAppend => {
+                    let mut file = File {
+                        starting_cluster: dir_entry.cluster,
+                        current_cluster: (0, dir_entry.cluster),
+                        current_offset: 0,
+                        length: dir_entry.size,
+                        mode,
+                        entry: dir_entry,
+                    };
+                    // seek_from_end with 0 can't fail
+                    file.seek_from_end(0).ok();
+                    file
+                }
+                Mode::ReadWriteTruncate => {
+                    let mut file = File {
+                        starting_cluster: dir_entry.cluster,
+                        current_cluster: (0, dir_entry.cluster),
+                        current_offset: 0,
+                        length: dir_entry.size,
+                        mode,
+                        entry: dir_entry,
+                    };
+                    match &mut volume.volume_type {
+                        VolumeType::Fat(fat) => maybe_await!(fat::truncate_cluster_chain(
+                            fat,
+                            self,
+                            file.starting_cluster
+                        ))?,
+                        #[cfg(feature = "exfat")]
+                        VolumeType::ExFat(exfat) => {
+                            // exFAT files with no data have no clusters at all
+                            maybe_await!(exfat::truncate_cluster_chain(
+                                exfat,
+                                self,
+                                &mut file.entry
+                            ))?;
+                            file.starting_cluster = file.entry.cluster;
+                            file.current_cluster = (0, file.entry.cluster);
+                        }
+                    };
+                    file.update_length(0);
+                    // TODO update entry Timestamps
+                    match &volume.volume_type {
+                        VolumeType::Fat(fat) => {
+                            let fat_type = fat.get_fat_type();
+                            maybe_await!(self.write_entry_to_disk(fat_type, &file.entry))?;
+                        }
+                        #[cfg(feature = "exfat")]
+                        VolumeType::ExFat(exfat) => {
+                            maybe_await!(exfat::write_entry(exfat, self, &file.entry))?
+                        }
+                    };
+
+                    file
+                }
+                _ => return Err(Error::Unsupported),
+            };
+            // Remember this open file
+            self.open_files[open_files_row] = (volume.idx, file.starting_cluster);
+            Ok(file)
+        }
+    });
+
+    maybe_async!({
+        /// Open a file with the given full path. A file can only be opened once.
+        pub async fn open_file_in_dir(
+            &mut self,
+            volume: &mut Volume,
+            dir: &Directory,
+            name: &str,
+            mode: Mode,
+        ) -> Result<File, Error<D::Error>> {
+            let dir_entry = match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fat::find_directory_entry(fat, self, dir, name))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::find_directory_entry(exfat, self, dir, name))
+                }
+            };
+
+            let open_files_row = self.get_open_files_row()?;
+            let dir_entry = match dir_entry {
+                Ok(entry) => Some(entry),
+                Err(_)
+                    if (mode == Mode::ReadWriteCreate)
+                        | (mode == Mode::ReadWriteCreateOrTruncate)
+                        | (mode == Mode::ReadWriteCreateOrAppend) =>
+                {
+                    None
+                }
+                _ => return Err(Error::FileNotFound),
+            };
+
+            let mode = solve_mode_variant(mode, dir_entry.is_some());
+
+            match mode {
+                Mode::ReadWriteCreate => {
+                    if dir_entry.is_some() {
+                        return Err(Error::FileAlreadyExists);
+                    }
+                    let att = Attributes::create_from_fat(0);
+                    let entry = match &mut volume.volume_type {
+                        VolumeType::Fat(fat) => {
+                            let file_name = ShortFileName::create_from_str(name)
+                                .map_err(Error::FilenameError)?;
+                            maybe_await!(fat::write_new_directory_entry(
+                                fat, self, dir, file_name, att
+                            ))?
+                        }
+                        #[cfg(feature = "exfat")]
+                        VolumeType::ExFat(exfat) => maybe_await!(
+                            exfat::write_new_directory_entry(exfat, self, dir, name, att)
+                        )?,
+                    };
+
+                    let file = File {
+                        starting_cluster: entry.cluster,
+                        current_cluster: (0, entry.cluster),
+                        current_offset: 0,
+                        length: entry.size,
+                        mode,
+                        entry,
+                    };
+                    // Remember this open file
+                    self.open_files[open_files_row] = (volume.idx, file.starting_cluster);
+                    Ok(file)
+                }
+                _ => {
+                    // Safe to unwrap, since we actually have an entry if we got here
+                    let dir_entry = dir_entry.unwrap();
+                    maybe_await!(self.open_dir_entry(volume, dir_entry, mode))
+                }
+            }
+        }
+    });
+
+    /// Get the next entry in open_files list
+    fn get_open_files_row(&self) -> Result<usize, Error<D::Error>> {
+        // Find a free directory entry
+        let mut open_files_row = None;
+        for (i, d) in self.open_files.iter().enumerate() {
+            if d.1 == Cluster::INVALID {
+                open_files_row = Some(i);
+            }
+        }
+        open_files_row.ok_or(Error::TooManyOpenDirs)
+    }
+
+    maybe_async!({
+        /// Delete a closed file with the given full path, if exists.
+        pub async fn delete_file_in_dir(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            name: &str,
+        ) -> Result<(), Error<D::Error>> {
+            debug!(
+                "delete_file(volume={:?}, dir={:?}, filename={:?}",
+                volume, dir, name
+            );
+            let dir_entry = match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fat::find_directory_entry(fat, self, dir, name))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::find_directory_entry(exfat, self, dir, name))
+                }
+            }?;
+
+            if dir_entry.attributes.is_directory() {
+                return Err(Error::DeleteDirAsFile);
+            }
+
+            let target = (volume.idx, dir_entry.cluster);
+            for d in self.open_files.iter_mut() {
+                if *d == target {
+                    return Err(Error::FileIsOpen);
+                }
+            }
+
+            match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fat::delete_directory_entry(fat, self, dir, name))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::delete_directory_entry(exfat, self, dir, name))
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Change the read-only, hidden, system and archive attributes of a file
+        /// or directory. Any other attributes given are ignored.
+        pub async fn set_attributes(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            name: &str,
+            attributes: Attributes,
+        ) -> Result<(), Error<D::Error>> {
+            const CHANGEABLE: u8 = Attributes::READ_ONLY
+                | Attributes::HIDDEN
+                | Attributes::SYSTEM
+                | Attributes::ARCHIVE;
+            maybe_await!(self.update_dir_entry(volume, dir, name, |entry| {
+                entry.attributes = Attributes::create_from_fat(
+                    (entry.attributes.0 & !CHANGEABLE) | (attributes.0 & CHANGEABLE),
+                );
+            }))
+        }
+    });
+
+    maybe_async!({
+        /// Set the creation, modification and last access times of a file or
+        /// directory. Times given as `None` are left alone. FAT volumes only
+        /// record the date of the last access, not the time.
+        pub async fn set_times(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            name: &str,
+            ctime: Option<Timestamp>,
+            mtime: Option<Timestamp>,
+            atime: Option<Timestamp>,
+        ) -> Result<(), Error<D::Error>> {
+            maybe_await!(self.update_dir_entry(volume, dir, name, |entry| {
+                if let Some(ctime) = ctime {
+                    entry.ctime = ctime;
+                    entry.ctime_hundredths = 0;
+                }
+                if let Some(atime) = atime {
+                    entry.atime = Some(atime);
+                }
+                if let Some(mtime) = mtime {
+                    entry.mtime = mtime;
+                }
+            }))
+        }
+    });
+
+    maybe_async!({
+        /// Find a directory entry, change it, and write it back to disk.
+        async fn update_dir_entry<F>(
+            &mut self,
+            volume: &Volume,
+            dir: &Directory,
+            name: &str,
+            func: F,
+        ) -> Result<(), Error<D::Error>>
+        where
+            F: FnOnce(&mut DirEntry),
+        {
+            let mut dir_entry = maybe_await!(self.find_directory_entry(volume, dir, name))?;
+            // Writing to an open file would overwrite our changes
+            if !dir_entry.attributes.is_directory()
+                && self.open_files.contains(&(volume.idx, dir_entry.cluster))
+            {
+                return Err(Error::FileIsOpen);
+            }
+            func(&mut dir_entry);
+            match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(self.write_entry_to_disk(fat.get_fat_type(), &dir_entry))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => {
+                    maybe_await!(exfat::write_entry(exfat, self, &dir_entry))
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Read from an open file.
+        pub async fn read(
+            &mut self,
+            volume: &Volume,
+            file: &mut File,
+            buffer: &mut [u8],
+        ) -> Result<usize, Error<D::Error>> {
+            // Calculate which file block the current offset lies within
+            // While there is more to read, read the block and copy in to the buffer.
+            // If we need to find the next cluster, walk the FAT.
+            let mut space = buffer.len();
+            let mut read = 0;
+            while space > 0 && !file.eof() {
+                let (block_idx, block_offset, block_avail) = maybe_await!(self.find_data_on_disk(
+                    volume,
+                    &file.entry,
+                    &mut file.current_cluster,
+                    file.current_offset,
+                ))?;
+                let mut blocks = [Block::new()];
+                maybe_await!(self.block_device.read(&mut blocks, block_idx, "read"))
+                    .map_err(Error::DeviceError)?;
+                let block = &blocks[0];
+                let to_copy = block_avail
+                    .min(space)
+                    .min(usize::try_from(file.left()).unwrap_or(usize::MAX));
+                assert!(to_copy != 0);
+                buffer[read..read + to_copy]
+                    .copy_from_slice(&block[block_offset..block_offset + to_copy]);
+                read += to_copy;
+                space -= to_copy;
+                file.seek_from_current(to_copy as i32).unwrap();
+            }
+            if self.update_access_dates && read > 0 {
+                let now = self.timesource.get_timestamp();
+                let today = (
+                    now.year_since_1970,
+                    now.zero_indexed_month,
+                    now.zero_indexed_day,
+                );
+                let accessed_today = match file.entry.atime {
+                    Some(atime) => {
+                        (
+                            atime.year_since_1970,
+                            atime.zero_indexed_month,
+                            atime.zero_indexed_day,
+                        ) == today
+                    }
+                    None => false,
+                };
+                if !accessed_today {
+                    file.entry.atime = Some(now);
+                    match &volume.volume_type {
+                        VolumeType::Fat(fat) => {
+                            maybe_await!(self.write_entry_to_disk(fat.get_fat_type(), &file.entry))?
+                        }
+                        #[cfg(feature = "exfat")]
+                        VolumeType::ExFat(exfat) => {
+                            maybe_await!(exfat::write_entry(exfat, self, &file.entry))?
+                        }
+                    }
+                }
+            }
+            Ok(read)
+        }
+    });
+
+    maybe_async!({
+        /// Write to a open file.
+        pub async fn write(
+            &mut self,
+            volume: &mut Volume,
+            file: &mut File,
+            buffer: &[u8],
+        ) -> Result<usize, Error<D::Error>> {
+            #[cfg(feature = "defmt-log")]
+            debug!(
+                "write(volume={:?}, file={:?}, buffer={:x}",
+                volume, file, buffer
+            );
+
+            #[cfg(feature = "log")]
+            debug!(
+                "write(volume={:?}, file={:?}, buffer={:x?}",
+                volume, file, buffer
+            );
+
+            if file.mode == Mode::ReadOnly {
+                return Err(Error::ReadOnly);
+            }
+            if file.starting_cluster.0 < RESERVED_ENTRIES {
+                // file doesn't have a valid allocated cluster (possible zero-length file), allocate one
+                let old_cluster = file.starting_cluster;
+                file.starting_cluster = match &mut volume.volume_type {
+                    VolumeType::Fat(fat) => {
+                        maybe_await!(fat::alloc_cluster(fat, self, None, false))?
+                    }
+                    #[cfg(feature = "exfat")]
+                    VolumeType::ExFat(exfat) => {
+                        maybe_await!(exfat::alloc_cluster(exfat, self, &mut file.entry, None))?
+                    }
+                };
+                // Open files are tracked by their first cluster, so update our
+                // record to match
+                if let Some(row) = self
+                    .open_files
+                    .iter_mut()
+                    .find(|row| **row == (volume.idx, old_cluster))
+                {
+                    row.1 = file.starting_cluster;
+                }
+                file.entry.cluster = file.starting_cluster;
+                debug!("Alloc first cluster {:?}", file.starting_cluster);
+            }
+            if (file.current_cluster.1).0 < file.starting_cluster.0 {
+                debug!("Rewinding to start");
+                file.current_cluster = (0, file.starting_cluster);
+            }
+            let max_file_size = match &volume.volume_type {
+                VolumeType::Fat(_) => FileSize::from(MAX_FILE_SIZE),
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(_) => FileSize::MAX,
+            };
+            let bytes_until_max = max_file_size.saturating_sub(file.current_offset);
+            let bytes_to_write = usize::try_from(bytes_until_max)
+                .map_or(buffer.len(), |bytes_until_max| {
+                    core::cmp::min(buffer.len(), bytes_until_max)
+                });
+            let mut written = 0;
+
+            while written < bytes_to_write {
+                let mut current_cluster = file.current_cluster;
+                debug!(
+                    "Have written bytes {}/{}, finding cluster {:?}",
+                    written, bytes_to_write, current_cluster
+                );
+                let (block_idx, block_offset, block_avail) = match maybe_await!(self
+                    .find_data_on_disk(
+                        volume,
+                        &file.entry,
+                        &mut current_cluster,
+                        file.current_offset,
+                    )) {
+                    Ok(vars) => {
+                        debug!(
+                            "Found block_idx={:?}, block_offset={:?}, block_avail={}",
+                            vars.0, vars.1, vars.2
+                        );
+                        vars
+                    }
+                    Err(Error::EndOfFile) => {
+                        debug!("Extending file");
+                        match &mut volume.volume_type {
+                            VolumeType::Fat(ref mut fat) => {
+                                if maybe_await!(fat::alloc_cluster(
+                                    fat,
+                                    self,
+                                    Some(current_cluster.1),
+                                    false
+                                ))
+                                .is_err()
+                                {
+                                    return Ok(written);
+                                }
+                                debug!("Allocated new FAT cluster, finding offsets...");
+                                let new_offset = maybe_await!(self.find_data_on_disk(
+                                    volume,
+                                    &file.entry,
+                                    &mut current_cluster,
+                                    file.current_offset,
+                                ))
+                                .map_err(|_| Error::AllocationError)?;
+                                debug!("New offset {:?}", new_offset);
+                                new_offset
+                            }
+                            #[cfg(feature = "exfat")]
+                            VolumeType::ExFat(ref mut exfat) => {
+                                let bytes_per_cluster = u64::from(exfat.bytes_per_cluster());
+                                let new_cluster = match maybe_await!(exfat::alloc_cluster(
+                                    exfat,
+                                    self,
+                                    &mut file.entry,
+                                    Some(current_cluster.1)
+                                )) {
+                                    Ok(cluster) => cluster,
+                                    Err(_) => return Ok(written),
+                                };
+                                // The file's length doesn't cover the new
+                                // cluster yet, so step into it ourselves
+                                current_cluster =
+                                    (current_cluster.0 + bytes_per_cluster, new_cluster);
+                                debug!("Allocated new exFAT cluster, finding offsets...");
+                                let new_offset = maybe_await!(self.find_data_on_disk(
+                                    volume,
+                                    &file.entry,
+                                    &mut current_cluster,
+                                    file.current_offset,
+                                ))
+                                .map_err(|_| Error::AllocationError)?;
+                                debug!("New offset {:?}", new_offset);
+                                new_offset
+                            }
+                        }
+                    }
+                    Err(e) => return Err(e),
+                };
+                let mut blocks = [Block::new()];
+                let to_copy = core::cmp::min(block_avail, bytes_to_write - written);
+                if block_offset != 0 {
+                    debug!("Partial block write");
+                    maybe_await!(self.block_device.read(&mut blocks, block_idx, "read"))
+                        .map_err(Error::DeviceError)?;
+                }
+                let block = &mut blocks[0];
+                block[block_offset..block_offset + to_copy]
+                    .copy_from_slice(&buffer[written..written + to_copy]);
+                debug!("Writing block {:?}", block_idx);
+                maybe_await!(self.block_device.write(&blocks, block_idx))
+                    .map_err(Error::DeviceError)?;
+                written += to_copy;
+                file.current_cluster = current_cluster;
+                let to_copy = i32::try_from(to_copy).map_err(|_| Error::ConversionError)?;
+                // TODO: Should we do this once when the whole file is written?
+                let end_of_write = file.current_offset + FileSize::from(to_copy as u32);
+                file.update_length(file.length.max(end_of_write));
+                file.seek_from_current(to_copy).unwrap();
+                file.entry.attributes.set_archive(true);
+                file.entry.mtime = self.timesource.get_timestamp();
+                file.entry.atime = Some(file.entry.mtime);
+                debug!("Updating FAT info sector");
+                match &mut volume.volume_type {
+                    VolumeType::Fat(fat) => {
+                        maybe_await!(fat::update_info_sector(fat, self))?;
+                        debug!("Updating dir entry");
+                        maybe_await!(self.write_entry_to_disk(fat.get_fat_type(), &file.entry))?;
+                    }
+                    #[cfg(feature = "exfat")]
+                    VolumeType::ExFat(exfat) => {
+                        debug!("Updating dir entry");
+                        maybe_await!(exfat::write_entry(exfat, self, &file.entry))?;
+                    }
+                }
+            }
+            Ok(written)
+        }
+    });
+
+    /// Close a file with the given full path.
+    pub fn close_file(&mut self, volume: &Volume, file: File) -> Result<(), Error<D::Error>> {
+        let target = (volume.idx, file.starting_cluster);
+        for d in self.open_files.iter_mut() {
+            if *d == target {
+                d.1 = Cluster::INVALID;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if any files or folders are open.
+    pub fn has_open_handles(&self) -> bool {
+        !self
+            .open_dirs
+            .iter()
+            .chain(self.open_files.iter())
+            .all(|(_, c)| c == &Cluster::INVALID)
+    }
+
+    maybe_async!({
+        /// Check a FAT volume for damage, such as lost clusters, cross-linked
+        /// files and files whose size doesn't match their cluster chain.
+        ///
+        /// `bitmap` is used to track which clusters are in use, and must be at
+        /// least `fsck::bitmap_len(volume)` bytes long. `func` is called with
+        /// every problem found, and whether it was repaired. Only simple problems
+        /// are repaired, and only if `repair` is set, in which case no files or
+        /// directories may be open.
+        pub async fn fsck<F>(
+            &mut self,
+            volume: &mut Volume,
+            bitmap: &mut [u8],
+            repair: bool,
+            func: F,
+        ) -> Result<FsckReport, Error<D::Error>>
+        where
+            F: FnMut(&FsckIssue, bool),
+        {
+            if repair && self.has_open_handles() {
+                return Err(Error::FileIsOpen);
+            }
+            match &mut volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    maybe_await!(fsck::check_fat_volume(self, fat, bitmap, repair, func))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(_) => Err(Error::Unsupported),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Get the size of a volume and how much of it is free.
+        ///
+        /// On a FAT volume, the free cluster count from the FAT32 FSInfo sector
+        /// (or from an earlier call to `recount_free_clusters`) is used if there
+        /// is one and it looks sensible. Otherwise the FAT is scanned, which can
+        /// take a while on a large volume. On an exFAT volume the allocation
+        /// bitmap is always scanned.
+        pub async fn volume_stats(
+            &mut self,
+            volume: &Volume,
+        ) -> Result<VolumeStats, Error<D::Error>> {
+            match &volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    let free_clusters = match fat.free_clusters_count {
+                        Some(count) if count <= fat.cluster_count => count,
+                        _ => maybe_await!(fat::count_free_clusters(fat, self))?,
+                    };
+                    Ok(VolumeStats::new(
+                        fat.bytes_per_cluster(),
+                        fat.cluster_count,
+                        free_clusters,
+                    ))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => Ok(VolumeStats::new(
+                    exfat.bytes_per_cluster(),
+                    exfat.cluster_count,
+                    maybe_await!(exfat::count_free_clusters(exfat, self))?,
+                )),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Get the size of a volume and how much of it is free, always scanning
+        /// the FAT to count the free clusters. Use this if the free cluster
+        /// count recorded on the volume may be wrong.
+        ///
+        /// The count is remembered in `volume`, so later calls to `volume_stats`
+        /// are quick. If `write_back` is set and this is a FAT32 volume, the
+        /// count is also written to the FSInfo sector.
+        pub async fn recount_free_clusters(
+            &mut self,
+            volume: &mut Volume,
+            write_back: bool,
+        ) -> Result<VolumeStats, Error<D::Error>> {
+            match &mut volume.volume_type {
+                VolumeType::Fat(fat) => {
+                    let free_clusters = maybe_await!(fat::count_free_clusters(fat, self))?;
+                    if fat.free_clusters_count != Some(free_clusters) {
+                        debug!(
+                            "Free cluster count was {:?}, is {}",
+                            fat.free_clusters_count, free_clusters
+                        );
+                    }
+                    fat.free_clusters_count = Some(free_clusters);
+                    if write_back {
+                        maybe_await!(fat::update_info_sector(fat, self))?;
+                    }
+                    Ok(VolumeStats::new(
+                        fat.bytes_per_cluster(),
+                        fat.cluster_count,
+                        free_clusters,
+                    ))
+                }
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(_) => maybe_await!(self.volume_stats(volume)),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Set the volume label. An empty label removes it.
+        ///
+        /// On a FAT volume, both the boot sector (and its FAT32 backup) and the
+        /// label entry in the root directory are updated. Labels are stored in
+        /// upper case.
+        pub async fn set_volume_label(
+            &mut self,
+            volume: &mut Volume,
+            label: &str,
+        ) -> Result<(), Error<D::Error>> {
+            let name = VolumeName::create_from_str(label).map_err(Error::FilenameError)?;
+            match &mut volume.volume_type {
+                VolumeType::Fat(fat) => maybe_await!(fat::set_label(fat, self, name)),
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => maybe_await!(exfat::set_label(exfat, self, name)),
+            }
+        }
+    });
+
+    maybe_async!({
+        /// This function turns `desired_offset` into an appropriate block to be
+        /// read. It either calculates this based on the start of the file, or
+        /// from the last cluster we read - whichever is better.
+        #[cfg_attr(not(feature = "exfat"), allow(unused_variables))]
+        async fn find_data_on_disk(
+            &mut self,
+            volume: &Volume,
+            entry: &DirEntry,
+            start: &mut (FileSize, Cluster),
+            desired_offset: FileSize,
+        ) -> Result<(BlockIdx, usize, usize), Error<D::Error>> {
+            let bytes_per_cluster = match &volume.volume_type {
+                VolumeType::Fat(fat) => fat.bytes_per_cluster(),
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => exfat.bytes_per_cluster(),
+            };
+            // How many clusters forward do we need to go?
+            let offset_from_cluster = desired_offset - start.0;
+            let num_clusters = offset_from_cluster / FileSize::from(bytes_per_cluster);
+            for _ in 0..num_clusters {
+                start.1 = match &volume.volume_type {
+                    VolumeType::Fat(fat) => maybe_await!(fat::next_cluster(fat, self, start.1))?,
+                    #[cfg(feature = "exfat")]
+                    VolumeType::ExFat(exfat) => {
+                        maybe_await!(exfat::next_file_cluster(exfat, self, entry, start))?
+                    }
+                };
+                start.0 += FileSize::from(bytes_per_cluster);
+            }
+            // How many blocks in are we?
+            let offset_from_cluster = desired_offset - start.0;
+            assert!(offset_from_cluster < FileSize::from(bytes_per_cluster));
+            // Safe, as we've just checked it's less than a u32
+            let num_blocks = BlockCount(offset_from_cluster as u32 / Block::LEN_U32);
+            let block_idx = match &volume.volume_type {
+                VolumeType::Fat(fat) => fat.cluster_to_block(start.1),
+                #[cfg(feature = "exfat")]
+                VolumeType::ExFat(exfat) => exfat.cluster_to_block(start.1),
+            } + num_blocks;
+            let block_offset = (desired_offset % FileSize::from(Block::LEN_U32)) as usize;
+            let available = Block::LEN - block_offset;
+            Ok((block_idx, block_offset, available))
+        }
+    });
+
+    maybe_async!({
+        /// Writes a Directory Entry to the disk
+        pub(crate) async fn write_entry_to_disk(
+            &mut self,
+            fat_type: FatType,
+            entry: &DirEntry,
+        ) -> Result<(), Error<D::Error>> {
+            let mut blocks = [Block::new()];
+            maybe_await!(self
+                .block_device
+                .read(&mut blocks, entry.entry_block, "read"))
+            .map_err(Error::DeviceError)?;
+            let block = &mut blocks[0];
+
+            let start = usize::try_from(entry.entry_offset).map_err(|_| Error::ConversionError)?;
+            block[start..start + 32].copy_from_slice(&entry.serialize(fat_type)[..]);
+
+            maybe_await!(self.block_device.write(&blocks, entry.entry_block))
+                .map_err(Error::DeviceError)?;
+            Ok(())
+        }
+    });
+
+    /// Consume self and return BlockDevice and TimeSource
+    pub fn free(self) -> (D, T) {
+        (self.block_device, self.timesource)
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1558 @@
+//! embedded-sdmmc-rs - exFAT file system I/O
+//!
+//! Reads and writes the FAT, allocation bitmap, directories and boot region
+//! of an `ExFatVolume`.
+
+use super::controller::Controller;
+use super::BlockDevice;
+use crate::blockdevice::BlockCount;
+use crate::exfat::*;
+use crate::fat::{VolumeName, RESERVED_ENTRIES};
+use crate::{
+    Attributes, Block, BlockIdx, Cluster, DirEntry, Directory, Error, ShortFileName, TimeSource,
+    VolumeType,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::{debug, trace, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace, warn};
+
+maybe_async!({
+    /// Look in the FAT to see which cluster comes next.
+    pub(crate) async fn next_cluster<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let this_fat_block_num =
+            exfat.lba_start + exfat.fat_start + BlockCount(cluster.0 / FAT_ENTRIES_PER_BLOCK);
+        let this_fat_ent_offset = ((cluster.0 % FAT_ENTRIES_PER_BLOCK) * 4) as usize;
+        maybe_await!(controller
+            .block_device
+            .read(&mut blocks, this_fat_block_num, "next_cluster"))
+        .map_err(Error::DeviceError)?;
+        let fat_entry =
+            LittleEndian::read_u32(&blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3]);
+        match fat_entry {
+            0x0000_0000 | 0x0000_0001 => {
+                // Jumped to free space
+                Err(Error::JumpedFree)
+            }
+            FAT_BAD_CLUSTER => {
+                // Bad cluster
+                Err(Error::BadCluster)
+            }
+            f if f > exfat.cluster_count + 1 => {
+                // There is no next cluster
+                Err(Error::EndOfFile)
+            }
+            f => {
+                // Seems legit
+                Ok(Cluster(f))
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Find the cluster following `start` in the given file. `start` holds
+    /// the cluster and the offset into the file at which it begins.
+    pub(crate) async fn next_file_cluster<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        entry: &DirEntry,
+        start: &(u64, Cluster),
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if entry.contiguous {
+            if start.0 + u64::from(exfat.bytes_per_cluster()) < entry.size {
+                Ok(start.1 + 1)
+            } else {
+                Err(Error::EndOfFile)
+            }
+        } else {
+            maybe_await!(next_cluster(exfat, controller, start.1))
+        }
+    }
+});
+
+maybe_async!({
+    /// Write a new entry in the FAT
+    async fn update_fat<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        cluster: Cluster,
+        new_value: Cluster,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let this_fat_block_num =
+            exfat.lba_start + exfat.fat_start + BlockCount(cluster.0 / FAT_ENTRIES_PER_BLOCK);
+        let this_fat_ent_offset = ((cluster.0 % FAT_ENTRIES_PER_BLOCK) * 4) as usize;
+        maybe_await!(controller
+            .block_device
+            .read(&mut blocks, this_fat_block_num, "read_fat"))
+        .map_err(Error::DeviceError)?;
+        let entry = match new_value {
+            Cluster::END_OF_FILE => FAT_END_OF_CHAIN,
+            Cluster::BAD => FAT_BAD_CLUSTER,
+            _ => new_value.0,
+        };
+        LittleEndian::write_u32(
+            &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+            entry,
+        );
+        maybe_await!(controller.block_device.write(&blocks, this_fat_block_num))
+            .map_err(Error::DeviceError)?;
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Mark a run of clusters as used (or free) in the allocation bitmap.
+    async fn set_allocated<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        first: Cluster,
+        count: u32,
+        allocated: bool,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let bitmap_block = exfat.cluster_to_block(exfat.bitmap_start);
+        let mut blocks = [Block::new()];
+        let mut loaded_block_num = None;
+        for bit in (first.0 - RESERVED_ENTRIES)..(first.0 - RESERVED_ENTRIES + count) {
+            let this_block_num = bitmap_block + BlockCount(bit / (Block::LEN_U32 * 8));
+            if loaded_block_num != Some(this_block_num) {
+                if let Some(block_num) = loaded_block_num {
+                    maybe_await!(controller.block_device.write(&blocks, block_num))
+                        .map_err(Error::DeviceError)?;
+                }
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_block_num,
+                    "read_bitmap"
+                ))
+                .map_err(Error::DeviceError)?;
+                loaded_block_num = Some(this_block_num);
+            }
+            let byte = ((bit / 8) % Block::LEN_U32) as usize;
+            let mask = 1 << (bit % 8);
+            if allocated {
+                blocks[0][byte] |= mask;
+            } else {
+                blocks[0][byte] &= !mask;
+            }
+        }
+        if let Some(block_num) = loaded_block_num {
+            maybe_await!(controller.block_device.write(&blocks, block_num))
+                .map_err(Error::DeviceError)?;
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Finds the next free cluster, according to the allocation bitmap, at or
+    /// after the start_cluster and before end_cluster
+    async fn find_next_free_cluster<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let bitmap_block = exfat.cluster_to_block(exfat.bitmap_start);
+        let mut blocks = [Block::new()];
+        let mut loaded_block_num = None;
+        let mut current_cluster = start_cluster;
+        while current_cluster.0 < end_cluster.0 {
+            let bit = current_cluster.0 - RESERVED_ENTRIES;
+            let this_block_num = bitmap_block + BlockCount(bit / (Block::LEN_U32 * 8));
+            if loaded_block_num != Some(this_block_num) {
+                trace!("Reading bitmap block {:?}", this_block_num);
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_block_num,
+                    "read_bitmap"
+                ))
+                .map_err(Error::DeviceError)?;
+                loaded_block_num = Some(this_block_num);
+            }
+            let byte = blocks[0][((bit / 8) % Block::LEN_U32) as usize];
+            if byte == 0xFF {
+                // Skip to the next byte
+                current_cluster += 8 - (bit % 8);
+                continue;
+            }
+            if byte & (1 << (bit % 8)) == 0 {
+                return Ok(current_cluster);
+            }
+            current_cluster += 1;
+        }
+        Err(Error::NotEnoughSpace)
+    }
+});
+
+maybe_async!({
+    /// Count the free clusters in the allocation bitmap.
+    pub(crate) async fn count_free_clusters<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+    ) -> Result<u32, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let bitmap_block = exfat.cluster_to_block(exfat.bitmap_start);
+        let bits_per_block = Block::LEN_U32 * 8;
+        let mut blocks = [Block::new()];
+        let mut used = 0;
+        for block in 0..exfat.cluster_count.div_ceil(bits_per_block) {
+            maybe_await!(controller.block_device.read(
+                &mut blocks,
+                bitmap_block + BlockCount(block),
+                "read_bitmap"
+            ))
+            .map_err(Error::DeviceError)?;
+            // Ignore any bits past the end of the volume
+            let bits = (exfat.cluster_count - block * bits_per_block).min(bits_per_block);
+            let full_bytes = (bits / 8) as usize;
+            used += blocks[0][..full_bytes]
+                .iter()
+                .map(|b| b.count_ones())
+                .sum::<u32>();
+            if !bits.is_multiple_of(8) {
+                let mask = (1u8 << (bits % 8)) - 1;
+                used += (blocks[0][full_bytes] & mask).count_ones();
+            }
+        }
+        Ok(exfat.cluster_count - used)
+    }
+});
+
+maybe_async!({
+    /// Find a free cluster, trying `preferred` first, and mark it as used in
+    /// the allocation bitmap.
+    async fn allocate<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        preferred: Option<Cluster>,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(exfat.cluster_count + RESERVED_ENTRIES);
+        let start_cluster = match preferred.or(exfat.next_free_cluster) {
+            Some(cluster) if cluster.0 >= RESERVED_ENTRIES && cluster.0 < end_cluster.0 => cluster,
+            _ => Cluster(RESERVED_ENTRIES),
+        };
+        trace!(
+            "Finding next free between {:?}..={:?}",
+            start_cluster,
+            end_cluster
+        );
+        let new_cluster = match maybe_await!(find_next_free_cluster(
+            exfat,
+            controller,
+            start_cluster,
+            end_cluster
+        )) {
+            Ok(cluster) => cluster,
+            Err(_) if start_cluster.0 > RESERVED_ENTRIES => maybe_await!(find_next_free_cluster(
+                exfat,
+                controller,
+                Cluster(RESERVED_ENTRIES),
+                start_cluster
+            ))
+            .inspect_err(|_| warn!("Out of space..."))?,
+            Err(e) => return Err(e),
+        };
+        maybe_await!(set_allocated(exfat, controller, new_cluster, 1, true))?;
+        exfat.next_free_cluster = if new_cluster.0 + 1 < end_cluster.0 {
+            Some(new_cluster + 1)
+        } else {
+            None
+        };
+        Ok(new_cluster)
+    }
+});
+
+maybe_async!({
+    /// Allocate a cluster and add it to the end of a chain. `last` is the
+    /// final cluster of the chain (`None` for a new chain), and `contiguous`
+    /// says whether the chain is currently stored without the FAT.
+    ///
+    /// Returns the new cluster and whether the chain is still contiguous. A
+    /// contiguous chain which can't be extended in place is written out to
+    /// the FAT.
+    async fn extend_chain<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        first: Cluster,
+        last: Option<Cluster>,
+        contiguous: bool,
+        zero: bool,
+    ) -> Result<(Cluster, bool), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        debug!("Allocating new cluster, prev_cluster={:?}", last);
+        let new_cluster = maybe_await!(allocate(exfat, controller, last.map(|c| c + 1)))?;
+        let still_contiguous = match last {
+            None => true,
+            Some(last) if contiguous && new_cluster == last + 1 => true,
+            Some(last) => {
+                if contiguous {
+                    debug!("Moving contiguous chain {:?}..={:?} to FAT", first, last);
+                    let mut cluster = first;
+                    while cluster != last {
+                        maybe_await!(update_fat(exfat, controller, cluster, cluster + 1))?;
+                        cluster += 1;
+                    }
+                }
+                maybe_await!(update_fat(exfat, controller, last, new_cluster))?;
+                maybe_await!(update_fat(
+                    exfat,
+                    controller,
+                    new_cluster,
+                    Cluster::END_OF_FILE
+                ))?;
+                false
+            }
+        };
+        if zero {
+            let blocks = [Block::new()];
+            let first_block = exfat.cluster_to_block(new_cluster);
+            for block in first_block.range(BlockCount(exfat.blocks_per_cluster)) {
+                maybe_await!(controller.block_device.write(&blocks, block))
+                    .map_err(Error::DeviceError)?;
+            }
+        }
+        debug!("All done, returning {:?}", new_cluster);
+        Ok((new_cluster, still_contiguous))
+    }
+});
+
+maybe_async!({
+    /// Release every cluster of a chain in the allocation bitmap.
+    async fn free_chain<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        first: Cluster,
+        contiguous: bool,
+        size: u64,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if first.0 < RESERVED_ENTRIES {
+            // Nothing allocated
+            return Ok(());
+        }
+        if contiguous {
+            let count = size.div_ceil(u64::from(exfat.bytes_per_cluster())).max(1);
+            let count = u32::try_from(count).map_err(|_| Error::ConversionError)?;
+            return maybe_await!(set_allocated(exfat, controller, first, count, false));
+        }
+        // Free runs of consecutive clusters together, to save on bitmap writes
+        let mut run_start = first;
+        let mut run_len = 1;
+        let mut cluster = first;
+        loop {
+            match maybe_await!(next_cluster(exfat, controller, cluster)) {
+                Ok(next) if next == cluster + 1 => {
+                    run_len += 1;
+                    cluster = next;
+                }
+                Ok(next) => {
+                    maybe_await!(set_allocated(exfat, controller, run_start, run_len, false))?;
+                    run_start = next;
+                    run_len = 1;
+                    cluster = next;
+                }
+                Err(Error::EndOfFile) => {
+                    return maybe_await!(set_allocated(
+                        exfat, controller, run_start, run_len, false
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Allocate a new cluster for a file, after `prev_cluster` (or as its
+    /// first cluster). Updates the cluster and layout recorded in `entry`.
+    pub(crate) async fn alloc_cluster<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        entry: &mut DirEntry,
+        prev_cluster: Option<Cluster>,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let (new_cluster, contiguous) = maybe_await!(extend_chain(
+            exfat,
+            controller,
+            entry.cluster,
+            prev_cluster,
+            entry.contiguous,
+            false,
+        ))?;
+        if prev_cluster.is_none() {
+            entry.cluster = new_cluster;
+        }
+        entry.contiguous = contiguous;
+        Ok(new_cluster)
+    }
+});
+
+maybe_async!({
+    /// Release all the clusters of a file. The caller is expected to set the
+    /// file's length to zero and write the entry back to disk.
+    pub(crate) async fn truncate_cluster_chain<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        entry: &mut DirEntry,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        maybe_await!(free_chain(
+            exfat,
+            controller,
+            entry.cluster,
+            entry.contiguous,
+            entry.size
+        ))?;
+        entry.cluster = Cluster(0);
+        entry.contiguous = false;
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Map a character to upper case, using the volume's up-case table.
+    async fn upcase<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        ch: u16,
+    ) -> Result<u16, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if let Some(&mapped) = exfat.upcase_table.ascii.get(usize::from(ch)) {
+            return Ok(u16::from(mapped));
+        }
+        let mut result = ch;
+        let mut decoder = UpcaseDecoder::new();
+        maybe_await!(for_each_table_block(
+            exfat,
+            controller,
+            &exfat.upcase_table,
+            |block| {
+                for pair in block.chunks(2) {
+                    match decoder.feed(LittleEndian::read_u16(pair)) {
+                        Some((idx, mapped)) if idx == u32::from(ch) => {
+                            result = mapped;
+                            return false;
+                        }
+                        _ if decoder.index > u32::from(ch) => return false,
+                        _ => {}
+                    }
+                }
+                true
+            }
+        ))?;
+        Ok(result)
+    }
+});
+
+maybe_async!({
+    /// Calls `func` with each block of the up-case table, until it returns
+    /// false. Bytes past the end of the table are cut off.
+    async fn for_each_table_block<D, T, F>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        table: &UpcaseTable,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(&[u8]) -> bool,
+    {
+        let num_blocks = table.length.div_ceil(Block::LEN_U32);
+        let mut walker = ChainWalker::new(table.first_cluster, false, Some(num_blocks));
+        let mut blocks = [Block::new()];
+        let mut left = table.length as usize;
+        loop {
+            maybe_await!(controller.block_device.read(
+                &mut blocks,
+                walker.block(exfat),
+                "read_upcase"
+            ))
+            .map_err(Error::DeviceError)?;
+            let len = left.min(Block::LEN);
+            if !func(&blocks[0][..len]) {
+                return Ok(());
+            }
+            left -= len;
+            if !maybe_await!(advance(&mut walker, exfat, controller))? {
+                return Ok(());
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Work out where a directory starts, and whether it is contiguous. Sub
+    /// directories are looked up on disk, as the `Directory` handle may be out
+    /// of date if the directory has grown.
+    async fn dir_walker<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+    ) -> Result<ChainWalker, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        match &dir.entry {
+            Some(entry) if dir.cluster != Cluster::ROOT_DIR => {
+                let mut flags = 0;
+                let mut first_cluster = dir.cluster;
+                let mut data_length = 0;
+                maybe_await!(visit_slots(
+                    exfat,
+                    controller,
+                    exfat.slot_position(entry),
+                    2,
+                    |idx, slot| {
+                        if idx == 1 && slot[0] == ENTRY_STREAM_EXTENSION {
+                            flags = slot[1];
+                            first_cluster = Cluster(LittleEndian::read_u32(&slot[20..24]));
+                            data_length = LittleEndian::read_u64(&slot[24..32]);
+                        }
+                        false
+                    }
+                ))?;
+                if flags & FLAG_NO_FAT_CHAIN != 0 {
+                    let num_blocks = data_length.div_ceil(u64::from(Block::LEN_U32));
+                    let num_blocks =
+                        u32::try_from(num_blocks).map_err(|_| Error::ConversionError)?;
+                    Ok(ChainWalker::new(first_cluster, true, Some(num_blocks)))
+                } else {
+                    Ok(ChainWalker::new(first_cluster, false, None))
+                }
+            }
+            _ => Ok(ChainWalker::new(exfat.first_root_dir_cluster, false, None)),
+        }
+    }
+});
+
+maybe_async!({
+    /// Calls `func` with every entry in a directory, along with its position,
+    /// until `func` returns something.
+    async fn scan_dir<D, T, F, R>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        walker: ChainWalker,
+        func: F,
+    ) -> Result<Option<R>, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(&[u8], SlotPosition) -> Result<Option<R>, Error<D::Error>>,
+    {
+        let start = SlotPosition { walker, offset: 0 };
+        maybe_await!(scan_dir_from(exfat, controller, start, func))
+    }
+});
+
+maybe_async!({
+    /// Like `scan_dir`, but starts at the given entry rather than at the
+    /// beginning of the directory.
+    async fn scan_dir_from<D, T, F, R>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        start: SlotPosition,
+        mut func: F,
+    ) -> Result<Option<R>, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(&[u8], SlotPosition) -> Result<Option<R>, Error<D::Error>>,
+    {
+        let mut walker = start.walker;
+        let mut first_offset = start.offset;
+        let mut blocks = [Block::new()];
+        loop {
+            if first_offset < Block::LEN {
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    walker.block(exfat),
+                    "read_dir"
+                ))
+                .map_err(Error::DeviceError)?;
+            }
+            for offset in (first_offset..Block::LEN).step_by(ENTRY_LEN) {
+                let position = SlotPosition { walker, offset };
+                if let Some(result) = func(&blocks[0][offset..offset + ENTRY_LEN], position)? {
+                    return Ok(Some(result));
+                }
+            }
+            first_offset = 0;
+            if !maybe_await!(advance(&mut walker, exfat, controller))? {
+                return Ok(None);
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Calls `func` with each of `count` consecutive directory entries,
+    /// starting at the given position. Blocks are written back to disk if
+    /// `func` returns true for any entry within them.
+    async fn visit_slots<D, T, F>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        position: SlotPosition,
+        count: usize,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(usize, &mut [u8]) -> bool,
+    {
+        let mut walker = position.walker;
+        let mut offset = position.offset;
+        let mut idx = 0;
+        let mut blocks = [Block::new()];
+        while idx < count {
+            let block_num = walker.block(exfat);
+            maybe_await!(controller
+                .block_device
+                .read(&mut blocks, block_num, "read_dir"))
+            .map_err(Error::DeviceError)?;
+            let mut dirty = false;
+            while offset < Block::LEN && idx < count {
+                dirty |= func(idx, &mut blocks[0][offset..offset + ENTRY_LEN]);
+                offset += ENTRY_LEN;
+                idx += 1;
+            }
+            if dirty {
+                maybe_await!(controller.block_device.write(&blocks, block_num))
+                    .map_err(Error::DeviceError)?;
+            }
+            if idx < count {
+                if !maybe_await!(advance(&mut walker, exfat, controller))? {
+                    return Err(Error::FormatError("Directory entry set is truncated"));
+                }
+                offset = 0;
+            }
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Work out the alias for the entry set at `location`, whose alias has
+    /// the given stem, by checking it against the other entry sets in the
+    /// directory.
+    async fn alias_in_dir<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        location: (BlockIdx, usize),
+        stem: &ShortFileName,
+    ) -> Result<ShortFileName, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let walker = maybe_await!(dir_walker(exfat, controller, dir))?;
+        let mut parser = EntrySetParser::new();
+        let mut reached = false;
+        let mut earlier = 0;
+        let mut taken = 0u64;
+        maybe_await!(scan_dir(exfat, controller, walker, |slot, position| {
+            if slot[0] == ENTRY_END_OF_DIRECTORY {
+                // Can quit early
+                return Ok(Some(()));
+            }
+            if let Some(set) = parser.feed(slot, position) {
+                if set.location(exfat) == location {
+                    reached = true;
+                    return Ok(None);
+                }
+                match set.short_name() {
+                    ShortName::Literal(sfn) => {
+                        if let Some(tail @ 1..=64) = alias_tail(stem, &sfn) {
+                            taken |= 1 << (tail - 1);
+                        }
+                    }
+                    ShortName::Alias(other) => {
+                        if !reached && other == *stem {
+                            earlier += 1;
+                        }
+                    }
+                }
+            }
+            Ok(None)
+        }))?;
+        Ok(alias_name(stem, pick_alias_tail(earlier, taken)))
+    }
+});
+
+maybe_async!({
+    /// Calls callback `func` with every valid entry in the given directory.
+    /// Useful for performing directory listings.
+    pub(crate) async fn iterate_dir<D, T, F>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        F: FnMut(&DirEntry),
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        // First note which name hashes (give or take) are shared, as only
+        // those aliases need checking against the rest of the directory
+        let walker = maybe_await!(dir_walker(exfat, controller, dir))?;
+        let mut parser = EntrySetParser::new();
+        let mut seen = [0u8; 128];
+        let mut shared = [0u8; 128];
+        let mut tilde_names = false;
+        maybe_await!(scan_dir(exfat, controller, walker, |slot, position| {
+            if slot[0] == ENTRY_END_OF_DIRECTORY {
+                // Can quit early
+                return Ok(Some(()));
+            }
+            if let Some(set) = parser.feed(slot, position) {
+                match set.short_name() {
+                    ShortName::Literal(sfn) => tilde_names |= sfn.contents.contains(&b'~'),
+                    ShortName::Alias(_) => {
+                        let bit = usize::from(set.name_hash) % (seen.len() * 8);
+                        if seen[bit / 8] & (1 << (bit % 8)) != 0 {
+                            shared[bit / 8] |= 1 << (bit % 8);
+                        }
+                        seen[bit / 8] |= 1 << (bit % 8);
+                    }
+                }
+            }
+            Ok(None)
+        }))?;
+
+        let mut start = SlotPosition {
+            walker: maybe_await!(dir_walker(exfat, controller, dir))?,
+            offset: 0,
+        };
+        let mut parser = EntrySetParser::new();
+        loop {
+            // Stop at each alias which needs checking, as that means scanning
+            // the directory again
+            let stopped =
+                maybe_await!(scan_dir_from(exfat, controller, start, |slot, position| {
+                    if slot[0] == ENTRY_END_OF_DIRECTORY {
+                        return Ok(Some(None));
+                    }
+                    if let Some(set) = parser.feed(slot, position) {
+                        match set.short_name() {
+                            ShortName::Literal(sfn) => func(&exfat.get_entry(set, sfn)),
+                            ShortName::Alias(stem) => {
+                                let bit = usize::from(set.name_hash) % (shared.len() * 8);
+                                if tilde_names || shared[bit / 8] & (1 << (bit % 8)) != 0 {
+                                    return Ok(Some(Some((position, stem))));
+                                }
+                                func(&exfat.get_entry(set, alias_name(&stem, 1)));
+                            }
+                        }
+                    }
+                    Ok(None)
+                }))?;
+            let (position, stem) = match stopped.flatten() {
+                Some(stopped) => stopped,
+                None => return Ok(()),
+            };
+            let set = &parser.set;
+            let sfn = maybe_await!(alias_in_dir(
+                exfat,
+                controller,
+                dir,
+                set.location(exfat),
+                &stem
+            ))?;
+            func(&exfat.get_entry(set, sfn));
+            start = SlotPosition {
+                walker: position.walker,
+                offset: position.offset + ENTRY_LEN,
+            };
+        }
+    }
+});
+
+maybe_async!({
+    /// Find the entry set for the named file, checking both the long name
+    /// and the short name we derive from it. `func` is given the entry set
+    /// and its short name.
+    async fn find_entry_set<D, T, F, R>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+        mut func: F,
+    ) -> Result<R, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(&EntrySet, ShortFileName) -> R,
+    {
+        let mut match_name = LongFileName::create_from_str(name).map_err(Error::FilenameError)?;
+        for ch in match_name.chars[..match_name.len].iter_mut() {
+            *ch = maybe_await!(upcase(exfat, controller, *ch))?;
+        }
+        let match_hash = name_hash(&match_name.chars[..match_name.len]);
+        let match_sfn = ShortFileName::create_from_str(name).ok();
+        // Could this entry set be known by the short name we're looking for?
+        let may_match_sfn = |set: &EntrySet| match (&match_sfn, set.short_name()) {
+            (Some(match_sfn), ShortName::Literal(sfn)) => {
+                sfn.contents.eq_ignore_ascii_case(&match_sfn.contents)
+            }
+            (Some(match_sfn), ShortName::Alias(stem)) => alias_tail(&stem, match_sfn).is_some(),
+            (None, _) => false,
+        };
+        let mut start = SlotPosition {
+            walker: maybe_await!(dir_walker(exfat, controller, dir))?,
+            offset: 0,
+        };
+        let mut parser = EntrySetParser::new();
+        loop {
+            // Comparing long names may mean reading the up-case table, and
+            // checking an alias means scanning the directory again, which we
+            // can't do from inside the scan, so stop at each candidate
+            let candidate =
+                maybe_await!(scan_dir_from(exfat, controller, start, |slot, position| {
+                    if slot[0] == ENTRY_END_OF_DIRECTORY {
+                        // Can quit early
+                        return Ok(Some(None));
+                    }
+                    if let Some(set) = parser.feed(slot, position) {
+                        if (set.name_length == match_name.len && set.name_hash == match_hash)
+                            || may_match_sfn(set)
+                        {
+                            return Ok(Some(Some(position)));
+                        }
+                    }
+                    Ok(None)
+                }))?;
+            let position = candidate.flatten().ok_or(Error::FileNotFound)?;
+            let set = &parser.set;
+            let mut found = false;
+            if set.name_length == match_name.len && set.name_hash == match_hash {
+                found = true;
+                for (a, b) in set.name[..set.name_length]
+                    .iter()
+                    .zip(match_name.chars.iter())
+                {
+                    if maybe_await!(upcase(exfat, controller, *a))? != *b {
+                        found = false;
+                        break;
+                    }
+                }
+            }
+            let sfn = match set.short_name() {
+                ShortName::Literal(sfn) => Some(sfn),
+                ShortName::Alias(stem) if found || may_match_sfn(set) => Some(maybe_await!(
+                    alias_in_dir(exfat, controller, dir, set.location(exfat), &stem)
+                )?),
+                // Only the hashes matched
+                ShortName::Alias(_) => None,
+            };
+            if let Some(sfn) = sfn {
+                let matches_sfn = match_sfn.as_ref().is_some_and(|match_sfn| {
+                    sfn.contents.eq_ignore_ascii_case(&match_sfn.contents)
+                });
+                if found || matches_sfn {
+                    return Ok(func(set, sfn));
+                }
+            }
+            start = SlotPosition {
+                walker: position.walker,
+                offset: position.offset + ENTRY_LEN,
+            };
+        }
+    }
+});
+
+maybe_async!({
+    /// Get an entry from the given directory
+    pub(crate) async fn find_directory_entry<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        maybe_await!(find_entry_set(exfat, controller, dir, name, |set, sfn| {
+            exfat.get_entry(set, sfn)
+        }))
+    }
+});
+
+maybe_async!({
+    /// Delete an entry from the given directory, and release its clusters.
+    pub(crate) async fn delete_directory_entry<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let (entry, secondary_count) =
+            maybe_await!(find_entry_set(exfat, controller, dir, name, |set, sfn| {
+                (exfat.get_entry(set, sfn), set.secondary_count)
+            }))?;
+        maybe_await!(free_chain(
+            exfat,
+            controller,
+            entry.cluster,
+            entry.contiguous,
+            entry.size
+        ))?;
+        maybe_await!(visit_slots(
+            exfat,
+            controller,
+            exfat.slot_position(&entry),
+            usize::from(secondary_count) + 1,
+            |_idx, slot| {
+                slot[0] &= !ENTRY_IN_USE;
+                true
+            },
+        ))
+    }
+});
+
+maybe_async!({
+    /// Write the details of a `DirEntry` back to its entry set on disk.
+    pub(crate) async fn write_entry<D, T>(
+        exfat: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+        entry: &DirEntry,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let position = exfat.slot_position(entry);
+        let mut secondary_count = 0;
+        maybe_await!(visit_slots(exfat, controller, position, 1, |_idx, slot| {
+            secondary_count = slot[1];
+            false
+        }))?;
+        if slot_count_is_bad(secondary_count) {
+            return Err(Error::FormatError("Bad directory entry set"));
+        }
+        // Update the entries, working out the new checksum as we go
+        let mut checksum = 0;
+        maybe_await!(visit_slots(
+            exfat,
+            controller,
+            position,
+            usize::from(secondary_count) + 1,
+            |idx, slot| {
+                let dirty = match idx {
+                    0 => {
+                        slot[4] = entry.attributes.0;
+                        let (ctime, ctime_10ms) = timestamp_to_exfat(entry.ctime);
+                        LittleEndian::write_u32(&mut slot[8..12], ctime);
+                        slot[20] = ctime_10ms + entry.ctime_hundredths.min(99);
+                        let (mtime, mtime_10ms) = timestamp_to_exfat(entry.mtime);
+                        LittleEndian::write_u32(&mut slot[12..16], mtime);
+                        slot[21] = mtime_10ms;
+                        // There is no 10 ms field for the access time
+                        let atime = entry.atime.map_or(0, |atime| timestamp_to_exfat(atime).0);
+                        LittleEndian::write_u32(&mut slot[16..20], atime);
+                        // We don't know the UTC offsets
+                        slot[22..25].fill(0);
+                        true
+                    }
+                    1 => {
+                        let mut flags = FLAG_ALLOCATION_POSSIBLE;
+                        if entry.contiguous && entry.cluster.0 >= RESERVED_ENTRIES {
+                            flags |= FLAG_NO_FAT_CHAIN;
+                        }
+                        slot[1] = flags;
+                        LittleEndian::write_u64(&mut slot[8..16], entry.size);
+                        LittleEndian::write_u32(&mut slot[20..24], entry.cluster.0);
+                        LittleEndian::write_u64(&mut slot[24..32], entry.size);
+                        true
+                    }
+                    _ => false,
+                };
+                checksum = entry_set_checksum(checksum, slot, idx == 0);
+                dirty
+            },
+        ))?;
+        maybe_await!(visit_slots(exfat, controller, position, 1, |_idx, slot| {
+            LittleEndian::write_u16(&mut slot[2..4], checksum);
+            true
+        }))
+    }
+});
+
+maybe_async!({
+    /// Add a cluster to the end of a directory.
+    async fn extend_dir<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let walker = maybe_await!(dir_walker(exfat, controller, dir))?;
+        let first = walker.cluster;
+        // Find the final cluster
+        let last = if walker.contiguous {
+            let num_clusters = walker
+                .blocks_left
+                .unwrap_or(1)
+                .div_ceil(exfat.blocks_per_cluster);
+            first + (num_clusters.max(1) - 1)
+        } else {
+            let mut cluster = first;
+            loop {
+                match maybe_await!(next_cluster(exfat, controller, cluster)) {
+                    Ok(next) => cluster = next,
+                    Err(Error::EndOfFile) => break cluster,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        let (_new_cluster, contiguous) = maybe_await!(extend_chain(
+            exfat,
+            controller,
+            first,
+            Some(last),
+            walker.contiguous,
+            true
+        ))?;
+        if let (Some(entry), false) = (&dir.entry, dir.cluster == Cluster::ROOT_DIR) {
+            // Sub-directories record their size, and whether they are contiguous
+            let mut entry = entry.clone();
+            let mut data_length = 0;
+            maybe_await!(visit_slots(
+                exfat,
+                controller,
+                exfat.slot_position(&entry),
+                2,
+                |idx, slot| {
+                    if idx == 1 {
+                        data_length = LittleEndian::read_u64(&slot[24..32]);
+                    }
+                    false
+                }
+            ))?;
+            entry.cluster = first;
+            entry.contiguous = contiguous;
+            entry.size = data_length + u64::from(exfat.bytes_per_cluster());
+            maybe_await!(write_entry(exfat, controller, &entry))?;
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Set the volume label, which lives in an entry in the root directory.
+    /// A blank name removes the label.
+    pub(crate) async fn set_label<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        name: VolumeName,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let label = name.name();
+        // Use the existing label entry if there is one, otherwise the first
+        // free entry
+        let mut free = None;
+        let walker = ChainWalker::new(exfat.first_root_dir_cluster, false, None);
+        let position = maybe_await!(scan_dir(exfat, controller, walker, |slot, position| {
+            Ok(match slot[0] {
+                ENTRY_VOLUME_LABEL | ENTRY_VOLUME_LABEL_UNUSED => Some(position),
+                ENTRY_END_OF_DIRECTORY => Some(free.unwrap_or(position)),
+                t if t & ENTRY_IN_USE == 0 && free.is_none() => {
+                    free = Some(position);
+                    None
+                }
+                _ => None,
+            })
+        }))?;
+        let position = match position.or(free) {
+            Some(position) => position,
+            None if label.is_empty() => {
+                exfat.name = name;
+                return Ok(());
+            }
+            None => return Err(Error::NotEnoughSpace),
+        };
+        maybe_await!(visit_slots(exfat, controller, position, 1, |_idx, slot| {
+            if label.is_empty() && slot[0] != ENTRY_VOLUME_LABEL {
+                // Nothing to remove
+                return false;
+            }
+            slot.fill(0);
+            if label.is_empty() {
+                slot[0] = ENTRY_VOLUME_LABEL_UNUSED;
+            } else {
+                slot[0] = ENTRY_VOLUME_LABEL;
+                // Safe, as a label is at most 11 characters
+                slot[1] = label.len() as u8;
+                for (pair, ch) in slot[2..].chunks_mut(2).zip(label) {
+                    LittleEndian::write_u16(pair, u16::from(*ch));
+                }
+            }
+            true
+        }))?;
+        exfat.name = name;
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Finds a run of empty entries big enough for a new entry set and writes
+    /// it there, growing the directory if it's needed.
+    pub(crate) async fn write_new_directory_entry<D, T>(
+        exfat: &mut ExFatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+        attributes: Attributes,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let lfn = LongFileName::create_from_str(name).map_err(Error::FilenameError)?;
+        lfn.check_valid().map_err(Error::FilenameError)?;
+        let mut upcased = [0u16; MAX_NAME_LEN];
+        for (dest, ch) in upcased.iter_mut().zip(lfn.chars[..lfn.len].iter()) {
+            *dest = maybe_await!(upcase(exfat, controller, *ch))?;
+        }
+        let hash = name_hash(&upcased[..lfn.len]);
+        let secondary_count = 1 + lfn.len.div_ceil(NAME_CHARS_PER_ENTRY);
+        let total = secondary_count + 1;
+
+        loop {
+            let walker = maybe_await!(dir_walker(exfat, controller, dir))?;
+            let mut run_start = None;
+            let mut run_len = 0;
+            let found = maybe_await!(scan_dir(exfat, controller, walker, |slot, position| {
+                if slot[0] & ENTRY_IN_USE == 0 {
+                    if run_len == 0 {
+                        run_start = Some(position);
+                    }
+                    run_len += 1;
+                    if run_len == total {
+                        return Ok(run_start);
+                    }
+                } else {
+                    run_len = 0;
+                }
+                Ok(None)
+            }))?;
+            if let Some(position) = found {
+                let ctime = controller.timesource.get_timestamp();
+                let (time, time_10ms) = timestamp_to_exfat(ctime);
+                let mut slots = [[0u8; ENTRY_LEN]; MAX_SECONDARY_COUNT + 1];
+                slots[0][0] = ENTRY_FILE;
+                // Safe, as names are limited to 255 characters
+                slots[0][1] = secondary_count as u8;
+                slots[0][4] = attributes.0;
+                LittleEndian::write_u32(&mut slots[0][8..12], time);
+                LittleEndian::write_u32(&mut slots[0][12..16], time);
+                LittleEndian::write_u32(&mut slots[0][16..20], time);
+                slots[0][20] = time_10ms;
+                slots[0][21] = time_10ms;
+                slots[1][0] = ENTRY_STREAM_EXTENSION;
+                slots[1][1] = FLAG_ALLOCATION_POSSIBLE;
+                // Safe, as names are limited to 255 characters
+                slots[1][3] = lfn.len as u8;
+                LittleEndian::write_u16(&mut slots[1][4..6], hash);
+                for (i, chunk) in lfn.chars[..lfn.len]
+                    .chunks(NAME_CHARS_PER_ENTRY)
+                    .enumerate()
+                {
+                    let slot = &mut slots[2 + i];
+                    slot[0] = ENTRY_FILE_NAME;
+                    for (j, ch) in chunk.iter().enumerate() {
+                        LittleEndian::write_u16(&mut slot[2 + j * 2..4 + j * 2], *ch);
+                    }
+                }
+                let mut checksum = 0;
+                for (i, slot) in slots[..total].iter().enumerate() {
+                    checksum = entry_set_checksum(checksum, slot, i == 0);
+                }
+                LittleEndian::write_u16(&mut slots[0][2..4], checksum);
+                maybe_await!(visit_slots(
+                    exfat,
+                    controller,
+                    position,
+                    total,
+                    |idx, slot| {
+                        slot.copy_from_slice(&slots[idx]);
+                        true
+                    }
+                ))?;
+                let sfn = match short_name(&lfn.chars[..lfn.len], hash) {
+                    ShortName::Literal(sfn) => sfn,
+                    ShortName::Alias(stem) => maybe_await!(alias_in_dir(
+                        exfat,
+                        controller,
+                        dir,
+                        (position.walker.block(exfat), position.offset),
+                        &stem
+                    ))?,
+                };
+                let mut entry = DirEntry::new(
+                    sfn,
+                    attributes,
+                    Cluster(0),
+                    ctime,
+                    position.walker.block(exfat),
+                    // Safe, since Block::LEN always fits on a u32
+                    u32::try_from(position.offset).unwrap(),
+                );
+                // exFAT keeps the time of the last access as well
+                entry.atime = Some(ctime);
+                entry.dir_contiguous = position.walker.contiguous;
+                return Ok(entry);
+            }
+            debug!("Directory full, extending");
+            maybe_await!(extend_dir(exfat, controller, dir))?;
+        }
+    }
+});
+
+maybe_async!({
+    /// Step on to the next block. Returns `false` if the chain has ended.
+    async fn advance<D, T>(
+        walker: &mut ChainWalker,
+        volume: &ExFatVolume,
+        controller: &mut Controller<D, T>,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if let Some(blocks_left) = walker.blocks_left.as_mut() {
+            *blocks_left = blocks_left.saturating_sub(1);
+            if *blocks_left == 0 {
+                return Ok(false);
+            }
+        }
+        walker.block_in_cluster += 1;
+        if walker.block_in_cluster < volume.blocks_per_cluster {
+            return Ok(true);
+        }
+        let next = if walker.contiguous {
+            walker.cluster + 1
+        } else {
+            match maybe_await!(next_cluster(volume, controller, walker.cluster)) {
+                Ok(next) => next,
+                Err(Error::EndOfFile) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        };
+        walker.cluster = next;
+        walker.block_in_cluster = 0;
+        Ok(true)
+    }
+});
+
+maybe_async!({
+    /// Load the boot sector from the start of the given partition and determine
+    /// if the partition contains a valid exFAT file system.
+    pub(crate) async fn parse_volume<D, T>(
+        controller: &mut Controller<D, T>,
+        lba_start: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<VolumeType, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        D::Error: core::fmt::Debug,
+    {
+        // Use the backup boot region if the main one is damaged
+        let boot_region = match maybe_await!(check_boot_region(controller, lba_start)) {
+            Ok(()) => lba_start,
+            Err(Error::FormatError(e)) => {
+                warn!("Main boot region is bad ({}), trying backup", e);
+                let backup = lba_start + BlockCount(BOOT_REGION_BLOCKS);
+                maybe_await!(check_boot_region(controller, backup))?;
+                backup
+            }
+            Err(e) => return Err(e),
+        };
+        let mut blocks = [Block::new()];
+        maybe_await!(controller
+            .block_device
+            .read(&mut blocks, boot_region, "read_boot_sector"))
+        .map_err(Error::DeviceError)?;
+        let block = &blocks[0];
+
+        let bytes_per_block_shift = block[108];
+        if bytes_per_block_shift != 9 {
+            return Err(Error::BadBlockSize(
+                1u16.checked_shl(u32::from(bytes_per_block_shift))
+                    .unwrap_or(0),
+            ));
+        }
+        let blocks_per_cluster_shift = block[109];
+        if blocks_per_cluster_shift > 16 {
+            return Err(Error::FormatError("Bad exFAT cluster size"));
+        }
+        let num_fats = block[110];
+        if num_fats != 1 && num_fats != 2 {
+            return Err(Error::FormatError("Bad exFAT FAT count"));
+        }
+        let volume_flags = LittleEndian::read_u16(&block[106..108]);
+        let active_fat = u32::from(volume_flags & 0x0001);
+        if active_fat >= u32::from(num_fats) {
+            return Err(Error::FormatError("Bad exFAT active FAT"));
+        }
+        let fat_offset = LittleEndian::read_u32(&block[80..84]);
+        let fat_length = LittleEndian::read_u32(&block[84..88]);
+        let cluster_heap_offset = LittleEndian::read_u32(&block[88..92]);
+        let cluster_count = LittleEndian::read_u32(&block[92..96]);
+        let first_root_dir_cluster = LittleEndian::read_u32(&block[96..100]);
+        if first_root_dir_cluster < RESERVED_ENTRIES
+            || first_root_dir_cluster >= cluster_count + RESERVED_ENTRIES
+        {
+            return Err(Error::FormatError("Bad exFAT root directory cluster"));
+        }
+
+        let mut volume = ExFatVolume {
+            lba_start,
+            num_blocks,
+            name: VolumeName::new([b' '; 11]),
+            serial_number: LittleEndian::read_u32(&block[100..104]),
+            blocks_per_cluster: 1 << blocks_per_cluster_shift,
+            fat_start: BlockCount(fat_offset + active_fat * fat_length),
+            cluster_heap_start: BlockCount(cluster_heap_offset),
+            cluster_count,
+            first_root_dir_cluster: Cluster(first_root_dir_cluster),
+            bitmap_start: Cluster(0),
+            upcase_table: UpcaseTable {
+                first_cluster: Cluster(0),
+                length: 0,
+                ascii: [0u8; 128],
+            },
+            next_free_cluster: None,
+        };
+
+        // Find the allocation bitmap, up-case table and volume label
+        let mut bitmap = None;
+        let mut upcase_table = None;
+        let mut label = None;
+        let walker = ChainWalker::new(volume.first_root_dir_cluster, false, None);
+        maybe_await!(scan_dir(&volume, controller, walker, |slot, _position| {
+            match slot[0] {
+                ENTRY_END_OF_DIRECTORY => return Ok(Some(())),
+                ENTRY_ALLOCATION_BITMAP if u32::from(slot[1] & 0x01) == active_fat => {
+                    bitmap = Some((
+                        Cluster(LittleEndian::read_u32(&slot[20..24])),
+                        LittleEndian::read_u64(&slot[24..32]),
+                    ));
+                }
+                ENTRY_UPCASE_TABLE => {
+                    upcase_table = Some((
+                        LittleEndian::read_u32(&slot[4..8]),
+                        Cluster(LittleEndian::read_u32(&slot[20..24])),
+                        LittleEndian::read_u64(&slot[24..32]),
+                    ));
+                }
+                ENTRY_VOLUME_LABEL => {
+                    let mut name = [b' '; 11];
+                    let len = usize::from(slot[1]).min(name.len());
+                    for (dest, pair) in name[..len].iter_mut().zip(slot[2..].chunks(2)) {
+                        let ch = LittleEndian::read_u16(pair);
+                        *dest = if ch < 0x80 { ch as u8 } else { b'?' };
+                    }
+                    label = Some(VolumeName::new(name));
+                }
+                _ => {}
+            }
+            Ok(None)
+        }))?;
+        if let Some(label) = label {
+            volume.name = label;
+        }
+
+        // Check the bitmap covers the volume, and is contiguous so we can index
+        // straight into it
+        let (bitmap_start, bitmap_length) =
+            bitmap.ok_or(Error::FormatError("exFAT allocation bitmap missing"))?;
+        if bitmap_length < u64::from(cluster_count).div_ceil(8)
+            || bitmap_start.0 < RESERVED_ENTRIES
+            || bitmap_start.0 >= cluster_count + RESERVED_ENTRIES
+        {
+            return Err(Error::FormatError("Bad exFAT allocation bitmap"));
+        }
+        let bitmap_clusters = bitmap_length.div_ceil(u64::from(volume.bytes_per_cluster()));
+        let mut cluster = bitmap_start;
+        for _ in 1..bitmap_clusters {
+            let next = maybe_await!(next_cluster(&volume, controller, cluster))?;
+            if next != cluster + 1 {
+                return Err(Error::FormatError("exFAT allocation bitmap is fragmented"));
+            }
+            cluster = next;
+        }
+        volume.bitmap_start = bitmap_start;
+
+        // Check the up-case table, and decode the ASCII range
+        let (expected_checksum, table_start, table_length) =
+            upcase_table.ok_or(Error::FormatError("exFAT up-case table missing"))?;
+        let table = UpcaseTable {
+            first_cluster: table_start,
+            length: u32::try_from(table_length)
+                .map_err(|_| Error::FormatError("Bad exFAT up-case table"))?,
+            ascii: [0u8; 128],
+        };
+        if table.first_cluster.0 < RESERVED_ENTRIES
+            || table.first_cluster.0 >= cluster_count + RESERVED_ENTRIES
+            || table.length == 0
+            || !table.length.is_multiple_of(2)
+        {
+            return Err(Error::FormatError("Bad exFAT up-case table"));
+        }
+        let mut ascii = [0u8; 128];
+        for (i, ch) in ascii.iter_mut().enumerate() {
+            *ch = i as u8;
+        }
+        let mut checksum = 0;
+        let mut decoder = UpcaseDecoder::new();
+        maybe_await!(for_each_table_block(&volume, controller, &table, |data| {
+            checksum = table_checksum(checksum, data);
+            for pair in data.chunks(2) {
+                if let Some((idx, mapped)) = decoder.feed(LittleEndian::read_u16(pair)) {
+                    if idx < 128 && mapped < 128 {
+                        ascii[idx as usize] = mapped as u8;
+                    }
+                }
+            }
+            true
+        }))?;
+        if checksum != expected_checksum {
+            return Err(Error::FormatError("Bad exFAT up-case table checksum"));
+        }
+        volume.upcase_table = UpcaseTable { ascii, ..table };
+
+        debug!("Mounted exFAT volume {:?}", volume);
+        Ok(VolumeType::ExFat(volume))
+    }
+});
+
+maybe_async!({
+    /// Check the signatures and checksum of the boot region starting at the
+    /// given block.
+    async fn check_boot_region<D, T>(
+        controller: &mut Controller<D, T>,
+        start: BlockIdx,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let mut checksum = 0;
+        for (i, block_idx) in start.range(BlockCount(BOOT_REGION_BLOCKS)).enumerate() {
+            maybe_await!(controller
+                .block_device
+                .read(&mut blocks, block_idx, "read_boot_region"))
+            .map_err(Error::DeviceError)?;
+            let block = &blocks[0];
+            match i {
+                0 => {
+                    if block[0..3] != [0xEB, 0x76, 0x90] || &block[3..11] != FILE_SYSTEM_NAME {
+                        return Err(Error::FormatError("Not an exFAT volume"));
+                    }
+                    if block[11..64].iter().any(|&b| b != 0) {
+                        return Err(Error::FormatError("Bad exFAT boot sector"));
+                    }
+                    if LittleEndian::read_u16(&block[510..512]) != 0xAA55 {
+                        return Err(Error::FormatError("Bad exFAT boot sector signature"));
+                    }
+                    checksum = boot_checksum(checksum, &block[..], true);
+                }
+                1..=8 => {
+                    if LittleEndian::read_u32(&block[508..512]) != 0xAA55_0000 {
+                        return Err(Error::FormatError("Bad exFAT extended boot sector"));
+                    }
+                    checksum = boot_checksum(checksum, &block[..], false);
+                }
+                9 | 10 => {
+                    checksum = boot_checksum(checksum, &block[..], false);
+                }
+                _ => {
+                    if block
+                        .chunks(4)
+                        .any(|word| LittleEndian::read_u32(word) != checksum)
+                    {
+                        return Err(Error::FormatError("Bad exFAT boot region checksum"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+});
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1287 @@
+//! embedded-sdmmc-rs - FAT12/FAT16/FAT32 file system I/O
+//!
+//! Reads and writes the FAT, directories and boot sector of a `FatVolume`.
+
+use super::controller::Controller;
+use super::BlockDevice;
+use crate::blockdevice::BlockCount;
+use crate::fat::{
+    read_fat12_entry, write_fat12_entry, Bpb, Fat16Info, Fat32Info, FatSpecificInfo, FatType,
+    FatVolume, InfoSector, OnDiskDirEntry, VolumeName, RESERVED_ENTRIES,
+};
+use crate::{
+    Attributes, Block, BlockIdx, Cluster, DirEntry, Directory, Error, ShortFileName, TimeSource,
+    VolumeType,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::{debug, trace, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace, warn};
+
+maybe_async!({
+    /// Write a new entry in the FAT
+    pub(crate) async fn update_info_sector<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(_) | FatSpecificInfo::Fat16(_) => {}
+            FatSpecificInfo::Fat32(fat32_info) => {
+                if fat.free_clusters_count.is_none() && fat.next_free_cluster.is_none() {
+                    return Ok(());
+                }
+                let mut blocks = [Block::new()];
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    fat32_info.info_location,
+                    "read_info_sector"
+                ))
+                .map_err(Error::DeviceError)?;
+                let block = &mut blocks[0];
+                if let Some(count) = fat.free_clusters_count {
+                    block[488..492].copy_from_slice(&count.to_le_bytes());
+                }
+                if let Some(next_free_cluster) = fat.next_free_cluster {
+                    block[492..496].copy_from_slice(&next_free_cluster.0.to_le_bytes());
+                }
+                maybe_await!(controller
+                    .block_device
+                    .write(&blocks, fat32_info.info_location))
+                .map_err(Error::DeviceError)?;
+            }
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Replace the name from the boot sector with the name from the volume
+    /// label entry in the root directory, if there is one, as that is the
+    /// one which counts.
+    async fn read_label_entry<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut label = None;
+        let root = Directory {
+            cluster: Cluster::ROOT_DIR,
+            #[cfg(feature = "exfat")]
+            entry: None,
+        };
+        maybe_await!(iterate_dir(fat, controller, &root, |entry| {
+            if entry.attributes.is_volume() && label.is_none() {
+                label = Some(VolumeName::new(entry.name.contents));
+            }
+        }))?;
+        if let Some(label) = label {
+            fat.name = label;
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Set the volume label, in both the boot sector and the root directory.
+    /// A blank name removes the label.
+    pub(crate) async fn set_label<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+        name: VolumeName,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let is_blank = name.name().is_empty();
+        // The boot sector, and on FAT32 its backup
+        let mut blocks = [Block::new()];
+        maybe_await!(controller
+            .block_device
+            .read(&mut blocks, fat.lba_start, "read_bpb"))
+        .map_err(Error::DeviceError)?;
+        let bpb = Bpb::create_from_bytes(&blocks[0]).map_err(Error::FormatError)?;
+        if bpb.has_volume_label() {
+            let offset = bpb.volume_label_offset();
+            let backup_boot_block = match bpb.fat_type {
+                FatType::Fat32 => match bpb.backup_boot_block() {
+                    0 | 0xFFFF => None,
+                    block => Some(BlockCount(u32::from(block))),
+                },
+                _ => None,
+            };
+            let label = if is_blank {
+                &VolumeName::NO_NAME
+            } else {
+                &name.data
+            };
+            blocks[0][offset..offset + 11].copy_from_slice(label);
+            maybe_await!(controller.block_device.write(&blocks, fat.lba_start))
+                .map_err(Error::DeviceError)?;
+            if let Some(backup_boot_block) = backup_boot_block {
+                let block_num = fat.lba_start + backup_boot_block;
+                maybe_await!(controller
+                    .block_device
+                    .read(&mut blocks, block_num, "read_bpb"))
+                .map_err(Error::DeviceError)?;
+                blocks[0][offset..offset + 11].copy_from_slice(label);
+                maybe_await!(controller.block_device.write(&blocks, block_num))
+                    .map_err(Error::DeviceError)?;
+            }
+        }
+        // The label entry in the root directory
+        let root = Directory {
+            cluster: Cluster::ROOT_DIR,
+            #[cfg(feature = "exfat")]
+            entry: None,
+        };
+        let mut existing = None;
+        maybe_await!(iterate_dir(fat, controller, &root, |entry| {
+            if entry.attributes.is_volume() && existing.is_none() {
+                existing = Some((entry.entry_block, entry.entry_offset as usize));
+            }
+        }))?;
+        match existing {
+            Some((block_num, offset)) => {
+                maybe_await!(controller
+                    .block_device
+                    .read(&mut blocks, block_num, "read_dir"))
+                .map_err(Error::DeviceError)?;
+                if is_blank {
+                    blocks[0][offset] = 0xE5;
+                } else {
+                    blocks[0][offset..offset + 11].copy_from_slice(&name.data);
+                }
+                maybe_await!(controller.block_device.write(&blocks, block_num))
+                    .map_err(Error::DeviceError)?;
+            }
+            None if !is_blank => {
+                maybe_await!(write_new_directory_entry(
+                    fat,
+                    controller,
+                    &root,
+                    ShortFileName {
+                        contents: name.data,
+                    },
+                    Attributes::create_from_fat(Attributes::VOLUME),
+                ))?;
+            }
+            None => {}
+        }
+        fat.name = name;
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Write a new entry in the FAT
+    pub(crate) async fn update_fat<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        cluster: Cluster,
+        new_value: Cluster,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let this_fat_block_num;
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(_fat12_info) => {
+                let (this_fat_block_num, this_fat_ent_offset, num_blocks) =
+                    fat.fat12_entry_location(cluster);
+                let mut blocks = [Block::new(), Block::new()];
+                maybe_await!(controller.block_device.read(
+                    &mut blocks[..num_blocks],
+                    this_fat_block_num,
+                    "read_fat"
+                ))
+                .map_err(Error::DeviceError)?;
+                let entry = match new_value {
+                    Cluster::INVALID => 0xFF6,
+                    Cluster::BAD => 0xFF7,
+                    Cluster::EMPTY => 0x000,
+                    Cluster::END_OF_FILE => 0xFFF,
+                    _ => new_value.0 as u16,
+                };
+                write_fat12_entry(&mut blocks, cluster, this_fat_ent_offset, entry);
+                // The entry may have straddled two blocks, so write both back
+                maybe_await!(controller
+                    .block_device
+                    .write(&blocks[..num_blocks], this_fat_block_num))
+                .map_err(Error::DeviceError)?;
+                return Ok(());
+            }
+            FatSpecificInfo::Fat16(_fat16_info) => {
+                let fat_offset = cluster.0 * 2;
+                this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_fat_block_num,
+                    "read_fat"
+                ))
+                .map_err(Error::DeviceError)?;
+                let entry = match new_value {
+                    Cluster::INVALID => 0xFFF6,
+                    Cluster::BAD => 0xFFF7,
+                    Cluster::EMPTY => 0x0000,
+                    Cluster::END_OF_FILE => 0xFFFF,
+                    _ => new_value.0 as u16,
+                };
+                LittleEndian::write_u16(
+                    &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                    entry,
+                );
+            }
+            FatSpecificInfo::Fat32(_fat32_info) => {
+                // FAT32 => 4 bytes per entry
+                let fat_offset = cluster.0 * 4;
+                this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_fat_block_num,
+                    "read_fat"
+                ))
+                .map_err(Error::DeviceError)?;
+                let entry = match new_value {
+                    Cluster::INVALID => 0x0FFF_FFF6,
+                    Cluster::BAD => 0x0FFF_FFF7,
+                    Cluster::EMPTY => 0x0000_0000,
+                    _ => new_value.0,
+                };
+                let existing = LittleEndian::read_u32(
+                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                );
+                let new = (existing & 0xF000_0000) | (entry & 0x0FFF_FFFF);
+                LittleEndian::write_u32(
+                    &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                    new,
+                );
+            }
+        }
+        maybe_await!(controller.block_device.write(&blocks, this_fat_block_num))
+            .map_err(Error::DeviceError)?;
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Look in the FAT to see which cluster comes next.
+    pub(crate) async fn next_cluster<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(_fat12_info) => {
+                let (this_fat_block_num, this_fat_ent_offset, num_blocks) =
+                    fat.fat12_entry_location(cluster);
+                let mut blocks = [Block::new(), Block::new()];
+                maybe_await!(controller.block_device.read(
+                    &mut blocks[..num_blocks],
+                    this_fat_block_num,
+                    "next_cluster",
+                ))
+                .map_err(Error::DeviceError)?;
+                match read_fat12_entry(&blocks, cluster, this_fat_ent_offset) {
+                    0x000 => {
+                        // Jumped to free space
+                        Err(Error::JumpedFree)
+                    }
+                    0xFF7 => {
+                        // Bad cluster
+                        Err(Error::BadCluster)
+                    }
+                    0x001 | 0xFF8..=0xFFF => {
+                        // There is no next cluster
+                        Err(Error::EndOfFile)
+                    }
+                    f => {
+                        // Seems legit
+                        Ok(Cluster(u32::from(f)))
+                    }
+                }
+            }
+            FatSpecificInfo::Fat16(_fat16_info) => {
+                let fat_offset = cluster.0 * 2;
+                let this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_fat_block_num,
+                    "next_cluster"
+                ))
+                .map_err(Error::DeviceError)?;
+                let fat_entry = LittleEndian::read_u16(
+                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                );
+                match fat_entry {
+                    0xFFF7 => {
+                        // Bad cluster
+                        Err(Error::BadCluster)
+                    }
+                    0xFFF8..=0xFFFF => {
+                        // There is no next cluster
+                        Err(Error::EndOfFile)
+                    }
+                    f => {
+                        // Seems legit
+                        Ok(Cluster(u32::from(f)))
+                    }
+                }
+            }
+            FatSpecificInfo::Fat32(_fat32_info) => {
+                let fat_offset = cluster.0 * 4;
+                let this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+                maybe_await!(controller.block_device.read(
+                    &mut blocks,
+                    this_fat_block_num,
+                    "next_cluster"
+                ))
+                .map_err(Error::DeviceError)?;
+                let fat_entry = LittleEndian::read_u32(
+                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                ) & 0x0FFF_FFFF;
+                match fat_entry {
+                    0x0000_0000 => {
+                        // Jumped to free space
+                        Err(Error::JumpedFree)
+                    }
+                    0x0FFF_FFF7 => {
+                        // Bad cluster
+                        Err(Error::BadCluster)
+                    }
+                    0x0000_0001 | 0x0FFF_FFF8..=0x0FFF_FFFF => {
+                        // There is no next cluster
+                        Err(Error::EndOfFile)
+                    }
+                    f => {
+                        // Seems legit
+                        Ok(Cluster(f))
+                    }
+                }
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Finds a empty entry space and writes the new entry to it, allocates a new cluster if it's
+    /// needed
+    pub(crate) async fn write_new_directory_entry<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: ShortFileName,
+        attributes: Attributes,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info) => {
+                let mut first_dir_block_num = match dir.cluster {
+                    Cluster::ROOT_DIR => fat.lba_start + fat16_info.first_root_dir_block,
+                    _ => fat.cluster_to_block(dir.cluster),
+                };
+                let mut current_cluster = Some(dir.cluster);
+                let mut blocks = [Block::new()];
+
+                let dir_size = match dir.cluster {
+                    Cluster::ROOT_DIR => BlockCount(
+                        (u32::from(fat16_info.root_entries_count) * 32).div_ceil(Block::LEN_U32),
+                    ),
+                    _ => BlockCount(u32::from(fat.blocks_per_cluster)),
+                };
+                while let Some(cluster) = current_cluster {
+                    for block in first_dir_block_num.range(dir_size) {
+                        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+                            .map_err(Error::DeviceError)?;
+                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+                            let start = entry * OnDiskDirEntry::LEN;
+                            let end = (entry + 1) * OnDiskDirEntry::LEN;
+                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+                            // 0x00 or 0xE5 represents a free entry
+                            if !dir_entry.is_valid() {
+                                let ctime = controller.timesource.get_timestamp();
+                                let entry = DirEntry::new(
+                                    name,
+                                    attributes,
+                                    Cluster(0),
+                                    ctime,
+                                    block,
+                                    start as u32,
+                                );
+                                blocks[0][start..start + 32]
+                                    .copy_from_slice(&entry.serialize(fat.get_fat_type())[..]);
+                                maybe_await!(controller.block_device.write(&blocks, block))
+                                    .map_err(Error::DeviceError)?;
+                                return Ok(entry);
+                            }
+                        }
+                    }
+                    if cluster != Cluster::ROOT_DIR {
+                        current_cluster = match maybe_await!(next_cluster(fat, controller, cluster))
+                        {
+                            Ok(n) => {
+                                first_dir_block_num = fat.cluster_to_block(n);
+                                Some(n)
+                            }
+                            Err(Error::EndOfFile) => {
+                                let c = maybe_await!(alloc_cluster(
+                                    fat,
+                                    controller,
+                                    Some(cluster),
+                                    true
+                                ))?;
+                                first_dir_block_num = fat.cluster_to_block(c);
+                                Some(c)
+                            }
+                            _ => None,
+                        };
+                    } else {
+                        current_cluster = None;
+                    }
+                }
+                Err(Error::NotEnoughSpace)
+            }
+            FatSpecificInfo::Fat32(fat32_info) => {
+                let mut first_dir_block_num = match dir.cluster {
+                    Cluster::ROOT_DIR => fat.cluster_to_block(fat32_info.first_root_dir_cluster),
+                    _ => fat.cluster_to_block(dir.cluster),
+                };
+                let mut current_cluster = Some(dir.cluster);
+                let mut blocks = [Block::new()];
+
+                let dir_size = BlockCount(u32::from(fat.blocks_per_cluster));
+                while let Some(cluster) = current_cluster {
+                    for block in first_dir_block_num.range(dir_size) {
+                        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+                            .map_err(Error::DeviceError)?;
+                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+                            let start = entry * OnDiskDirEntry::LEN;
+                            let end = (entry + 1) * OnDiskDirEntry::LEN;
+                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+                            // 0x00 or 0xE5 represents a free entry
+                            if !dir_entry.is_valid() {
+                                let ctime = controller.timesource.get_timestamp();
+                                let entry = DirEntry::new(
+                                    name,
+                                    attributes,
+                                    Cluster(0),
+                                    ctime,
+                                    block,
+                                    start as u32,
+                                );
+                                blocks[0][start..start + 32]
+                                    .copy_from_slice(&entry.serialize(FatType::Fat32)[..]);
+                                maybe_await!(controller.block_device.write(&blocks, block))
+                                    .map_err(Error::DeviceError)?;
+                                return Ok(entry);
+                            }
+                        }
+                    }
+                    current_cluster = match maybe_await!(next_cluster(fat, controller, cluster)) {
+                        Ok(n) => {
+                            first_dir_block_num = fat.cluster_to_block(n);
+                            Some(n)
+                        }
+                        Err(Error::EndOfFile) => {
+                            let c =
+                                maybe_await!(alloc_cluster(fat, controller, Some(cluster), true))?;
+                            first_dir_block_num = fat.cluster_to_block(c);
+                            Some(c)
+                        }
+                        _ => None,
+                    };
+                }
+                Err(Error::NotEnoughSpace)
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Calls callback `func` with every valid entry in the given directory.
+    /// Useful for performing directory listings.
+    pub(crate) async fn iterate_dir<D, T, F>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        F: FnMut(&DirEntry),
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info) => {
+                let mut first_dir_block_num = match dir.cluster {
+                    Cluster::ROOT_DIR => fat.lba_start + fat16_info.first_root_dir_block,
+                    _ => fat.cluster_to_block(dir.cluster),
+                };
+                let mut current_cluster = Some(dir.cluster);
+                let dir_size = match dir.cluster {
+                    Cluster::ROOT_DIR => BlockCount(
+                        (u32::from(fat16_info.root_entries_count) * 32).div_ceil(Block::LEN_U32),
+                    ),
+                    _ => BlockCount(u32::from(fat.blocks_per_cluster)),
+                };
+                let mut blocks = [Block::new()];
+                while let Some(cluster) = current_cluster {
+                    for block in first_dir_block_num.range(dir_size) {
+                        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+                            .map_err(Error::DeviceError)?;
+                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+                            let start = entry * OnDiskDirEntry::LEN;
+                            let end = (entry + 1) * OnDiskDirEntry::LEN;
+                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+                            if dir_entry.is_end() {
+                                // Can quit early
+                                return Ok(());
+                            } else if dir_entry.is_valid() && !dir_entry.is_lfn() {
+                                // Safe, since Block::LEN always fits on a u32
+                                let start = u32::try_from(start).unwrap();
+                                let entry = dir_entry.get_entry(fat.get_fat_type(), block, start);
+                                func(&entry);
+                            }
+                        }
+                    }
+                    if cluster != Cluster::ROOT_DIR {
+                        current_cluster = match maybe_await!(next_cluster(fat, controller, cluster))
+                        {
+                            Ok(n) => {
+                                first_dir_block_num = fat.cluster_to_block(n);
+                                Some(n)
+                            }
+                            _ => None,
+                        };
+                    } else {
+                        current_cluster = None;
+                    }
+                }
+                Ok(())
+            }
+            FatSpecificInfo::Fat32(fat32_info) => {
+                let mut current_cluster = match dir.cluster {
+                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
+                    _ => Some(dir.cluster),
+                };
+                let mut blocks = [Block::new()];
+                while let Some(cluster) = current_cluster {
+                    let block_idx = fat.cluster_to_block(cluster);
+                    for block in block_idx.range(BlockCount(u32::from(fat.blocks_per_cluster))) {
+                        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+                            .map_err(Error::DeviceError)?;
+                        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+                            let start = entry * OnDiskDirEntry::LEN;
+                            let end = (entry + 1) * OnDiskDirEntry::LEN;
+                            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+                            if dir_entry.is_end() {
+                                // Can quit early
+                                return Ok(());
+                            } else if dir_entry.is_valid() && !dir_entry.is_lfn() {
+                                // Safe, since Block::LEN always fits on a u32
+                                let start = u32::try_from(start).unwrap();
+                                let entry = dir_entry.get_entry(FatType::Fat32, block, start);
+                                func(&entry);
+                            }
+                        }
+                    }
+                    current_cluster = maybe_await!(next_cluster(fat, controller, cluster)).ok();
+                }
+                Ok(())
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Get an entry from the given directory
+    pub(crate) async fn find_directory_entry<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info) => {
+                let mut current_cluster = Some(dir.cluster);
+                let mut first_dir_block_num = match dir.cluster {
+                    Cluster::ROOT_DIR => fat.lba_start + fat16_info.first_root_dir_block,
+                    _ => fat.cluster_to_block(dir.cluster),
+                };
+                let dir_size = match dir.cluster {
+                    Cluster::ROOT_DIR => BlockCount(
+                        (u32::from(fat16_info.root_entries_count) * 32).div_ceil(Block::LEN_U32),
+                    ),
+                    _ => BlockCount(u32::from(fat.blocks_per_cluster)),
+                };
+
+                while let Some(cluster) = current_cluster {
+                    for block in first_dir_block_num.range(dir_size) {
+                        match maybe_await!(find_entry_in_block(
+                            controller,
+                            fat.get_fat_type(),
+                            &match_name,
+                            block,
+                        )) {
+                            Err(Error::NotInBlock) => continue,
+                            x => return x,
+                        }
+                    }
+                    if cluster != Cluster::ROOT_DIR {
+                        current_cluster = match maybe_await!(next_cluster(fat, controller, cluster))
+                        {
+                            Ok(n) => {
+                                first_dir_block_num = fat.cluster_to_block(n);
+                                Some(n)
+                            }
+                            _ => None,
+                        };
+                    } else {
+                        current_cluster = None;
+                    }
+                }
+                Err(Error::FileNotFound)
+            }
+            FatSpecificInfo::Fat32(fat32_info) => {
+                let mut current_cluster = match dir.cluster {
+                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
+                    _ => Some(dir.cluster),
+                };
+                while let Some(cluster) = current_cluster {
+                    let block_idx = fat.cluster_to_block(cluster);
+                    for block in block_idx.range(BlockCount(u32::from(fat.blocks_per_cluster))) {
+                        match maybe_await!(find_entry_in_block(
+                            controller,
+                            FatType::Fat32,
+                            &match_name,
+                            block
+                        )) {
+                            Err(Error::NotInBlock) => continue,
+                            x => return x,
+                        }
+                    }
+                    current_cluster = maybe_await!(next_cluster(fat, controller, cluster)).ok();
+                }
+                Err(Error::FileNotFound)
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Finds an entry in a given block
+    async fn find_entry_in_block<D, T>(
+        controller: &mut Controller<D, T>,
+        fat_type: FatType,
+        match_name: &ShortFileName,
+        block: BlockIdx,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+            .map_err(Error::DeviceError)?;
+        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+            let start = entry * OnDiskDirEntry::LEN;
+            let end = (entry + 1) * OnDiskDirEntry::LEN;
+            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+            if dir_entry.is_end() {
+                // Can quit early
+                return Err(Error::FileNotFound);
+            } else if dir_entry.matches(match_name) {
+                // Found it
+                // Safe, since Block::LEN always fits on a u32
+                let start = u32::try_from(start).unwrap();
+                return Ok(dir_entry.get_entry(fat_type, block, start));
+            }
+        }
+        Err(Error::NotInBlock)
+    }
+});
+
+maybe_async!({
+    /// Delete an entry from the given directory
+    pub(crate) async fn delete_directory_entry<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info) => {
+                let mut current_cluster = Some(dir.cluster);
+                let mut first_dir_block_num = match dir.cluster {
+                    Cluster::ROOT_DIR => fat.lba_start + fat16_info.first_root_dir_block,
+                    _ => fat.cluster_to_block(dir.cluster),
+                };
+                let dir_size = match dir.cluster {
+                    Cluster::ROOT_DIR => BlockCount(
+                        (u32::from(fat16_info.root_entries_count) * 32).div_ceil(Block::LEN_U32),
+                    ),
+                    _ => BlockCount(u32::from(fat.blocks_per_cluster)),
+                };
+
+                while let Some(cluster) = current_cluster {
+                    for block in first_dir_block_num.range(dir_size) {
+                        match maybe_await!(delete_entry_in_block(controller, &match_name, block)) {
+                            Err(Error::NotInBlock) => continue,
+                            x => return x,
+                        }
+                    }
+                    if cluster != Cluster::ROOT_DIR {
+                        current_cluster = match maybe_await!(next_cluster(fat, controller, cluster))
+                        {
+                            Ok(n) => {
+                                first_dir_block_num = fat.cluster_to_block(n);
+                                Some(n)
+                            }
+                            _ => None,
+                        };
+                    } else {
+                        current_cluster = None;
+                    }
+                }
+                Err(Error::FileNotFound)
+            }
+            FatSpecificInfo::Fat32(fat32_info) => {
+                let mut current_cluster = match dir.cluster {
+                    Cluster::ROOT_DIR => Some(fat32_info.first_root_dir_cluster),
+                    _ => Some(dir.cluster),
+                };
+                while let Some(cluster) = current_cluster {
+                    let block_idx = fat.cluster_to_block(cluster);
+                    for block in block_idx.range(BlockCount(u32::from(fat.blocks_per_cluster))) {
+                        match maybe_await!(delete_entry_in_block(controller, &match_name, block)) {
+                            Err(Error::NotInBlock) => continue,
+                            x => return x,
+                        }
+                    }
+                    current_cluster = maybe_await!(next_cluster(fat, controller, cluster)).ok();
+                }
+                Err(Error::FileNotFound)
+            }
+        }
+    }
+});
+
+maybe_async!({
+    /// Deletes an entry in a given block
+    async fn delete_entry_in_block<D, T>(
+        controller: &mut Controller<D, T>,
+        match_name: &ShortFileName,
+        block: BlockIdx,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        maybe_await!(controller.block_device.read(&mut blocks, block, "read_dir"))
+            .map_err(Error::DeviceError)?;
+        for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
+            let start = entry * OnDiskDirEntry::LEN;
+            let end = (entry + 1) * OnDiskDirEntry::LEN;
+            let dir_entry = OnDiskDirEntry::new(&blocks[0][start..end]);
+            if dir_entry.is_end() {
+                // Can quit early
+                return Err(Error::FileNotFound);
+            } else if dir_entry.matches(match_name) {
+                let mut blocks = blocks;
+                blocks[0].contents[start] = 0xE5;
+                maybe_await!(controller.block_device.write(&blocks, block))
+                    .map_err(Error::DeviceError)?;
+                return Ok(());
+            }
+        }
+        Err(Error::NotInBlock)
+    }
+});
+
+maybe_async!({
+    /// Finds the next free cluster after the start_cluster and before end_cluster
+    pub(crate) async fn find_next_free_cluster<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let mut current_cluster = start_cluster;
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(_fat12_info) => {
+                let mut blocks = [Block::new(), Block::new()];
+                let mut loaded_block_num = None;
+                while current_cluster.0 < end_cluster.0 {
+                    trace!(
+                        "current_cluster={:?}, end_cluster={:?}",
+                        current_cluster,
+                        end_cluster
+                    );
+                    let (this_fat_block_num, this_fat_ent_offset, _num_blocks) =
+                        fat.fat12_entry_location(current_cluster);
+                    if loaded_block_num != Some(this_fat_block_num) {
+                        trace!("Reading blocks {:?}", this_fat_block_num);
+                        // Always load the following block too, in case entries
+                        // straddle the boundary.
+                        maybe_await!(controller.block_device.read(
+                            &mut blocks,
+                            this_fat_block_num,
+                            "next_cluster"
+                        ))
+                        .map_err(Error::DeviceError)?;
+                        loaded_block_num = Some(this_fat_block_num);
+                    }
+                    if read_fat12_entry(&blocks, current_cluster, this_fat_ent_offset) == 0 {
+                        return Ok(current_cluster);
+                    }
+                    current_cluster += 1;
+                }
+            }
+            FatSpecificInfo::Fat16(_fat16_info) => {
+                while current_cluster.0 < end_cluster.0 {
+                    trace!(
+                        "current_cluster={:?}, end_cluster={:?}",
+                        current_cluster,
+                        end_cluster
+                    );
+                    let fat_offset = current_cluster.0 * 2;
+                    trace!("fat_offset = {:?}", fat_offset);
+                    let this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                    trace!("this_fat_block_num = {:?}", this_fat_block_num);
+                    let mut this_fat_ent_offset = usize::try_from(fat_offset % Block::LEN_U32)
+                        .map_err(|_| Error::ConversionError)?;
+                    trace!("Reading block {:?}", this_fat_block_num);
+                    maybe_await!(controller.block_device.read(
+                        &mut blocks,
+                        this_fat_block_num,
+                        "next_cluster"
+                    ))
+                    .map_err(Error::DeviceError)?;
+
+                    while this_fat_ent_offset <= Block::LEN - 2 {
+                        let fat_entry = LittleEndian::read_u16(
+                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                        );
+                        if fat_entry == 0 {
+                            return Ok(current_cluster);
+                        }
+                        this_fat_ent_offset += 2;
+                        current_cluster += 1;
+                    }
+                }
+            }
+            FatSpecificInfo::Fat32(_fat32_info) => {
+                while current_cluster.0 < end_cluster.0 {
+                    trace!(
+                        "current_cluster={:?}, end_cluster={:?}",
+                        current_cluster,
+                        end_cluster
+                    );
+                    let fat_offset = current_cluster.0 * 4;
+                    trace!("fat_offset = {:?}", fat_offset);
+                    let this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                    trace!("this_fat_block_num = {:?}", this_fat_block_num);
+                    let mut this_fat_ent_offset = usize::try_from(fat_offset % Block::LEN_U32)
+                        .map_err(|_| Error::ConversionError)?;
+                    trace!("Reading block {:?}", this_fat_block_num);
+                    maybe_await!(controller.block_device.read(
+                        &mut blocks,
+                        this_fat_block_num,
+                        "next_cluster"
+                    ))
+                    .map_err(Error::DeviceError)?;
+
+                    while this_fat_ent_offset <= Block::LEN - 4 {
+                        let fat_entry = LittleEndian::read_u32(
+                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                        ) & 0x0FFF_FFFF;
+                        if fat_entry == 0 {
+                            return Ok(current_cluster);
+                        }
+                        this_fat_ent_offset += 4;
+                        current_cluster += 1;
+                    }
+                }
+            }
+        }
+        warn!("Out of space...");
+        Err(Error::NotEnoughSpace)
+    }
+});
+
+maybe_async!({
+    /// Count the free clusters by scanning the whole FAT.
+    pub(crate) async fn count_free_clusters<D, T>(
+        fat: &FatVolume,
+        controller: &mut Controller<D, T>,
+    ) -> Result<u32, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(fat.cluster_count + RESERVED_ENTRIES);
+        let mut current_cluster = Cluster(RESERVED_ENTRIES);
+        let mut free = 0;
+        match &fat.fat_specific_info {
+            FatSpecificInfo::Fat12(_fat12_info) => {
+                let mut blocks = [Block::new(), Block::new()];
+                let mut loaded_block_num = None;
+                while current_cluster.0 < end_cluster.0 {
+                    let (this_fat_block_num, this_fat_ent_offset, _num_blocks) =
+                        fat.fat12_entry_location(current_cluster);
+                    if loaded_block_num != Some(this_fat_block_num) {
+                        // Always load the following block too, in case entries
+                        // straddle the boundary.
+                        maybe_await!(controller.block_device.read(
+                            &mut blocks,
+                            this_fat_block_num,
+                            "count_free"
+                        ))
+                        .map_err(Error::DeviceError)?;
+                        loaded_block_num = Some(this_fat_block_num);
+                    }
+                    if read_fat12_entry(&blocks, current_cluster, this_fat_ent_offset) == 0 {
+                        free += 1;
+                    }
+                    current_cluster += 1;
+                }
+            }
+            FatSpecificInfo::Fat16(_) | FatSpecificInfo::Fat32(_) => {
+                let entry_size = match fat.get_fat_type() {
+                    FatType::Fat32 => 4,
+                    _ => 2,
+                };
+                let mut blocks = [Block::new()];
+                while current_cluster.0 < end_cluster.0 {
+                    let fat_offset = current_cluster.0 * entry_size;
+                    let this_fat_block_num = fat.lba_start + fat.fat_start.offset_bytes(fat_offset);
+                    let mut this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+                    maybe_await!(controller.block_device.read(
+                        &mut blocks,
+                        this_fat_block_num,
+                        "count_free"
+                    ))
+                    .map_err(Error::DeviceError)?;
+                    while this_fat_ent_offset < Block::LEN && current_cluster.0 < end_cluster.0 {
+                        let fat_entry = if entry_size == 4 {
+                            LittleEndian::read_u32(
+                                &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                            ) & 0x0FFF_FFFF
+                        } else {
+                            u32::from(LittleEndian::read_u16(
+                                &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                            ))
+                        };
+                        if fat_entry == 0 {
+                            free += 1;
+                        }
+                        this_fat_ent_offset += entry_size as usize;
+                        current_cluster += 1;
+                    }
+                }
+            }
+        }
+        Ok(free)
+    }
+});
+
+maybe_async!({
+    /// Tries to allocate a cluster
+    pub(crate) async fn alloc_cluster<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+        prev_cluster: Option<Cluster>,
+        zero: bool,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        debug!("Allocating new cluster, prev_cluster={:?}", prev_cluster);
+        let end_cluster = Cluster(fat.cluster_count + RESERVED_ENTRIES);
+        let start_cluster = match fat.next_free_cluster {
+            Some(cluster) if cluster.0 < end_cluster.0 => cluster,
+            _ => Cluster(RESERVED_ENTRIES),
+        };
+        trace!(
+            "Finding next free between {:?}..={:?}",
+            start_cluster,
+            end_cluster
+        );
+        let new_cluster = match maybe_await!(find_next_free_cluster(
+            fat,
+            controller,
+            start_cluster,
+            end_cluster
+        )) {
+            Ok(cluster) => cluster,
+            Err(_) if start_cluster.0 > RESERVED_ENTRIES => {
+                debug!(
+                    "Retrying, finding next free between {:?}..={:?}",
+                    Cluster(RESERVED_ENTRIES),
+                    end_cluster
+                );
+                maybe_await!(find_next_free_cluster(
+                    fat,
+                    controller,
+                    Cluster(RESERVED_ENTRIES),
+                    end_cluster
+                ))?
+            }
+            Err(e) => return Err(e),
+        };
+        maybe_await!(update_fat(
+            fat,
+            controller,
+            new_cluster,
+            Cluster::END_OF_FILE
+        ))?;
+        if let Some(cluster) = prev_cluster {
+            trace!(
+                "Updating old cluster {:?} to {:?} in FAT",
+                cluster,
+                new_cluster
+            );
+            maybe_await!(update_fat(fat, controller, cluster, new_cluster))?;
+        }
+        trace!(
+            "Finding next free between {:?}..={:?}",
+            new_cluster,
+            end_cluster
+        );
+        fat.next_free_cluster = match maybe_await!(find_next_free_cluster(
+            fat,
+            controller,
+            new_cluster,
+            end_cluster
+        )) {
+            Ok(cluster) => Some(cluster),
+            Err(_) if new_cluster.0 > RESERVED_ENTRIES => {
+                match maybe_await!(find_next_free_cluster(
+                    fat,
+                    controller,
+                    Cluster(RESERVED_ENTRIES),
+                    end_cluster
+                )) {
+                    Ok(cluster) => Some(cluster),
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Next free cluster is {:?}", fat.next_free_cluster);
+        if let Some(ref mut number_free_cluster) = fat.free_clusters_count {
+            *number_free_cluster -= 1;
+        };
+        if zero {
+            let blocks = [Block::new()];
+            let first_block = fat.cluster_to_block(new_cluster);
+            let num_blocks = BlockCount(u32::from(fat.blocks_per_cluster));
+            for block in first_block.range(num_blocks) {
+                maybe_await!(controller.block_device.write(&blocks, block))
+                    .map_err(Error::DeviceError)?;
+            }
+        }
+        debug!("All done, returning {:?}", new_cluster);
+        Ok(new_cluster)
+    }
+});
+
+maybe_async!({
+    /// Marks the input cluster as an EOF and all the subsequent clusters in the chain as free
+    pub(crate) async fn truncate_cluster_chain<D, T>(
+        fat: &mut FatVolume,
+        controller: &mut Controller<D, T>,
+        cluster: Cluster,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if cluster.0 < RESERVED_ENTRIES {
+            // file doesn't have any valid cluster allocated, there is nothing to do
+            return Ok(());
+        }
+        let mut next = match maybe_await!(next_cluster(fat, controller, cluster)) {
+            Ok(n) => n,
+            Err(Error::EndOfFile) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if let Some(ref mut next_free_cluster) = fat.next_free_cluster {
+            if next_free_cluster.0 > next.0 {
+                *next_free_cluster = next;
+            }
+        } else {
+            fat.next_free_cluster = Some(next);
+        }
+        maybe_await!(update_fat(fat, controller, cluster, Cluster::END_OF_FILE))?;
+        loop {
+            let following = match maybe_await!(next_cluster(fat, controller, next)) {
+                Ok(n) => Some(n),
+                Err(Error::EndOfFile) => None,
+                Err(e) => return Err(e),
+            };
+            maybe_await!(update_fat(fat, controller, next, Cluster::EMPTY))?;
+            if let Some(ref mut number_free_cluster) = fat.free_clusters_count {
+                *number_free_cluster += 1;
+            };
+            match following {
+                Some(n) => next = n,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+});
+
+maybe_async!({
+    /// Load the boot parameter block from the start of the given partition and
+    /// determine if the partition contains a valid FAT12, FAT16 or FAT32 file
+    /// system.
+    pub(crate) async fn parse_volume<D, T>(
+        controller: &mut Controller<D, T>,
+        lba_start: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<VolumeType, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        D::Error: core::fmt::Debug,
+    {
+        let mut blocks = [Block::new()];
+        maybe_await!(controller
+            .block_device
+            .read(&mut blocks, lba_start, "read_bpb"))
+        .map_err(Error::DeviceError)?;
+        let block = &blocks[0];
+        let bpb = Bpb::create_from_bytes(block).map_err(Error::FormatError)?;
+        match bpb.fat_type {
+            FatType::Fat12 | FatType::Fat16 => {
+                if bpb.bytes_per_block() as usize != Block::LEN {
+                    return Err(Error::BadBlockSize(bpb.bytes_per_block()));
+                }
+                // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz) + RootDirSectors;
+                let root_dir_blocks = (u32::from(bpb.root_entries_count())
+                    * OnDiskDirEntry::LEN_U32)
+                    .div_ceil(Block::LEN_U32);
+                let fat_start = BlockCount(u32::from(bpb.reserved_block_count()));
+                let first_root_dir_block =
+                    fat_start + BlockCount(u32::from(bpb.num_fats()) * bpb.fat_size());
+                let first_data_block = first_root_dir_block + BlockCount(root_dir_blocks);
+                let fat16_info = Fat16Info {
+                    root_entries_count: bpb.root_entries_count(),
+                    first_root_dir_block,
+                };
+                let mut volume = FatVolume {
+                    lba_start,
+                    num_blocks,
+                    name: VolumeName { data: [0u8; 11] },
+                    serial_number: bpb.volume_id(),
+                    blocks_per_cluster: bpb.blocks_per_cluster(),
+                    first_data_block: (first_data_block),
+                    fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
+                    fat_size: BlockCount(bpb.fat_size()),
+                    num_fats: bpb.num_fats(),
+                    free_clusters_count: None,
+                    next_free_cluster: None,
+                    cluster_count: bpb.total_clusters(),
+                    fat_specific_info: if bpb.fat_type == FatType::Fat12 {
+                        FatSpecificInfo::Fat12(fat16_info)
+                    } else {
+                        FatSpecificInfo::Fat16(fat16_info)
+                    },
+                };
+                volume.name.data[..].copy_from_slice(bpb.volume_label());
+                maybe_await!(read_label_entry(&mut volume, controller))?;
+                Ok(VolumeType::Fat(volume))
+            }
+            FatType::Fat32 => {
+                // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz);
+                let first_data_block = u32::from(bpb.reserved_block_count())
+                    + (u32::from(bpb.num_fats()) * bpb.fat_size());
+
+                // Safe to unwrap since this is a Fat32 Type
+                let info_location = bpb.fs_info_block().unwrap();
+                let mut info_blocks = [Block::new()];
+                maybe_await!(controller.block_device.read(
+                    &mut info_blocks,
+                    lba_start + info_location,
+                    "read_info_sector",
+                ))
+                .map_err(Error::DeviceError)?;
+                let info_block = &info_blocks[0];
+                let info_sector =
+                    InfoSector::create_from_bytes(info_block).map_err(Error::FormatError)?;
+
+                let mut volume = FatVolume {
+                    lba_start,
+                    num_blocks,
+                    name: VolumeName { data: [0u8; 11] },
+                    serial_number: bpb.volume_id(),
+                    blocks_per_cluster: bpb.blocks_per_cluster(),
+                    first_data_block: BlockCount(first_data_block),
+                    fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
+                    fat_size: BlockCount(bpb.fat_size()),
+                    num_fats: bpb.num_fats(),
+                    free_clusters_count: info_sector.free_clusters_count(),
+                    next_free_cluster: info_sector.next_free_cluster(),
+                    cluster_count: bpb.total_clusters(),
+                    fat_specific_info: FatSpecificInfo::Fat32(Fat32Info {
+                        info_location: lba_start + info_location,
+                        first_root_dir_cluster: Cluster(bpb.first_root_dir_cluster()),
+                    }),
+                };
+                volume.name.data[..].copy_from_slice(bpb.volume_label());
+                maybe_await!(read_label_entry(&mut volume, controller))?;
+                Ok(VolumeType::Fat(volume))
+            }
+        }
+    }
+});
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
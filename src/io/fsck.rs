@@ -0,0 +1,896 @@
+//! embedded-sdmmc-rs - FAT file system consistency checker I/O
+//!
+//! Walks the FAT, directories and cluster chains of a `FatVolume` for
+//! `Controller::fsck`.
+
+use super::controller::Controller;
+use super::fat;
+use super::BlockDevice;
+use crate::blockdevice::BlockCount;
+use crate::fat::RESERVED_ENTRIES;
+use crate::fat::{read_fat12_entry, FatSpecificInfo, FatVolume, InfoSector, OnDiskDirEntry};
+use crate::fsck::MAX_DEPTH;
+use crate::{
+    Block, BlockIdx, Cluster, DirEntry, Error, FileSize, FsckIssue, FsckReport, ShortFileName,
+    TimeSource,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::{debug, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, warn};
+
+/// Number of directory entries in a block
+const ENTRIES_PER_BLOCK: usize = Block::LEN / OnDiskDirEntry::LEN;
+
+/// What the FAT says about a cluster
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FatEntry {
+    /// The cluster is free
+    Free,
+    /// The cluster is bad
+    Bad,
+    /// The cluster is the last in its chain
+    EndOfChain,
+    /// The cluster is followed by this one
+    Next(Cluster),
+}
+
+/// Reads FAT entries, keeping the last pair of blocks we read so that
+/// scanning the FAT in order is quick.
+struct FatReader {
+    /// The cached blocks
+    blocks: [Block; 2],
+    /// Which block is in `blocks[0]`
+    loaded: Option<BlockIdx>,
+}
+
+/// How a cluster chain ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ChainEnd {
+    /// At an end-of-chain marker
+    Ok,
+    /// At a cluster already in use elsewhere
+    CrossLinked(Cluster),
+    /// At something which isn't a valid cluster
+    Broken,
+}
+
+/// What we found when walking a cluster chain
+struct Chain {
+    /// How many clusters the chain has
+    clusters: u32,
+    /// How many clusters we were asked to keep (or all of them)
+    kept: u32,
+    /// The final cluster of those we kept
+    last_kept: Option<Cluster>,
+    /// The final good cluster of the chain
+    last: Option<Cluster>,
+    /// How the chain ended
+    end: ChainEnd,
+}
+
+/// How far we have got through checking one directory. The directories
+/// being checked are kept on a stack of these, rather than by recursion.
+#[derive(Debug, Copy, Clone)]
+struct DirCursor {
+    /// The directory being checked
+    dir: Cluster,
+    /// The directory it lives in
+    parent: Cluster,
+    /// The cluster we are in, or `None` for a FAT12/FAT16 root directory
+    cluster: Option<Cluster>,
+    /// The block we are in
+    block: BlockIdx,
+    /// The blocks left in this cluster (or root directory), including
+    /// `block`
+    blocks_left: u32,
+    /// The next entry to look at within `block`
+    entry: usize,
+    /// Have we seen a `.` entry?
+    dot_ok: bool,
+    /// Have we seen a `..` entry?
+    dot_dot_ok: bool,
+}
+
+/// The state of a volume check
+struct Checker<'a, F> {
+    /// The volume being checked
+    volume: &'a FatVolume,
+    /// One bit per cluster, set when a cluster is found to be in use
+    bitmap: &'a mut [u8],
+    /// Our FAT cache
+    reader: FatReader,
+    /// The directory block we are reading entries from
+    dir_block: [Block; 1],
+    /// Which block is in `dir_block`
+    dir_loaded: Option<BlockIdx>,
+    /// Should we fix what we find?
+    repair: bool,
+    /// Were some directories too deep to check?
+    too_deep: bool,
+    /// Have we changed the first FAT?
+    fat_dirty: bool,
+    /// The results so far
+    report: FsckReport,
+    /// Called for every issue
+    func: F,
+}
+
+impl FatReader {
+    /// Create an empty reader
+    fn new() -> FatReader {
+        FatReader {
+            blocks: [Block::new(), Block::new()],
+            loaded: None,
+        }
+    }
+
+    /// Forget the cached blocks, because the FAT has been written to
+    fn invalidate(&mut self) {
+        self.loaded = None;
+    }
+
+    maybe_async!({
+        /// Look up the FAT entry for the given cluster
+        async fn entry<D, T>(
+            &mut self,
+            volume: &FatVolume,
+            controller: &mut Controller<D, T>,
+            cluster: Cluster,
+        ) -> Result<FatEntry, Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            let (block_num, offset) = match &volume.fat_specific_info {
+                FatSpecificInfo::Fat12(_) => {
+                    let (block_num, offset, _num_blocks) = volume.fat12_entry_location(cluster);
+                    (block_num, offset)
+                }
+                FatSpecificInfo::Fat16(_) => {
+                    let fat_offset = cluster.0 * 2;
+                    (
+                        volume.lba_start + volume.fat_start.offset_bytes(fat_offset),
+                        (fat_offset % Block::LEN_U32) as usize,
+                    )
+                }
+                FatSpecificInfo::Fat32(_) => {
+                    let fat_offset = cluster.0 * 4;
+                    (
+                        volume.lba_start + volume.fat_start.offset_bytes(fat_offset),
+                        (fat_offset % Block::LEN_U32) as usize,
+                    )
+                }
+            };
+            if self.loaded != Some(block_num) {
+                // Always load the following block too, in case FAT12 entries
+                // straddle the boundary.
+                maybe_await!(controller.block_device.read(
+                    &mut self.blocks,
+                    block_num,
+                    "fsck_read_fat"
+                ))
+                .map_err(Error::DeviceError)?;
+                self.loaded = Some(block_num);
+            }
+            let (value, bad, end) = match &volume.fat_specific_info {
+                FatSpecificInfo::Fat12(_) => (
+                    u32::from(read_fat12_entry(&self.blocks, cluster, offset)),
+                    0xFF7,
+                    0xFF8,
+                ),
+                FatSpecificInfo::Fat16(_) => (
+                    u32::from(LittleEndian::read_u16(&self.blocks[0][offset..offset + 2])),
+                    0xFFF7,
+                    0xFFF8,
+                ),
+                FatSpecificInfo::Fat32(_) => (
+                    LittleEndian::read_u32(&self.blocks[0][offset..offset + 4]) & 0x0FFF_FFFF,
+                    0x0FFF_FFF7,
+                    0x0FFF_FFF8,
+                ),
+            };
+            Ok(match value {
+                0 => FatEntry::Free,
+                1 => FatEntry::EndOfChain,
+                v if v == bad => FatEntry::Bad,
+                v if v >= end => FatEntry::EndOfChain,
+                v => FatEntry::Next(Cluster(v)),
+            })
+        }
+    });
+}
+
+impl DirCursor {
+    /// Start at the first entry of the given directory
+    fn new(volume: &FatVolume, dir: Cluster, parent: Cluster) -> DirCursor {
+        let blocks_per_cluster = u32::from(volume.blocks_per_cluster);
+        let (cluster, block, blocks_left) = match (&volume.fat_specific_info, dir) {
+            (
+                FatSpecificInfo::Fat12(fat16_info) | FatSpecificInfo::Fat16(fat16_info),
+                Cluster::ROOT_DIR,
+            ) => (
+                None,
+                volume.lba_start + fat16_info.first_root_dir_block,
+                (u32::from(fat16_info.root_entries_count) * OnDiskDirEntry::LEN_U32)
+                    .div_ceil(Block::LEN_U32),
+            ),
+            (FatSpecificInfo::Fat32(fat32_info), Cluster::ROOT_DIR) => {
+                let cluster = fat32_info.first_root_dir_cluster;
+                (
+                    Some(cluster),
+                    volume.cluster_to_block(cluster),
+                    blocks_per_cluster,
+                )
+            }
+            (_, cluster) => (
+                Some(cluster),
+                volume.cluster_to_block(cluster),
+                blocks_per_cluster,
+            ),
+        };
+        DirCursor {
+            dir,
+            parent,
+            cluster,
+            block,
+            blocks_left,
+            entry: 0,
+            dot_ok: false,
+            dot_dot_ok: false,
+        }
+    }
+}
+
+impl<'a, F> Checker<'a, F>
+where
+    F: FnMut(&FsckIssue, bool),
+{
+    /// Record an issue
+    fn issue(&mut self, issue: FsckIssue, repaired: bool) {
+        debug!("fsck: {:?} (repaired: {})", issue, repaired);
+        self.report.issues_found += 1;
+        if repaired {
+            self.report.issues_repaired += 1;
+        }
+        (self.func)(&issue, repaired);
+    }
+
+    /// Is this a cluster number which exists on this volume?
+    fn in_range(&self, cluster: Cluster) -> bool {
+        cluster.0 >= RESERVED_ENTRIES && cluster.0 < self.volume.cluster_count + RESERVED_ENTRIES
+    }
+
+    /// Is this cluster marked as in use?
+    fn is_marked(&self, cluster: Cluster) -> bool {
+        self.bitmap[(cluster.0 / 8) as usize] & (1 << (cluster.0 % 8)) != 0
+    }
+
+    /// Mark a cluster as in use.
+    fn mark(&mut self, cluster: Cluster) {
+        self.bitmap[(cluster.0 / 8) as usize] |= 1 << (cluster.0 % 8);
+    }
+
+    maybe_async!({
+        /// Follow a chain, marking the clusters as in use. If `keep` is given,
+        /// only that many clusters are marked - the rest are left to be found as
+        /// lost clusters.
+        async fn walk_chain<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            first: Cluster,
+            keep: Option<u32>,
+        ) -> Result<Chain, Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            let mut chain = Chain {
+                clusters: 0,
+                kept: 0,
+                last_kept: None,
+                last: None,
+                end: ChainEnd::Ok,
+            };
+            let mut cluster = first;
+            loop {
+                if !self.in_range(cluster) {
+                    chain.end = ChainEnd::Broken;
+                    break;
+                }
+                let keeping = keep.is_none_or(|keep| chain.clusters < keep);
+                if keeping {
+                    if self.is_marked(cluster) {
+                        chain.end = ChainEnd::CrossLinked(cluster);
+                        break;
+                    }
+                    self.mark(cluster);
+                    chain.kept += 1;
+                    chain.last_kept = Some(cluster);
+                } else if chain.clusters > self.volume.cluster_count {
+                    // This part of the chain goes round in circles
+                    chain.end = ChainEnd::Broken;
+                    break;
+                }
+                chain.clusters += 1;
+                chain.last = Some(cluster);
+                match maybe_await!(self.reader.entry(self.volume, controller, cluster))? {
+                    FatEntry::EndOfChain => break,
+                    FatEntry::Next(next) => cluster = next,
+                    FatEntry::Free | FatEntry::Bad => {
+                        chain.end = ChainEnd::Broken;
+                        break;
+                    }
+                }
+            }
+            Ok(chain)
+        }
+    });
+
+    maybe_async!({
+        /// End a chain at the given cluster.
+        async fn end_chain_at<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            cluster: Cluster,
+        ) -> Result<(), Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            maybe_await!(fat::update_fat(
+                self.volume,
+                controller,
+                cluster,
+                Cluster::END_OF_FILE
+            ))?;
+            self.reader.invalidate();
+            self.fat_dirty = true;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Check a file's cluster chain matches its size.
+        async fn check_file<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            entry: &DirEntry,
+        ) -> Result<(), Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            let fat_type = self.volume.get_fat_type();
+            let bytes_per_cluster = FileSize::from(self.volume.bytes_per_cluster());
+            let needed = u32::try_from(entry.size.div_ceil(bytes_per_cluster))
+                .map_err(|_| Error::ConversionError)?;
+            let mut fixed = entry.clone();
+            if entry.cluster.0 == 0 {
+                if entry.size != 0 {
+                    if self.repair {
+                        fixed.size = 0;
+                        maybe_await!(controller.write_entry_to_disk(fat_type, &fixed))?;
+                    }
+                    self.issue(
+                        FsckIssue::ChainTooShort {
+                            name: entry.name.clone(),
+                            size: entry.size,
+                            clusters: 0,
+                        },
+                        self.repair,
+                    );
+                }
+                return Ok(());
+            }
+            let keep = if self.repair { Some(needed) } else { None };
+            let chain = maybe_await!(self.walk_chain(controller, entry.cluster, keep))?;
+            match chain.end {
+                ChainEnd::Ok => {}
+                ChainEnd::CrossLinked(cluster) => {
+                    // Needs a human to work out who owns what
+                    self.issue(
+                        FsckIssue::CrossLinked {
+                            name: entry.name.clone(),
+                            cluster,
+                        },
+                        false,
+                    );
+                    return Ok(());
+                }
+                ChainEnd::Broken => {
+                    let mut repaired = false;
+                    if self.repair {
+                        match chain.last {
+                            // If the chain is also too long, it gets truncated below
+                            Some(_) if chain.kept < chain.clusters => {}
+                            Some(last) => maybe_await!(self.end_chain_at(controller, last))?,
+                            None => {
+                                fixed.cluster = Cluster(0);
+                                fixed.size = 0;
+                                maybe_await!(controller.write_entry_to_disk(fat_type, &fixed))?;
+                            }
+                        }
+                        repaired = true;
+                    }
+                    self.issue(
+                        FsckIssue::BrokenChain {
+                            name: entry.name.clone(),
+                            last_good: chain.last,
+                        },
+                        repaired,
+                    );
+                    if fixed.cluster.0 == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+            if chain.clusters < needed {
+                if self.repair {
+                    fixed.size = FileSize::from(chain.clusters) * bytes_per_cluster;
+                    maybe_await!(controller.write_entry_to_disk(fat_type, &fixed))?;
+                }
+                self.issue(
+                    FsckIssue::ChainTooShort {
+                        name: entry.name.clone(),
+                        size: entry.size,
+                        clusters: chain.clusters,
+                    },
+                    self.repair,
+                );
+            } else if chain.clusters > needed {
+                if self.repair {
+                    match chain.last_kept {
+                        Some(last) => maybe_await!(self.end_chain_at(controller, last))?,
+                        None => {
+                            fixed.cluster = Cluster(0);
+                            maybe_await!(controller.write_entry_to_disk(fat_type, &fixed))?;
+                        }
+                    }
+                }
+                self.issue(
+                    FsckIssue::ChainTooLong {
+                        name: entry.name.clone(),
+                        size: entry.size,
+                        clusters: chain.clusters,
+                    },
+                    self.repair,
+                );
+            }
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Check every entry in the root directory, and then every directory
+        /// below it.
+        async fn check_dirs<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+        ) -> Result<(), Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            let root = DirCursor::new(self.volume, Cluster::ROOT_DIR, Cluster::ROOT_DIR);
+            let mut stack = [root; MAX_DEPTH + 1];
+            let mut depth = 0;
+            loop {
+                match maybe_await!(self.next_entry(controller, &mut stack[depth]))? {
+                    Some(entry) => {
+                        let child = maybe_await!(self.check_entry(
+                            controller,
+                            &entry,
+                            &mut stack[depth],
+                            depth
+                        ))?;
+                        if let Some(child) = child {
+                            stack[depth + 1] = DirCursor::new(self.volume, child, stack[depth].dir);
+                            depth += 1;
+                        }
+                    }
+                    None => {
+                        self.check_dot_entries(&stack[depth]);
+                        if depth == 0 {
+                            return Ok(());
+                        }
+                        depth -= 1;
+                    }
+                }
+            }
+        }
+    });
+
+    maybe_async!({
+        /// Get the next entry from a directory, skipping over unused and long
+        /// file name entries. Returns `None` at the end of the directory.
+        async fn next_entry<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            cursor: &mut DirCursor,
+        ) -> Result<Option<DirEntry>, Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            loop {
+                if cursor.entry == ENTRIES_PER_BLOCK {
+                    cursor.entry = 0;
+                    cursor.block += BlockCount(1);
+                    cursor.blocks_left -= 1;
+                    if cursor.blocks_left == 0 {
+                        if let Some(cluster) = cursor.cluster {
+                            let next = match maybe_await!(fat::next_cluster(
+                                self.volume,
+                                controller,
+                                cluster
+                            )) {
+                                Ok(next) => next,
+                                // A chain we can't follow has already been reported
+                                Err(_) => return Ok(None),
+                            };
+                            cursor.cluster = Some(next);
+                            cursor.block = self.volume.cluster_to_block(next);
+                            cursor.blocks_left = u32::from(self.volume.blocks_per_cluster);
+                        }
+                    }
+                }
+                if cursor.blocks_left == 0 {
+                    return Ok(None);
+                }
+                if self.dir_loaded != Some(cursor.block) {
+                    maybe_await!(controller.block_device.read(
+                        &mut self.dir_block,
+                        cursor.block,
+                        "fsck_read_dir"
+                    ))
+                    .map_err(Error::DeviceError)?;
+                    self.dir_loaded = Some(cursor.block);
+                }
+                let start = cursor.entry * OnDiskDirEntry::LEN;
+                cursor.entry += 1;
+                let dir_entry =
+                    OnDiskDirEntry::new(&self.dir_block[0][start..start + OnDiskDirEntry::LEN]);
+                if dir_entry.is_end() {
+                    return Ok(None);
+                } else if dir_entry.is_valid() && !dir_entry.is_lfn() {
+                    // Safe, since Block::LEN always fits on a u32
+                    let start = u32::try_from(start).unwrap();
+                    return Ok(Some(dir_entry.get_entry(
+                        self.volume.get_fat_type(),
+                        cursor.block,
+                        start,
+                    )));
+                }
+            }
+        }
+    });
+
+    /// Report a missing `.` or `..` entry in a directory we have finished
+    /// checking.
+    fn check_dot_entries(&mut self, cursor: &DirCursor) {
+        if cursor.dir == Cluster::ROOT_DIR {
+            return;
+        }
+        // Missing entries can't be put back without moving everything
+        // else in the directory along
+        if !cursor.dot_ok {
+            self.issue(
+                FsckIssue::BadDotEntry {
+                    dir: cursor.dir,
+                    name: ShortFileName::this_dir(),
+                },
+                false,
+            );
+        }
+        if !cursor.dot_dot_ok {
+            self.issue(
+                FsckIssue::BadDotEntry {
+                    dir: cursor.dir,
+                    name: ShortFileName::parent_dir(),
+                },
+                false,
+            );
+        }
+    }
+
+    maybe_async!({
+        /// Check one directory entry. Returns the directory to check next, if
+        /// this entry is one which should be checked.
+        async fn check_entry<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            entry: &DirEntry,
+            cursor: &mut DirCursor,
+            depth: usize,
+        ) -> Result<Option<Cluster>, Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            if entry.attributes.is_volume() {
+                return Ok(None);
+            }
+            let dir = cursor.dir;
+            let dot_target = if entry.name == ShortFileName::this_dir() {
+                Some((dir, &mut cursor.dot_ok))
+            } else if entry.name == ShortFileName::parent_dir() {
+                // The root directory is always recorded as cluster zero
+                let parent = match cursor.parent {
+                    Cluster::ROOT_DIR => Cluster(0),
+                    p => p,
+                };
+                Some((parent, &mut cursor.dot_dot_ok))
+            } else {
+                None
+            };
+            if let Some((expected, found)) = dot_target {
+                if dir == Cluster::ROOT_DIR {
+                    return Ok(None);
+                }
+                *found = true;
+                let root_cluster = match &self.volume.fat_specific_info {
+                    FatSpecificInfo::Fat32(fat32_info) => Some(fat32_info.first_root_dir_cluster),
+                    _ => None,
+                };
+                // Some systems record the FAT32 root directory's real cluster
+                let also_ok = expected == Cluster(0) && Some(entry.cluster) == root_cluster;
+                if entry.cluster != expected && !also_ok {
+                    if self.repair {
+                        let mut fixed = entry.clone();
+                        fixed.cluster = expected;
+                        maybe_await!(
+                            controller.write_entry_to_disk(self.volume.get_fat_type(), &fixed)
+                        )?;
+                    }
+                    self.issue(
+                        FsckIssue::BadDotEntry {
+                            dir,
+                            name: entry.name.clone(),
+                        },
+                        self.repair,
+                    );
+                }
+                return Ok(None);
+            }
+
+            if !entry.attributes.is_directory() {
+                self.report.files += 1;
+                maybe_await!(self.check_file(controller, entry))?;
+                return Ok(None);
+            }
+
+            self.report.directories += 1;
+            let chain = maybe_await!(self.walk_chain(controller, entry.cluster, None))?;
+            match chain.end {
+                ChainEnd::Ok => {}
+                ChainEnd::CrossLinked(cluster) => {
+                    // Don't go in, in case we end up going round in circles
+                    self.issue(
+                        FsckIssue::CrossLinked {
+                            name: entry.name.clone(),
+                            cluster,
+                        },
+                        false,
+                    );
+                    return Ok(None);
+                }
+                ChainEnd::Broken => {
+                    let repaired = self.repair && chain.last.is_some();
+                    if let (true, Some(last)) = (repaired, chain.last) {
+                        maybe_await!(self.end_chain_at(controller, last))?;
+                    }
+                    self.issue(
+                        FsckIssue::BrokenChain {
+                            name: entry.name.clone(),
+                            last_good: chain.last,
+                        },
+                        repaired,
+                    );
+                    if chain.last.is_none() {
+                        return Ok(None);
+                    }
+                }
+            }
+            if depth >= MAX_DEPTH {
+                warn!("fsck: directory {:?} is too deep to check", entry.cluster);
+                self.too_deep = true;
+                self.issue(FsckIssue::TooDeep(entry.cluster), false);
+                return Ok(None);
+            }
+            Ok(Some(entry.cluster))
+        }
+    });
+
+    maybe_async!({
+        /// Look for clusters which are in use but not part of any chain, and
+        /// count the free clusters.
+        async fn check_lost_clusters<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+        ) -> Result<(), Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            // If we didn't see every directory, we can't tell what's lost
+            let repair = self.repair && !self.too_deep;
+            let mut free = 0;
+            for cluster_num in RESERVED_ENTRIES..(self.volume.cluster_count + RESERVED_ENTRIES) {
+                let cluster = Cluster(cluster_num);
+                match maybe_await!(self.reader.entry(self.volume, controller, cluster))? {
+                    FatEntry::Free => free += 1,
+                    FatEntry::Bad => {}
+                    _ if self.is_marked(cluster) => {}
+                    _ => {
+                        if repair {
+                            maybe_await!(fat::update_fat(
+                                self.volume,
+                                controller,
+                                cluster,
+                                Cluster::EMPTY
+                            ))?;
+                            self.reader.invalidate();
+                            self.fat_dirty = true;
+                            free += 1;
+                        }
+                        self.issue(FsckIssue::LostCluster(cluster), repair);
+                    }
+                }
+            }
+            self.report.free_clusters = free;
+            Ok(())
+        }
+    });
+
+    maybe_async!({
+        /// Compare each copy of the FAT with the first. If `report` is false,
+        /// differences are fixed without being reported.
+        async fn check_fat_copies<D, T>(
+            &mut self,
+            controller: &mut Controller<D, T>,
+            report: bool,
+        ) -> Result<(), Error<D::Error>>
+        where
+            D: BlockDevice,
+            T: TimeSource,
+        {
+            let volume = self.volume;
+            let mut first = [Block::new()];
+            let mut other = [Block::new()];
+            for block in 0..volume.fat_size.0 {
+                let first_block_num = volume.lba_start + volume.fat_start + BlockCount(block);
+                maybe_await!(controller.block_device.read(
+                    &mut first,
+                    first_block_num,
+                    "fsck_read_fat"
+                ))
+                .map_err(Error::DeviceError)?;
+                for copy in 1..volume.num_fats {
+                    let other_block_num =
+                        first_block_num + BlockCount(u32::from(copy) * volume.fat_size.0);
+                    maybe_await!(controller.block_device.read(
+                        &mut other,
+                        other_block_num,
+                        "fsck_read_fat"
+                    ))
+                    .map_err(Error::DeviceError)?;
+                    if first[0].contents != other[0].contents {
+                        if self.repair {
+                            maybe_await!(controller.block_device.write(&first, other_block_num))
+                                .map_err(Error::DeviceError)?;
+                        }
+                        if !report {
+                            continue;
+                        }
+                        self.issue(
+                            FsckIssue::FatMismatch {
+                                copy,
+                                block: BlockCount(block),
+                            },
+                            self.repair,
+                        );
+                    }
+                }
+            }
+            Ok(())
+        }
+    });
+}
+
+maybe_async!({
+    /// Check (and optionally repair) a FAT volume. See `Controller::fsck`.
+    pub(crate) async fn check_fat_volume<D, T, F>(
+        controller: &mut Controller<D, T>,
+        volume: &mut FatVolume,
+        bitmap: &mut [u8],
+        repair: bool,
+        func: F,
+    ) -> Result<FsckReport, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(&FsckIssue, bool),
+    {
+        let needed = (volume.cluster_count + RESERVED_ENTRIES).div_ceil(8) as usize;
+        let bitmap = bitmap.get_mut(..needed).ok_or(Error::BufferTooSmall)?;
+        bitmap.fill(0);
+        let mut checker = Checker {
+            volume,
+            bitmap,
+            reader: FatReader::new(),
+            dir_block: [Block::new()],
+            dir_loaded: None,
+            repair,
+            too_deep: false,
+            fat_dirty: false,
+            report: FsckReport::default(),
+            func,
+        };
+        maybe_await!(checker.check_fat_copies(controller, true))?;
+        if let FatSpecificInfo::Fat32(fat32_info) = &checker.volume.fat_specific_info {
+            let chain = maybe_await!(checker.walk_chain(
+                controller,
+                fat32_info.first_root_dir_cluster,
+                None
+            ))?;
+            if chain.end != ChainEnd::Ok {
+                return Err(Error::FormatError(
+                    "Root directory cluster chain is damaged",
+                ));
+            }
+        }
+        maybe_await!(checker.check_dirs(controller))?;
+        maybe_await!(checker.check_lost_clusters(controller))?;
+        if checker.fat_dirty {
+            // Our repairs only went to the first copy of the FAT
+            maybe_await!(checker.check_fat_copies(controller, false))?;
+        }
+
+        let free_clusters = checker.report.free_clusters;
+        let mut free_count_wrong = false;
+        if let FatSpecificInfo::Fat32(fat32_info) = &checker.volume.fat_specific_info {
+            let mut blocks = [Block::new()];
+            maybe_await!(controller.block_device.read(
+                &mut blocks,
+                fat32_info.info_location,
+                "read_info_sector"
+            ))
+            .map_err(Error::DeviceError)?;
+            let info_sector =
+                InfoSector::create_from_bytes(&blocks[0]).map_err(Error::FormatError)?;
+            if let Some(recorded) = info_sector.free_clusters_count() {
+                if recorded != free_clusters {
+                    free_count_wrong = true;
+                    checker.issue(
+                        FsckIssue::WrongFreeCount {
+                            recorded,
+                            actual: free_clusters,
+                        },
+                        repair,
+                    );
+                }
+            }
+        }
+        let report = checker.report;
+        if repair {
+            // Whatever we freed is now the best place to look for space
+            volume.next_free_cluster = None;
+            if free_count_wrong || volume.free_clusters_count.is_some() {
+                volume.free_clusters_count = Some(free_clusters);
+                maybe_await!(fat::update_info_sector(volume, controller))?;
+            }
+        }
+        Ok(report)
+    }
+});
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
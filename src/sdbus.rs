@@ -0,0 +1,786 @@
+//! embedded-sdmmc-rs - SD Bus Protocol
+//!
+//! Implements the SD protocol on top of a native SD bus host controller
+//! (often called SDIO or SDMMC on microcontrollers), which can move data
+//! over four lines rather than the one that SPI mode uses.
+//!
+//! The host controller issues commands and moves data; this module knows
+//! which commands to send, and in what order.
+
+use super::sdmmc::{CardType, State, Timeout};
+use super::sdmmc_proto::*;
+use super::{Block, BlockCount, BlockDevice, BlockIdx};
+use core::cell::RefCell;
+use core::convert::TryFrom;
+
+#[cfg(feature = "log")]
+use log::{debug, trace};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace};
+
+/// The clock speed the spec requires while identifying the card
+const IDENTIFICATION_CLOCK_HZ: u32 = 400_000;
+
+/// The check pattern we send with CMD8, and expect to get back
+const CMD8_CHECK_PATTERN: u32 = 0x1AA;
+
+/// Represents an inactive SD Card interface.
+/// Built from an `SdHost`, which is how we talk to the card.
+pub struct SdBus<H>
+where
+    H: SdHost,
+{
+    host: RefCell<H>,
+    card_type: CardType,
+    state: State,
+    options: AcquireOpts,
+    rca: u16,
+    cid: Cid,
+    csd: [u8; 16],
+}
+
+/// An initialized block device used to access the SD card.
+/// **Caution**: any data must be flushed manually before dropping
+/// `BlockSdBus`.
+/// Uses the native SD bus.
+pub struct BlockSdBus<'a, H>(&'a mut SdBus<H>)
+where
+    H: SdHost;
+
+/// Something `SdBus` can use to talk to an SD card over the native SD bus.
+///
+/// The host is responsible for framing: start and end bits, command and
+/// data CRCs, and spotting timeouts. It should report failures using the
+/// `Host`, `TimeoutCommand`, `CommandCrcError`, `TimeoutData` and
+/// `DataCrcError` variants of `Error`.
+pub trait SdHost {
+    /// Set the bus clock. The card is identified at 400 kHz, and then run
+    /// at the speed it says it can manage.
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error>;
+    /// Set how many data lines the host uses.
+    fn set_bus_width(&mut self, width: BusWidth) -> Result<(), Error>;
+    /// Send a command, and collect a response of the given type. For `R1b`
+    /// responses, wait for the card to stop signalling busy before
+    /// returning.
+    fn command(
+        &mut self,
+        command: u8,
+        arg: u32,
+        response_type: ResponseType,
+    ) -> Result<Response, Error>;
+    /// Send a command with an R1 response, and then read the blocks the card
+    /// sends back. Returns the card status from the response.
+    fn read_blocks(&mut self, command: u8, arg: u32, blocks: &mut [Block]) -> Result<u32, Error>;
+    /// Send a command with an R1 response, and then send the blocks to the
+    /// card. Returns the card status from the response.
+    fn write_blocks(&mut self, command: u8, arg: u32, blocks: &[Block]) -> Result<u32, Error>;
+    /// Wait for the given number of microseconds.
+    fn delay_us(&mut self, us: u32);
+}
+
+/// The number of data lines on the SD bus.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusWidth {
+    /// Only DAT0 is used
+    One,
+    /// DAT0 to DAT3 are used
+    Four,
+}
+
+/// The kinds of response a command can get on the SD bus.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResponseType {
+    /// No response (CMD0)
+    None,
+    /// 48-bit card status
+    R1,
+    /// 48-bit card status, then the card holds DAT0 low while it is busy
+    R1b,
+    /// 136-bit CID or CSD register
+    R2,
+    /// 48-bit OCR register, with no valid CRC
+    R3,
+    /// 48-bit published RCA and some of the card status
+    R6,
+    /// 48-bit card interface condition
+    R7,
+}
+
+/// A response from the card, as collected by an `SdHost`.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The command has no response
+    None,
+    /// The 32-bit payload of an R1, R1b, R3, R6 or R7 response
+    Short(u32),
+    /// The 128-bit payload of an R2 response, most significant byte first,
+    /// as it would be read from the register in SPI mode. The host may
+    /// leave the final (CRC) byte as zero.
+    Long([u8; 16]),
+}
+
+/// The possible errors `SdBus` can generate.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// We got an error from the host controller
+    Host,
+    /// We didn't get a response when executing this command
+    TimeoutCommand(u8),
+    /// The response to this command was corrupted
+    CommandCrcError(u8),
+    /// The card didn't send or accept data in time
+    TimeoutData,
+    /// A block of data was corrupted
+    DataCrcError,
+    /// The host gave us the wrong kind of response for this command
+    BadResponse(u8),
+    /// The card reported an error in its status (the bits are the card
+    /// status from the response)
+    CardStatus(u32),
+    /// The card doesn't work at our voltage, or echoed the wrong CMD8 check
+    /// pattern
+    UnsupportedCard,
+    /// The card didn't finish initialising in time
+    TimeoutInit,
+    /// The card didn't finish writing in time
+    TimeoutWaitNotBusy,
+    /// Can't perform this operation with the card in this state
+    BadState,
+    /// The card has more blocks than a `BlockIdx` can address (it is bigger
+    /// than 2 TiB)
+    CardTooLarge,
+}
+
+/// Options for acquiring the card.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone)]
+pub struct AcquireOpts {
+    /// How many data lines to use once the card is identified.
+    pub bus_width: BusWidth,
+    /// The fastest clock the host (or the board) can run the bus at. The
+    /// card's own limit is read from its CSD.
+    pub max_clock_hz: u32,
+    /// How long to wait for the card to finish initialising. The spec says
+    /// ACMD41 can take up to 1 second.
+    pub init_timeout_ms: u32,
+    /// How long to wait for the card to finish writing. The spec says
+    /// 250 ms for SDSC and SDHC cards, and 500 ms for SDXC cards.
+    pub write_timeout_ms: u32,
+}
+
+impl Default for AcquireOpts {
+    fn default() -> Self {
+        AcquireOpts {
+            bus_width: BusWidth::Four,
+            max_clock_hz: 25_000_000,
+            init_timeout_ms: 1000,
+            write_timeout_ms: 500,
+        }
+    }
+}
+
+impl<H> SdBus<H>
+where
+    H: SdHost,
+{
+    /// Create a new SD bus controller which talks to the card using the
+    /// given host.
+    pub fn new(host: H) -> SdBus<H> {
+        SdBus {
+            host: RefCell::new(host),
+            card_type: CardType::SD1,
+            state: State::NoInit,
+            options: AcquireOpts::default(),
+            rca: 0,
+            cid: Cid::new(),
+            csd: [0u8; 16],
+        }
+    }
+
+    /// Get the host back.
+    pub fn free(self) -> H {
+        self.host.into_inner()
+    }
+
+    /// Initializes the card into a known state
+    pub fn acquire(&mut self) -> Result<BlockSdBus<'_, H>, Error> {
+        self.acquire_with_opts(Default::default())
+    }
+
+    /// Initializes the card into a known state
+    pub fn acquire_with_opts(&mut self, options: AcquireOpts) -> Result<BlockSdBus<'_, H>, Error> {
+        debug!("acquiring card with opts: {:?}", options);
+        self.options = options;
+        self.state = State::Error;
+        self.init()?;
+        self.state = State::Idle;
+        Ok(BlockSdBus(self))
+    }
+
+    /// Reset the card, identify it, and get it ready to move data.
+    fn init(&mut self) -> Result<(), Error> {
+        let options = self.options;
+        {
+            let mut host = self.host.borrow_mut();
+            host.set_bus_width(BusWidth::One)?;
+            host.set_clock_hz(IDENTIFICATION_CLOCK_HZ)?;
+        }
+        trace!("Reset card..");
+        self.command(CMD0, 0, ResponseType::None)?;
+        // Version 2 cards echo back the check pattern; older cards ignore
+        // the command.
+        let v2 = match self.command(CMD8, CMD8_CHECK_PATTERN, ResponseType::R7) {
+            Ok(Response::Short(echo)) if echo & 0xFFF == CMD8_CHECK_PATTERN => true,
+            Ok(_) => return Err(Error::UnsupportedCard),
+            Err(Error::TimeoutCommand(_)) => false,
+            Err(e) => return Err(e),
+        };
+        let arg = if v2 {
+            OCR_CCS | OCR_VOLTAGE_WINDOW
+        } else {
+            OCR_VOLTAGE_WINDOW
+        };
+        let mut timeout = Timeout::new(options.init_timeout_ms);
+        let ocr = loop {
+            self.card_status(CMD55, 0, ResponseType::R1)?;
+            let ocr = self.short_response(ACMD41, arg, ResponseType::R3)?;
+            if ocr & OCR_VOLTAGE_WINDOW == 0 {
+                return Err(Error::UnsupportedCard);
+            }
+            if ocr & OCR_POWER_UP_DONE != 0 {
+                break ocr;
+            }
+            self.delay(&mut timeout, Error::TimeoutInit)?;
+        };
+        self.card_type = match (v2, ocr & OCR_CCS != 0) {
+            (false, _) => CardType::SD1,
+            (true, false) => CardType::SD2,
+            (true, true) => CardType::SDHC,
+        };
+        debug!("Card version: {:?}", self.card_type);
+        self.cid = Cid {
+            data: self.long_response(CMD2, 0)?,
+        };
+        let published = self.short_response(CMD3, 0, ResponseType::R6)?;
+        // The low half has status bits 23, 22, 19 and 12:0 squashed together
+        let status =
+            (published & 0x1FFF) | ((published & 0x2000) << 6) | ((published & 0xC000) << 8);
+        if status & CARD_STATUS_ERROR_MASK != 0 {
+            return Err(Error::CardStatus(status));
+        }
+        self.rca = (published >> 16) as u16;
+        debug!("Card RCA: {:#06x}", self.rca);
+        self.csd = self.long_response(CMD9, self.rca_arg())?;
+        self.card_status(CMD7, self.rca_arg(), ResponseType::R1b)?;
+        let clock_hz = match self.csd() {
+            Csd::V1(ref contents) => contents.max_transfer_rate_bps(),
+            Csd::V2(ref contents) => contents.max_transfer_rate_bps(),
+            Csd::V3(ref contents) => contents.max_transfer_rate_bps(),
+            Csd::Mmc(ref contents) => contents.max_transfer_rate_bps(),
+        };
+        let clock_hz = match clock_hz {
+            0 => IDENTIFICATION_CLOCK_HZ,
+            hz => hz.min(options.max_clock_hz),
+        };
+        self.host.borrow_mut().set_clock_hz(clock_hz)?;
+        if options.bus_width == BusWidth::Four {
+            self.card_status(CMD55, self.rca_arg(), ResponseType::R1)?;
+            self.card_status(ACMD6, 0b10, ResponseType::R1)?;
+            self.host.borrow_mut().set_bus_width(BusWidth::Four)?;
+        }
+        if self.card_type != CardType::SDHC {
+            self.card_status(CMD16, Block::LEN_U32, ResponseType::R1)?;
+        }
+        Ok(())
+    }
+
+    /// The argument for commands addressed to our card.
+    fn rca_arg(&self) -> u32 {
+        u32::from(self.rca) << 16
+    }
+
+    /// Decode the CSD we read when the card was acquired.
+    fn csd(&self) -> Csd {
+        // Version 2 standard capacity cards still have a version 1 CSD
+        match self.csd[0] >> 6 {
+            1 => Csd::V2(CsdV2 { data: self.csd }),
+            2 => Csd::V3(CsdV3 { data: self.csd }),
+            _ => Csd::V1(CsdV1 { data: self.csd }),
+        }
+    }
+
+    /// Wait a little while before polling the card again, or give up with
+    /// the given error if we've run out of time.
+    fn delay(&self, timeout: &mut Timeout, err: Error) -> Result<(), Error> {
+        let delay_us = timeout.next_delay_us().ok_or(err)?;
+        self.host.borrow_mut().delay_us(delay_us);
+        Ok(())
+    }
+
+    /// Send a command to the card.
+    fn command(
+        &self,
+        command: u8,
+        arg: u32,
+        response_type: ResponseType,
+    ) -> Result<Response, Error> {
+        trace!("CMD{} {:#010x}", command, arg);
+        self.host.borrow_mut().command(command, arg, response_type)
+    }
+
+    /// Send a command which gets a 32-bit response.
+    fn short_response(
+        &self,
+        command: u8,
+        arg: u32,
+        response_type: ResponseType,
+    ) -> Result<u32, Error> {
+        match self.command(command, arg, response_type)? {
+            Response::Short(value) => Ok(value),
+            _ => Err(Error::BadResponse(command)),
+        }
+    }
+
+    /// Send a command which gets an R2 response.
+    fn long_response(&self, command: u8, arg: u32) -> Result<[u8; 16], Error> {
+        match self.command(command, arg, ResponseType::R2)? {
+            Response::Long(value) => Ok(value),
+            _ => Err(Error::BadResponse(command)),
+        }
+    }
+
+    /// Send a command which gets a card status response, and check the
+    /// status for errors.
+    fn card_status(
+        &self,
+        command: u8,
+        arg: u32,
+        response_type: ResponseType,
+    ) -> Result<u32, Error> {
+        check_status(self.short_response(command, arg, response_type)?)
+    }
+
+    /// Convert a block index into the address the card wants.
+    fn block_address(&self, block_idx: BlockIdx) -> u32 {
+        match self.card_type {
+            CardType::SD1 | CardType::SD2 | CardType::MMC => block_idx.0 * Block::LEN_U32,
+            CardType::SDHC | CardType::MMCHC => block_idx.0,
+        }
+    }
+
+    /// Poll the card status until the card is back in the transfer state,
+    /// ready for more data.
+    fn wait_ready(&self) -> Result<(), Error> {
+        let mut timeout = Timeout::new(self.options.write_timeout_ms);
+        loop {
+            let status = self.card_status(CMD13, self.rca_arg(), ResponseType::R1)?;
+            let state = (status >> 9) & 0x0F;
+            if status & CARD_STATUS_READY_FOR_DATA != 0 && state == CARD_STATE_TRAN {
+                return Ok(());
+            }
+            self.delay(&mut timeout, Error::TimeoutWaitNotBusy)?;
+        }
+    }
+
+    /// Read one or more blocks, starting at the given block index.
+    fn read_blocks(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Error> {
+        let address = self.block_address(start_block_idx);
+        if blocks.len() == 1 {
+            check_status(self.host.borrow_mut().read_blocks(CMD17, address, blocks)?)?;
+        } else {
+            check_status(self.host.borrow_mut().read_blocks(CMD18, address, blocks)?)?;
+            self.card_status(CMD12, 0, ResponseType::R1b)?;
+        }
+        Ok(())
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    fn write_blocks(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Error> {
+        let address = self.block_address(start_block_idx);
+        if blocks.len() == 1 {
+            check_status(
+                self.host
+                    .borrow_mut()
+                    .write_blocks(CMD24, address, blocks)?,
+            )?;
+        } else {
+            check_status(
+                self.host
+                    .borrow_mut()
+                    .write_blocks(CMD25, address, blocks)?,
+            )?;
+            self.card_status(CMD12, 0, ResponseType::R1b)?;
+        }
+        self.wait_ready()
+    }
+}
+
+/// Turn a card status with error bits set into an `Error`.
+fn check_status(status: u32) -> Result<u32, Error> {
+    if status & CARD_STATUS_ERROR_MASK != 0 {
+        Err(Error::CardStatus(status))
+    } else {
+        Ok(status)
+    }
+}
+
+impl<H> BlockSdBus<'_, H>
+where
+    H: SdHost,
+{
+    /// Get a temporary borrow on the underlying host.
+    pub fn host(&mut self) -> core::cell::RefMut<'_, H> {
+        self.0.host.borrow_mut()
+    }
+
+    /// Mark the card as unused.
+    fn deinit(&mut self) {
+        self.0.state = State::NoInit;
+    }
+
+    /// Return the usable size of this SD card in bytes.
+    pub fn card_size_bytes(&self) -> u64 {
+        match self.0.csd() {
+            Csd::V1(ref contents) => contents.card_capacity_bytes(),
+            Csd::V2(ref contents) => contents.card_capacity_bytes(),
+            Csd::V3(ref contents) => contents.card_capacity_bytes(),
+            Csd::Mmc(ref contents) => contents.card_capacity_bytes(),
+        }
+    }
+
+    /// The card's identification register, as read (using CMD2) when the
+    /// card was acquired.
+    pub fn card_info(&self) -> Cid {
+        self.0.cid.clone()
+    }
+
+    /// The card's 'card specific data' register, as read (using CMD9) when
+    /// the card was acquired.
+    pub fn card_csd(&self) -> Csd {
+        self.0.csd()
+    }
+
+    /// The Relative Card Address the card published (in response to CMD3).
+    pub fn rca(&self) -> u16 {
+        self.0.rca
+    }
+}
+
+impl<H> BlockDevice for BlockSdBus<'_, H>
+where
+    H: SdHost,
+{
+    type Error = Error;
+
+    /// Read one or more blocks, starting at the given block index.
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        if self.0.state != State::Idle {
+            return Err(Error::BadState);
+        }
+        self.0.read_blocks(blocks, start_block_idx)
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        if self.0.state != State::Idle {
+            return Err(Error::BadState);
+        }
+        self.0.write_blocks(blocks, start_block_idx)
+    }
+
+    /// Determine how many blocks this device can hold.
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let num_blocks =
+            u32::try_from(self.card_size_bytes() / 512).map_err(|_| Error::CardTooLarge)?;
+        Ok(BlockCount(num_blocks))
+    }
+}
+
+impl<H> Drop for BlockSdBus<'_, H>
+where
+    H: SdHost,
+{
+    fn drop(&mut self) {
+        self.deinit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An SDHC card with 64 blocks, and a host which talks to it directly.
+    struct MockHost {
+        blocks: [Block; 64],
+        high_capacity: bool,
+        clock_hz: u32,
+        bus_width: BusWidth,
+        /// The RCA we publish
+        rca: u16,
+        selected: bool,
+        app_command: bool,
+        /// Commands sent, oldest first
+        log: [(u8, u32); 64],
+        log_len: usize,
+        /// How many ACMD41s to report busy for
+        busy_polls: u32,
+        /// The CSD we send
+        csd: [u8; 16],
+    }
+
+    impl MockHost {
+        fn new(high_capacity: bool) -> MockHost {
+            MockHost {
+                blocks: core::array::from_fn(|_| Block::new()),
+                high_capacity,
+                clock_hz: 0,
+                bus_width: BusWidth::One,
+                rca: 0xB368,
+                selected: false,
+                app_command: false,
+                log: [(0, 0); 64],
+                log_len: 0,
+                busy_polls: 3,
+                // A CSD v2 for a card with 1024 blocks, at 25 MHz
+                csd: hex!("40 0E 00 32 5B 59 00 00 00 00 7F 80 0A 40 00 00"),
+            }
+        }
+
+        fn sent(&self) -> &[(u8, u32)] {
+            &self.log[..self.log_len]
+        }
+
+        /// Card status for the transfer state, ready for data
+        fn status(&self) -> u32 {
+            let state = if self.selected { CARD_STATE_TRAN } else { 3 };
+            (state << 9) | CARD_STATUS_READY_FOR_DATA
+        }
+
+        fn block_idx(&self, arg: u32) -> usize {
+            if self.high_capacity {
+                arg as usize
+            } else {
+                assert_eq!(arg % 512, 0, "SDSC address not block aligned");
+                arg as usize / 512
+            }
+        }
+
+        fn record(&mut self, command: u8, arg: u32) {
+            self.log[self.log_len] = (command, arg);
+            self.log_len += 1;
+        }
+    }
+
+    impl SdHost for MockHost {
+        fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+            self.clock_hz = hz;
+            Ok(())
+        }
+
+        fn set_bus_width(&mut self, width: BusWidth) -> Result<(), Error> {
+            self.bus_width = width;
+            Ok(())
+        }
+
+        fn command(
+            &mut self,
+            command: u8,
+            arg: u32,
+            response_type: ResponseType,
+        ) -> Result<Response, Error> {
+            self.record(command, arg);
+            let app_command = core::mem::replace(&mut self.app_command, false);
+            let rca_arg = u32::from(self.rca) << 16;
+            let response = match (app_command, command, response_type) {
+                (false, CMD0, ResponseType::None) => Response::None,
+                (false, CMD8, ResponseType::R7) => Response::Short(arg & 0xFFF),
+                (false, CMD55, ResponseType::R1) => {
+                    self.app_command = true;
+                    Response::Short(self.status() | (1 << 5))
+                }
+                (true, ACMD41, ResponseType::R3) => {
+                    let mut ocr = OCR_VOLTAGE_WINDOW;
+                    if self.busy_polls > 0 {
+                        self.busy_polls -= 1;
+                    } else {
+                        ocr |= OCR_POWER_UP_DONE;
+                        if self.high_capacity && arg & OCR_CCS != 0 {
+                            ocr |= OCR_CCS;
+                        }
+                    }
+                    Response::Short(ocr)
+                }
+                (false, CMD2, ResponseType::R2) => {
+                    Response::Long(hex!("03 53 44 53 55 30 31 47 80 1B 7A 55 C6 00 D3 C9"))
+                }
+                (false, CMD3, ResponseType::R6) => Response::Short(rca_arg | 0x0500),
+                (false, CMD9, ResponseType::R2) if arg == rca_arg => Response::Long(self.csd),
+                (false, CMD7, ResponseType::R1b) if arg == rca_arg => {
+                    self.selected = true;
+                    Response::Short(self.status())
+                }
+                (true, ACMD6, ResponseType::R1) if self.selected && arg == 0b10 => {
+                    Response::Short(self.status())
+                }
+                (false, CMD12, ResponseType::R1b)
+                | (false, CMD16, ResponseType::R1)
+                | (false, CMD13, ResponseType::R1) => Response::Short(self.status()),
+                _ => return Err(Error::TimeoutCommand(command)),
+            };
+            Ok(response)
+        }
+
+        fn read_blocks(
+            &mut self,
+            command: u8,
+            arg: u32,
+            blocks: &mut [Block],
+        ) -> Result<u32, Error> {
+            self.record(command, arg);
+            assert!(self.selected);
+            assert_eq!(command == CMD18, blocks.len() > 1);
+            let start = self.block_idx(arg);
+            for (block, stored) in blocks.iter_mut().zip(&self.blocks[start..]) {
+                block.contents.copy_from_slice(&stored.contents);
+            }
+            Ok(self.status())
+        }
+
+        fn write_blocks(&mut self, command: u8, arg: u32, blocks: &[Block]) -> Result<u32, Error> {
+            self.record(command, arg);
+            assert!(self.selected);
+            assert_eq!(command == CMD25, blocks.len() > 1);
+            let start = self.block_idx(arg);
+            for (block, stored) in blocks.iter().zip(&mut self.blocks[start..]) {
+                stored.contents.copy_from_slice(&block.contents);
+            }
+            Ok(self.status())
+        }
+
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    #[test]
+    fn acquire_sdhc() {
+        let mut bus = SdBus::new(MockHost::new(true));
+        let mut card = bus.acquire().unwrap();
+        assert_eq!(card.rca(), 0xB368);
+        assert_eq!(card.card_info().product_name(), b"SU01G");
+        assert_eq!(card.num_blocks().unwrap(), BlockCount(1024));
+        let host = card.host();
+        assert_eq!(host.bus_width, BusWidth::Four);
+        assert_eq!(host.clock_hz, 25_000_000);
+        let commands: [u8; 13] = core::array::from_fn(|i| host.sent()[i].0);
+        assert_eq!(
+            commands,
+            [
+                CMD0, CMD8, CMD55, ACMD41, CMD55, ACMD41, CMD55, ACMD41, CMD55, ACMD41, CMD2, CMD3,
+                CMD9
+            ]
+        );
+        assert_eq!(
+            &host.sent()[13..],
+            &[(CMD7, 0xB368_0000), (CMD55, 0xB368_0000), (ACMD6, 0b10)]
+        );
+    }
+
+    #[test]
+    fn acquire_one_bit_sdsc() {
+        let mut bus = SdBus::new(MockHost::new(false));
+        let mut card = bus
+            .acquire_with_opts(AcquireOpts {
+                bus_width: BusWidth::One,
+                max_clock_hz: 12_000_000,
+                ..Default::default()
+            })
+            .unwrap();
+        let host = card.host();
+        assert_eq!(host.bus_width, BusWidth::One);
+        assert_eq!(host.clock_hz, 12_000_000);
+        assert_eq!(host.sent().last(), Some(&(CMD16, 512)));
+    }
+
+    #[test]
+    fn sduc_card_too_large() {
+        let mut host = MockHost::new(true);
+        // A CSD v3 for a 128 TB card
+        host.csd = hex!("80 0E 00 32 5B 59 0E 8D 4A 4F 7F 80 0A 40 00 89");
+        let mut bus = SdBus::new(host);
+        let card = bus.acquire().unwrap();
+        assert!(matches!(card.card_csd(), Csd::V3(_)));
+        assert_eq!(card.card_size_bytes(), 127_999_999_475_712);
+        assert!(matches!(card.num_blocks(), Err(Error::CardTooLarge)));
+    }
+
+    #[test]
+    fn read_write() {
+        for high_capacity in [true, false] {
+            let mut bus = SdBus::new(MockHost::new(high_capacity));
+            let card = bus.acquire().unwrap();
+            let mut blocks = [Block::new(), Block::new(), Block::new()];
+            for (i, block) in blocks.iter_mut().enumerate() {
+                block.contents.fill(i as u8 + 1);
+            }
+            card.write(&blocks[0..1], BlockIdx(4)).unwrap();
+            card.write(&blocks[1..], BlockIdx(5)).unwrap();
+            let mut read_back = [Block::new(), Block::new(), Block::new(), Block::new()];
+            card.read(&mut read_back, BlockIdx(3), "test").unwrap();
+            assert_eq!(read_back[0].contents, [0u8; 512]);
+            for (read, written) in read_back[1..].iter().zip(&blocks) {
+                assert_eq!(read.contents, written.contents);
+            }
+            card.read(&mut read_back[0..1], BlockIdx(6), "test")
+                .unwrap();
+            assert_eq!(read_back[0].contents, blocks[2].contents);
+        }
+    }
+
+    #[test]
+    fn multi_block_transfers_are_stopped() {
+        let mut bus = SdBus::new(MockHost::new(true));
+        let mut card = bus.acquire().unwrap();
+        let mut blocks = [Block::new(), Block::new()];
+        card.write(&blocks, BlockIdx(10)).unwrap();
+        card.read(&mut blocks, BlockIdx(10), "test").unwrap();
+        let host = card.host();
+        let sent = host.sent();
+        assert_eq!(
+            &sent[sent.len() - 5..],
+            &[
+                (CMD25, 10),
+                (CMD12, 0),
+                (CMD13, 0xB368_0000),
+                (CMD18, 10),
+                (CMD12, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn card_status_errors() {
+        assert_eq!(check_status(0x900), Ok(0x900));
+        // OUT_OF_RANGE
+        assert_eq!(
+            check_status(0x8000_0900),
+            Err(Error::CardStatus(0x8000_0900))
+        );
+        // CARD_IS_LOCKED is status, not an error
+        assert_eq!(check_status(0x0200_0900), Ok(0x0200_0900));
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -6,570 +6,2675 @@
 //! performance.
 
 use super::sdmmc_proto::*;
+#[cfg(feature = "async")]
+use super::AsyncBlockDevice;
 use super::{Block, BlockCount, BlockDevice, BlockIdx};
+#[cfg(feature = "async")]
+use crate::asynchronous::card::Card as AsyncCard;
+use crate::blocking::card::Card;
 use core::cell::RefCell;
 
-#[cfg(feature = "log")]
-use log::{debug, trace, warn};
+/// How long to wait, in microseconds, between each of the first few polls
+/// of a busy card
+pub(crate) const POLL_INTERVAL_US: u8 = 10;
 
-#[cfg(feature = "defmt-log")]
-use defmt::{debug, trace, warn};
+/// How many times to poll a busy card in quick succession, before backing off
+/// to `SLOW_POLL_INTERVAL_US`
+pub(crate) const FAST_POLLS: u8 = 10;
+
+/// How long to wait, in microseconds, between polls once a card has been busy
+/// for more than `FAST_POLLS` polls
+pub(crate) const SLOW_POLL_INTERVAL_US: u32 = 1000;
+
+/// CMD6 argument asking whether the card can switch to high-speed mode
+pub(crate) const SWITCH_CHECK_HIGH_SPEED: u32 = 0x00FF_FFF1;
+
+/// CMD6 argument switching the card to high-speed mode
+pub(crate) const SWITCH_SET_HIGH_SPEED: u32 = 0x80FF_FFF1;
 
-const DEFAULT_DELAY_COUNT: u32 = 32_000;
+/// How fast the SPI clock can go once the card is in high-speed mode
+pub(crate) const HIGH_SPEED_HZ: u32 = 50_000_000;
+
+/// Multi-block writes of at least this many blocks tell the card how many
+/// blocks are coming (with ACMD23), so it can pre-erase them. For shorter
+/// writes the extra command costs more than it saves.
+pub(crate) const PRE_ERASE_MIN_BLOCKS: usize = 8;
+
+/// How long a forced erase of a locked card can take - the spec allows up to
+/// three minutes
+pub(crate) const FORCE_ERASE_TIMEOUT_MS: u32 = 180_000;
+
+/// How fast the SPI clock can go while the card is being initialised
+pub(crate) const INIT_CLOCK_HZ: u32 = 400_000;
 
 /// Represents an inactive SD Card interface.
-/// Built from an SPI peripheral and a Chip
-/// Select pin. We need Chip Select to be separate so we can clock out some
-/// bytes without Chip Select asserted (which puts the card into SPI mode).
-pub struct SdMmcSpi<SPI, CS>
+/// Built from an `SpiTransport`, which is how we talk to the card - usually
+/// either an `SpiCsTransport` (for embedded-hal 0.2) or an
+/// `SpiDeviceTransport` (for embedded-hal 1.0).
+pub struct SdMmcSpi<T>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+    T: SpiTransport,
 {
-    spi: RefCell<SPI>,
-    cs: RefCell<CS>,
-    card_type: CardType,
-    state: State,
+    card: RefCell<Card<T>>,
 }
 
 /// An initialized block device used to access the SD card.
 /// **Caution**: any data must be flushed manually before dropping `BlockSpi`, see `deinit`.
 /// Uses SPI mode.
-pub struct BlockSpi<'a, SPI, CS>(&'a mut SdMmcSpi<SPI, CS>)
+pub struct BlockSpi<'a, T>(&'a mut SdMmcSpi<T>)
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug;
+    T: SpiTransport;
 
-/// The possible errors `SdMmcSpi` can generate.
-#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
-#[derive(Debug, Copy, Clone)]
-pub enum Error {
-    /// We got an error from the SPI peripheral
-    Transport,
-    /// We failed to enable CRC checking on the SD card
-    CantEnableCRC,
-    /// We didn't get a response when reading data from the card
-    TimeoutReadBuffer,
-    /// We didn't get a response when waiting for the card to not be busy
-    TimeoutWaitNotBusy,
-    /// We didn't get a response when executing this command
-    TimeoutCommand(u8),
-    /// We didn't get a response when executing this application-specific command
-    TimeoutACommand(u8),
-    /// We got a bad response from Command 58
-    Cmd58Error,
-    /// We failed to read the Card Specific Data register
-    RegisterReadError,
-    /// We got a CRC mismatch (card gave us, we calculated)
-    CrcError(u16, u16),
-    /// Error reading from the card
-    ReadError,
-    /// Error writing to the card
-    WriteError,
-    /// Can't perform this operation with the card in this state
-    BadState,
-    /// Couldn't find the card
-    CardNotFound,
-    /// Couldn't set a GPIO pin
-    GpioError,
+/// Represents an inactive SD Card interface, which is driven from async code.
+/// Built from an `AsyncSpiTransport` - usually an `AsyncSpiDeviceTransport`.
+#[cfg(feature = "async")]
+pub struct AsyncSdMmcSpi<T>
+where
+    T: AsyncSpiTransport,
+{
+    card: AsyncCard<T>,
 }
 
-/// The possible states `SdMmcSpi` can be in.
-#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum State {
-    /// Card is not initialised
-    NoInit,
-    /// Card is in an error state
-    Error,
-    /// Card is initialised and idle
-    Idle,
+/// An initialized async block device used to access the SD card. Uses SPI
+/// mode.
+#[cfg(feature = "async")]
+pub struct AsyncBlockSpi<'a, T>(&'a mut AsyncSdMmcSpi<T>)
+where
+    T: AsyncSpiTransport;
+
+/// Something `SdMmcSpi` can use to talk to an SD card over SPI.
+pub trait SpiTransport {
+    /// Assert the card's chip select.
+    fn select(&mut self) -> Result<(), Error>;
+    /// De-assert the card's chip select.
+    fn deselect(&mut self) -> Result<(), Error>;
+    /// Clock out at least 74 cycles with chip select de-asserted, which puts
+    /// the card into SPI mode.
+    fn send_initial_clocks(&mut self) -> Result<(), Error>;
+    /// Send the bytes in `data`, replacing each one with the byte received.
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error>;
+    /// Send the bytes in `data`, ignoring whatever is received.
+    ///
+    /// The default implementation uses `transfer` on a small buffer, so
+    /// implement this if your SPI peripheral can do write-only transfers.
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut scratch = [0u8; 64];
+        for chunk in data.chunks(scratch.len()) {
+            let scratch = &mut scratch[..chunk.len()];
+            scratch.copy_from_slice(chunk);
+            self.transfer(scratch)?;
+        }
+        Ok(())
+    }
+    /// Wait for the given number of microseconds.
+    fn delay_us(&mut self, us: u8);
+    /// Is there a card in the socket? The default says yes, for sockets
+    /// without a card-detect switch.
+    fn card_present(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+    /// Is the card's write-protect tab set? The default says no, for sockets
+    /// without a write-protect switch.
+    fn write_protected(&mut self) -> Result<bool, Error> {
+        Ok(false)
+    }
+    /// Switch the card's power on or off. Returns whether the power was
+    /// switched - the default can't, for sockets which always power the card.
+    fn set_power(&mut self, _on: bool) -> Result<bool, Error> {
+        Ok(false)
+    }
+    /// Set the SPI clock. It is set to 400 kHz before the card is
+    /// initialised, and then to the fastest clock the card can take (from its
+    /// TRAN_SPEED, or 50 MHz in high-speed mode). The default does nothing,
+    /// for transports whose clock is set up elsewhere.
+    fn set_clock_hz(&mut self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
-/// The different types of card we support.
-#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum CardType {
-    SD1,
-    SD2,
-    SDHC,
+/// Something `AsyncSdMmcSpi` can use to talk to an SD card over SPI. The same
+/// as `SpiTransport`, except that transfers and delays let other tasks run
+/// while they wait.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncSpiTransport {
+    /// Assert the card's chip select.
+    async fn select(&mut self) -> Result<(), Error>;
+    /// De-assert the card's chip select.
+    async fn deselect(&mut self) -> Result<(), Error>;
+    /// Clock out at least 74 cycles with chip select de-asserted, which puts
+    /// the card into SPI mode.
+    async fn send_initial_clocks(&mut self) -> Result<(), Error>;
+    /// Send the bytes in `data`, replacing each one with the byte received.
+    async fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error>;
+    /// Send the bytes in `data`, ignoring whatever is received.
+    ///
+    /// The default implementation uses `transfer` on a small buffer, so
+    /// implement this if your SPI peripheral can do write-only transfers.
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut scratch = [0u8; 64];
+        for chunk in data.chunks(scratch.len()) {
+            let scratch = &mut scratch[..chunk.len()];
+            scratch.copy_from_slice(chunk);
+            self.transfer(scratch).await?;
+        }
+        Ok(())
+    }
+    /// Wait for the given number of microseconds.
+    async fn delay_us(&mut self, us: u8);
+    /// Is there a card in the socket? The default says yes, for sockets
+    /// without a card-detect switch.
+    fn card_present(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+    /// Is the card's write-protect tab set? The default says no, for sockets
+    /// without a write-protect switch.
+    fn write_protected(&mut self) -> Result<bool, Error> {
+        Ok(false)
+    }
+    /// Switch the card's power on or off. Returns whether the power was
+    /// switched - the default can't, for sockets which always power the card.
+    fn set_power(&mut self, _on: bool) -> Result<bool, Error> {
+        Ok(false)
+    }
+    /// Set the SPI clock. It is set to 400 kHz before the card is
+    /// initialised, and then to the fastest clock the card can take (from its
+    /// TRAN_SPEED, or 50 MHz in high-speed mode). The default does nothing,
+    /// for transports whose clock is set up elsewhere.
+    fn set_clock_hz(&mut self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
-/// A terrible hack for busy-waiting the CPU while we wait for the card to
-/// sort itself out.
-///
-/// @TODO replace this!
-struct Delay(u32);
+/// Sets the SPI clock for one of the built-in transports (see
+/// `SpiTransport::set_clock_hz`). Any `FnMut(u32) -> Result<(), Error>` will
+/// do.
+pub trait SpiClock {
+    /// Set the SPI clock to the given frequency, or as close as you can get
+    /// below it.
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error>;
+}
 
-impl Delay {
-    fn new() -> Delay {
-        Delay(DEFAULT_DELAY_COUNT)
+/// An SPI clock which is set up elsewhere, and left alone.
+pub struct NoClock;
+
+impl SpiClock for NoClock {
+    fn set_clock_hz(&mut self, _hz: u32) -> Result<(), Error> {
+        Ok(())
     }
+}
 
-    fn delay(&mut self, err: Error) -> Result<(), Error> {
-        if self.0 == 0 {
-            Err(err)
-        } else {
-            let dummy_var: u32 = 0;
-            for _ in 0..100 {
-                unsafe { core::ptr::read_volatile(&dummy_var) };
-            }
-            self.0 -= 1;
-            Ok(())
-        }
+impl<F> SpiClock for F
+where
+    F: FnMut(u32) -> Result<(), Error>,
+{
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        self(hz)
     }
 }
 
-/// Options for acquiring the card.
-#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
-#[derive(Debug)]
-pub struct AcquireOpts {
-    /// Some cards don't support CRC mode. At least a 512MiB Transcend one.
-    pub require_crc: bool,
+/// An `SpiTransport` built from an embedded-hal 0.2 SPI peripheral, a Chip
+/// Select pin and a delay. We need Chip Select to be separate so we can clock
+/// out some bytes without Chip Select asserted (which puts the card into SPI
+/// mode). Add something to set the SPI clock with `with_clock`.
+pub struct SpiCsTransport<SPI, CS, DELAY, CLK = NoClock>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
+    CLK: SpiClock,
+    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+{
+    spi: SPI,
+    cs: CS,
+    delay: DELAY,
+    clock: CLK,
 }
 
-impl Default for AcquireOpts {
-    fn default() -> Self {
-        AcquireOpts { require_crc: true }
+impl<SPI, CS, DELAY> SpiCsTransport<SPI, CS, DELAY>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
+    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+{
+    /// Create a new transport from an SPI peripheral, a Chip Select pin and a
+    /// delay.
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> SpiCsTransport<SPI, CS, DELAY> {
+        SpiCsTransport {
+            spi,
+            cs,
+            delay,
+            clock: NoClock,
+        }
     }
 }
 
-impl<SPI, CS> SdMmcSpi<SPI, CS>
+impl<SPI, CS, DELAY, CLK> SpiCsTransport<SPI, CS, DELAY, CLK>
 where
     SPI: embedded_hal::blocking::spi::Transfer<u8>,
     CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
+    CLK: SpiClock,
     <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
 {
-    /// Create a new SD/MMC controller using a raw SPI interface.
-    pub fn new(spi: SPI, cs: CS) -> SdMmcSpi<SPI, CS> {
-        SdMmcSpi {
-            spi: RefCell::new(spi),
-            cs: RefCell::new(cs),
-            card_type: CardType::SD1,
-            state: State::NoInit,
+    /// Add something to set the SPI clock, such as a closure which re-clocks
+    /// the SPI peripheral.
+    pub fn with_clock<C>(self, clock: C) -> SpiCsTransport<SPI, CS, DELAY, C>
+    where
+        C: SpiClock,
+    {
+        SpiCsTransport {
+            spi: self.spi,
+            cs: self.cs,
+            delay: self.delay,
+            clock,
         }
     }
 
-    fn cs_high(&self) -> Result<(), Error> {
-        self.cs
-            .borrow_mut()
-            .set_high()
-            .map_err(|_| Error::GpioError)
+    /// Get the SPI peripheral, Chip Select pin and delay back.
+    pub fn free(self) -> (SPI, CS, DELAY) {
+        (self.spi, self.cs, self.delay)
     }
+}
 
-    fn cs_low(&self) -> Result<(), Error> {
-        self.cs.borrow_mut().set_low().map_err(|_| Error::GpioError)
+impl<SPI, CS, DELAY, CLK> SpiTransport for SpiCsTransport<SPI, CS, DELAY, CLK>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
+    CLK: SpiClock,
+    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+{
+    fn select(&mut self) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| Error::GpioError)
     }
 
-    /// Initializes the card into a known state
-    pub fn acquire(&mut self) -> Result<BlockSpi<SPI, CS>, Error> {
-        self.acquire_with_opts(Default::default())
+    fn deselect(&mut self) -> Result<(), Error> {
+        self.cs.set_high().map_err(|_| Error::GpioError)
     }
 
-    /// Initializes the card into a known state
-    pub fn acquire_with_opts(&mut self, options: AcquireOpts) -> Result<BlockSpi<SPI, CS>, Error> {
-        debug!("acquiring card with opts: {:?}", options);
-        let f = |s: &mut Self| {
-            // Assume it hasn't worked
-            s.state = State::Error;
-            trace!("Reset card..");
-            // Supply minimum of 74 clock cycles without CS asserted.
-            s.cs_high()?;
-            for _ in 0..10 {
-                s.send(0xFF)?;
-            }
-            // Assert CS
-            s.cs_low()?;
-            // Enter SPI mode
-            let mut delay = Delay::new();
-            let mut attempts = 32;
-            while attempts > 0 {
-                trace!("Enter SPI mode, attempt: {}..", 32i32 - attempts);
-
-                match s.card_command(CMD0, 0) {
-                    Err(Error::TimeoutCommand(0)) => {
-                        // Try again?
-                        warn!("Timed out, trying again..");
-                        attempts -= 1;
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                    Ok(R1_IDLE_STATE) => {
-                        break;
-                    }
-                    Ok(r) => {
-                        // Try again
-                        warn!("Got response: {:x}, trying again..", r);
-                    }
-                }
-
-                delay.delay(Error::TimeoutCommand(CMD0))?;
-            }
-            if attempts == 0 {
-                return Err(Error::CardNotFound);
-            }
-            // Enable CRC
-            debug!("Enable CRC: {}", options.require_crc);
-            if s.card_command(CMD59, 1)? != R1_IDLE_STATE && options.require_crc {
-                return Err(Error::CantEnableCRC);
-            }
-            // Check card version
-            let mut delay = Delay::new();
-            loop {
-                if s.card_command(CMD8, 0x1AA)? == (R1_ILLEGAL_COMMAND | R1_IDLE_STATE) {
-                    s.card_type = CardType::SD1;
-                    break;
-                }
-                s.receive()?;
-                s.receive()?;
-                s.receive()?;
-                let status = s.receive()?;
-                if status == 0xAA {
-                    s.card_type = CardType::SD2;
-                    break;
-                }
-                delay.delay(Error::TimeoutCommand(CMD8))?;
-            }
-            debug!("Card version: {:?}", s.card_type);
+    fn send_initial_clocks(&mut self) -> Result<(), Error> {
+        SpiTransport::deselect(self)?;
+        SpiTransport::transfer(self, &mut [0xFF; 10])
+    }
 
-            let arg = match s.card_type {
-                CardType::SD1 => 0,
-                CardType::SD2 | CardType::SDHC => 0x4000_0000,
-            };
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        self.spi
+            .transfer(data)
+            .map(|_| ())
+            .map_err(|_e| Error::Transport)
+    }
 
-            let mut delay = Delay::new();
-            while s.card_acmd(ACMD41, arg)? != R1_READY_STATE {
-                delay.delay(Error::TimeoutACommand(ACMD41))?;
-            }
+    fn delay_us(&mut self, us: u8) {
+        self.delay.delay_us(us)
+    }
 
-            if s.card_type == CardType::SD2 {
-                if s.card_command(CMD58, 0)? != 0 {
-                    return Err(Error::Cmd58Error);
-                }
-                if (s.receive()? & 0xC0) == 0xC0 {
-                    s.card_type = CardType::SDHC;
-                }
-                // Discard other three bytes
-                s.receive()?;
-                s.receive()?;
-                s.receive()?;
-            }
-            s.state = State::Idle;
-            Ok(())
-        };
-        let result = f(self);
-        self.cs_high()?;
-        let _ = self.receive();
-        result.map(move |()| BlockSpi(self))
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        self.clock.set_clock_hz(hz)
     }
+}
 
-    /// Perform a function that might error with the chipselect low.
-    /// Always releases the chipselect, even if the function errors.
-    fn with_chip_select_mut<F, T>(&self, func: F) -> Result<T, Error>
-    where
-        F: FnOnce(&Self) -> Result<T, Error>,
-    {
-        self.cs_low()?;
-        let result = func(self);
-        self.cs_high()?;
-        result
+/// An `SpiTransport` built from an embedded-hal 1.0 `SpiDevice`, which looks
+/// after Chip Select itself, and a delay. This lets you share the SPI bus
+/// with other devices.
+///
+/// An `SpiDevice` can't clock the bus with Chip Select de-asserted, so the
+/// card may not enter SPI mode properly. If you have access to the
+/// underlying `SpiBus`, call `send_initial_clocks` on it before acquiring the
+/// card.
+///
+/// As with `SpiCsTransport`, add something to set the SPI clock with
+/// `with_clock`.
+#[cfg(feature = "embedded-hal-1")]
+pub struct SpiDeviceTransport<SPI, DELAY, CLK = NoClock>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+    CLK: SpiClock,
+{
+    spi: SPI,
+    delay: DELAY,
+    clock: CLK,
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<SPI, DELAY> SpiDeviceTransport<SPI, DELAY>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+{
+    /// Create a new transport from an SPI device and a delay.
+    pub fn new(spi: SPI, delay: DELAY) -> SpiDeviceTransport<SPI, DELAY> {
+        SpiDeviceTransport {
+            spi,
+            delay,
+            clock: NoClock,
+        }
     }
+}
 
-    /// Perform a function that might error with the chipselect low.
-    /// Always releases the chipselect, even if the function errors.
-    fn with_chip_select<F, T>(&self, func: F) -> Result<T, Error>
+#[cfg(feature = "embedded-hal-1")]
+impl<SPI, DELAY, CLK> SpiDeviceTransport<SPI, DELAY, CLK>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+    CLK: SpiClock,
+{
+    /// Add something to set the SPI clock, such as a closure which re-clocks
+    /// the SPI bus.
+    pub fn with_clock<C>(self, clock: C) -> SpiDeviceTransport<SPI, DELAY, C>
     where
-        F: FnOnce(&Self) -> Result<T, Error>,
+        C: SpiClock,
     {
-        self.cs_low()?;
-        let result = func(self);
-        self.cs_high()?;
-        result
+        SpiDeviceTransport {
+            spi: self.spi,
+            delay: self.delay,
+            clock,
+        }
     }
 
-    /// Perform an application-specific command.
-    fn card_acmd(&self, command: u8, arg: u32) -> Result<u8, Error> {
-        self.card_command(CMD55, 0)?;
-        self.card_command(command, arg)
+    /// Get the SPI device and delay back.
+    pub fn free(self) -> (SPI, DELAY) {
+        (self.spi, self.delay)
     }
+}
 
-    /// Perform a command.
-    fn card_command(&self, command: u8, arg: u32) -> Result<u8, Error> {
-        self.wait_not_busy()?;
-        let mut buf = [
-            0x40 | command,
-            (arg >> 24) as u8,
-            (arg >> 16) as u8,
-            (arg >> 8) as u8,
-            arg as u8,
-            0,
-        ];
-        buf[5] = crc7(&buf[0..5]);
+#[cfg(feature = "embedded-hal-1")]
+impl<SPI, DELAY, CLK> SpiTransport for SpiDeviceTransport<SPI, DELAY, CLK>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+    CLK: SpiClock,
+{
+    fn select(&mut self) -> Result<(), Error> {
+        // Each transfer is its own transaction, so the device does this
+        Ok(())
+    }
 
-        for b in buf.iter() {
-            self.send(*b)?;
-        }
+    fn deselect(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 
-        // skip stuff byte for stop read
-        if command == CMD12 {
-            let _result = self.receive()?;
-        }
+    fn send_initial_clocks(&mut self) -> Result<(), Error> {
+        // The best we can do is to clock with Chip Select asserted, which
+        // most cards put up with.
+        SpiTransport::transfer(self, &mut [0xFF; 10])
+    }
 
-        for _ in 0..512 {
-            let result = self.receive()?;
-            if (result & 0x80) == ERROR_OK {
-                return Ok(result);
-            }
-        }
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        self.spi
+            .transfer_in_place(data)
+            .map_err(|_e| Error::Transport)
+    }
 
-        Err(Error::TimeoutCommand(command))
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.spi.write(data).map_err(|_e| Error::Transport)
     }
 
-    /// Receive a byte from the SD card by clocking in an 0xFF byte.
-    fn receive(&self) -> Result<u8, Error> {
-        self.transfer(0xFF)
+    fn delay_us(&mut self, us: u8) {
+        self.delay.delay_us(u32::from(us))
     }
 
-    /// Send a byte from the SD card.
-    fn send(&self, out: u8) -> Result<(), Error> {
-        let _ = self.transfer(out)?;
-        Ok(())
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        self.clock.set_clock_hz(hz)
     }
+}
 
-    /// Send one byte and receive one byte.
-    fn transfer(&self, out: u8) -> Result<u8, Error> {
-        let mut spi = self.spi.borrow_mut();
-        spi.transfer(&mut [out])
-            .map(|b| b[0])
-            .map_err(|_e| Error::Transport)
+/// Clock out the 74 cycles a card needs, with Chip Select de-asserted, to
+/// put it into SPI mode. Call this on the `SpiBus` underneath an
+/// `SpiDeviceTransport` before acquiring the card.
+#[cfg(feature = "embedded-hal-1")]
+pub fn send_initial_clocks<BUS>(bus: &mut BUS) -> Result<(), Error>
+where
+    BUS: embedded_hal_1::spi::SpiBus<u8>,
+{
+    bus.write(&[0xFF; 10]).map_err(|_e| Error::Transport)?;
+    bus.flush().map_err(|_e| Error::Transport)
+}
+
+/// An `AsyncSpiTransport` built from an embedded-hal-async `SpiDevice`, which
+/// looks after Chip Select itself, and an async delay.
+///
+/// As with `SpiDeviceTransport`, the card may not enter SPI mode properly
+/// unless you call `send_initial_clocks_async` on the underlying `SpiBus`
+/// before acquiring the card, and you can add something to set the SPI clock
+/// with `with_clock`.
+#[cfg(feature = "async")]
+pub struct AsyncSpiDeviceTransport<SPI, DELAY, CLK = NoClock>
+where
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    CLK: SpiClock,
+{
+    spi: SPI,
+    delay: DELAY,
+    clock: CLK,
+}
+
+#[cfg(feature = "async")]
+impl<SPI, DELAY> AsyncSpiDeviceTransport<SPI, DELAY>
+where
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    /// Create a new transport from an async SPI device and an async delay.
+    pub fn new(spi: SPI, delay: DELAY) -> AsyncSpiDeviceTransport<SPI, DELAY> {
+        AsyncSpiDeviceTransport {
+            spi,
+            delay,
+            clock: NoClock,
+        }
     }
+}
 
-    /// Spin until the card returns 0xFF, or we spin too many times and
-    /// timeout.
-    fn wait_not_busy(&self) -> Result<(), Error> {
-        let mut delay = Delay::new();
-        loop {
-            let s = self.receive()?;
-            if s == 0xFF {
-                break;
-            }
-            delay.delay(Error::TimeoutWaitNotBusy)?;
+#[cfg(feature = "async")]
+impl<SPI, DELAY, CLK> AsyncSpiDeviceTransport<SPI, DELAY, CLK>
+where
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    CLK: SpiClock,
+{
+    /// Add something to set the SPI clock, such as a closure which re-clocks
+    /// the SPI bus.
+    pub fn with_clock<C>(self, clock: C) -> AsyncSpiDeviceTransport<SPI, DELAY, C>
+    where
+        C: SpiClock,
+    {
+        AsyncSpiDeviceTransport {
+            spi: self.spi,
+            delay: self.delay,
+            clock,
         }
-        Ok(())
+    }
+
+    /// Get the SPI device and delay back.
+    pub fn free(self) -> (SPI, DELAY) {
+        (self.spi, self.delay)
     }
 }
 
-impl<SPI, CS> BlockSpi<'_, SPI, CS>
+#[cfg(feature = "async")]
+impl<SPI, DELAY, CLK> AsyncSpiTransport for AsyncSpiDeviceTransport<SPI, DELAY, CLK>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    CLK: SpiClock,
 {
-    /// Get a temporary borrow on the underlying SPI device. Useful if you
-    /// need to re-clock the SPI.
-    pub fn spi(&mut self) -> core::cell::RefMut<SPI> {
-        self.0.spi.borrow_mut()
+    async fn select(&mut self) -> Result<(), Error> {
+        // Each transfer is its own transaction, so the device does this
+        Ok(())
     }
 
-    /// Mark the card as unused.
-    /// This should be kept infallible, because Drop is unable to fail.
-    /// See https://github.com/rust-lang/rfcs/issues/814
-    // If there is any need to flush data, it should be implemented here.
-    fn deinit(&mut self) {
-        self.0.state = State::NoInit;
+    async fn deselect(&mut self) -> Result<(), Error> {
+        Ok(())
     }
 
-    /// Return the usable size of this SD card in bytes.
-    pub fn card_size_bytes(&self) -> Result<u64, Error> {
-        self.0.with_chip_select(|_s| {
-            let csd = self.read_csd()?;
-            match csd {
-                Csd::V1(ref contents) => Ok(contents.card_capacity_bytes()),
-                Csd::V2(ref contents) => Ok(contents.card_capacity_bytes()),
-            }
-        })
+    async fn send_initial_clocks(&mut self) -> Result<(), Error> {
+        // The best we can do is to clock with Chip Select asserted, which
+        // most cards put up with.
+        self.transfer(&mut [0xFF; 10]).await
     }
 
-    /// Erase some blocks on the card.
-    pub fn erase(&mut self, _first_block: BlockIdx, _last_block: BlockIdx) -> Result<(), Error> {
-        unimplemented!();
+    async fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        self.spi
+            .transfer_in_place(data)
+            .await
+            .map_err(|_e| Error::Transport)
     }
 
-    /// Can this card erase single blocks?
-    pub fn erase_single_block_enabled(&self) -> Result<bool, Error> {
-        self.0.with_chip_select(|_s| {
-            let csd = self.read_csd()?;
-            match csd {
-                Csd::V1(ref contents) => Ok(contents.erase_single_block_enabled()),
-                Csd::V2(ref contents) => Ok(contents.erase_single_block_enabled()),
-            }
-        })
-    }
-
-    /// Read the 'card specific data' block.
-    fn read_csd(&self) -> Result<Csd, Error> {
-        match self.0.card_type {
-            CardType::SD1 => {
-                let mut csd = CsdV1::new();
-                if self.0.card_command(CMD9, 0)? != 0 {
-                    return Err(Error::RegisterReadError);
-                }
-                self.read_data(&mut csd.data)?;
-                Ok(Csd::V1(csd))
-            }
-            CardType::SD2 | CardType::SDHC => {
-                let mut csd = CsdV2::new();
-                if self.0.card_command(CMD9, 0)? != 0 {
-                    return Err(Error::RegisterReadError);
-                }
-                self.read_data(&mut csd.data)?;
-                Ok(Csd::V2(csd))
-            }
-        }
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.spi.write(data).await.map_err(|_e| Error::Transport)
     }
 
-    /// Read an arbitrary number of bytes from the card. Always fills the
-    /// given buffer, so make sure it's the right size.
-    fn read_data(&self, buffer: &mut [u8]) -> Result<(), Error> {
-        // Get first non-FF byte.
-        let mut delay = Delay::new();
-        let status = loop {
-            let s = self.0.receive()?;
-            if s != 0xFF {
-                break s;
-            }
-            delay.delay(Error::TimeoutReadBuffer)?;
-        };
-        if status != DATA_START_BLOCK {
-            return Err(Error::ReadError);
-        }
+    async fn delay_us(&mut self, us: u8) {
+        self.delay.delay_us(u32::from(us)).await
+    }
 
-        for b in buffer.iter_mut() {
-            *b = self.0.receive()?;
-        }
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        self.clock.set_clock_hz(hz)
+    }
+}
 
-        let mut crc = u16::from(self.0.receive()?);
-        crc <<= 8;
-        crc |= u16::from(self.0.receive()?);
+/// Clock out the 74 cycles a card needs, with Chip Select de-asserted, to
+/// put it into SPI mode. Call this on the async `SpiBus` underneath an
+/// `AsyncSpiDeviceTransport` before acquiring the card.
+#[cfg(feature = "async")]
+pub async fn send_initial_clocks_async<BUS>(bus: &mut BUS) -> Result<(), Error>
+where
+    BUS: embedded_hal_async::spi::SpiBus<u8>,
+{
+    bus.write(&[0xFF; 10])
+        .await
+        .map_err(|_e| Error::Transport)?;
+    bus.flush().await.map_err(|_e| Error::Transport)
+}
 
-        let calc_crc = crc16(buffer);
-        if crc != calc_crc {
-            return Err(Error::CrcError(crc, calc_crc));
-        }
+/// Wraps the transport for a card socket which has a card-detect and/or a
+/// write-protect switch, so the driver can tell when the card is pulled out
+/// and refuse to write to a locked card, and/or a switch for the card's
+/// power. Add the switches with `with_card_detect`, `with_write_protect` and
+/// `with_power`.
+pub struct CardSocket<T, CD, WP, PW>
+where
+    CD: SocketSwitch,
+    WP: SocketSwitch,
+    PW: SocketPower,
+{
+    transport: T,
+    card_detect: CD,
+    write_protect: WP,
+    power: PW,
+}
 
-        Ok(())
-    }
+/// A card socket switch.
+pub trait SocketSwitch {
+    /// Is the switch on? `None` if there isn't a switch.
+    fn is_on(&self) -> Result<Option<bool>, Error>;
+}
 
-    /// Write an arbitrary number of bytes to the card.
-    fn write_data(&self, token: u8, buffer: &[u8]) -> Result<(), Error> {
-        let calc_crc = crc16(buffer);
-        self.0.send(token)?;
-        for &b in buffer.iter() {
-            self.0.send(b)?;
-        }
-        self.0.send((calc_crc >> 8) as u8)?;
-        self.0.send(calc_crc as u8)?;
-        let status = self.0.receive()?;
-        if (status & DATA_RES_MASK) != DATA_RES_ACCEPTED {
-            Err(Error::WriteError)
-        } else {
-            Ok(())
-        }
-    }
+/// A switch for the card's power supply.
+pub trait SocketPower {
+    /// Switch the power on or off. Returns `false` if there isn't a switch.
+    fn set_power(&mut self, on: bool) -> Result<bool, Error>;
 }
 
-impl<SPI, CS> BlockDevice for BlockSpi<'_, SPI, CS>
+/// A card socket switch wired to an embedded-hal 0.2 input pin.
+pub struct SwitchPin<P>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-    CS: embedded_hal::digital::v2::OutputPin,
+    P: embedded_hal::digital::v2::InputPin,
 {
-    type Error = Error;
+    pin: P,
+    active_low: bool,
+}
 
-    /// Read one or more blocks, starting at the given block index.
-    fn read(
-        &self,
-        blocks: &mut [Block],
-        start_block_idx: BlockIdx,
-        _reason: &str,
-    ) -> Result<(), Self::Error> {
-        let start_idx = match self.0.card_type {
-            CardType::SD1 | CardType::SD2 => start_block_idx.0 * 512,
-            CardType::SDHC => start_block_idx.0,
-        };
-        self.0.with_chip_select(|s| {
-            if blocks.len() == 1 {
-                // Start a single-block read
-                s.card_command(CMD17, start_idx)?;
-                self.read_data(&mut blocks[0].contents)?;
-            } else {
-                // Start a multi-block read
-                s.card_command(CMD18, start_idx)?;
-                for block in blocks.iter_mut() {
-                    self.read_data(&mut block.contents)?;
-                }
-                // Stop the read
-                s.card_command(CMD12, 0)?;
-            }
-            Ok(())
-        })
+/// A card power switch (such as a load switch or a P-channel MOSFET) driven
+/// by an embedded-hal 0.2 output pin.
+pub struct PowerPin<P>
+where
+    P: embedded_hal::digital::v2::OutputPin,
+{
+    pin: P,
+    active_low: bool,
+}
+
+/// A card socket switch which isn't fitted.
+pub struct NoSwitch;
+
+impl<P> SwitchPin<P>
+where
+    P: embedded_hal::digital::v2::InputPin,
+{
+    /// A switch which pulls the pin high when it is on.
+    pub fn active_high(pin: P) -> SwitchPin<P> {
+        SwitchPin {
+            pin,
+            active_low: false,
+        }
     }
 
-    /// Write one or more blocks, starting at the given block index.
-    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
-        let start_idx = match self.0.card_type {
-            CardType::SD1 | CardType::SD2 => start_block_idx.0 * 512,
-            CardType::SDHC => start_block_idx.0,
-        };
-        self.0.with_chip_select_mut(|s| {
-            if blocks.len() == 1 {
-                // Start a single-block write
-                s.card_command(CMD24, start_idx)?;
-                self.write_data(DATA_START_BLOCK, &blocks[0].contents)?;
-                s.wait_not_busy()?;
-                if s.card_command(CMD13, 0)? != 0x00 {
-                    return Err(Error::WriteError);
-                }
-                if s.receive()? != 0x00 {
-                    return Err(Error::WriteError);
-                }
-            } else {
-                // Start a multi-block write
-                s.card_command(CMD25, start_idx)?;
-                for block in blocks.iter() {
-                    s.wait_not_busy()?;
-                    self.write_data(WRITE_MULTIPLE_TOKEN, &block.contents)?;
-                }
-                // Stop the write
-                s.wait_not_busy()?;
-                s.send(STOP_TRAN_TOKEN)?;
-            }
-            Ok(())
-        })
+    /// A switch which pulls the pin low when it is on - usually a switch to
+    /// ground, with a pull-up resistor.
+    pub fn active_low(pin: P) -> SwitchPin<P> {
+        SwitchPin {
+            pin,
+            active_low: true,
+        }
     }
 
-    /// Determine how many blocks this device can hold.
-    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
-        let num_bytes = self.card_size_bytes()?;
-        let num_blocks = (num_bytes / 512) as u32;
-        Ok(BlockCount(num_blocks))
+    /// Get the pin back.
+    pub fn free(self) -> P {
+        self.pin
     }
 }
 
-impl<SPI, CS> Drop for BlockSpi<'_, SPI, CS>
+impl<P> PowerPin<P>
+where
+    P: embedded_hal::digital::v2::OutputPin,
+{
+    /// A power switch which is on when the pin is high.
+    pub fn active_high(pin: P) -> PowerPin<P> {
+        PowerPin {
+            pin,
+            active_low: false,
+        }
+    }
+
+    /// A power switch which is on when the pin is low - such as a P-channel
+    /// MOSFET in the supply.
+    pub fn active_low(pin: P) -> PowerPin<P> {
+        PowerPin {
+            pin,
+            active_low: true,
+        }
+    }
+
+    /// Get the pin back.
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+impl<P> SocketSwitch for SwitchPin<P>
+where
+    P: embedded_hal::digital::v2::InputPin,
+{
+    fn is_on(&self) -> Result<Option<bool>, Error> {
+        let high = self.pin.is_high().map_err(|_| Error::GpioError)?;
+        Ok(Some(high != self.active_low))
+    }
+}
+
+impl<P> SocketPower for PowerPin<P>
+where
+    P: embedded_hal::digital::v2::OutputPin,
+{
+    fn set_power(&mut self, on: bool) -> Result<bool, Error> {
+        if on != self.active_low {
+            self.pin.set_high().map_err(|_| Error::GpioError)?;
+        } else {
+            self.pin.set_low().map_err(|_| Error::GpioError)?;
+        }
+        Ok(true)
+    }
+}
+
+impl SocketSwitch for NoSwitch {
+    fn is_on(&self) -> Result<Option<bool>, Error> {
+        Ok(None)
+    }
+}
+
+impl SocketPower for NoSwitch {
+    fn set_power(&mut self, _on: bool) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+impl<T> CardSocket<T, NoSwitch, NoSwitch, NoSwitch> {
+    /// Wrap the transport for a card socket, which (so far) has no switches.
+    pub fn new(transport: T) -> CardSocket<T, NoSwitch, NoSwitch, NoSwitch> {
+        CardSocket {
+            transport,
+            card_detect: NoSwitch,
+            write_protect: NoSwitch,
+            power: NoSwitch,
+        }
+    }
+}
+
+impl<T, CD, WP, PW> CardSocket<T, CD, WP, PW>
+where
+    CD: SocketSwitch,
+    WP: SocketSwitch,
+    PW: SocketPower,
+{
+    /// Add the switch which is on when there is a card in the socket.
+    pub fn with_card_detect<P>(
+        self,
+        card_detect: SwitchPin<P>,
+    ) -> CardSocket<T, SwitchPin<P>, WP, PW>
+    where
+        P: embedded_hal::digital::v2::InputPin,
+    {
+        CardSocket {
+            transport: self.transport,
+            card_detect,
+            write_protect: self.write_protect,
+            power: self.power,
+        }
+    }
+
+    /// Add the switch which is on when the card's write-protect tab is set.
+    pub fn with_write_protect<P>(
+        self,
+        write_protect: SwitchPin<P>,
+    ) -> CardSocket<T, CD, SwitchPin<P>, PW>
+    where
+        P: embedded_hal::digital::v2::InputPin,
+    {
+        CardSocket {
+            transport: self.transport,
+            card_detect: self.card_detect,
+            write_protect,
+            power: self.power,
+        }
+    }
+
+    /// Add the switch for the card's power. The card is powered up when it
+    /// is acquired, and can be powered down again with
+    /// `BlockSpi::power_down`.
+    pub fn with_power<P>(self, power: PowerPin<P>) -> CardSocket<T, CD, WP, PowerPin<P>>
+    where
+        P: embedded_hal::digital::v2::OutputPin,
+    {
+        CardSocket {
+            transport: self.transport,
+            card_detect: self.card_detect,
+            write_protect: self.write_protect,
+            power,
+        }
+    }
+
+    /// Get a borrow on the wrapped transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Get the wrapped transport and the switches back.
+    pub fn free(self) -> (T, CD, WP, PW) {
+        (
+            self.transport,
+            self.card_detect,
+            self.write_protect,
+            self.power,
+        )
+    }
+}
+
+impl<T, CD, WP, PW> SpiTransport for CardSocket<T, CD, WP, PW>
+where
+    T: SpiTransport,
+    CD: SocketSwitch,
+    WP: SocketSwitch,
+    PW: SocketPower,
+{
+    fn select(&mut self) -> Result<(), Error> {
+        SpiTransport::select(&mut self.transport)
+    }
+
+    fn deselect(&mut self) -> Result<(), Error> {
+        SpiTransport::deselect(&mut self.transport)
+    }
+
+    fn send_initial_clocks(&mut self) -> Result<(), Error> {
+        SpiTransport::send_initial_clocks(&mut self.transport)
+    }
+
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        SpiTransport::transfer(&mut self.transport, data)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        SpiTransport::write(&mut self.transport, data)
+    }
+
+    fn delay_us(&mut self, us: u8) {
+        SpiTransport::delay_us(&mut self.transport, us)
+    }
+
+    fn card_present(&mut self) -> Result<bool, Error> {
+        match self.card_detect.is_on()? {
+            Some(present) => Ok(present),
+            None => SpiTransport::card_present(&mut self.transport),
+        }
+    }
+
+    fn write_protected(&mut self) -> Result<bool, Error> {
+        match self.write_protect.is_on()? {
+            Some(protected) => Ok(protected),
+            None => SpiTransport::write_protected(&mut self.transport),
+        }
+    }
+
+    fn set_power(&mut self, on: bool) -> Result<bool, Error> {
+        if self.power.set_power(on)? {
+            Ok(true)
+        } else {
+            SpiTransport::set_power(&mut self.transport, on)
+        }
+    }
+
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        SpiTransport::set_clock_hz(&mut self.transport, hz)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, CD, WP, PW> AsyncSpiTransport for CardSocket<T, CD, WP, PW>
+where
+    T: AsyncSpiTransport,
+    CD: SocketSwitch,
+    WP: SocketSwitch,
+    PW: SocketPower,
+{
+    async fn select(&mut self) -> Result<(), Error> {
+        AsyncSpiTransport::select(&mut self.transport).await
+    }
+
+    async fn deselect(&mut self) -> Result<(), Error> {
+        AsyncSpiTransport::deselect(&mut self.transport).await
+    }
+
+    async fn send_initial_clocks(&mut self) -> Result<(), Error> {
+        AsyncSpiTransport::send_initial_clocks(&mut self.transport).await
+    }
+
+    async fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        AsyncSpiTransport::transfer(&mut self.transport, data).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        AsyncSpiTransport::write(&mut self.transport, data).await
+    }
+
+    async fn delay_us(&mut self, us: u8) {
+        AsyncSpiTransport::delay_us(&mut self.transport, us).await
+    }
+
+    fn card_present(&mut self) -> Result<bool, Error> {
+        match self.card_detect.is_on()? {
+            Some(present) => Ok(present),
+            None => AsyncSpiTransport::card_present(&mut self.transport),
+        }
+    }
+
+    fn write_protected(&mut self) -> Result<bool, Error> {
+        match self.write_protect.is_on()? {
+            Some(protected) => Ok(protected),
+            None => AsyncSpiTransport::write_protected(&mut self.transport),
+        }
+    }
+
+    fn set_power(&mut self, on: bool) -> Result<bool, Error> {
+        if self.power.set_power(on)? {
+            Ok(true)
+        } else {
+            AsyncSpiTransport::set_power(&mut self.transport, on)
+        }
+    }
+
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        AsyncSpiTransport::set_clock_hz(&mut self.transport, hz)
+    }
+}
+
+/// The possible errors `SdMmcSpi` can generate.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+    /// We got an error from the SPI peripheral
+    Transport,
+    /// We failed to enable CRC checking on the SD card
+    CantEnableCRC,
+    /// We didn't get a response when reading data from the card
+    TimeoutReadBuffer,
+    /// We didn't get a response when waiting for the card to not be busy
+    TimeoutWaitNotBusy,
+    /// We didn't get a response when executing this command
+    TimeoutCommand(u8),
+    /// We didn't get a response when executing this application-specific command
+    TimeoutACommand(u8),
+    /// We got a bad response from Command 58
+    Cmd58Error,
+    /// We read one of the card's registers, but couldn't make sense of it
+    RegisterReadError,
+    /// We got a CRC mismatch (card gave us, we calculated)
+    CrcError(u16, u16),
+    /// The card rejected this command, for the reasons given in its R1
+    /// response
+    CommandError(u8, R1Status),
+    /// The card's status (from CMD13, or the R2 response to ACMD13) says
+    /// something went wrong
+    CardStatusError(CardStatus),
+    /// The card sent a data error token instead of a block of data
+    DataError(DataErrorToken),
+    /// The card got a block from us with a bad CRC, so didn't write it
+    WriteCrcError,
+    /// A multi-block write failed, after the card had written this many of
+    /// the blocks
+    PartialWrite(u32),
+    /// Error reading from the card
+    ReadError,
+    /// Error writing to the card
+    WriteError,
+    /// Can't perform this operation with the card in this state
+    BadState,
+    /// Couldn't find the card, or the card-detect switch says it has been
+    /// taken out
+    CardNotFound,
+    /// The card's write-protect tab is set
+    WriteProtected,
+    /// The card is locked with a password, so has to be unlocked first
+    CardLocked,
+    /// The card refused to set, clear or change its password, or to lock or
+    /// unlock (usually because the password was wrong)
+    LockUnlockFailed,
+    /// Card passwords can only be 16 bytes long
+    PasswordTooLong,
+    /// The card has more blocks than a `BlockIdx` can address (it is bigger
+    /// than 2 TiB)
+    CardTooLarge,
+    /// Couldn't set a GPIO pin
+    GpioError,
+}
+
+impl Error {
+    /// Could this error have been caused by a glitch, so that trying again
+    /// might work?
+    pub(crate) fn is_transient(self) -> bool {
+        match self {
+            Error::TimeoutReadBuffer
+            | Error::TimeoutWaitNotBusy
+            | Error::TimeoutCommand(_)
+            | Error::CrcError(_, _)
+            | Error::WriteCrcError
+            | Error::PartialWrite(_)
+            | Error::ReadError
+            | Error::WriteError => true,
+            Error::CommandError(_, status) => status.is_crc_error(),
+            Error::DataError(token) => token.is_ecc_failed() || token.is_cc_error(),
+            _ => false,
+        }
+    }
+}
+
+/// Write out the names of the flags which are set in `bits`, or "OK" if none
+/// of them are.
+fn fmt_flags(f: &mut core::fmt::Formatter, bits: u8, names: &[(u8, &str)]) -> core::fmt::Result {
+    if bits == 0 {
+        return write!(f, "OK");
+    }
+    let mut first = true;
+    for (mask, name) in names {
+        if bits & mask != 0 {
+            if !first {
+                write!(f, "|")?;
+            }
+            write!(f, "{}", name)?;
+            first = false;
+        }
+    }
+    Ok(())
+}
+
+/// The error bits from the R1 response the card sends after every command.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct R1Status(pub(crate) u8);
+
+impl R1Status {
+    /// Get the raw R1 byte.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Is the card still initialising?
+    pub fn is_idle(self) -> bool {
+        self.0 & R1_IDLE_STATE != 0
+    }
+
+    /// Did another command interrupt an erase sequence?
+    pub fn is_erase_reset(self) -> bool {
+        self.0 & R1_ERASE_RESET != 0
+    }
+
+    /// Was the command one the card doesn't understand (or not right now)?
+    pub fn is_illegal_command(self) -> bool {
+        self.0 & R1_ILLEGAL_COMMAND != 0
+    }
+
+    /// Did the command arrive with a bad CRC?
+    pub fn is_crc_error(self) -> bool {
+        self.0 & R1_COM_CRC_ERROR != 0
+    }
+
+    /// Were the erase commands sent in the wrong order?
+    pub fn is_erase_sequence_error(self) -> bool {
+        self.0 & R1_ERASE_SEQUENCE_ERROR != 0
+    }
+
+    /// Was the address not aligned to a block?
+    pub fn is_address_error(self) -> bool {
+        self.0 & R1_ADDRESS_ERROR != 0
+    }
+
+    /// Was the argument out of range, such as an address past the end of
+    /// the card?
+    pub fn is_parameter_error(self) -> bool {
+        self.0 & R1_PARAMETER_ERROR != 0
+    }
+}
+
+impl core::fmt::Debug for R1Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt_flags(
+            f,
+            self.0,
+            &[
+                (R1_IDLE_STATE, "IDLE"),
+                (R1_ERASE_RESET, "ERASE_RESET"),
+                (R1_ILLEGAL_COMMAND, "ILLEGAL_COMMAND"),
+                (R1_COM_CRC_ERROR, "COM_CRC_ERROR"),
+                (R1_ERASE_SEQUENCE_ERROR, "ERASE_SEQUENCE_ERROR"),
+                (R1_ADDRESS_ERROR, "ADDRESS_ERROR"),
+                (R1_PARAMETER_ERROR, "PARAMETER_ERROR"),
+            ],
+        )
+    }
+}
+
+/// The card status bits from the second byte of an R2 response (to CMD13,
+/// SEND_STATUS).
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct CardStatus(pub(crate) u8);
+
+impl CardStatus {
+    /// Get the raw status byte.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Is the card locked with a password?
+    pub fn is_card_locked(self) -> bool {
+        self.0 & R2_CARD_LOCKED != 0
+    }
+
+    /// Were write-protected blocks skipped by an erase, or did a lock/unlock
+    /// command fail?
+    pub fn is_wp_erase_skip(self) -> bool {
+        self.0 & R2_WP_ERASE_SKIP != 0
+    }
+
+    /// Did something unspecified go wrong?
+    pub fn is_error(self) -> bool {
+        self.0 & R2_ERROR != 0
+    }
+
+    /// Did the card's internal controller go wrong?
+    pub fn is_cc_error(self) -> bool {
+        self.0 & R2_CC_ERROR != 0
+    }
+
+    /// Did the card's ECC fail to correct the data?
+    pub fn is_ecc_failed(self) -> bool {
+        self.0 & R2_CARD_ECC_FAILED != 0
+    }
+
+    /// Was there an attempt to write a write-protected block?
+    pub fn is_wp_violation(self) -> bool {
+        self.0 & R2_WP_VIOLATION != 0
+    }
+
+    /// Was an invalid set of blocks picked to be erased?
+    pub fn is_erase_param(self) -> bool {
+        self.0 & R2_ERASE_PARAM != 0
+    }
+
+    /// Was an argument out of range?
+    pub fn is_out_of_range(self) -> bool {
+        self.0 & R2_OUT_OF_RANGE != 0
+    }
+}
+
+impl core::fmt::Debug for CardStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt_flags(
+            f,
+            self.0,
+            &[
+                (R2_CARD_LOCKED, "CARD_LOCKED"),
+                (R2_WP_ERASE_SKIP, "WP_ERASE_SKIP"),
+                (R2_ERROR, "ERROR"),
+                (R2_CC_ERROR, "CC_ERROR"),
+                (R2_CARD_ECC_FAILED, "CARD_ECC_FAILED"),
+                (R2_WP_VIOLATION, "WP_VIOLATION"),
+                (R2_ERASE_PARAM, "ERASE_PARAM"),
+                (R2_OUT_OF_RANGE, "OUT_OF_RANGE"),
+            ],
+        )
+    }
+}
+
+/// The data error token the card sends instead of a block it can't read.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct DataErrorToken(pub(crate) u8);
+
+impl DataErrorToken {
+    /// Get the raw token.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Did something unspecified go wrong?
+    pub fn is_error(self) -> bool {
+        self.0 & DATA_ERROR_ERROR != 0
+    }
+
+    /// Did the card's internal controller go wrong?
+    pub fn is_cc_error(self) -> bool {
+        self.0 & DATA_ERROR_CC_ERROR != 0
+    }
+
+    /// Did the card's ECC fail to correct the data?
+    pub fn is_ecc_failed(self) -> bool {
+        self.0 & DATA_ERROR_CARD_ECC_FAILED != 0
+    }
+
+    /// Was the address past the end of the card?
+    pub fn is_out_of_range(self) -> bool {
+        self.0 & DATA_ERROR_OUT_OF_RANGE != 0
+    }
+
+    /// Is the card locked with a password?
+    pub fn is_card_locked(self) -> bool {
+        self.0 & DATA_ERROR_CARD_LOCKED != 0
+    }
+}
+
+impl core::fmt::Debug for DataErrorToken {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt_flags(
+            f,
+            self.0,
+            &[
+                (DATA_ERROR_ERROR, "ERROR"),
+                (DATA_ERROR_CC_ERROR, "CC_ERROR"),
+                (DATA_ERROR_CARD_ECC_FAILED, "CARD_ECC_FAILED"),
+                (DATA_ERROR_OUT_OF_RANGE, "OUT_OF_RANGE"),
+                (DATA_ERROR_CARD_LOCKED, "CARD_LOCKED"),
+            ],
+        )
+    }
+}
+
+/// How often reads and writes have had to be retried, since the card was
+/// created (or the statistics were cleared).
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Reads and writes which were tried again after an error
+    pub retries: u32,
+    /// Times the card was re-initialised to recover from errors
+    pub reinits: u32,
+    /// Reads and writes which failed, even after any retries
+    pub failures: u32,
+}
+
+/// The possible states `SdMmcSpi` can be in.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    /// Card is not initialised
+    NoInit,
+    /// Card is in an error state
+    Error,
+    /// Card is initialised and idle
+    Idle,
+    /// Card is initialised, but put to sleep until it is re-acquired
+    Asleep,
+    /// Card's power has been switched off, so it will have to be
+    /// initialised again
+    PoweredDown,
+}
+
+/// The different types of card we support.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum CardType {
+    SD1,
+    SD2,
+    SDHC,
+    MMC,
+    MMCHC,
+}
+
+impl CardType {
+    /// Is this an MMC (or eMMC) card, rather than an SD card?
+    pub(crate) fn is_mmc(self) -> bool {
+        matches!(self, CardType::MMC | CardType::MMCHC)
+    }
+}
+
+/// Tracks how much longer we're prepared to wait for the card to sort itself
+/// out.
+///
+/// Only the time spent in the delays is counted, not the time spent talking
+/// to the card. A poll can take longer than `POLL_INTERVAL_US` (a CMD55 and
+/// ACMD41 at the 400 kHz init clock takes most of a millisecond), so only the
+/// first few polls are quick, and after that we wait a millisecond between
+/// polls to keep the real timeout close to the one asked for.
+pub(crate) struct Timeout {
+    remaining_us: u32,
+    fast_polls: u8,
+}
+
+impl Timeout {
+    pub(crate) fn new(timeout_ms: u32) -> Timeout {
+        Timeout {
+            remaining_us: timeout_ms.saturating_mul(1000),
+            fast_polls: FAST_POLLS,
+        }
+    }
+
+    /// How long to wait, in microseconds, before polling the card again, or
+    /// `None` if we've run out of time.
+    pub(crate) fn next_delay_us(&mut self) -> Option<u32> {
+        if self.remaining_us == 0 {
+            return None;
+        }
+        let delay_us = if self.fast_polls > 0 {
+            self.fast_polls -= 1;
+            u32::from(POLL_INTERVAL_US)
+        } else {
+            SLOW_POLL_INTERVAL_US
+        };
+        self.remaining_us = self.remaining_us.saturating_sub(delay_us);
+        Some(delay_us)
+    }
+}
+
+/// Options for acquiring the card.
+#[derive(Debug, Copy, Clone)]
+pub struct AcquireOpts {
+    /// Some cards don't support CRC mode. At least a 512MiB Transcend one.
+    pub require_crc: bool,
+    /// How long to wait for the card to finish initialising. The spec says
+    /// ACMD41 can take up to 1 second.
+    pub init_timeout_ms: u32,
+    /// How long to wait for a block of data to arrive when reading. The
+    /// spec says 100 ms.
+    pub read_timeout_ms: u32,
+    /// How long to wait for the card to finish writing a block. The spec
+    /// says 250 ms for SDSC and SDHC cards, and 500 ms for SDXC cards.
+    pub write_timeout_ms: u32,
+    /// How long to wait for an erase to finish, per block erased. The spec
+    /// says 250 ms per block, if the card doesn't tell you otherwise in its
+    /// SD Status register.
+    pub erase_timeout_ms: u32,
+    /// Use CMD6 to switch the card into high-speed mode (up to 50 MHz), if
+    /// it supports it.
+    pub high_speed: bool,
+    /// How many times to retry a read or write which fails with what might
+    /// be a glitch on the bus, such as a CRC error or a timeout. None by
+    /// default.
+    pub retries: u8,
+    /// Whether to stop a multi-block read which fails part way through with
+    /// CMD12, so the card is ready for it to be retried. Off by default. A
+    /// multi-block write which fails is always stopped.
+    pub stop_on_error: bool,
+    /// Re-initialise the card when this many reads and writes in a row have
+    /// failed with what might be a glitch, whether or not they are retried,
+    /// or never if zero. The transport's clock is set to 400 kHz first,
+    /// and then the card's speed again once it is initialised.
+    pub reinit_after_failures: u8,
+    /// How long to wait after switching the card's power on (see
+    /// `CardSocket::with_power`) before talking to it. The spec says 1 ms
+    /// once the supply is up, but the supply has to ramp up first.
+    pub power_up_delay_ms: u32,
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for AcquireOpts {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "AcquireOpts(require_crc={}, init_timeout_ms={}, read_timeout_ms={}, write_timeout_ms={}, erase_timeout_ms={}, high_speed={}, retries={}, stop_on_error={}, reinit_after_failures={}, power_up_delay_ms={})",
+            self.require_crc,
+            self.init_timeout_ms,
+            self.read_timeout_ms,
+            self.write_timeout_ms,
+            self.erase_timeout_ms,
+            self.high_speed,
+            self.retries,
+            self.stop_on_error,
+            self.reinit_after_failures,
+            self.power_up_delay_ms
+        )
+    }
+}
+
+impl Default for AcquireOpts {
+    fn default() -> Self {
+        AcquireOpts {
+            require_crc: true,
+            init_timeout_ms: 1000,
+            read_timeout_ms: 100,
+            write_timeout_ms: 500,
+            erase_timeout_ms: 250,
+            high_speed: false,
+            retries: 0,
+            stop_on_error: false,
+            reinit_after_failures: 0,
+            power_up_delay_ms: 10,
+        }
+    }
+}
+
+impl<T> SdMmcSpi<T>
+where
+    T: SpiTransport,
+{
+    /// Create a new SD/MMC controller which talks to the card using the
+    /// given transport.
+    pub fn with_transport(transport: T) -> SdMmcSpi<T> {
+        SdMmcSpi {
+            card: RefCell::new(Card::new(transport)),
+        }
+    }
+
+    /// Get the transport back.
+    pub fn free(self) -> T {
+        self.card.into_inner().transport
+    }
+
+    /// Is there a card in the socket? Always yes, unless the transport has a
+    /// card-detect switch (see `CardSocket`).
+    pub fn card_present(&self) -> Result<bool, Error> {
+        self.card.borrow_mut().card_present()
+    }
+
+    /// Initializes the card into a known state
+    pub fn acquire(&mut self) -> Result<BlockSpi<'_, T>, Error> {
+        self.acquire_with_opts(Default::default())
+    }
+
+    /// Initializes the card into a known state
+    pub fn acquire_with_opts(&mut self, options: AcquireOpts) -> Result<BlockSpi<'_, T>, Error> {
+        self.card.get_mut().acquire(options)?;
+        Ok(BlockSpi(self))
+    }
+
+    /// Get the card going again after `BlockSpi::sleep` or
+    /// `BlockSpi::power_down`, with the options it was last acquired with.
+    /// A card which kept its power is ready straight away, without being
+    /// initialised again.
+    pub fn reacquire(&mut self) -> Result<BlockSpi<'_, T>, Error> {
+        self.card.get_mut().wake()?;
+        Ok(BlockSpi(self))
+    }
+}
+
+impl<T> BlockSpi<'_, T>
+where
+    T: SpiTransport,
+{
+    /// Get a temporary borrow on the underlying transport.
+    pub fn transport(&mut self) -> core::cell::RefMut<'_, T> {
+        core::cell::RefMut::map(self.0.card.borrow_mut(), |c| &mut c.transport)
+    }
+
+    /// Mark the card as unused, unless it has been put to sleep.
+    /// This should be kept infallible, because Drop is unable to fail.
+    /// See https://github.com/rust-lang/rfcs/issues/814
+    // Anything which needs flushing is done by `sleep` or `power_down`.
+    fn deinit(&mut self) {
+        self.0.card.get_mut().release();
+    }
+
+    /// Wait for the card to finish writing, then deselect it and let it go,
+    /// so it can be picked up again quickly with `SdMmcSpi::reacquire`. The
+    /// card drops into its low-power standby mode while it is deselected.
+    pub fn sleep(self) -> Result<(), Error> {
+        self.0.card.borrow_mut().sleep()
+    }
+
+    /// Like `sleep`, but then switch the card's power off, if the transport
+    /// has a power switch (see `CardSocket::with_power`). The next
+    /// `SdMmcSpi::reacquire` (or `acquire`) switches it back on.
+    pub fn power_down(self) -> Result<(), Error> {
+        self.0.card.borrow_mut().power_down()
+    }
+
+    /// Is the card still in the socket? If it has been pulled out, it has to
+    /// be acquired again - even if it has been put back since.
+    pub fn card_present(&self) -> Result<bool, Error> {
+        self.0.card.borrow_mut().card_present()
+    }
+
+    /// Is the card's write-protect tab set? If so, writes and erases will
+    /// fail with `Error::WriteProtected`.
+    pub fn write_protected(&self) -> Result<bool, Error> {
+        self.0.card.borrow_mut().transport.write_protected()
+    }
+
+    /// How often reads and writes have had to be retried (see
+    /// `AcquireOpts::retries`).
+    pub fn retry_stats(&self) -> RetryStats {
+        self.0.card.borrow().retry_stats
+    }
+
+    /// Start counting retries from zero again.
+    pub fn clear_retry_stats(&self) {
+        self.0.card.borrow_mut().retry_stats = RetryStats::default();
+    }
+
+    /// Return the usable size of this SD card in bytes.
+    pub fn card_size_bytes(&self) -> Result<u64, Error> {
+        self.0.card.borrow_mut().card_size_bytes()
+    }
+
+    /// Read the card's identification register (using CMD10), which says who
+    /// made the card, what it is called, and its serial number.
+    pub fn card_info(&self) -> Result<Cid, Error> {
+        self.0.card.borrow_mut().card_info()
+    }
+
+    /// Read the card's 'card specific data' register (using CMD9), which
+    /// describes its capacity, timings and write protection.
+    pub fn card_csd(&self) -> Result<Csd, Error> {
+        self.0.card.borrow_mut().card_csd()
+    }
+
+    /// Read an MMC card's Extended CSD register (using CMD8), which
+    /// describes cards bigger than 2 GiB. SD cards don't have one, so give
+    /// `Error::BadState`.
+    pub fn card_ext_csd(&self) -> Result<ExtCsd, Error> {
+        self.0.card.borrow_mut().card_ext_csd()
+    }
+
+    /// Is this an MMC or eMMC card, rather than an SD card?
+    pub fn is_mmc(&self) -> bool {
+        self.0.card.borrow().card_type.is_mmc()
+    }
+
+    /// Read the card's SD Configuration Register (using ACMD51), which
+    /// describes the bus widths and spec version it supports.
+    pub fn card_scr(&self) -> Result<Scr, Error> {
+        self.0.card.borrow_mut().card_scr()
+    }
+
+    /// Read the card's SD Status register (using ACMD13), which describes
+    /// its speed class, allocation unit size and erase timings.
+    pub fn card_sd_status(&self) -> Result<SdStatus, Error> {
+        self.0.card.borrow_mut().card_sd_status()
+    }
+
+    /// Erase some blocks on the card, from `first_block` to `last_block`
+    /// inclusive.
+    pub fn erase(&mut self, first_block: BlockIdx, last_block: BlockIdx) -> Result<(), Error> {
+        self.0.card.borrow_mut().erase(first_block, last_block)
+    }
+
+    /// Can this card erase single blocks?
+    pub fn erase_single_block_enabled(&self) -> Result<bool, Error> {
+        self.0.card.borrow_mut().erase_single_block_enabled()
+    }
+
+    /// Is the card locked with a password? If so, reads, writes and erases
+    /// fail with `Error::CardLocked` until it is unlocked with `unlock`.
+    pub fn is_locked(&self) -> Result<bool, Error> {
+        self.0.card.borrow_mut().is_locked()
+    }
+
+    /// Give the card a password (of up to 16 bytes), if it doesn't have one.
+    /// The card stays unlocked until it is locked with `lock`, or powered up
+    /// again.
+    pub fn set_password(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .lock_unlock(LOCK_SET_PASSWORD, &[], password)
+    }
+
+    /// Change the card's password.
+    pub fn change_password(&mut self, old: &[u8], new: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .lock_unlock(LOCK_SET_PASSWORD, old, new)
+    }
+
+    /// Take the card's password away, which unlocks it for good.
+    pub fn clear_password(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .lock_unlock(LOCK_CLEAR_PASSWORD, password, &[])
+    }
+
+    /// Lock the card, which must have a password.
+    pub fn lock(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .lock_unlock(LOCK_LOCK, password, &[])
+    }
+
+    /// Unlock the card, until it is next powered up.
+    pub fn unlock(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0.card.borrow_mut().lock_unlock(0, password, &[])
+    }
+
+    /// Erase everything on the card, including its password, for when the
+    /// password has been forgotten. This can take minutes.
+    pub fn force_erase(&mut self) -> Result<(), Error> {
+        self.0.card.borrow_mut().force_erase()
+    }
+}
+
+impl<T> BlockDevice for BlockSpi<'_, T>
+where
+    T: SpiTransport,
+{
+    type Error = Error;
+
+    /// Read one or more blocks, starting at the given block index.
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .read_blocks(blocks, start_block_idx)
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.0
+            .card
+            .borrow_mut()
+            .write_blocks(blocks, start_block_idx)
+    }
+
+    /// Determine how many blocks this device can hold.
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.0.card.borrow_mut().num_blocks()
+    }
+}
+
+impl<SPI, CS, DELAY> SdMmcSpi<SpiCsTransport<SPI, CS, DELAY>>
 where
     SPI: embedded_hal::blocking::spi::Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
     <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+{
+    /// Create a new SD/MMC controller using a raw SPI interface. The delay
+    /// is used to time out when the card stops responding.
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> SdMmcSpi<SpiCsTransport<SPI, CS, DELAY>> {
+        SdMmcSpi::with_transport(SpiCsTransport::new(spi, cs, delay))
+    }
+
+    /// Add something to set the SPI clock (see `SpiCsTransport::with_clock`),
+    /// before the card is acquired.
+    pub fn with_clock<C>(self, clock: C) -> SdMmcSpi<SpiCsTransport<SPI, CS, DELAY, C>>
+    where
+        C: SpiClock,
+    {
+        SdMmcSpi::with_transport(self.free().with_clock(clock))
+    }
+}
+
+impl<SPI, CS, DELAY, CLK> BlockSpi<'_, SpiCsTransport<SPI, CS, DELAY, CLK>>
+where
+    SPI: embedded_hal::blocking::spi::Transfer<u8>,
     CS: embedded_hal::digital::v2::OutputPin,
+    DELAY: embedded_hal::blocking::delay::DelayUs<u8>,
+    CLK: SpiClock,
+    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+{
+    /// Get a temporary borrow on the underlying SPI device. Useful if you
+    /// need to re-clock the SPI.
+    pub fn spi(&mut self) -> core::cell::RefMut<'_, SPI> {
+        core::cell::RefMut::map(self.0.card.borrow_mut(), |c| &mut c.transport.spi)
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<SPI, DELAY> SdMmcSpi<SpiDeviceTransport<SPI, DELAY>>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+{
+    /// Create a new SD/MMC controller using an embedded-hal 1.0 SPI device.
+    /// The delay is used to time out when the card stops responding.
+    pub fn new_spi_device(spi: SPI, delay: DELAY) -> SdMmcSpi<SpiDeviceTransport<SPI, DELAY>> {
+        SdMmcSpi::with_transport(SpiDeviceTransport::new(spi, delay))
+    }
+
+    /// Add something to set the SPI clock (see
+    /// `SpiDeviceTransport::with_clock`), before the card is acquired.
+    pub fn with_clock<C>(self, clock: C) -> SdMmcSpi<SpiDeviceTransport<SPI, DELAY, C>>
+    where
+        C: SpiClock,
+    {
+        SdMmcSpi::with_transport(self.free().with_clock(clock))
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<SPI, DELAY, CLK> BlockSpi<'_, SpiDeviceTransport<SPI, DELAY, CLK>>
+where
+    SPI: embedded_hal_1::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_1::delay::DelayNs,
+    CLK: SpiClock,
+{
+    /// Get a temporary borrow on the underlying SPI device. Useful if you
+    /// need to re-clock the SPI.
+    pub fn spi(&mut self) -> core::cell::RefMut<'_, SPI> {
+        core::cell::RefMut::map(self.0.card.borrow_mut(), |c| &mut c.transport.spi)
+    }
+}
+
+impl<T> Drop for BlockSpi<'_, T>
+where
+    T: SpiTransport,
 {
     fn drop(&mut self) {
         self.deinit()
     }
 }
 
+#[cfg(feature = "async")]
+impl<T> AsyncSdMmcSpi<T>
+where
+    T: AsyncSpiTransport,
+{
+    /// Create a new SD/MMC controller which talks to the card using the
+    /// given async transport.
+    pub fn with_transport(transport: T) -> AsyncSdMmcSpi<T> {
+        AsyncSdMmcSpi {
+            card: AsyncCard::new(transport),
+        }
+    }
+
+    /// Get the transport back.
+    pub fn free(self) -> T {
+        self.card.transport
+    }
+
+    /// Is there a card in the socket? See `SdMmcSpi::card_present`.
+    pub fn card_present(&mut self) -> Result<bool, Error> {
+        self.card.card_present()
+    }
+
+    /// Initializes the card into a known state
+    pub async fn acquire(&mut self) -> Result<AsyncBlockSpi<'_, T>, Error> {
+        self.acquire_with_opts(Default::default()).await
+    }
+
+    /// Initializes the card into a known state
+    pub async fn acquire_with_opts(
+        &mut self,
+        options: AcquireOpts,
+    ) -> Result<AsyncBlockSpi<'_, T>, Error> {
+        self.card.acquire(options).await?;
+        Ok(AsyncBlockSpi(self))
+    }
+
+    /// Get the card going again. See `SdMmcSpi::reacquire`.
+    pub async fn reacquire(&mut self) -> Result<AsyncBlockSpi<'_, T>, Error> {
+        self.card.wake().await?;
+        Ok(AsyncBlockSpi(self))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncBlockSpi<'_, T>
+where
+    T: AsyncSpiTransport,
+{
+    /// Get a temporary borrow on the underlying transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.0.card.transport
+    }
+
+    /// Is the card still in the socket? See `BlockSpi::card_present`.
+    pub fn card_present(&mut self) -> Result<bool, Error> {
+        self.0.card.card_present()
+    }
+
+    /// Is the card's write-protect tab set? See `BlockSpi::write_protected`.
+    pub fn write_protected(&mut self) -> Result<bool, Error> {
+        self.0.card.transport.write_protected()
+    }
+
+    /// How often reads and writes have had to be retried. See
+    /// `BlockSpi::retry_stats`.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.0.card.retry_stats
+    }
+
+    /// Start counting retries from zero again.
+    pub fn clear_retry_stats(&mut self) {
+        self.0.card.retry_stats = RetryStats::default();
+    }
+
+    /// Let the card go until it is re-acquired. See `BlockSpi::sleep`.
+    pub async fn sleep(self) -> Result<(), Error> {
+        self.0.card.sleep().await
+    }
+
+    /// Let the card go and switch its power off. See `BlockSpi::power_down`.
+    pub async fn power_down(self) -> Result<(), Error> {
+        self.0.card.power_down().await
+    }
+
+    /// Return the usable size of this SD card in bytes.
+    pub async fn card_size_bytes(&mut self) -> Result<u64, Error> {
+        self.0.card.card_size_bytes().await
+    }
+
+    /// Read the card's identification register. See `BlockSpi::card_info`.
+    pub async fn card_info(&mut self) -> Result<Cid, Error> {
+        self.0.card.card_info().await
+    }
+
+    /// Read the card's 'card specific data' register. See
+    /// `BlockSpi::card_csd`.
+    pub async fn card_csd(&mut self) -> Result<Csd, Error> {
+        self.0.card.card_csd().await
+    }
+
+    /// Read an MMC card's Extended CSD register. See
+    /// `BlockSpi::card_ext_csd`.
+    pub async fn card_ext_csd(&mut self) -> Result<ExtCsd, Error> {
+        self.0.card.card_ext_csd().await
+    }
+
+    /// Is this an MMC or eMMC card, rather than an SD card?
+    pub fn is_mmc(&self) -> bool {
+        self.0.card.card_type.is_mmc()
+    }
+
+    /// Read the card's SD Configuration Register. See `BlockSpi::card_scr`.
+    pub async fn card_scr(&mut self) -> Result<Scr, Error> {
+        self.0.card.card_scr().await
+    }
+
+    /// Read the card's SD Status register. See `BlockSpi::card_sd_status`.
+    pub async fn card_sd_status(&mut self) -> Result<SdStatus, Error> {
+        self.0.card.card_sd_status().await
+    }
+
+    /// Erase some blocks on the card, from `first_block` to `last_block`
+    /// inclusive.
+    pub async fn erase(
+        &mut self,
+        first_block: BlockIdx,
+        last_block: BlockIdx,
+    ) -> Result<(), Error> {
+        self.0.card.erase(first_block, last_block).await
+    }
+
+    /// Can this card erase single blocks?
+    pub async fn erase_single_block_enabled(&mut self) -> Result<bool, Error> {
+        self.0.card.erase_single_block_enabled().await
+    }
+
+    /// Is the card locked with a password? See `BlockSpi::is_locked`.
+    pub async fn is_locked(&mut self) -> Result<bool, Error> {
+        self.0.card.is_locked().await
+    }
+
+    /// Give the card a password. See `BlockSpi::set_password`.
+    pub async fn set_password(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .lock_unlock(LOCK_SET_PASSWORD, &[], password)
+            .await
+    }
+
+    /// Change the card's password.
+    pub async fn change_password(&mut self, old: &[u8], new: &[u8]) -> Result<(), Error> {
+        self.0.card.lock_unlock(LOCK_SET_PASSWORD, old, new).await
+    }
+
+    /// Take the card's password away, which unlocks it for good.
+    pub async fn clear_password(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0
+            .card
+            .lock_unlock(LOCK_CLEAR_PASSWORD, password, &[])
+            .await
+    }
+
+    /// Lock the card, which must have a password.
+    pub async fn lock(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0.card.lock_unlock(LOCK_LOCK, password, &[]).await
+    }
+
+    /// Unlock the card, until it is next powered up.
+    pub async fn unlock(&mut self, password: &[u8]) -> Result<(), Error> {
+        self.0.card.lock_unlock(0, password, &[]).await
+    }
+
+    /// Erase everything on the card, including its password. See
+    /// `BlockSpi::force_erase`.
+    pub async fn force_erase(&mut self) -> Result<(), Error> {
+        self.0.card.force_erase().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncBlockDevice for AsyncBlockSpi<'_, T>
+where
+    T: AsyncSpiTransport,
+{
+    type Error = Error;
+
+    /// Read one or more blocks, starting at the given block index.
+    async fn read(
+        &mut self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.0.card.read_blocks(blocks, start_block_idx).await
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    async fn write(
+        &mut self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Self::Error> {
+        self.0.card.write_blocks(blocks, start_block_idx).await
+    }
+
+    /// Determine how many blocks this device can hold.
+    async fn num_blocks(&mut self) -> Result<BlockCount, Self::Error> {
+        self.0.card.num_blocks().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<SPI, DELAY> AsyncSdMmcSpi<AsyncSpiDeviceTransport<SPI, DELAY>>
+where
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+{
+    /// Create a new SD/MMC controller using an embedded-hal-async SPI device.
+    /// The delay is used to time out when the card stops responding.
+    pub fn new_spi_device(
+        spi: SPI,
+        delay: DELAY,
+    ) -> AsyncSdMmcSpi<AsyncSpiDeviceTransport<SPI, DELAY>> {
+        AsyncSdMmcSpi::with_transport(AsyncSpiDeviceTransport::new(spi, delay))
+    }
+
+    /// Add something to set the SPI clock (see
+    /// `AsyncSpiDeviceTransport::with_clock`), before the card is acquired.
+    pub fn with_clock<C>(self, clock: C) -> AsyncSdMmcSpi<AsyncSpiDeviceTransport<SPI, DELAY, C>>
+    where
+        C: SpiClock,
+    {
+        AsyncSdMmcSpi::with_transport(self.free().with_clock(clock))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<SPI, DELAY, CLK> AsyncBlockSpi<'_, AsyncSpiDeviceTransport<SPI, DELAY, CLK>>
+where
+    SPI: embedded_hal_async::spi::SpiDevice<u8>,
+    DELAY: embedded_hal_async::delay::DelayNs,
+    CLK: SpiClock,
+{
+    /// Get a temporary borrow on the underlying SPI device. Useful if you
+    /// need to re-clock the SPI.
+    pub fn spi(&mut self) -> &mut SPI {
+        &mut self.0.card.transport.spi
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Drop for AsyncBlockSpi<'_, T>
+where
+    T: AsyncSpiTransport,
+{
+    fn drop(&mut self) {
+        self.0.card.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sdmmc_sim::{SimCard, SimCardType, SimCs, SimDelay, SimPin, SimPower, SimSpi};
+    #[cfg(feature = "async")]
+    use crate::test_disk::{run, yield_once};
+
+    type SimSdMmcSpi = SdMmcSpi<SpiCsTransport<SimSpi, SimCs, SimDelay>>;
+
+    type SimSocketSpi = SdMmcSpi<
+        CardSocket<
+            SpiCsTransport<SimSpi, SimCs, SimDelay>,
+            SwitchPin<SimPin>,
+            SwitchPin<SimPin>,
+            PowerPin<SimPower>,
+        >,
+    >;
+
+    fn sd_mmc_spi(card: &SimCard) -> SimSdMmcSpi {
+        SdMmcSpi::new(card.spi(), card.cs(), SimDelay)
+    }
+
+    fn pattern(seed: u8) -> Block {
+        let mut block = Block::new();
+        for (i, byte) in block.contents.iter_mut().enumerate() {
+            *byte = seed.wrapping_add(i as u8);
+        }
+        block
+    }
+
+    #[test]
+    fn acquire_each_card_type() {
+        for (card_type, num_blocks, expected) in [
+            (SimCardType::Sd1, 64, CardType::SD1),
+            (SimCardType::Sd2, 128, CardType::SD2),
+            (SimCardType::Sdhc, 2048, CardType::SDHC),
+            (SimCardType::Mmc, 64, CardType::MMC),
+            (SimCardType::Emmc, 4096, CardType::MMCHC),
+        ] {
+            let card = SimCard::new(card_type, num_blocks);
+            let mut spi = sd_mmc_spi(&card);
+            let block = spi.acquire().unwrap();
+            assert_eq!(block.0.card.borrow().card_type, expected);
+            assert_eq!(block.num_blocks().unwrap(), BlockCount(num_blocks as u32));
+            assert_eq!(block.card_info().unwrap().product_name(), b"SIM01");
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        for (card_type, num_blocks) in [(SimCardType::Sd2, 64), (SimCardType::Sdhc, 1024)] {
+            let card = SimCard::new(card_type, num_blocks);
+            let mut spi = sd_mmc_spi(&card);
+            let block = spi.acquire().unwrap();
+            let written = [pattern(1), pattern(2), pattern(3), pattern(4)];
+            block.write(&written[0..1], BlockIdx(3)).unwrap();
+            block.write(&written[1..], BlockIdx(4)).unwrap();
+            // Block 3 lives at byte 1536, however the card is addressed
+            assert_eq!(&card.image()[1536..2048], &written[0].contents[..]);
+            let mut read_back = [Block::new(), Block::new(), Block::new(), Block::new()];
+            block.read(&mut read_back, BlockIdx(3), "test").unwrap();
+            for (read, written) in read_back.iter().zip(&written) {
+                assert_eq!(read.contents, written.contents);
+            }
+            block
+                .read(&mut read_back[0..1], BlockIdx(5), "test")
+                .unwrap();
+            assert_eq!(read_back[0].contents, written[2].contents);
+            let commands = card.commands();
+            assert_eq!(&commands[commands.len() - 3..], &[CMD18, CMD12, CMD17]);
+        }
+    }
+
+    #[test]
+    fn slow_card() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        {
+            let mut knobs = card.knobs();
+            knobs.response_delay = 8;
+            knobs.read_delay = 100;
+            knobs.busy_bytes = 300;
+            knobs.init_polls = 50;
+        }
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi.acquire().unwrap();
+        let written = [pattern(5), pattern(6)];
+        block.write(&written, BlockIdx(0)).unwrap();
+        block.write(&written[0..1], BlockIdx(2)).unwrap();
+        let mut read_back = [Block::new(), Block::new(), Block::new()];
+        block.read(&mut read_back, BlockIdx(0), "test").unwrap();
+        assert_eq!(read_back[1].contents, written[1].contents);
+        assert_eq!(read_back[2].contents, written[0].contents);
+    }
+
+    #[test]
+    fn card_never_ready() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().init_polls = u32::MAX;
+        let mut spi = sd_mmc_spi(&card);
+        let result = spi.acquire_with_opts(AcquireOpts {
+            init_timeout_ms: 5,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(Error::TimeoutACommand(ACMD41))));
+        // After the first few polls, each one uses up a millisecond
+        let polls = card
+            .commands()
+            .iter()
+            .filter(|&&command| command == ACMD41)
+            .count();
+        assert_eq!(polls, usize::from(FAST_POLLS) + 5 + 1);
+    }
+
+    #[test]
+    fn no_card() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().unresponsive = true;
+        let mut spi = sd_mmc_spi(&card);
+        assert!(matches!(spi.acquire(), Err(Error::CardNotFound)));
+    }
+
+    #[test]
+    fn read_crc_error() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        // Nothing is retried unless we ask for it
+        let block = spi.acquire().unwrap();
+        card.knobs().bad_read_crcs = 1;
+        let mut blocks = [Block::new()];
+        assert!(matches!(
+            block.read(&mut blocks, BlockIdx(0), "test"),
+            Err(Error::CrcError(_, _))
+        ));
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(
+            block.retry_stats(),
+            RetryStats {
+                retries: 0,
+                reinits: 0,
+                failures: 1
+            }
+        );
+    }
+
+    #[test]
+    fn read_retried() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi
+            .acquire_with_opts(AcquireOpts {
+                retries: 2,
+                stop_on_error: true,
+                ..Default::default()
+            })
+            .unwrap();
+        block.write(&[pattern(3)], BlockIdx(1)).unwrap();
+        // A glitch in a multi-block read
+        card.knobs().bad_read_crcs = 1;
+        let mut blocks = [Block::new(), Block::new()];
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(blocks[1].contents, pattern(3).contents);
+        assert_eq!(block.retry_stats().retries, 1);
+        // A card that never gets it right
+        card.knobs().bad_read_crcs = 100;
+        assert!(matches!(
+            block.read(&mut blocks, BlockIdx(0), "test"),
+            Err(Error::CrcError(_, _))
+        ));
+        assert_eq!(
+            block.retry_stats(),
+            RetryStats {
+                retries: 3,
+                reinits: 0,
+                failures: 1
+            }
+        );
+        card.knobs().bad_read_crcs = 0;
+        block.clear_retry_stats();
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(block.retry_stats(), RetryStats::default());
+    }
+
+    #[test]
+    fn write_retried() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi
+            .acquire_with_opts(AcquireOpts {
+                retries: 2,
+                stop_on_error: true,
+                ..Default::default()
+            })
+            .unwrap();
+        card.knobs().bad_write_crcs = 1;
+        block.write(&[pattern(1), pattern(2)], BlockIdx(4)).unwrap();
+        assert_eq!(&card.image()[4 * 512..5 * 512], &pattern(1).contents[..]);
+        assert_eq!(&card.image()[5 * 512..6 * 512], &pattern(2).contents[..]);
+        assert_eq!(block.retry_stats().retries, 1);
+    }
+
+    #[test]
+    fn reinit_after_failures() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi
+            .acquire_with_opts(AcquireOpts {
+                retries: 1,
+                reinit_after_failures: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        card.knobs().bad_read_crcs = 1;
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(
+            block.retry_stats(),
+            RetryStats {
+                retries: 1,
+                reinits: 1,
+                failures: 0
+            }
+        );
+        assert_eq!(card.commands().iter().filter(|&&c| c == CMD0).count(), 2);
+    }
+
+    #[test]
+    fn reinit_without_retries() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi
+            .acquire_with_opts(AcquireOpts {
+                retries: 0,
+                reinit_after_failures: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        card.knobs().bad_read_crcs = 1;
+        let mut blocks = [Block::new()];
+        assert!(block.read(&mut blocks, BlockIdx(0), "test").is_err());
+        assert_eq!(
+            block.retry_stats(),
+            RetryStats {
+                retries: 0,
+                reinits: 1,
+                failures: 1
+            }
+        );
+        assert_eq!(card.commands().iter().filter(|&&c| c == CMD0).count(), 2);
+        // The card was re-initialised ready for the next read
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+    }
+
+    #[test]
+    fn decoded_errors() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi.acquire().unwrap();
+        let mut blocks = [Block::new()];
+        // Off the end of the card
+        match block.read(&mut blocks, BlockIdx(1024), "test") {
+            Err(Error::CommandError(CMD17, status)) => {
+                assert!(status.is_parameter_error());
+                assert!(!status.is_address_error());
+                assert_eq!(format!("{:?}", status), "PARAMETER_ERROR");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // The card couldn't read the block
+        card.knobs().read_error_token = DATA_ERROR_CARD_ECC_FAILED | DATA_ERROR_ERROR;
+        match block.read(&mut blocks, BlockIdx(0), "test") {
+            Err(Error::DataError(token)) => {
+                assert!(token.is_ecc_failed());
+                assert!(!token.is_out_of_range());
+                assert_eq!(format!("{:?}", token), "ERROR|CARD_ECC_FAILED");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // The block got corrupted on the way to the card
+        card.knobs().bad_write_crcs = 1;
+        assert!(matches!(
+            block.write(&[pattern(1)], BlockIdx(0)),
+            Err(Error::WriteCrcError)
+        ));
+        // The card says it didn't like the write
+        card.knobs().card_status = R2_WP_VIOLATION;
+        match block.write(&[pattern(1)], BlockIdx(0)) {
+            Err(Error::CardStatusError(status)) => {
+                assert!(status.is_wp_violation());
+                assert_eq!(format!("{:?}", status), "WP_VIOLATION");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        card.knobs().card_status = 0;
+        block.write(&[pattern(1)], BlockIdx(0)).unwrap();
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(blocks[0].contents, pattern(1).contents);
+    }
+
+    #[test]
+    fn multi_block_write() {
+        // Whether failed reads are stopped makes no difference to writes
+        for stop_on_error in [false, true] {
+            let card = SimCard::new(SimCardType::Sdhc, 1024);
+            let mut spi = sd_mmc_spi(&card);
+            let block = spi
+                .acquire_with_opts(AcquireOpts {
+                    stop_on_error,
+                    ..Default::default()
+                })
+                .unwrap();
+            let blocks = [pattern(1), pattern(2), pattern(3), pattern(4)];
+            // Short writes don't bother asking the card to pre-erase
+            block.write(&blocks, BlockIdx(10)).unwrap();
+            let commands = card.commands();
+            let end = commands.len();
+            assert_eq!(&commands[end - 2..], &[CMD25, CMD13]);
+            assert!(!commands.contains(&ACMD23));
+            let long: Vec<Block> = (0..PRE_ERASE_MIN_BLOCKS as u8).map(pattern).collect();
+            block.write(&long, BlockIdx(30)).unwrap();
+            let commands = card.commands();
+            let end = commands.len();
+            assert_eq!(&commands[end - 4..], &[CMD55, ACMD23, CMD25, CMD13]);
+            assert_eq!(&card.image()[37 * 512..38 * 512], &pattern(7).contents[..]);
+            // The third block fails, so the write is stopped and the card
+            // says only two got written
+            card.knobs().write_error_block = Some(22);
+            assert!(matches!(
+                block.write(&blocks, BlockIdx(20)),
+                Err(Error::PartialWrite(2))
+            ));
+            let commands = card.commands();
+            let end = commands.len();
+            assert_eq!(&commands[end - 4..], &[CMD25, CMD12, CMD55, ACMD22]);
+            assert_eq!(&card.image()[21 * 512..22 * 512], &pattern(2).contents[..]);
+            // The card wrote everything, but says something went wrong
+            card.knobs().write_error_block = None;
+            card.knobs().card_status = R2_CC_ERROR;
+            assert!(matches!(
+                block.write(&blocks, BlockIdx(20)),
+                Err(Error::CardStatusError(status)) if status.is_cc_error()
+            ));
+            // The card is still usable afterwards
+            card.knobs().card_status = 0;
+            block.write(&blocks, BlockIdx(40)).unwrap();
+        }
+    }
+
+    #[test]
+    fn mmc_cards() {
+        for (card_type, num_blocks) in [(SimCardType::Mmc, 64), (SimCardType::Emmc, 4096)] {
+            let card = SimCard::new(card_type, num_blocks);
+            let mut spi = sd_mmc_spi(&card);
+            let mut block = spi.acquire().unwrap();
+            assert!(block.is_mmc());
+            let csd = match block.card_csd().unwrap() {
+                Csd::Mmc(csd) => csd,
+                _ => panic!("expected an MMC CSD"),
+            };
+            assert_eq!(csd.has_ext_csd(), card_type == SimCardType::Emmc);
+            if card_type == SimCardType::Emmc {
+                // The CSD can't describe it, so the Extended CSD has to
+                assert_eq!(csd.device_size(), 0xFFF);
+                let ext_csd = block.card_ext_csd().unwrap();
+                assert_eq!(ext_csd.sector_count(), num_blocks as u32);
+            }
+            assert_eq!(block.num_blocks().unwrap(), BlockCount(num_blocks as u32));
+            let blocks = [pattern(1), pattern(2), pattern(3)];
+            block.write(&blocks, BlockIdx(5)).unwrap();
+            let mut read_back = [Block::new(), Block::new(), Block::new()];
+            block.read(&mut read_back, BlockIdx(5), "test").unwrap();
+            for (written, read) in blocks.iter().zip(read_back.iter()) {
+                assert_eq!(written.contents, read.contents);
+            }
+            block.erase(BlockIdx(6), BlockIdx(6)).unwrap();
+            assert!(card.image()[6 * 512..7 * 512].iter().all(|&b| b == 0));
+            assert_eq!(&card.image()[7 * 512..8 * 512], &pattern(3).contents[..]);
+            assert!(card.commands().contains(&CMD35));
+            assert!(!card.commands().contains(&ACMD23));
+        }
+        // SD cards don't have an Extended CSD
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi.acquire().unwrap();
+        assert!(!block.is_mmc());
+        assert!(matches!(block.card_ext_csd(), Err(Error::BadState)));
+    }
+
+    #[test]
+    fn password_lock() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let mut blocks = [Block::new()];
+        {
+            let mut block = spi.acquire().unwrap();
+            assert!(!block.is_locked().unwrap());
+            block.write(&[pattern(7)], BlockIdx(3)).unwrap();
+            block.set_password(b"secret").unwrap();
+            // Nothing changes until it is locked
+            assert!(!block.is_locked().unwrap());
+            assert!(matches!(block.lock(b"wrong"), Err(Error::LockUnlockFailed)));
+            block.lock(b"secret").unwrap();
+            assert!(block.is_locked().unwrap());
+            assert!(matches!(
+                block.read(&mut blocks, BlockIdx(3), "test"),
+                Err(Error::CardLocked)
+            ));
+            assert!(matches!(
+                block.set_password(&[0u8; 17]),
+                Err(Error::PasswordTooLong)
+            ));
+        }
+        // Starting again finds it still locked
+        card.knobs().inserted = false;
+        card.knobs().inserted = true;
+        let mut block = spi.acquire().unwrap();
+        assert!(block.is_locked().unwrap());
+        assert!(matches!(
+            block.write(&[pattern(1)], BlockIdx(3)),
+            Err(Error::CardLocked)
+        ));
+        assert!(matches!(
+            block.unlock(b"guess"),
+            Err(Error::LockUnlockFailed)
+        ));
+        block.unlock(b"secret").unwrap();
+        block.read(&mut blocks, BlockIdx(3), "test").unwrap();
+        assert_eq!(blocks[0].contents, pattern(7).contents);
+        block.change_password(b"secret", b"better").unwrap();
+        block.clear_password(b"better").unwrap();
+        block.lock(b"better").unwrap_err();
+        assert!(!block.is_locked().unwrap());
+    }
+
+    #[test]
+    fn password_forgotten() {
+        let card = SimCard::from_image(SimCardType::Sdhc, vec![0xAA; 1024 * 512]);
+        card.lock_with(b"forgotten");
+        let mut spi = sd_mmc_spi(&card);
+        let mut block = spi.acquire().unwrap();
+        assert!(block.is_locked().unwrap());
+        block.force_erase().unwrap();
+        assert!(!block.is_locked().unwrap());
+        assert!(card.image().iter().all(|&b| b == 0));
+        block.write(&[pattern(1)], BlockIdx(0)).unwrap();
+    }
+
+    /// An async transport for the simulated card, which makes the caller wait
+    /// once before every transfer
+    #[cfg(feature = "async")]
+    struct AsyncSimTransport(SpiCsTransport<SimSpi, SimCs, SimDelay>);
+
+    #[cfg(feature = "async")]
+    impl AsyncSpiTransport for AsyncSimTransport {
+        async fn select(&mut self) -> Result<(), Error> {
+            SpiTransport::select(&mut self.0)
+        }
+
+        async fn deselect(&mut self) -> Result<(), Error> {
+            SpiTransport::deselect(&mut self.0)
+        }
+
+        async fn send_initial_clocks(&mut self) -> Result<(), Error> {
+            SpiTransport::send_initial_clocks(&mut self.0)
+        }
+
+        async fn transfer(&mut self, data: &mut [u8]) -> Result<(), Error> {
+            yield_once().await;
+            SpiTransport::transfer(&mut self.0, data)
+        }
+
+        async fn delay_us(&mut self, us: u8) {
+            SpiTransport::delay_us(&mut self.0, us)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_read_write() {
+        use crate::AsyncBlockDevice;
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let transport = SpiCsTransport::new(card.spi(), card.cs(), SimDelay);
+        let mut spi = AsyncSdMmcSpi::with_transport(AsyncSimTransport(transport));
+        let written = [pattern(1), pattern(2), pattern(3)];
+        let mut read_back = [Block::new(), Block::new(), Block::new()];
+        let (result, waits) = run(async {
+            let mut block = spi.acquire().await?;
+            block.write(&written[0..1], BlockIdx(3)).await?;
+            block.write(&written[1..], BlockIdx(4)).await?;
+            block.read(&mut read_back, BlockIdx(3), "test").await?;
+            block.num_blocks().await
+        });
+        assert_eq!(result.unwrap(), BlockCount(1024));
+        assert!(waits > 0);
+        assert_eq!(&card.image()[1536..2048], &written[0].contents[..]);
+        for (read, written) in read_back.iter().zip(&written) {
+            assert_eq!(read.contents, written.contents);
+        }
+        let commands = card.commands();
+        assert_eq!(&commands[commands.len() - 3..], &[CMD18, CMD12, CMD9]);
+    }
+
+    #[test]
+    fn erase() {
+        for (card_type, num_blocks) in [(SimCardType::Sd1, 64), (SimCardType::Sdhc, 1024)] {
+            let card = SimCard::new(card_type, num_blocks);
+            let mut spi = sd_mmc_spi(&card);
+            let mut block = spi.acquire().unwrap();
+            let blocks = [pattern(1), pattern(2), pattern(3), pattern(4)];
+            block.write(&blocks, BlockIdx(2)).unwrap();
+            block.erase(BlockIdx(3), BlockIdx(4)).unwrap();
+            // Block 3 lives at byte 1536, however the card is addressed
+            assert_eq!(&card.image()[1024..1536], &pattern(1).contents[..]);
+            assert!(card.image()[1536..2560].iter().all(|&b| b == 0));
+            assert_eq!(&card.image()[2560..3072], &pattern(4).contents[..]);
+            let commands = card.commands();
+            assert_eq!(&commands[commands.len() - 3..], &[CMD32, CMD33, CMD38]);
+        }
+    }
+
+    #[test]
+    fn erase_timeout() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().busy_bytes = 100;
+        let mut spi = sd_mmc_spi(&card);
+        let mut block = spi
+            .acquire_with_opts(AcquireOpts {
+                erase_timeout_ms: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        // One block only gets a millisecond, which is a dozen polls
+        assert!(matches!(
+            block.erase(BlockIdx(0), BlockIdx(0)),
+            Err(Error::TimeoutWaitNotBusy)
+        ));
+        // A hundred blocks get a hundred times as long
+        block.erase(BlockIdx(0), BlockIdx(99)).unwrap();
+    }
+
+    #[test]
+    fn erase_refused() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().write_protect = true;
+        let mut spi = socket(&card);
+        let mut block = spi.acquire().unwrap();
+        assert!(matches!(
+            block.erase(BlockIdx(0), BlockIdx(1)),
+            Err(Error::WriteProtected)
+        ));
+        assert!(!card.commands().contains(&CMD32));
+        let card = SimCard::from_image(SimCardType::Sdhc, vec![0xAA; 1024 * 512]);
+        card.lock_with(b"secret");
+        let mut spi = sd_mmc_spi(&card);
+        let mut block = spi.acquire().unwrap();
+        assert!(matches!(
+            block.erase(BlockIdx(0), BlockIdx(1)),
+            Err(Error::CardLocked)
+        ));
+        // Neither card was asked to erase anything
+        assert!(!card.commands().contains(&CMD32));
+        assert!(card.image().iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn clock_switch() {
+        // card type, ask for high speed, card can do it, expected clock, CMD6s
+        for (card_type, high_speed, capable, expected, switches) in [
+            (SimCardType::Sdhc, false, true, 25_000_000, 0),
+            (SimCardType::Sdhc, true, true, 50_000_000, 2),
+            (SimCardType::Sd2, true, false, 25_000_000, 1),
+            (SimCardType::Sd1, true, true, 25_000_000, 0),
+        ] {
+            let card = SimCard::new(card_type, 1024);
+            card.knobs().high_speed = capable;
+            let clocks = RefCell::new(Vec::new());
+            let mut spi = sd_mmc_spi(&card).with_clock(|hz| {
+                clocks.borrow_mut().push(hz);
+                Ok(())
+            });
+            let block = spi
+                .acquire_with_opts(AcquireOpts {
+                    high_speed,
+                    ..Default::default()
+                })
+                .unwrap();
+            let commands = card.commands();
+            assert_eq!(commands.iter().filter(|&&c| c == CMD6).count(), switches);
+            // The card still works afterwards
+            let mut blocks = [Block::new()];
+            block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+            drop(block);
+            assert_eq!(*clocks.borrow(), [INIT_CLOCK_HZ, expected]);
+        }
+    }
+
+    fn socket(card: &SimCard) -> SimSocketSpi {
+        let transport = SpiCsTransport::new(card.spi(), card.cs(), SimDelay);
+        SdMmcSpi::with_transport(
+            CardSocket::new(transport)
+                .with_card_detect(SwitchPin::active_low(card.card_detect()))
+                .with_write_protect(SwitchPin::active_high(card.write_protect()))
+                .with_power(PowerPin::active_high(card.power())),
+        )
+    }
+
+    #[test]
+    fn no_card_in_socket() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().inserted = false;
+        let mut spi = socket(&card);
+        assert!(!spi.card_present().unwrap());
+        assert!(matches!(spi.acquire(), Err(Error::CardNotFound)));
+        // We didn't even try talking to it
+        assert!(card.commands().is_empty());
+    }
+
+    #[test]
+    fn card_pulled_out() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = socket(&card);
+        let mut blocks = [Block::new()];
+        {
+            let block = spi.acquire().unwrap();
+            block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+            card.knobs().inserted = false;
+            assert!(!block.card_present().unwrap());
+            assert!(matches!(
+                block.read(&mut blocks, BlockIdx(0), "test"),
+                Err(Error::CardNotFound)
+            ));
+            // Putting it back isn't enough - it needs initialising again
+            card.knobs().inserted = true;
+            assert!(block.card_present().unwrap());
+            assert!(matches!(
+                block.read(&mut blocks, BlockIdx(0), "test"),
+                Err(Error::BadState)
+            ));
+        }
+        let block = spi.acquire().unwrap();
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+    }
+
+    #[test]
+    fn write_protected_card() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        card.knobs().write_protect = true;
+        let mut spi = socket(&card);
+        let mut block = spi.acquire().unwrap();
+        assert!(block.write_protected().unwrap());
+        assert!(matches!(
+            block.write(&[pattern(1)], BlockIdx(0)),
+            Err(Error::WriteProtected)
+        ));
+        assert!(matches!(
+            block.erase(BlockIdx(0), BlockIdx(1)),
+            Err(Error::WriteProtected)
+        ));
+        assert!(card.image().iter().all(|&b| b == 0));
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        card.knobs().write_protect = false;
+        block.write(&[pattern(1)], BlockIdx(0)).unwrap();
+    }
+
+    #[test]
+    fn sleep_and_reacquire() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi.acquire().unwrap();
+        block.write(&[pattern(1)], BlockIdx(2)).unwrap();
+        let commands = card.commands().len();
+        block.sleep().unwrap();
+        assert_eq!(card.commands()[commands..], [CMD13]);
+        // It is only checked, not initialised again
+        let commands = card.commands().len();
+        let block = spi.reacquire().unwrap();
+        assert_eq!(card.commands()[commands..], [CMD13]);
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(2), "test").unwrap();
+        assert_eq!(blocks[0].contents, pattern(1).contents);
+        // Without a power switch, powering down is the same as sleeping
+        block.power_down().unwrap();
+        let commands = card.commands().len();
+        let block = spi.reacquire().unwrap();
+        assert_eq!(card.commands()[commands..], [CMD13]);
+        // A card which was just dropped has to be initialised again
+        drop(block);
+        let commands = card.commands().len();
+        let block = spi.reacquire().unwrap();
+        assert_eq!(card.commands()[commands], CMD0);
+        block.read(&mut blocks, BlockIdx(2), "test").unwrap();
+    }
+
+    #[test]
+    fn power_down() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = socket(&card);
+        let block = spi.acquire().unwrap();
+        block.write(&[pattern(1)], BlockIdx(2)).unwrap();
+        block.power_down().unwrap();
+        assert!(!card.powered());
+        let commands = card.commands().len();
+        let block = spi.reacquire().unwrap();
+        assert!(card.powered());
+        assert_eq!(card.commands()[commands], CMD0);
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(2), "test").unwrap();
+        assert_eq!(blocks[0].contents, pattern(1).contents);
+        // Acquiring it from scratch powers it up too
+        block.power_down().unwrap();
+        assert!(!card.powered());
+        spi.acquire().unwrap();
+        assert!(card.powered());
+    }
+
+    #[test]
+    fn card_too_large() {
+        let card = SimCard::new(SimCardType::Sdhc, 1024);
+        let mut spi = sd_mmc_spi(&card);
+        // The biggest an SDXC card can be still fits
+        card.knobs().claimed_blocks = Some(0x3F_FF00 * 1024);
+        let block = spi.acquire().unwrap();
+        assert!(matches!(block.card_csd(), Ok(Csd::V2(_))));
+        assert_eq!(block.num_blocks().unwrap(), BlockCount(0xFFFC_0000));
+        drop(block);
+        // An SDUC card doesn't, but the blocks we can address still work
+        card.knobs().claimed_blocks = Some(4 << 31);
+        let block = spi.acquire().unwrap();
+        assert!(matches!(block.card_csd(), Ok(Csd::V3(_))));
+        assert_eq!(block.card_size_bytes().unwrap(), 4 << 40);
+        assert!(matches!(block.num_blocks(), Err(Error::CardTooLarge)));
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+    }
+
+    #[test]
+    fn disk_image_file() {
+        let mut image = vec![0u8; 64 * 512];
+        image[510] = 0x55;
+        image[511] = 0xAA;
+        let path = std::env::temp_dir().join("embedded-sdmmc-sim-test.img");
+        std::fs::write(&path, &image).unwrap();
+        let card = SimCard::from_file(SimCardType::Sd1, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut spi = sd_mmc_spi(&card);
+        let block = spi.acquire().unwrap();
+        let mut blocks = [Block::new()];
+        block.read(&mut blocks, BlockIdx(0), "test").unwrap();
+        assert_eq!(blocks[0].contents[510..], [0x55, 0xAA]);
+    }
+}
+
 // ****************************************************************************
 //
 // End Of File
@@ -38,14 +38,31 @@ pub const ERROR_OK: u8 = 0x00;
 
 /// GO_IDLE_STATE - init card in spi mode if CS low
 pub const CMD0: u8 = 0x00;
+/// SEND_OP_COND - start an MMC card's initialization process
+pub const CMD1: u8 = 0x01;
+/// ALL_SEND_CID - ask every card on the SD bus to send its CID
+pub const CMD2: u8 = 0x02;
+/// SEND_RELATIVE_ADDR - ask the card on the SD bus to publish a new RCA
+pub const CMD3: u8 = 0x03;
+/// SWITCH_FUNC - check or switch the card's functions, such as high-speed
+/// mode
+pub const CMD6: u8 = 0x06;
+/// SELECT/DESELECT_CARD - move the card with the given RCA to the transfer
+/// state
+pub const CMD7: u8 = 0x07;
 /// SEND_IF_COND - verify SD Memory Card interface operating condition.*/
+/// On an initialised MMC card, SEND_EXT_CSD - read the Extended CSD
 pub const CMD8: u8 = 0x08;
 /// SEND_CSD - read the Card Specific Data (CSD register)
 pub const CMD9: u8 = 0x09;
+/// SEND_CID - read the Card Identification (CID register)
+pub const CMD10: u8 = 0x0A;
 /// STOP_TRANSMISSION - end multiple block read sequence
 pub const CMD12: u8 = 0x0C;
 /// SEND_STATUS - read the card status register
 pub const CMD13: u8 = 0x0D;
+/// SET_BLOCKLEN - set the block length used by SDSC cards
+pub const CMD16: u8 = 0x10;
 /// READ_SINGLE_BLOCK - read a single data block from the card
 pub const CMD17: u8 = 0x11;
 /// READ_MULTIPLE_BLOCK - read a multiple data blocks from the card
@@ -54,15 +71,39 @@ pub const CMD18: u8 = 0x12;
 pub const CMD24: u8 = 0x18;
 /// WRITE_MULTIPLE_BLOCK - write blocks of data until a STOP_TRANSMISSION
 pub const CMD25: u8 = 0x19;
+/// ERASE_WR_BLK_START_ADDR - set the address of the first block to erase
+pub const CMD32: u8 = 0x20;
+/// ERASE_WR_BLK_END_ADDR - set the address of the last block to erase
+pub const CMD33: u8 = 0x21;
+/// ERASE_GROUP_START - set the address of the first MMC erase group to erase
+pub const CMD35: u8 = 0x23;
+/// ERASE_GROUP_END - set the address of the last MMC erase group to erase
+pub const CMD36: u8 = 0x24;
+/// ERASE - erase the selected blocks
+pub const CMD38: u8 = 0x26;
+/// LOCK_UNLOCK - set or clear the card's password, or lock or unlock it
+pub const CMD42: u8 = 0x2A;
 /// APP_CMD - escape for application specific command
 pub const CMD55: u8 = 0x37;
 /// READ_OCR - read the OCR register of a card
 pub const CMD58: u8 = 0x3A;
 /// CRC_ON_OFF - enable or disable CRC checking
 pub const CMD59: u8 = 0x3B;
+/// SET_BUS_WIDTH - switch the SD bus between 1 and 4 data lines
+pub const ACMD6: u8 = 0x06;
+/// SD_STATUS - read the 64-byte SD Status register
+pub const ACMD13: u8 = 0x0D;
+/// SEND_NUM_WR_BLOCKS - read how many blocks of the last multi-block write
+/// were written without errors
+pub const ACMD22: u8 = 0x16;
+/// SET_WR_BLK_ERASE_COUNT - say how many blocks the next multi-block write
+/// will be, so the card can erase them first
+pub const ACMD23: u8 = 0x17;
 /// SD_SEND_OP_COMD - Sends host capacity support information and activates
 /// the card's initialization process
 pub const ACMD41: u8 = 0x29;
+/// SEND_SCR - read the SD Configuration Register
+pub const ACMD51: u8 = 0x33;
 
 //==============================================================================
 
@@ -72,9 +113,51 @@ pub const R1_READY_STATE: u8 = 0x00;
 /// status for card in the idle state
 pub const R1_IDLE_STATE: u8 = 0x01;
 
+/// status bit for an erase sequence cleared by another command
+pub const R1_ERASE_RESET: u8 = 0x02;
+
 /// status bit for illegal command
 pub const R1_ILLEGAL_COMMAND: u8 = 0x04;
 
+/// status bit for a command with a bad CRC
+pub const R1_COM_CRC_ERROR: u8 = 0x08;
+
+/// status bit for an erase command sent out of order
+pub const R1_ERASE_SEQUENCE_ERROR: u8 = 0x10;
+
+/// status bit for a misaligned address
+pub const R1_ADDRESS_ERROR: u8 = 0x20;
+
+/// status bit for an argument out of range, such as an address past the end
+/// of the card
+pub const R1_PARAMETER_ERROR: u8 = 0x40;
+
+/// R2 (second byte) status bit for a locked card
+pub const R2_CARD_LOCKED: u8 = 0x01;
+
+/// R2 (second byte) status bit for a write-protected erase being skipped, or
+/// a lock/unlock command failing
+pub const R2_WP_ERASE_SKIP: u8 = 0x02;
+
+/// R2 (second byte) status bit for an unspecified error
+pub const R2_ERROR: u8 = 0x04;
+
+/// R2 (second byte) status bit for an internal card controller error
+pub const R2_CC_ERROR: u8 = 0x08;
+
+/// R2 (second byte) status bit for the card's ECC failing to correct the data
+pub const R2_CARD_ECC_FAILED: u8 = 0x10;
+
+/// R2 (second byte) status bit for a write to a write-protected block
+pub const R2_WP_VIOLATION: u8 = 0x20;
+
+/// R2 (second byte) status bit for an invalid selection of blocks to erase
+pub const R2_ERASE_PARAM: u8 = 0x40;
+
+/// R2 (second byte) status bit for an argument out of range, or a CSD
+/// overwrite
+pub const R2_OUT_OF_RANGE: u8 = 0x80;
+
 /// start data token for read or write single block*/
 pub const DATA_START_BLOCK: u8 = 0xFE;
 
@@ -90,6 +173,168 @@ pub const DATA_RES_MASK: u8 = 0x1F;
 /// write data accepted token
 pub const DATA_RES_ACCEPTED: u8 = 0x05;
 
+/// write data rejected because of a CRC error token
+pub const DATA_RES_CRC_ERROR: u8 = 0x0B;
+
+/// write data rejected because of a write error token
+pub const DATA_RES_WRITE_ERROR: u8 = 0x0D;
+
+/// mask for data error tokens, sent instead of a start block token when a
+/// read fails
+pub const DATA_ERROR_TOKEN_MASK: u8 = 0xE0;
+
+/// data error token bit for an unspecified error
+pub const DATA_ERROR_ERROR: u8 = 0x01;
+
+/// data error token bit for an internal card controller error
+pub const DATA_ERROR_CC_ERROR: u8 = 0x02;
+
+/// data error token bit for the card's ECC failing to correct the data
+pub const DATA_ERROR_CARD_ECC_FAILED: u8 = 0x04;
+
+/// data error token bit for an address out of range
+pub const DATA_ERROR_OUT_OF_RANGE: u8 = 0x08;
+
+/// data error token bit for a locked card
+pub const DATA_ERROR_CARD_LOCKED: u8 = 0x10;
+
+/// LOCK_UNLOCK flag to set a new password
+pub const LOCK_SET_PASSWORD: u8 = 0x01;
+
+/// LOCK_UNLOCK flag to clear the password
+pub const LOCK_CLEAR_PASSWORD: u8 = 0x02;
+
+/// LOCK_UNLOCK flag to lock the card (or unlock it, if clear)
+pub const LOCK_LOCK: u8 = 0x04;
+
+/// LOCK_UNLOCK flag to erase the whole card, password and all
+pub const LOCK_ERASE: u8 = 0x08;
+
+/// The longest password a card can have
+pub const MAX_PASSWORD_LEN: usize = 16;
+
+/// SD bus card status bits which indicate an error
+pub const CARD_STATUS_ERROR_MASK: u32 = 0xFDF9_8008;
+
+/// SD bus card status bit set when the card can accept more data
+pub const CARD_STATUS_READY_FOR_DATA: u32 = 1 << 8;
+
+/// SD bus card status CURRENT_STATE value for the transfer state
+pub const CARD_STATE_TRAN: u32 = 4;
+
+/// OCR bit set once the card has finished powering up
+pub const OCR_POWER_UP_DONE: u32 = 1 << 31;
+
+/// OCR bit set if the card is high capacity (SDHC or SDXC)
+pub const OCR_CCS: u32 = 1 << 30;
+
+/// OCR access mode bits of an MMC card
+pub const OCR_MMC_ACCESS_MODE: u32 = 3 << 29;
+
+/// OCR access mode of an MMC card which is addressed in sectors, not bytes
+pub const OCR_MMC_SECTOR_MODE: u32 = 2 << 29;
+
+/// OCR voltage window bits for 2.7 V to 3.6 V
+pub const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+/// Multipliers (in tenths) for the TAAC and TRAN_SPEED fields
+const TIME_VALUE_TENTHS: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
+/// Multipliers (in tenths) for the TAAC and TRAN_SPEED fields of an MMC card,
+/// which differ in two places
+const MMC_TIME_VALUE_TENTHS: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 26, 30, 35, 40, 45, 52, 55, 60, 70, 80,
+];
+
+/// How the data on a card is laid out, according to its CSD.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Hard disk-like file system with a partition table
+    PartitionTable,
+    /// DOS FAT (floppy-like) with boot sector only, no partition table
+    BootSectorOnly,
+    /// Universal File Format
+    Universal,
+    /// Others or unknown
+    Other,
+}
+
+/// Decoding methods which are common to every version of the CSD, given the
+/// table of time value multipliers it uses.
+macro_rules! csd_common_methods {
+    ($time_values:expr) => {
+        /// Returns the asynchronous part of the read access time (TAAC) in
+        /// nanoseconds
+        pub fn read_access_time_ns(&self) -> u32 {
+            let taac = self.data_read_access_time1();
+            let unit_ns = 10u32.pow(u32::from(taac & 0x07));
+            $time_values[usize::from((taac >> 3) & 0x0F)] * unit_ns / 10
+        }
+
+        /// Returns the clock-dependent part of the read access time (NSAC)
+        /// in clock cycles
+        pub fn read_access_clocks(&self) -> u32 {
+            u32::from(self.data_read_access_time2()) * 100
+        }
+
+        /// Returns the maximum transfer rate (TRAN_SPEED) in bits per second,
+        /// or zero if the card reports a reserved value.
+        pub fn max_transfer_rate_bps(&self) -> u32 {
+            let tran_speed = self.max_data_transfer_rate();
+            let unit = tran_speed & 0x07;
+            if unit > 3 {
+                return 0;
+            }
+            let unit_bps = 100_000 * 10u32.pow(u32::from(unit));
+            $time_values[usize::from((tran_speed >> 3) & 0x0F)] * (unit_bps / 10)
+        }
+
+        /// Does the card support the given command class (0 to 11)?
+        pub fn supports_command_class(&self, class: u8) -> bool {
+            class < 12 && (self.card_command_classes() & (1 << class)) != 0
+        }
+
+        /// Returns the maximum read block length in bytes
+        pub fn read_block_length_bytes(&self) -> u32 {
+            1 << self.read_block_length()
+        }
+
+        /// Returns the maximum write block length in bytes
+        pub fn max_write_data_length_bytes(&self) -> u32 {
+            1 << self.max_write_data_length()
+        }
+
+        /// Returns the size of a write protect group, in write blocks
+        pub fn write_protect_group_size_blocks(&self) -> u32 {
+            (u32::from(self.write_protect_group_size()) + 1) * self.erase_sector_size_blocks()
+        }
+
+        /// Returns how many times longer a block write takes than a block
+        /// read
+        pub fn write_speed_multiplier(&self) -> u32 {
+            1 << self.write_speed_factor()
+        }
+
+        /// Returns the file format the card says it holds
+        pub fn file_format_type(&self) -> FileFormat {
+            match (self.file_format_group_set(), self.file_format()) {
+                (false, 0) => FileFormat::PartitionTable,
+                (false, 1) => FileFormat::BootSectorOnly,
+                (false, 2) => FileFormat::Universal,
+                _ => FileFormat::Other,
+            }
+        }
+
+        /// Is the card write protected, either permanently or temporarily?
+        pub fn is_write_protected(&self) -> bool {
+            self.permanent_write_protection() || self.temporary_write_protection()
+        }
+    };
+}
+
 /// Card Specific Data, version 1
 #[derive(Default)]
 pub struct CsdV1 {
@@ -104,12 +349,30 @@ pub struct CsdV2 {
     pub data: [u8; 16],
 }
 
+/// Card Specific Data, version 3 (SDUC cards, bigger than 2 TiB)
+#[derive(Default)]
+pub struct CsdV3 {
+    /// The 16-bytes of data in this Card Specific Data block
+    pub data: [u8; 16],
+}
+
+/// Card Specific Data of an MMC or eMMC card
+#[derive(Default)]
+pub struct CsdMmc {
+    /// The 16-bytes of data in this Card Specific Data block
+    pub data: [u8; 16],
+}
+
 /// Card Specific Data
 pub enum Csd {
     /// A version 1 CSD
     V1(CsdV1),
     /// A version 2 CSD
     V2(CsdV2),
+    /// A version 3 CSD
+    V3(CsdV3),
+    /// The CSD of an MMC card
+    Mmc(CsdMmc),
 }
 
 impl CsdV1 {
@@ -148,6 +411,8 @@ impl CsdV1 {
     define_field!(file_format_group_set, bool, 14, 7);
     define_field!(crc, u8, 15, 0, 8);
 
+    csd_common_methods!(TIME_VALUE_TENTHS);
+
     /// Returns the card capacity in bytes
     pub fn card_capacity_bytes(&self) -> u64 {
         let multiplier = self.device_size_multiplier() + self.read_block_length() + 2;
@@ -159,6 +424,11 @@ impl CsdV1 {
         let multiplier = self.device_size_multiplier() + self.read_block_length() - 7;
         (self.device_size() + 1) << multiplier
     }
+
+    /// Returns the size of an erasable sector, in write blocks
+    pub fn erase_sector_size_blocks(&self) -> u32 {
+        u32::from(self.erase_sector_size()) + 1
+    }
 }
 
 impl CsdV2 {
@@ -192,14 +462,479 @@ impl CsdV2 {
     define_field!(file_format_group_set, bool, 14, 7);
     define_field!(crc, u8, 15, 0, 8);
 
+    csd_common_methods!(TIME_VALUE_TENTHS);
+
+    /// Returns the card capacity in bytes
+    pub fn card_capacity_bytes(&self) -> u64 {
+        (u64::from(self.device_size()) + 1) * 512 * 1024
+    }
+
+    /// Returns the card capacity in 512-byte blocks
+    pub fn card_capacity_blocks(&self) -> u64 {
+        (u64::from(self.device_size()) + 1) * 1024
+    }
+
+    /// Returns the size of an erasable sector, in write blocks
+    pub fn erase_sector_size_blocks(&self) -> u32 {
+        u32::from(self.erase_sector_size()) + 1
+    }
+}
+
+impl CsdV3 {
+    /// Create a new, empty, CSD
+    pub fn new() -> CsdV3 {
+        CsdV3::default()
+    }
+
+    define_field!(csd_ver, u8, 0, 6, 2);
+    define_field!(data_read_access_time1, u8, 1, 0, 8);
+    define_field!(data_read_access_time2, u8, 2, 0, 8);
+    define_field!(max_data_transfer_rate, u8, 3, 0, 8);
+    define_field!(card_command_classes, u16, [(4, 0, 8), (5, 4, 4)]);
+    define_field!(read_block_length, u8, 5, 0, 4);
+    define_field!(read_partial_blocks, bool, 6, 7);
+    define_field!(write_block_misalignment, bool, 6, 6);
+    define_field!(read_block_misalignment, bool, 6, 5);
+    define_field!(dsr_implemented, bool, 6, 4);
+    define_field!(
+        device_size,
+        u32,
+        [(6, 0, 4), (7, 0, 8), (8, 0, 8), (9, 0, 8)]
+    );
+    define_field!(erase_single_block_enabled, bool, 10, 6);
+    define_field!(erase_sector_size, u8, [(10, 0, 6), (11, 7, 1)]);
+    define_field!(write_protect_group_size, u8, 11, 0, 7);
+    define_field!(write_protect_group_enable, bool, 12, 7);
+    define_field!(write_speed_factor, u8, 12, 2, 3);
+    define_field!(max_write_data_length, u8, [(12, 0, 2), (13, 6, 2)]);
+    define_field!(write_partial_blocks, bool, 13, 5);
+    define_field!(file_format, u8, 14, 2, 2);
+    define_field!(temporary_write_protection, bool, 14, 4);
+    define_field!(permanent_write_protection, bool, 14, 5);
+    define_field!(copy_flag_set, bool, 14, 6);
+    define_field!(file_format_group_set, bool, 14, 7);
+    define_field!(crc, u8, 15, 0, 8);
+
+    csd_common_methods!(TIME_VALUE_TENTHS);
+
     /// Returns the card capacity in bytes
     pub fn card_capacity_bytes(&self) -> u64 {
         (u64::from(self.device_size()) + 1) * 512 * 1024
     }
 
     /// Returns the card capacity in 512-byte blocks
+    pub fn card_capacity_blocks(&self) -> u64 {
+        (u64::from(self.device_size()) + 1) * 1024
+    }
+
+    /// Returns the size of an erasable sector, in write blocks
+    pub fn erase_sector_size_blocks(&self) -> u32 {
+        u32::from(self.erase_sector_size()) + 1
+    }
+}
+
+impl CsdMmc {
+    /// Create a new, empty, CSD
+    pub fn new() -> CsdMmc {
+        CsdMmc::default()
+    }
+
+    define_field!(csd_ver, u8, 0, 6, 2);
+    define_field!(spec_version, u8, 0, 2, 4);
+    define_field!(data_read_access_time1, u8, 1, 0, 8);
+    define_field!(data_read_access_time2, u8, 2, 0, 8);
+    define_field!(max_data_transfer_rate, u8, 3, 0, 8);
+    define_field!(card_command_classes, u16, [(4, 0, 8), (5, 4, 4)]);
+    define_field!(read_block_length, u8, 5, 0, 4);
+    define_field!(read_partial_blocks, bool, 6, 7);
+    define_field!(write_block_misalignment, bool, 6, 6);
+    define_field!(read_block_misalignment, bool, 6, 5);
+    define_field!(dsr_implemented, bool, 6, 4);
+    define_field!(device_size, u32, [(6, 0, 2), (7, 0, 8), (8, 6, 2)]);
+    define_field!(max_read_current_vdd_max, u8, 8, 0, 3);
+    define_field!(max_read_current_vdd_min, u8, 8, 3, 3);
+    define_field!(max_write_current_vdd_max, u8, 9, 2, 3);
+    define_field!(max_write_current_vdd_min, u8, 9, 5, 3);
+    define_field!(device_size_multiplier, u8, [(9, 0, 2), (10, 7, 1)]);
+    define_field!(erase_group_size, u8, 10, 2, 5);
+    define_field!(erase_group_multiplier, u8, [(10, 0, 2), (11, 5, 3)]);
+    define_field!(write_protect_group_size, u8, 11, 0, 5);
+    define_field!(write_protect_group_enable, bool, 12, 7);
+    define_field!(default_ecc, u8, 12, 5, 2);
+    define_field!(write_speed_factor, u8, 12, 2, 3);
+    define_field!(max_write_data_length, u8, [(12, 0, 2), (13, 6, 2)]);
+    define_field!(write_partial_blocks, bool, 13, 5);
+    define_field!(content_protection_app, bool, 13, 0);
+    define_field!(file_format, u8, 14, 2, 2);
+    define_field!(temporary_write_protection, bool, 14, 4);
+    define_field!(permanent_write_protection, bool, 14, 5);
+    define_field!(copy_flag_set, bool, 14, 6);
+    define_field!(file_format_group_set, bool, 14, 7);
+    define_field!(ecc, u8, 14, 0, 2);
+    define_field!(crc, u8, 15, 0, 8);
+
+    csd_common_methods!(MMC_TIME_VALUE_TENTHS);
+
+    /// Does the card have an Extended CSD, which says how big it really is?
+    /// Cards bigger than 2 GiB can't describe their size in the CSD.
+    pub fn has_ext_csd(&self) -> bool {
+        self.csd_ver() == 3 || self.spec_version() >= 4
+    }
+
+    /// Returns the card capacity in bytes, as far as the CSD can say (see
+    /// `has_ext_csd`).
+    pub fn card_capacity_bytes(&self) -> u64 {
+        let multiplier = self.device_size_multiplier() + self.read_block_length() + 2;
+        (u64::from(self.device_size()) + 1) << multiplier
+    }
+
+    /// Returns the card capacity in 512-byte blocks, as far as the CSD can
+    /// say (see `has_ext_csd`).
     pub fn card_capacity_blocks(&self) -> u32 {
-        (self.device_size() + 1) * 1024
+        let multiplier = self.device_size_multiplier() + self.read_block_length() - 7;
+        (self.device_size() + 1) << multiplier
+    }
+
+    /// Returns the size of an erase group, which is the smallest unit MMC
+    /// cards can erase, in write blocks
+    pub fn erase_sector_size_blocks(&self) -> u32 {
+        (u32::from(self.erase_group_size()) + 1) * (u32::from(self.erase_group_multiplier()) + 1)
+    }
+}
+
+/// The Extended CSD of an MMC card (version 4 or later), which describes
+/// cards too big for their CSD, and their newer features.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ExtCsd {
+    /// The 512-bytes of data in this Extended CSD register
+    pub data: [u8; 512],
+}
+
+impl Default for ExtCsd {
+    fn default() -> Self {
+        ExtCsd { data: [0u8; 512] }
+    }
+}
+
+impl ExtCsd {
+    /// Create a new, empty, Extended CSD
+    pub fn new() -> ExtCsd {
+        ExtCsd::default()
+    }
+
+    define_field!(hs_timing, u8, 185);
+    define_field!(ext_csd_revision, u8, 192);
+    define_field!(csd_structure, u8, 194);
+    define_field!(device_type, u8, 196);
+
+    /// Returns the number of 512-byte sectors on the card. Only cards bigger
+    /// than 2 GiB set this.
+    pub fn sector_count(&self) -> u32 {
+        u32::from_le_bytes([
+            self.data[212],
+            self.data[213],
+            self.data[214],
+            self.data[215],
+        ])
+    }
+
+    /// Returns the card capacity in bytes
+    pub fn card_capacity_bytes(&self) -> u64 {
+        u64::from(self.sector_count()) * 512
+    }
+}
+
+impl core::fmt::Debug for ExtCsd {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ExtCsd")
+            .field("ext_csd_revision", &self.ext_csd_revision())
+            .field("device_type", &self.device_type())
+            .field("sector_count", &self.sector_count())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for ExtCsd {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ExtCsd(ext_csd_revision={}, device_type={}, sector_count={})",
+            self.ext_csd_revision(),
+            self.device_type(),
+            self.sector_count()
+        )
+    }
+}
+
+/// Card Identification register
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct Cid {
+    /// The 16-bytes of data in this Card Identification block
+    pub data: [u8; 16],
+}
+
+impl Cid {
+    /// Create a new, empty, CID
+    pub fn new() -> Cid {
+        Cid::default()
+    }
+
+    define_field!(manufacturer_id, u8, 0, 0, 8);
+    define_field!(oem_id, u16, [(1, 0, 8), (2, 0, 8)]);
+    define_field!(product_revision, u8, 8, 0, 8);
+    define_field!(
+        product_serial_number,
+        u32,
+        [(9, 0, 8), (10, 0, 8), (11, 0, 8), (12, 0, 8)]
+    );
+    define_field!(manufacturing_year_offset, u8, [(13, 0, 4), (14, 4, 4)]);
+    define_field!(manufacturing_month, u8, 14, 0, 4);
+    define_field!(crc, u8, 15, 1, 7);
+
+    /// Returns the two ASCII characters of the OEM/Application ID
+    pub fn oem_id_bytes(&self) -> &[u8] {
+        &self.data[1..3]
+    }
+
+    /// Returns the five ASCII characters of the product name
+    pub fn product_name(&self) -> &[u8] {
+        &self.data[3..8]
+    }
+
+    /// Returns the major and minor product revision, which are stored as
+    /// BCD digits (so 0x23 is revision 2.3).
+    pub fn product_revision_major_minor(&self) -> (u8, u8) {
+        let revision = self.product_revision();
+        (revision >> 4, revision & 0x0F)
+    }
+
+    /// Returns the year the card was made
+    pub fn manufacturing_year(&self) -> u16 {
+        2000 + u16::from(self.manufacturing_year_offset())
+    }
+
+    /// Does the CRC7 stored in the CID match the contents?
+    pub fn crc_valid(&self) -> bool {
+        crc7(&self.data[0..15]) == self.data[15]
+    }
+}
+
+impl core::fmt::Debug for Cid {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let (major, minor) = self.product_revision_major_minor();
+        write!(
+            f,
+            "Cid(mid=0x{:02x}, oid={:?}, pnm={:?}, prv={}.{}, psn=0x{:08x}, mdt={}-{:02})",
+            self.manufacturer_id(),
+            core::str::from_utf8(self.oem_id_bytes()).unwrap_or("?"),
+            core::str::from_utf8(self.product_name()).unwrap_or("?"),
+            major,
+            minor,
+            self.product_serial_number(),
+            self.manufacturing_year(),
+            self.manufacturing_month()
+        )
+    }
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for Cid {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "Cid(mid=0x{:02x}, oid={=[u8]}, pnm={=[u8]}, prv=0x{:02x}, psn=0x{:08x}, mdt={}-{})",
+            self.manufacturer_id(),
+            self.oem_id_bytes(),
+            self.product_name(),
+            self.product_revision(),
+            self.product_serial_number(),
+            self.manufacturing_year(),
+            self.manufacturing_month()
+        )
+    }
+}
+
+/// SD Configuration Register
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+pub struct Scr {
+    /// The 8-bytes of data in this SD Configuration Register
+    pub data: [u8; 8],
+}
+
+impl Scr {
+    /// Create a new, empty, SCR
+    pub fn new() -> Scr {
+        Scr::default()
+    }
+
+    define_field!(scr_structure, u8, 0, 4, 4);
+    define_field!(sd_spec, u8, 0, 0, 4);
+    define_field!(data_stat_after_erase, bool, 1, 7);
+    define_field!(sd_security, u8, 1, 4, 3);
+    define_field!(sd_bus_widths, u8, 1, 0, 4);
+    define_field!(sd_spec3, bool, 2, 7);
+    define_field!(ex_security, u8, 2, 3, 4);
+    define_field!(sd_spec4, bool, 2, 2);
+    define_field!(sd_specx, u8, [(2, 0, 2), (3, 6, 2)]);
+    define_field!(cmd_support, u8, 3, 0, 4);
+
+    /// Does the card support a 1-bit wide SD bus?
+    pub fn supports_1bit_bus(&self) -> bool {
+        (self.sd_bus_widths() & 0x01) != 0
+    }
+
+    /// Does the card support a 4-bit wide SD bus?
+    pub fn supports_4bit_bus(&self) -> bool {
+        (self.sd_bus_widths() & 0x04) != 0
+    }
+
+    /// Does the card support CMD23 (SET_BLOCK_COUNT)?
+    pub fn supports_set_block_count(&self) -> bool {
+        (self.cmd_support() & 0x02) != 0
+    }
+
+    /// Returns the version of the Physical Layer Specification the card
+    /// supports, as (major, minor). For example, (1, 10) is version 1.10.
+    pub fn spec_version(&self) -> (u8, u8) {
+        match (
+            self.sd_spec(),
+            self.sd_spec3(),
+            self.sd_spec4(),
+            self.sd_specx(),
+        ) {
+            (0, _, _, _) => (1, 0),
+            (1, _, _, _) => (1, 10),
+            (2, false, _, _) => (2, 0),
+            (2, true, false, 0) => (3, 0),
+            (2, true, true, 0) => (4, 0),
+            (2, true, _, specx) => (specx + 4, 0),
+            (_, _, _, _) => (0, 0),
+        }
+    }
+}
+
+/// SD Status register
+#[derive(Clone, PartialEq, Eq)]
+pub struct SdStatus {
+    /// The 64-bytes of data in this SD Status register
+    pub data: [u8; 64],
+}
+
+impl Default for SdStatus {
+    fn default() -> Self {
+        SdStatus { data: [0u8; 64] }
+    }
+}
+
+impl SdStatus {
+    /// Create a new, empty, SD Status
+    pub fn new() -> SdStatus {
+        SdStatus::default()
+    }
+
+    define_field!(dat_bus_width, u8, 0, 6, 2);
+    define_field!(secured_mode, bool, 0, 5);
+    define_field!(sd_card_type, u16, [(2, 0, 8), (3, 0, 8)]);
+    define_field!(
+        size_of_protected_area,
+        u32,
+        [(4, 0, 8), (5, 0, 8), (6, 0, 8), (7, 0, 8)]
+    );
+    define_field!(speed_class, u8, 8, 0, 8);
+    define_field!(performance_move, u8, 9, 0, 8);
+    define_field!(au_size, u8, 10, 4, 4);
+    define_field!(erase_size, u16, [(11, 0, 8), (12, 0, 8)]);
+    define_field!(erase_timeout, u8, 13, 2, 6);
+    define_field!(erase_offset, u8, 13, 0, 2);
+    define_field!(uhs_speed_grade, u8, 14, 4, 4);
+    define_field!(uhs_au_size, u8, 14, 0, 4);
+    define_field!(video_speed_class, u8, 15, 0, 8);
+    define_field!(app_perf_class, u8, 21, 0, 4);
+
+    /// Returns the speed class as a minimum write speed in MB/s (0, 2, 4, 6
+    /// or 10), or `None` if the card reports a reserved value.
+    pub fn speed_class_mb_per_s(&self) -> Option<u8> {
+        match self.speed_class() {
+            0 => Some(0),
+            1 => Some(2),
+            2 => Some(4),
+            3 => Some(6),
+            4 => Some(10),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of an Allocation Unit in bytes, or `None` if the
+    /// card doesn't say. Writes aligned to, and sized in, whole AUs are
+    /// the fastest.
+    pub fn au_size_bytes(&self) -> Option<u32> {
+        Self::decode_au_size(self.au_size())
+    }
+
+    /// Returns the UHS Allocation Unit size in bytes, or `None` if the card
+    /// doesn't say.
+    pub fn uhs_au_size_bytes(&self) -> Option<u32> {
+        Self::decode_au_size(self.uhs_au_size())
+    }
+
+    /// Returns how long it may take to erase the given number of Allocation
+    /// Units, in milliseconds, or `None` if the card doesn't say.
+    pub fn erase_timeout_ms(&self, num_aus: u32) -> Option<u32> {
+        let erase_size = u32::from(self.erase_size());
+        if erase_size == 0 || self.erase_timeout() == 0 {
+            return None;
+        }
+        let per_au_ms = u32::from(self.erase_timeout()) * 1000 / erase_size;
+        Some(
+            per_au_ms
+                .saturating_mul(num_aus)
+                .saturating_add(u32::from(self.erase_offset()) * 1000),
+        )
+    }
+
+    fn decode_au_size(value: u8) -> Option<u32> {
+        const KIB: u32 = 1024;
+        const MIB: u32 = 1024 * 1024;
+        match value {
+            1..=10 => Some((8 * KIB) << value),
+            11 => Some(12 * MIB),
+            12 => Some(16 * MIB),
+            13 => Some(24 * MIB),
+            14 => Some(32 * MIB),
+            15 => Some(64 * MIB),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Debug for SdStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SdStatus")
+            .field("dat_bus_width", &self.dat_bus_width())
+            .field("speed_class", &self.speed_class())
+            .field("au_size", &self.au_size())
+            .field("erase_size", &self.erase_size())
+            .field("erase_timeout", &self.erase_timeout())
+            .field("erase_offset", &self.erase_offset())
+            .field("uhs_speed_grade", &self.uhs_speed_grade())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for SdStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "SdStatus(speed_class={}, au_size={}, erase_size={}, erase_timeout={}, erase_offset={})",
+            self.speed_class(),
+            self.au_size(),
+            self.erase_size(),
+            self.erase_timeout(),
+            self.erase_offset()
+        )
     }
 }
 
@@ -232,6 +967,7 @@ pub fn crc16(data: &[u8]) -> u16 {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use super::*;
 
@@ -248,6 +984,162 @@ mod test {
         assert_eq!(crc16(&DATA), 0x9fc5);
     }
 
+    #[test]
+    fn test_cid() {
+        const EXAMPLE: Cid = Cid {
+            data: hex!("03 53 44 53 55 30 31 47 80 1B 7A 55 C6 00 D3 C9"),
+        };
+        assert_eq!(EXAMPLE.manufacturer_id(), 0x03);
+        assert_eq!(EXAMPLE.oem_id(), 0x5344);
+        assert_eq!(EXAMPLE.oem_id_bytes(), b"SD");
+        assert_eq!(EXAMPLE.product_name(), b"SU01G");
+        assert_eq!(EXAMPLE.product_revision(), 0x80);
+        assert_eq!(EXAMPLE.product_revision_major_minor(), (8, 0));
+        assert_eq!(EXAMPLE.product_serial_number(), 0x1B7A_55C6);
+        assert_eq!(EXAMPLE.manufacturing_year(), 2013);
+        assert_eq!(EXAMPLE.manufacturing_month(), 3);
+        assert_eq!(EXAMPLE.crc(), 0x64);
+        assert!(EXAMPLE.crc_valid());
+
+        let mut corrupt = EXAMPLE.clone();
+        corrupt.data[12] ^= 0x01;
+        assert!(!corrupt.crc_valid());
+
+        assert_eq!(
+            format!("{:?}", EXAMPLE),
+            "Cid(mid=0x03, oid=\"SD\", pnm=\"SU01G\", prv=8.0, psn=0x1b7a55c6, mdt=2013-03)"
+        );
+    }
+
+    #[test]
+    fn test_csd_decoding() {
+        let mut csd = CsdV2 {
+            data: hex!("40 0E 00 5A 5B 59 00 00 1D 69 7F 80 0A 40 00 8B"),
+        };
+        // 0x5A is 5.0 x 10 Mbit/s - high speed mode
+        assert_eq!(csd.max_transfer_rate_bps(), 50_000_000);
+        csd.data[3] = 0x0B;
+        // 0x0B is 1.0 x 100 Mbit/s
+        assert_eq!(csd.max_transfer_rate_bps(), 100_000_000);
+        csd.data[3] = 0x0F;
+        assert_eq!(csd.max_transfer_rate_bps(), 0);
+        csd.data[14] = 0x14;
+        assert!(csd.temporary_write_protection());
+        assert!(csd.is_write_protected());
+        assert_eq!(csd.file_format_type(), FileFormat::BootSectorOnly);
+        csd.data[14] = 0xA0;
+        assert!(csd.permanent_write_protection());
+        assert_eq!(csd.file_format_type(), FileFormat::Other);
+    }
+
+    #[test]
+    fn test_csdv3() {
+        let csd = CsdV3 {
+            data: hex!("80 0E 00 32 5B 59 0E 8D 4A 4F 7F 80 0A 40 00 89"),
+        };
+        assert_eq!(csd.csd_ver(), 2);
+        assert_eq!(csd.max_transfer_rate_bps(), 25_000_000);
+        assert!(csd.supports_command_class(10));
+        // C_SIZE is 28 bits, rather than 22
+        assert_eq!(csd.device_size(), 0x0E8D_4A4F);
+        assert_eq!(csd.card_capacity_bytes(), 127_999_999_475_712);
+        assert_eq!(csd.card_capacity_blocks(), 249_999_998_976);
+        assert_eq!(csd.erase_sector_size_blocks(), 128);
+        assert_eq!(crc7(&csd.data[0..15]), csd.crc());
+    }
+
+    #[test]
+    fn test_csd_mmc() {
+        let csd = CsdMmc {
+            data: hex!("90 5E 00 32 1F 59 83 D3 ED B6 BF 87 96 40 00 4D"),
+        };
+        assert_eq!(csd.csd_ver(), 2);
+        assert_eq!(csd.spec_version(), 4);
+        assert!(csd.has_ext_csd());
+        assert_eq!(csd.data_read_access_time1(), 0x5E);
+        // 0x32 is 2.6 x 10 Mbit/s on an MMC card
+        assert_eq!(csd.max_transfer_rate_bps(), 26_000_000);
+        assert_eq!(csd.card_command_classes(), 0x1F5);
+        assert_eq!(csd.read_block_length(), 9);
+        assert_eq!(csd.device_size(), 3919);
+        assert_eq!(csd.device_size_multiplier(), 5);
+        assert_eq!(csd.card_capacity_bytes(), 256_901_120);
+        assert_eq!(csd.card_capacity_blocks(), 501_760);
+        assert_eq!(csd.erase_group_size(), 15);
+        assert_eq!(csd.erase_group_multiplier(), 28);
+        assert_eq!(csd.erase_sector_size_blocks(), 464);
+        assert_eq!(csd.write_protect_group_size(), 7);
+        assert_eq!(csd.write_protect_group_size_blocks(), 3712);
+        assert!(csd.write_protect_group_enable());
+        assert_eq!(csd.default_ecc(), 0);
+        assert_eq!(csd.write_speed_factor(), 5);
+        assert_eq!(csd.max_write_data_length(), 9);
+        assert_eq!(csd.crc(), 0x4D);
+
+        let mut ext_csd = ExtCsd::new();
+        ext_csd.data[192] = 5;
+        ext_csd.data[212..216].copy_from_slice(&hex!("00 00 A4 01"));
+        assert_eq!(ext_csd.ext_csd_revision(), 5);
+        assert_eq!(ext_csd.sector_count(), 0x01A4_0000);
+        assert_eq!(ext_csd.card_capacity_bytes(), 0x01A4_0000 * 512);
+    }
+
+    #[test]
+    fn test_scr() {
+        // An SCR from a 32 GiB SDHC card
+        const EXAMPLE: Scr = Scr {
+            data: hex!("02 35 84 43 00 00 00 00"),
+        };
+        assert_eq!(EXAMPLE.scr_structure(), 0);
+        assert_eq!(EXAMPLE.sd_spec(), 2);
+        assert_eq!(EXAMPLE.data_stat_after_erase(), false);
+        assert_eq!(EXAMPLE.sd_security(), 3);
+        assert!(EXAMPLE.supports_1bit_bus());
+        assert!(EXAMPLE.supports_4bit_bus());
+        assert_eq!(EXAMPLE.sd_spec3(), true);
+        assert_eq!(EXAMPLE.sd_spec4(), true);
+        assert_eq!(EXAMPLE.sd_specx(), 1);
+        assert_eq!(EXAMPLE.spec_version(), (5, 0));
+        assert!(EXAMPLE.supports_set_block_count());
+
+        let old = Scr {
+            data: hex!("01 25 00 00 00 00 00 00"),
+        };
+        assert_eq!(old.spec_version(), (1, 10));
+        assert!(!old.supports_set_block_count());
+        let v3 = Scr {
+            data: hex!("02 35 80 00 00 00 00 00"),
+        };
+        assert_eq!(v3.spec_version(), (3, 0));
+    }
+
+    #[test]
+    fn test_sd_status() {
+        let mut example = SdStatus::new();
+        example.data[..16]
+            .copy_from_slice(&hex!("80 00 00 00 05 00 00 00 04 00 90 00 2A 55 11 00"));
+        assert_eq!(example.dat_bus_width(), 2);
+        assert_eq!(example.secured_mode(), false);
+        assert_eq!(example.size_of_protected_area(), 0x0500_0000);
+        assert_eq!(example.speed_class(), 4);
+        assert_eq!(example.speed_class_mb_per_s(), Some(10));
+        assert_eq!(example.au_size(), 9);
+        assert_eq!(example.au_size_bytes(), Some(4 * 1024 * 1024));
+        assert_eq!(example.erase_size(), 42);
+        assert_eq!(example.erase_timeout(), 21);
+        assert_eq!(example.erase_offset(), 1);
+        assert_eq!(example.uhs_speed_grade(), 1);
+        assert_eq!(example.uhs_au_size_bytes(), Some(16 * 1024));
+        // 21 seconds for 42 AUs is 500 ms each, plus a second of offset
+        assert_eq!(example.erase_timeout_ms(2), Some(2000));
+        example.data[13] = 0;
+        assert_eq!(example.erase_timeout_ms(2), None);
+        example.data[10] = 0xB0;
+        assert_eq!(example.au_size_bytes(), Some(12 * 1024 * 1024));
+        example.data[10] = 0x00;
+        assert_eq!(example.au_size_bytes(), None);
+    }
+
     #[test]
     fn test_csdv1b() {
         const EXAMPLE: CsdV1 = CsdV1 {
@@ -375,6 +1267,21 @@ mod test {
 
         assert_eq!(EXAMPLE.card_capacity_bytes(), 1_015_808_000);
         assert_eq!(EXAMPLE.card_capacity_blocks(), 1_984_000);
+
+        assert_eq!(EXAMPLE.read_access_time_ns(), 1_500_000);
+        assert_eq!(EXAMPLE.read_access_clocks(), 0);
+        assert_eq!(EXAMPLE.max_transfer_rate_bps(), 25_000_000);
+        assert!(EXAMPLE.supports_command_class(0));
+        assert!(!EXAMPLE.supports_command_class(1));
+        assert!(EXAMPLE.supports_command_class(10));
+        assert!(!EXAMPLE.supports_command_class(12));
+        assert_eq!(EXAMPLE.read_block_length_bytes(), 512);
+        assert_eq!(EXAMPLE.max_write_data_length_bytes(), 512);
+        assert_eq!(EXAMPLE.erase_sector_size_blocks(), 32);
+        assert_eq!(EXAMPLE.write_protect_group_size_blocks(), 128 * 32);
+        assert_eq!(EXAMPLE.write_speed_multiplier(), 16);
+        assert_eq!(EXAMPLE.file_format_type(), FileFormat::PartitionTable);
+        assert!(!EXAMPLE.is_write_protected());
     }
 
     #[test]
@@ -501,6 +1408,11 @@ mod test {
 
         assert_eq!(EXAMPLE.card_capacity_bytes(), 1_978_662_912);
         assert_eq!(EXAMPLE.card_capacity_blocks(), 3_864_576);
+
+        assert_eq!(EXAMPLE.read_access_time_ns(), 80_000_000);
+        assert_eq!(EXAMPLE.read_block_length_bytes(), 1024);
+        assert_eq!(EXAMPLE.write_protect_group_size_blocks(), 32);
+        assert_eq!(EXAMPLE.write_speed_multiplier(), 32);
     }
 
     #[test]
@@ -607,6 +1519,11 @@ mod test {
 
         assert_eq!(EXAMPLE.card_capacity_bytes(), 3_947_888_640);
         assert_eq!(EXAMPLE.card_capacity_blocks(), 7_710_720);
+
+        assert_eq!(EXAMPLE.read_access_time_ns(), 1_000_000);
+        assert_eq!(EXAMPLE.max_transfer_rate_bps(), 25_000_000);
+        assert_eq!(EXAMPLE.erase_sector_size_blocks(), 128);
+        assert_eq!(EXAMPLE.write_speed_multiplier(), 4);
     }
 
     #[test]
@@ -0,0 +1,861 @@
+//! embedded-sdmmc-rs - SD Card Simulator
+//!
+//! A pretend SD card which speaks the SPI mode protocol, byte by byte, so
+//! that `SdMmcSpi` can be tested without any hardware. It plugs in as an
+//! embedded-hal 0.2 SPI peripheral and Chip Select pin, and keeps its
+//! contents in memory (optionally loaded from a disk image file).
+//!
+//! It is only built for the tests, so it can use `std`.
+
+use crate::sdmmc_proto::*;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// The card wants at least 74 clocks (so 10 bytes) with Chip Select high
+/// before it will talk to us
+const INITIAL_CLOCK_BYTES: usize = 10;
+
+/// The sort of card to pretend to be.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SimCardType {
+    /// A version 1 standard capacity card, which doesn't know CMD8
+    Sd1,
+    /// A version 2 standard capacity card (byte addressed)
+    Sd2,
+    /// A version 2 high capacity card (block addressed)
+    Sdhc,
+    /// An MMC card (byte addressed), which doesn't know ACMD41
+    Mmc,
+    /// An eMMC part (sector addressed), with an Extended CSD
+    Emmc,
+}
+
+impl SimCardType {
+    fn is_mmc(self) -> bool {
+        matches!(self, SimCardType::Mmc | SimCardType::Emmc)
+    }
+}
+
+/// Things a test can change to make the card misbehave.
+#[derive(Debug, Clone)]
+pub(crate) struct Knobs {
+    /// How many bytes of 0xFF come before each command response (NCR)
+    pub(crate) response_delay: usize,
+    /// How many bytes of 0xFF come before each block we send (NAC)
+    pub(crate) read_delay: usize,
+    /// How many bytes the card stays busy for after a block is written or a
+    /// multi-block write is stopped
+    pub(crate) busy_bytes: usize,
+    /// How many ACMD41s the card answers "still idle" to before it is ready
+    pub(crate) init_polls: u32,
+    /// How many of the next blocks we send will have a bad CRC
+    pub(crate) bad_read_crcs: u32,
+    /// How many of the next blocks written to us will be rejected as if their
+    /// CRC was bad
+    pub(crate) bad_write_crcs: u32,
+    /// If set, writing this block fails with a write error
+    pub(crate) write_error_block: Option<usize>,
+    /// If not zero, the data error token we send instead of the next block
+    pub(crate) read_error_token: u8,
+    /// The second byte of our response to CMD13
+    pub(crate) card_status: u8,
+    /// If set, a high capacity card says (in its CSD) that it has this many
+    /// blocks, however big its image is. Over 2 TiB, it sends a version 3
+    /// CSD, like an SDUC card.
+    pub(crate) claimed_blocks: Option<u64>,
+    /// If set, the card ignores everything (as if it wasn't there)
+    pub(crate) unresponsive: bool,
+    /// Whether a version 2 card can switch to high-speed mode with CMD6
+    pub(crate) high_speed: bool,
+    /// Whether the card is in the socket. Taking it out resets it.
+    pub(crate) inserted: bool,
+    /// Whether the card's write-protect tab is set. Only the socket's switch
+    /// takes any notice.
+    pub(crate) write_protect: bool,
+}
+
+impl Default for Knobs {
+    fn default() -> Self {
+        Knobs {
+            response_delay: 1,
+            read_delay: 2,
+            busy_bytes: 4,
+            init_polls: 2,
+            bad_read_crcs: 0,
+            bad_write_crcs: 0,
+            write_error_block: None,
+            read_error_token: 0,
+            card_status: 0,
+            claimed_blocks: None,
+            unresponsive: false,
+            high_speed: true,
+            inserted: true,
+            write_protect: false,
+        }
+    }
+}
+
+/// What the card expects the next byte from the host to be.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    /// Waiting for a command frame
+    Command,
+    /// Sending blocks from here on until we get CMD12
+    ReadMultiple(usize),
+    /// Waiting for the data token of a single block write
+    WriteSingle(usize),
+    /// Waiting for the data token of the next block, or the stop token
+    WriteMultiple(usize),
+    /// Receiving a block of data (and its CRC)
+    Receiving { block_idx: usize, multiple: bool },
+    /// Waiting for the data token of a lock/unlock command
+    LockUnlock,
+    /// Receiving the data of a lock/unlock command (and its CRC)
+    ReceivingLockUnlock,
+}
+
+/// Everything the card knows.
+struct Sim {
+    card_type: SimCardType,
+    knobs: Knobs,
+    image: Vec<u8>,
+    selected: bool,
+    initial_clocks: usize,
+    idle: bool,
+    app_command: bool,
+    crc_enabled: bool,
+    high_speed: bool,
+    frame: [u8; 6],
+    frame_len: usize,
+    data: Vec<u8>,
+    mode: Mode,
+    output: VecDeque<u8>,
+    busy: usize,
+    blocks_written: u32,
+    erase_range: (usize, usize),
+    block_len: usize,
+    password: Vec<u8>,
+    locked: bool,
+    lock_unlock_failed: bool,
+    powered: bool,
+    commands: Vec<u8>,
+}
+
+impl Sim {
+    /// Clock one byte in each direction.
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        if !self.knobs.inserted || !self.powered {
+            self.power_off();
+            return 0xFF;
+        }
+        if !self.selected {
+            self.initial_clocks += 1;
+            return 0xFF;
+        }
+        if self.knobs.unresponsive || self.initial_clocks < INITIAL_CLOCK_BYTES {
+            return 0xFF;
+        }
+        if self.output.is_empty() && self.busy == 0 {
+            if let Mode::ReadMultiple(block_idx) = self.mode {
+                if self.block_range(block_idx).is_some() {
+                    self.send_block(block_idx);
+                    self.mode = Mode::ReadMultiple(block_idx + 1);
+                }
+            }
+        }
+        let miso = if let Some(byte) = self.output.pop_front() {
+            byte
+        } else if self.busy > 0 {
+            self.busy -= 1;
+            0x00
+        } else {
+            0xFF
+        };
+        self.receive(mosi);
+        miso
+    }
+
+    /// Forget everything, as if the card had been taken out.
+    fn power_off(&mut self) {
+        self.initial_clocks = 0;
+        self.idle = true;
+        self.app_command = false;
+        self.crc_enabled = false;
+        self.high_speed = false;
+        self.frame_len = 0;
+        self.mode = Mode::Command;
+        self.output.clear();
+        self.busy = 0;
+        self.block_len = 512;
+        // A card with a password always powers up locked
+        self.locked = !self.password.is_empty();
+    }
+
+    /// Deal with a byte from the host.
+    fn receive(&mut self, mosi: u8) {
+        match self.mode {
+            Mode::Receiving {
+                block_idx,
+                multiple,
+            } => {
+                self.data.push(mosi);
+                if self.data.len() == 512 + 2 {
+                    self.finish_write(block_idx, multiple);
+                }
+            }
+            Mode::ReceivingLockUnlock => {
+                self.data.push(mosi);
+                if self.data.len() == self.block_len + 2 {
+                    self.finish_lock_unlock();
+                }
+            }
+            Mode::LockUnlock if mosi == DATA_START_BLOCK => {
+                self.data.clear();
+                self.mode = Mode::ReceivingLockUnlock;
+            }
+            Mode::WriteSingle(block_idx) if mosi == DATA_START_BLOCK => {
+                self.start_receiving(block_idx, false);
+            }
+            Mode::WriteMultiple(block_idx) if mosi == WRITE_MULTIPLE_TOKEN => {
+                self.start_receiving(block_idx, true);
+            }
+            Mode::WriteMultiple(_) if mosi == STOP_TRAN_TOKEN => {
+                self.mode = Mode::Command;
+                self.busy = self.knobs.busy_bytes;
+            }
+            Mode::WriteSingle(_) | Mode::WriteMultiple(_) | Mode::LockUnlock => {}
+            Mode::Command | Mode::ReadMultiple(_) => {
+                if self.frame_len == 0 && (mosi & 0xC0) != 0x40 {
+                    return;
+                }
+                self.frame[self.frame_len] = mosi;
+                self.frame_len += 1;
+                if self.frame_len == self.frame.len() {
+                    self.frame_len = 0;
+                    self.command();
+                }
+            }
+        }
+    }
+
+    fn start_receiving(&mut self, block_idx: usize, multiple: bool) {
+        self.data.clear();
+        self.mode = Mode::Receiving {
+            block_idx,
+            multiple,
+        };
+    }
+
+    /// A whole block has arrived, so check it and store it.
+    fn finish_write(&mut self, block_idx: usize, multiple: bool) {
+        let crc = u16::from_be_bytes([self.data[512], self.data[513]]);
+        let token = if self.crc_enabled && crc != crc16(&self.data[0..512]) {
+            DATA_RES_CRC_ERROR
+        } else if self.knobs.bad_write_crcs > 0 {
+            self.knobs.bad_write_crcs -= 1;
+            DATA_RES_CRC_ERROR
+        } else if self.knobs.write_error_block == Some(block_idx) {
+            DATA_RES_WRITE_ERROR
+        } else if let Some(range) = self.block_range(block_idx) {
+            self.image[range].copy_from_slice(&self.data[0..512]);
+            self.busy = self.knobs.busy_bytes;
+            self.blocks_written += 1;
+            DATA_RES_ACCEPTED
+        } else {
+            DATA_RES_WRITE_ERROR
+        };
+        self.output.push_back(token);
+        self.mode = if multiple && token == DATA_RES_ACCEPTED {
+            Mode::WriteMultiple(block_idx + 1)
+        } else {
+            Mode::Command
+        };
+    }
+
+    /// The data of a lock/unlock command has arrived, so act on it.
+    fn finish_lock_unlock(&mut self) {
+        let flags = self.data[0];
+        let given = self.data[..self.block_len]
+            .get(2..)
+            .and_then(|rest| rest.get(..usize::from(self.data[1])))
+            .unwrap_or_default()
+            .to_vec();
+        let ok = if flags == LOCK_ERASE {
+            // Wipe the whole card, password and all
+            self.image.fill(0);
+            self.password.clear();
+            self.locked = false;
+            true
+        } else if flags & LOCK_SET_PASSWORD != 0 {
+            // The current password, then the new one
+            match given.strip_prefix(self.password.as_slice()) {
+                Some(new) if !new.is_empty() => {
+                    self.password = new.to_vec();
+                    self.locked = flags & LOCK_LOCK != 0;
+                    true
+                }
+                _ => false,
+            }
+        } else if self.password.is_empty() || given != self.password {
+            false
+        } else if flags & LOCK_CLEAR_PASSWORD != 0 {
+            self.password.clear();
+            self.locked = false;
+            true
+        } else {
+            self.locked = flags & LOCK_LOCK != 0;
+            true
+        };
+        self.lock_unlock_failed = !ok;
+        self.output.push_back(DATA_RES_ACCEPTED);
+        self.busy = self.knobs.busy_bytes;
+        self.mode = Mode::Command;
+    }
+
+    /// Where the given block lives in the image, if it's on the card.
+    fn block_range(&self, block_idx: usize) -> Option<std::ops::Range<usize>> {
+        let start = block_idx * 512;
+        if start + 512 <= self.image.len() {
+            Some(start..start + 512)
+        } else {
+            None
+        }
+    }
+
+    /// Turn a command argument into a block index, or an R1 error.
+    fn address(&self, arg: u32) -> Result<usize, u8> {
+        let block_idx = match self.card_type {
+            SimCardType::Sdhc | SimCardType::Emmc => arg as usize,
+            SimCardType::Sd1 | SimCardType::Sd2 | SimCardType::Mmc => {
+                if !arg.is_multiple_of(512) {
+                    return Err(R1_ADDRESS_ERROR);
+                }
+                arg as usize / 512
+            }
+        };
+        match self.block_range(block_idx) {
+            Some(_) => Ok(block_idx),
+            None => Err(R1_PARAMETER_ERROR),
+        }
+    }
+
+    /// Queue up a response, after the usual pause.
+    fn respond(&mut self, bytes: &[u8]) {
+        self.output
+            .extend(std::iter::repeat_n(0xFF, self.knobs.response_delay));
+        self.output.extend(bytes);
+    }
+
+    /// Queue up a data block (a register, or a block from the image).
+    fn send_data(&mut self, data: &[u8]) {
+        if self.knobs.read_error_token != 0 {
+            let token = core::mem::replace(&mut self.knobs.read_error_token, 0);
+            self.output
+                .extend(std::iter::repeat_n(0xFF, self.knobs.read_delay));
+            self.output.push_back(token);
+            return;
+        }
+        let mut crc = crc16(data);
+        if self.knobs.bad_read_crcs > 0 {
+            self.knobs.bad_read_crcs -= 1;
+            crc ^= 0xFFFF;
+        }
+        self.output
+            .extend(std::iter::repeat_n(0xFF, self.knobs.read_delay));
+        self.output.push_back(DATA_START_BLOCK);
+        self.output.extend(data);
+        self.output.extend(crc.to_be_bytes());
+    }
+
+    fn send_block(&mut self, block_idx: usize) {
+        let range = self.block_range(block_idx).unwrap();
+        let data = self.image[range].to_vec();
+        self.send_data(&data);
+    }
+
+    /// A whole command frame has arrived, so act on it.
+    fn command(&mut self) {
+        let command = self.frame[0] & 0x3F;
+        let arg = u32::from_be_bytes([self.frame[1], self.frame[2], self.frame[3], self.frame[4]]);
+        let app_command = core::mem::replace(&mut self.app_command, false);
+        self.commands.push(command);
+        // CMD0 and CMD8 are always checked, as the card starts with CRCs on
+        let crc_checked = self.crc_enabled || command == CMD0 || command == CMD8;
+        let r1 = if self.idle { R1_IDLE_STATE } else { 0 };
+        if crc_checked && self.frame[5] != crc7(&self.frame[0..5]) {
+            self.respond(&[r1 | R1_COM_CRC_ERROR]);
+            return;
+        }
+        match (app_command, command) {
+            (_, CMD0) => {
+                self.idle = true;
+                self.crc_enabled = false;
+                self.mode = Mode::Command;
+                self.respond(&[R1_IDLE_STATE]);
+            }
+            (false, CMD1) if self.card_type.is_mmc() => {
+                if self.knobs.init_polls > 0 {
+                    self.knobs.init_polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                let r1 = if self.idle { R1_IDLE_STATE } else { 0 };
+                self.respond(&[r1]);
+            }
+            (false, CMD8) if !self.idle && self.card_type == SimCardType::Emmc => {
+                self.respond(&[r1]);
+                let ext_csd = self.ext_csd();
+                self.send_data(&ext_csd);
+            }
+            (false, CMD8) if self.card_type != SimCardType::Sd1 && !self.card_type.is_mmc() => {
+                self.respond(&[r1, 0x00, 0x00, (arg >> 8) as u8 & 0x0F, arg as u8]);
+            }
+            (false, CMD6)
+                if !self.idle && self.card_type != SimCardType::Sd1 && !self.card_type.is_mmc() =>
+            {
+                self.respond(&[r1]);
+                let status = self.switch_function(arg);
+                self.send_data(&status);
+            }
+            (false, CMD9) if !self.idle => {
+                self.respond(&[r1]);
+                let csd = self.csd();
+                self.send_data(&csd);
+            }
+            (false, CMD10) if !self.idle => {
+                self.respond(&[r1]);
+                let cid = self.cid();
+                self.send_data(&cid);
+            }
+            (false, CMD12) => {
+                self.mode = Mode::Command;
+                self.output.clear();
+                // A stuff byte, then the response, then a little busy
+                self.output.push_back(0x00);
+                self.respond(&[r1]);
+                self.busy = self.knobs.busy_bytes;
+            }
+            (false, CMD13) => {
+                let mut status = self.knobs.card_status;
+                if self.locked {
+                    status |= R2_CARD_LOCKED;
+                }
+                if core::mem::replace(&mut self.lock_unlock_failed, false) {
+                    status |= R2_WP_ERASE_SKIP;
+                }
+                self.respond(&[r1, status]);
+            }
+            (false, CMD16) if !self.idle => {
+                if (1..=512).contains(&arg) {
+                    self.block_len = arg as usize;
+                    self.respond(&[r1]);
+                } else {
+                    self.respond(&[r1 | R1_PARAMETER_ERROR]);
+                }
+            }
+            // A locked card won't touch its data
+            (false, CMD17 | CMD18 | CMD24 | CMD25 | CMD32 | CMD33 | CMD35 | CMD36 | CMD38)
+                if self.locked =>
+            {
+                self.respond(&[r1 | R1_ILLEGAL_COMMAND]);
+            }
+            (false, CMD42) if !self.idle => {
+                self.respond(&[r1]);
+                self.mode = Mode::LockUnlock;
+            }
+            (false, CMD17) if !self.idle => match self.address(arg) {
+                Ok(block_idx) => {
+                    self.respond(&[r1]);
+                    self.send_block(block_idx);
+                }
+                Err(e) => self.respond(&[r1 | e]),
+            },
+            (false, CMD18) if !self.idle => match self.address(arg) {
+                Ok(block_idx) => {
+                    self.respond(&[r1]);
+                    self.mode = Mode::ReadMultiple(block_idx);
+                }
+                Err(e) => self.respond(&[r1 | e]),
+            },
+            (false, CMD24) if !self.idle => match self.address(arg) {
+                Ok(block_idx) => {
+                    self.respond(&[r1]);
+                    self.mode = Mode::WriteSingle(block_idx);
+                }
+                Err(e) => self.respond(&[r1 | e]),
+            },
+            (false, CMD25) if !self.idle => match self.address(arg) {
+                Ok(block_idx) => {
+                    self.respond(&[r1]);
+                    self.blocks_written = 0;
+                    self.mode = Mode::WriteMultiple(block_idx);
+                }
+                Err(e) => self.respond(&[r1 | e]),
+            },
+            (false, CMD32 | CMD33 | CMD35 | CMD36) if !self.idle => {
+                let mmc_command = command == CMD35 || command == CMD36;
+                match self.address(arg) {
+                    _ if mmc_command != self.card_type.is_mmc() => {
+                        self.respond(&[r1 | R1_ILLEGAL_COMMAND])
+                    }
+                    Ok(block_idx) => {
+                        if command == CMD32 || command == CMD35 {
+                            self.erase_range.0 = block_idx;
+                        } else {
+                            self.erase_range.1 = block_idx;
+                        }
+                        self.respond(&[r1]);
+                    }
+                    Err(e) => self.respond(&[r1 | e]),
+                }
+            }
+            (false, CMD38) if !self.idle => {
+                let (first, last) = self.erase_range;
+                if first <= last {
+                    self.image[first * 512..(last + 1) * 512].fill(0);
+                    self.respond(&[r1]);
+                    self.busy = self.knobs.busy_bytes;
+                } else {
+                    self.respond(&[r1 | R1_ERASE_SEQUENCE_ERROR]);
+                }
+            }
+            (false, CMD55) if !self.card_type.is_mmc() => {
+                self.app_command = true;
+                self.respond(&[r1]);
+            }
+            (false, CMD58) => {
+                let mut ocr = OCR_VOLTAGE_WINDOW;
+                if !self.idle {
+                    ocr |= OCR_POWER_UP_DONE;
+                    if self.card_type == SimCardType::Sdhc {
+                        ocr |= OCR_CCS;
+                    }
+                    if self.card_type == SimCardType::Emmc {
+                        ocr |= OCR_MMC_SECTOR_MODE;
+                    }
+                }
+                let ocr = ocr.to_be_bytes();
+                self.respond(&[r1, ocr[0], ocr[1], ocr[2], ocr[3]]);
+            }
+            (false, CMD59) => {
+                self.crc_enabled = arg & 1 != 0;
+                self.respond(&[r1]);
+            }
+            (true, ACMD22) if !self.idle => {
+                self.respond(&[r1]);
+                let count = self.blocks_written.to_be_bytes();
+                self.send_data(&count);
+            }
+            (true, ACMD23) if !self.idle => self.respond(&[r1]),
+            (true, ACMD41) => {
+                if self.knobs.init_polls > 0 {
+                    self.knobs.init_polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                let r1 = if self.idle { R1_IDLE_STATE } else { 0 };
+                self.respond(&[r1]);
+            }
+            _ => self.respond(&[r1 | R1_ILLEGAL_COMMAND]),
+        }
+    }
+
+    /// Check or switch the access mode (function group 1), leaving the
+    /// other groups alone, and return the 64-byte switch status.
+    fn switch_function(&mut self, arg: u32) -> [u8; 64] {
+        let mut status = [0u8; 64];
+        // Function group 1 supports default speed, and maybe high speed
+        status[13] = if self.knobs.high_speed { 0x03 } else { 0x01 };
+        let function = arg & 0x0F;
+        let selected = match function {
+            0 => 0,
+            1 if self.knobs.high_speed => 1,
+            // "No change", so report the current mode
+            0x0F => u32::from(self.high_speed),
+            _ => 0x0F,
+        };
+        status[16] = selected as u8;
+        if arg & (1 << 31) != 0 && selected != 0x0F {
+            self.high_speed = selected == 1;
+        }
+        status
+    }
+
+    /// A CSD describing the image: version 1 for standard capacity cards,
+    /// version 2 for high capacity ones. eMMC parts leave it to the Extended
+    /// CSD.
+    fn csd(&self) -> [u8; 16] {
+        let num_blocks = (self.image.len() / 512) as u32;
+        let mut csd = [
+            0x00, 0x26, 0x00, 0x32, 0x5B, 0x59, 0x80, 0x00, 0x00, 0x00, 0x7F, 0x80, 0x0A, 0x40,
+            0x00, 0x00,
+        ];
+        if self.card_type == SimCardType::Sd1 {
+            // Version 1.0 cards don't have the switch command class (10)
+            csd[4] = 0x1B;
+        }
+        if self.high_speed {
+            csd[3] = 0x5A;
+        }
+        if self.card_type == SimCardType::Emmc {
+            // CSD_STRUCTURE 3 and SPEC_VERS 4, with the biggest size a CSD
+            // can hold
+            csd[0] = 0xD0;
+            csd[6] |= 0x03;
+            csd[7] = 0xFF;
+            csd[8] = 0xC0;
+            csd[9] = 0x03;
+            csd[10] |= 0x80;
+        } else if self.card_type == SimCardType::Sdhc {
+            let num_blocks = self.knobs.claimed_blocks.unwrap_or(u64::from(num_blocks));
+            let c_size = num_blocks / 1024 - 1;
+            if c_size > 0x3F_FFFF {
+                csd[0] = 0x80;
+                csd[6] = (c_size >> 24) as u8 & 0x0F;
+                csd[7] = (c_size >> 16) as u8;
+            } else {
+                csd[0] = 0x40;
+                csd[6] = 0x00;
+                csd[7] = (c_size >> 16) as u8 & 0x3F;
+            }
+            csd[8] = (c_size >> 8) as u8;
+            csd[9] = c_size as u8;
+        } else {
+            if self.card_type == SimCardType::Mmc {
+                // CSD_STRUCTURE 2 and SPEC_VERS 3
+                csd[0] = 0x8C;
+            }
+            let c_size_mult = (0..8)
+                .find(|mult| num_blocks >> (mult + 2) <= 4096)
+                .expect("image too big for a standard capacity card");
+            let c_size = (num_blocks >> (c_size_mult + 2)) - 1;
+            csd[6] |= (c_size >> 10) as u8;
+            csd[7] = (c_size >> 2) as u8;
+            csd[8] = (c_size << 6) as u8;
+            csd[9] = (c_size_mult >> 1) as u8;
+            csd[10] |= (c_size_mult << 7) as u8;
+        }
+        csd[15] = crc7(&csd[0..15]);
+        csd
+    }
+
+    /// An Extended CSD, which only says how big the image is.
+    fn ext_csd(&self) -> Vec<u8> {
+        let mut ext_csd = vec![0u8; 512];
+        ext_csd[192] = 5;
+        ext_csd[194] = 2;
+        ext_csd[196] = 0x03;
+        let sector_count = (self.image.len() / 512) as u32;
+        ext_csd[212..216].copy_from_slice(&sector_count.to_le_bytes());
+        ext_csd
+    }
+
+    /// A CID for a made-up card.
+    fn cid(&self) -> [u8; 16] {
+        let mut cid = [
+            0x03, 0x53, 0x44, 0x53, 0x49, 0x4D, 0x30, 0x31, 0x10, 0x12, 0x34, 0x56, 0x78, 0x01,
+            0x7A, 0x00,
+        ];
+        cid[15] = crc7(&cid[0..15]);
+        cid
+    }
+}
+
+/// A simulated SD card. Hand its `spi()` and `cs()` to `SdMmcSpi::new`.
+#[derive(Clone)]
+pub(crate) struct SimCard(Rc<RefCell<Sim>>);
+
+impl SimCard {
+    /// Make a blank card with the given number of blocks. High capacity
+    /// cards must be a multiple of 1024 blocks.
+    pub(crate) fn new(card_type: SimCardType, num_blocks: usize) -> SimCard {
+        SimCard::from_image(card_type, vec![0u8; num_blocks * 512])
+    }
+
+    /// Make a card holding the given disk image.
+    pub(crate) fn from_image(card_type: SimCardType, image: Vec<u8>) -> SimCard {
+        SimCard(Rc::new(RefCell::new(Sim {
+            card_type,
+            knobs: Knobs::default(),
+            image,
+            selected: false,
+            initial_clocks: 0,
+            idle: true,
+            app_command: false,
+            crc_enabled: false,
+            high_speed: false,
+            frame: [0u8; 6],
+            frame_len: 0,
+            data: Vec::new(),
+            mode: Mode::Command,
+            output: VecDeque::new(),
+            busy: 0,
+            blocks_written: 0,
+            erase_range: (0, 0),
+            block_len: 512,
+            password: Vec::new(),
+            locked: false,
+            lock_unlock_failed: false,
+            powered: true,
+            commands: Vec::new(),
+        })))
+    }
+
+    /// Make a card holding a copy of the given disk image file. Writes go to
+    /// the copy, not the file.
+    pub(crate) fn from_file<P>(card_type: SimCardType, path: P) -> std::io::Result<SimCard>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Ok(SimCard::from_image(card_type, std::fs::read(path)?))
+    }
+
+    /// The SPI peripheral the card is attached to.
+    pub(crate) fn spi(&self) -> SimSpi {
+        SimSpi(self.clone())
+    }
+
+    /// The card's Chip Select pin.
+    pub(crate) fn cs(&self) -> SimCs {
+        SimCs(self.clone())
+    }
+
+    /// The socket's card-detect switch, which pulls its pin low when the
+    /// card is in.
+    pub(crate) fn card_detect(&self) -> SimPin {
+        SimPin {
+            card: self.clone(),
+            level: |knobs| !knobs.inserted,
+        }
+    }
+
+    /// The socket's write-protect switch, which pulls its pin high when the
+    /// card's tab is set.
+    pub(crate) fn write_protect(&self) -> SimPin {
+        SimPin {
+            card: self.clone(),
+            level: |knobs| knobs.write_protect,
+        }
+    }
+
+    /// The switch for the card's power, which powers it when its pin is
+    /// high. The card starts off powered, in case there isn't one.
+    pub(crate) fn power(&self) -> SimPower {
+        SimPower(self.clone())
+    }
+
+    /// Is the card's power on?
+    pub(crate) fn powered(&self) -> bool {
+        self.0.borrow().powered
+    }
+
+    /// Change how the card behaves.
+    pub(crate) fn knobs(&self) -> RefMut<'_, Knobs> {
+        RefMut::map(self.0.borrow_mut(), |sim| &mut sim.knobs)
+    }
+
+    /// The card's contents.
+    pub(crate) fn image(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.0.borrow(), |sim| sim.image.as_slice())
+    }
+
+    /// Give the card a password, which locks it.
+    pub(crate) fn lock_with(&self, password: &[u8]) {
+        let mut sim = self.0.borrow_mut();
+        sim.password = password.to_vec();
+        sim.locked = true;
+    }
+
+    /// Every command the card has received, oldest first.
+    pub(crate) fn commands(&self) -> Vec<u8> {
+        self.0.borrow().commands.clone()
+    }
+}
+
+/// The SPI side of a `SimCard`.
+pub(crate) struct SimSpi(SimCard);
+
+impl embedded_hal::blocking::spi::Transfer<u8> for SimSpi {
+    type Error = core::convert::Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let mut sim = self.0 .0.borrow_mut();
+        for word in words.iter_mut() {
+            *word = sim.exchange(*word);
+        }
+        Ok(words)
+    }
+}
+
+/// The Chip Select pin of a `SimCard`.
+pub(crate) struct SimCs(SimCard);
+
+impl embedded_hal::digital::v2::OutputPin for SimCs {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0 .0.borrow_mut().selected = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mut sim = self.0 .0.borrow_mut();
+        sim.selected = false;
+        // Anything the card was about to say is lost, but it stays busy
+        sim.output.clear();
+        sim.frame_len = 0;
+        Ok(())
+    }
+}
+
+/// One of the switches in a `SimCard`'s socket.
+pub(crate) struct SimPin {
+    card: SimCard,
+    level: fn(&Knobs) -> bool,
+}
+
+impl embedded_hal::digital::v2::InputPin for SimPin {
+    type Error = core::convert::Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok((self.level)(&self.card.0.borrow().knobs))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// The power switch of a `SimCard`'s socket.
+pub(crate) struct SimPower(SimCard);
+
+impl embedded_hal::digital::v2::OutputPin for SimPower {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        let mut sim = self.0 .0.borrow_mut();
+        sim.powered = false;
+        sim.power_off();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0 .0.borrow_mut().powered = true;
+        Ok(())
+    }
+}
+
+/// A delay which doesn't bother waiting, because the simulated card works
+/// in clocked bytes rather than time.
+pub(crate) struct SimDelay;
+
+impl embedded_hal::blocking::delay::DelayUs<u8> for SimDelay {
+    fn delay_us(&mut self, _us: u8) {}
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,219 @@
+//! embedded-sdmmc-rs - Test Disk
+//!
+//! A block device which keeps its blocks in memory, so the file system tests
+//! can share one, plus a builder for a small FAT12 volume and some helpers
+//! to poke at its FAT and root directory. With the `async` feature, there is
+//! also a slow async version of it, and a way to run async tests.
+//!
+//! It is only built for the tests, so it can use `std`.
+
+use crate::fat::write_fat12_entry;
+#[cfg(feature = "async")]
+use crate::AsyncBlockDevice;
+use crate::{Block, BlockCount, BlockDevice, BlockIdx, Cluster, Controller, TimeSource, Timestamp};
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+
+/// Where the FAT12 test partition starts
+pub(crate) const PARTITION_START: u32 = 1;
+/// Blocks in the FAT12 test partition
+pub(crate) const NUM_BLOCKS: u32 = 100;
+/// Where the two FATs (one block each) start
+pub(crate) const FAT_START: u32 = PARTITION_START + 1;
+/// Where the root directory starts
+pub(crate) const ROOT_DIR_START: u32 = FAT_START + 2;
+
+/// A disk held in RAM
+#[derive(Debug)]
+pub(crate) struct RamDisk {
+    pub(crate) blocks: RefCell<Vec<Block>>,
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let disk = self.blocks.borrow();
+        for (idx, block) in blocks.iter_mut().enumerate() {
+            *block = disk
+                .get(start_block_idx.0 as usize + idx)
+                .ok_or(())?
+                .clone();
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let mut disk = self.blocks.borrow_mut();
+        for (idx, block) in blocks.iter().enumerate() {
+            *disk.get_mut(start_block_idx.0 as usize + idx).ok_or(())? = block.clone();
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(self.blocks.borrow().len() as u32))
+    }
+}
+
+/// A clock which is always at 12:30:45 on 15 March 2022. The odd seconds
+/// don't fit in a FAT timestamp.
+pub(crate) struct Clock;
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 52,
+            zero_indexed_month: 2,
+            zero_indexed_day: 14,
+            hours: 12,
+            minutes: 30,
+            seconds: 45,
+        }
+    }
+}
+
+pub(crate) type TestController = Controller<RamDisk, Clock>;
+
+/// Make a controller for the given disk
+pub(crate) fn make_controller(disk: Vec<Block>) -> TestController {
+    Controller::new(
+        RamDisk {
+            blocks: RefCell::new(disk),
+        },
+        Clock,
+    )
+}
+
+/// Build a partitioned disk holding a FAT12 volume with one block per
+/// cluster, a 16 entry root directory and 96 clusters.
+pub(crate) fn make_fat12_disk() -> Vec<Block> {
+    let mut disk = vec![Block::new(); (PARTITION_START + NUM_BLOCKS) as usize + 1];
+    let mbr = &mut disk[0];
+    mbr[446 + 4] = 0x01;
+    LittleEndian::write_u32(&mut mbr[446 + 8..446 + 12], PARTITION_START);
+    LittleEndian::write_u32(&mut mbr[446 + 12..446 + 16], NUM_BLOCKS);
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    let bpb = &mut disk[PARTITION_START as usize];
+    LittleEndian::write_u16(&mut bpb[11..13], 512);
+    bpb[13] = 1;
+    LittleEndian::write_u16(&mut bpb[14..16], 1);
+    bpb[16] = 2;
+    LittleEndian::write_u16(&mut bpb[17..19], 16);
+    LittleEndian::write_u16(&mut bpb[19..21], NUM_BLOCKS as u16);
+    bpb[21] = 0xF8;
+    LittleEndian::write_u16(&mut bpb[22..24], 1);
+    bpb[38] = 0x29;
+    LittleEndian::write_u32(&mut bpb[39..43], 0x1234_5678);
+    bpb[43..54].copy_from_slice(b"FSCKTEST   ");
+    bpb[510] = 0x55;
+    bpb[511] = 0xAA;
+    for fat in FAT_START..FAT_START + 2 {
+        disk[fat as usize][0..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+    }
+    disk
+}
+
+/// Write a FAT entry in both copies of the FAT12 test volume's FAT
+pub(crate) fn set_fat(controller: &mut TestController, cluster: u32, value: u16) {
+    let mut disk = controller.device().blocks.borrow_mut();
+    for fat in FAT_START..FAT_START + 2 {
+        let mut blocks = [disk[fat as usize].clone(), Block::new()];
+        let offset = (cluster + cluster / 2) as usize;
+        write_fat12_entry(&mut blocks, Cluster(cluster), offset, value);
+        disk[fat as usize] = blocks[0].clone();
+    }
+}
+
+/// Write a raw directory entry
+pub(crate) fn set_dir_entry(
+    controller: &mut TestController,
+    block: u32,
+    idx: usize,
+    name: &[u8; 11],
+    attributes: u8,
+    cluster: u16,
+    size: u32,
+) {
+    let mut disk = controller.device().blocks.borrow_mut();
+    let entry = &mut disk[block as usize][idx * 32..(idx + 1) * 32];
+    entry.fill(0);
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    LittleEndian::write_u16(&mut entry[26..28], cluster);
+    LittleEndian::write_u32(&mut entry[28..32], size);
+}
+
+/// A `RamDisk` which makes the caller wait once before every operation
+#[cfg(feature = "async")]
+pub(crate) struct SlowRamDisk(pub(crate) RamDisk);
+
+/// Make the caller wait once, as if something else had to happen first
+#[cfg(feature = "async")]
+pub(crate) async fn yield_once() {
+    let mut yielded = false;
+    core::future::poll_fn(|_cx| {
+        if core::mem::replace(&mut yielded, true) {
+            core::task::Poll::Ready(())
+        } else {
+            core::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(feature = "async")]
+impl AsyncBlockDevice for SlowRamDisk {
+    type Error = ();
+
+    async fn read(
+        &mut self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error> {
+        yield_once().await;
+        BlockDevice::read(&self.0, blocks, start_block_idx, reason)
+    }
+
+    async fn write(
+        &mut self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Self::Error> {
+        yield_once().await;
+        BlockDevice::write(&self.0, blocks, start_block_idx)
+    }
+
+    async fn num_blocks(&mut self) -> Result<BlockCount, Self::Error> {
+        yield_once().await;
+        BlockDevice::num_blocks(&self.0)
+    }
+}
+
+/// Poll a future until it finishes, returning the result and how many
+/// times it had to wait.
+#[cfg(feature = "async")]
+pub(crate) fn run<F: core::future::Future>(future: F) -> (F::Output, usize) {
+    let mut future = core::pin::pin!(future);
+    let mut context = core::task::Context::from_waker(core::task::Waker::noop());
+    let mut waits = 0;
+    loop {
+        match future.as_mut().poll(&mut context) {
+            core::task::Poll::Ready(output) => return (output, waits),
+            core::task::Poll::Pending => waits += 1,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************